@@ -4,6 +4,8 @@ use clap::{Args, Parser};
 use url::Url;
 
 use crate::core::{AUTHOR_NAME, SERVICE_NAME};
+#[cfg(feature = "tls")]
+use crate::tls::TlsVersion;
 
 #[derive(Parser)]
 #[clap(name = SERVICE_NAME)]
@@ -137,6 +139,31 @@ pub struct ListenConfig {
     #[clap(long, default_value = "8192")]
     pub backlog: u32,
 
+    /// Maximum number of connections to accept from the listening socket in one go.
+    /// Extra connections are buffered and handed over one by one.
+    #[clap(long, default_value = "1", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_accepts_per_poll: u64,
+
+    /// Do not set TCP_NODELAY on incoming connections, i.e. keep Nagle's algorithm on.
+    /// Trades latency for bandwidth efficiency.
+    #[clap(long)]
+    pub disable_tcp_nodelay: bool,
+
+    /// Maximum read bandwidth of each incoming connection, in bytes per second.
+    /// Reads exceeding it are delayed. Unlimited if not specified.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_read_bytes_per_sec: Option<u64>,
+
+    /// Maximum number of incoming connections open at the same time.
+    /// Extra connections are closed right after being accepted. Unlimited if not specified.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_connections: Option<u64>,
+
+    /// Maximum number of incoming TCP connections open at the same time from a single IP address.
+    /// Extra connections are closed right after being accepted. Unlimited if not specified.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_connections_per_ip: Option<u64>,
+
     /// Disable HTTP2 support for outgoing connections (to replicas)
     #[clap(long)]
     pub disable_http2_client: bool,
@@ -204,6 +231,17 @@ pub struct TlsConfig {
     /// If ACME client is used (see above) - the file needs to be writeable.
     #[clap(long, default_value = "pkey.pem")]
     pub tls_pkey_path: PathBuf,
+
+    /// Comma-separated list of server names (SNI) to accept TLS connections for.
+    /// Connections with any other or without server name are rejected during the handshake.
+    /// If not specified, connections are accepted for any server name.
+    #[clap(long, value_delimiter = ',')]
+    pub allowed_sni: Vec<String>,
+
+    /// Minimum TLS protocol version to accept.
+    /// Handshakes of clients offering only lower versions are rejected.
+    #[clap(long, value_enum, default_value = "1.3")]
+    pub tls_min_version: TlsVersion,
 }
 
 #[derive(Args)]
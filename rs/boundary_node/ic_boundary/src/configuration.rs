@@ -11,7 +11,10 @@ use {
 use crate::{
     core::Run,
     metrics::{MetricParams, WithMetrics},
-    tls::{self, generate_rustls_config, load_pem, Provision, ProvisionResult, TLSCert},
+    tls::{
+        self, generate_rustls_config, load_pem, Provision, ProvisionResult, SniFilter, TLSCert,
+        TlsVersion,
+    },
 };
 
 #[non_exhaustive]
@@ -79,16 +82,22 @@ impl Configure for Configurator {
 pub struct TlsConfigurator {
     acceptor: Arc<ArcSwapOption<RustlsAcceptor>>,
     provisioner: Box<dyn Provision>,
+    sni_filter: Option<SniFilter>,
+    min_tls_version: TlsVersion,
 }
 
 impl TlsConfigurator {
     pub fn new(
         acceptor: Arc<ArcSwapOption<RustlsAcceptor>>,
         provisioner: Box<dyn Provision>,
+        sni_filter: Option<SniFilter>,
+        min_tls_version: TlsVersion,
     ) -> Self {
         Self {
             acceptor,
             provisioner,
+            sni_filter,
+            min_tls_version,
         }
     }
 
@@ -96,7 +105,8 @@ impl TlsConfigurator {
         let (certs, key) = load_pem(tls_cert.0.into_bytes(), tls_cert.1.into_bytes())
             .map_err(|e| anyhow!("unable to load PEM: {e:?}"))?;
 
-        let cfg = generate_rustls_config(certs, key)?;
+        let cfg =
+            generate_rustls_config(certs, key, self.sni_filter.clone(), self.min_tls_version)?;
         let cfg = RustlsConfig::from_config(Arc::new(cfg));
 
         // Construct new acceptor
@@ -57,12 +57,15 @@ use crate::{
         generate_stub_snapshot, generate_stub_subnet, RegistrySnapshot, SnapshotPersister,
         Snapshotter,
     },
-    socket::{TcpConnectInfo, TcpServerExt},
+    socket::{SocketMetrics, SocketTcpOptions, TcpConnectInfo, TcpServerExt},
     tls_verify::TlsVerifier,
 };
 
 #[cfg(not(feature = "tls"))]
-use {crate::socket::UnixServerExt, std::os::unix::fs::PermissionsExt};
+use {
+    crate::socket::{SocketUnixOptions, UnixConnectInfo, UnixServerExt},
+    std::os::unix::fs::PermissionsExt,
+};
 
 #[cfg(feature = "tls")]
 use {
@@ -207,11 +210,24 @@ pub async fn main(cli: Cli) -> Result<(), Error> {
     #[cfg(not(feature = "tls"))]
     let routers_http = routers_https;
 
+    let socket_metrics = SocketMetrics::new(&metrics_registry);
+
     // HTTP
     let srvs_http = cli.listen.http_port.map(|x| {
         hyper::Server::bind_tcp(
             SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), x),
-            cli.listen.backlog,
+            SocketTcpOptions {
+                backlog: cli.listen.backlog,
+                max_accepts_per_poll: cli.listen.max_accepts_per_poll as usize,
+                nodelay: !cli.listen.disable_tcp_nodelay,
+                max_read_bytes_per_sec: cli.listen.max_read_bytes_per_sec,
+                max_connections: cli.listen.max_connections.map(|x| x as usize),
+                max_connections_per_ip: cli.listen.max_connections_per_ip.map(|x| x as usize),
+                deprioritized_family: None,
+                dscp: None,
+            },
+            &socket_metrics,
+            "http",
         )
         .expect("cannot bind to the TCP socket")
         .serve(
@@ -229,9 +245,23 @@ pub async fn main(cli: Cli) -> Result<(), Error> {
             std::fs::remove_file(x).expect("unable to remove socket");
         }
 
-        let srv = hyper::Server::bind_unix(x, cli.listen.backlog)
-            .expect("cannot bind to the Unix socket")
-            .serve(routers_http.clone().into_make_service());
+        let srv = hyper::Server::bind_unix(
+            x,
+            SocketUnixOptions {
+                backlog: cli.listen.backlog,
+                max_accepts_per_poll: cli.listen.max_accepts_per_poll as usize,
+                max_read_bytes_per_sec: cli.listen.max_read_bytes_per_sec,
+                max_connections: cli.listen.max_connections.map(|x| x as usize),
+            },
+            &socket_metrics,
+            "http_unix",
+        )
+        .expect("cannot bind to the Unix socket")
+        .serve(
+            routers_http
+                .clone()
+                .into_make_service_with_connect_info::<UnixConnectInfo>(),
+        );
 
         std::fs::set_permissions(x, std::fs::Permissions::from_mode(0o666))
             .expect("unable to set permissions on socket");
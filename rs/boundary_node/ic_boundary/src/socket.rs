@@ -1,7 +1,15 @@
 use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    future::Future,
     io,
     net::{IpAddr, Ipv4Addr, SocketAddr},
     path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
 #[cfg(feature = "tls")]
@@ -10,107 +18,1239 @@ use anyhow::Error;
 use axum::extract::connect_info::Connected;
 use futures_util::ready;
 use hyper::server::{accept::Accept, Builder, Server};
+use prometheus::{
+    register_histogram_vec_with_registry, register_int_counter_vec_with_registry,
+    register_int_gauge_vec_with_registry, Histogram, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Registry,
+};
 use std::{
     pin::Pin,
     task::{Context, Poll},
 };
-use tokio::net::{TcpListener, TcpSocket, TcpStream, UnixListener, UnixSocket, UnixStream};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpSocket, TcpStream, UnixListener, UnixSocket, UnixStream},
+    sync::watch,
+    time::{sleep, Instant, Sleep},
+};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
 
 // These are used in case the peer_addr() below fails for whatever reason
 const DEFAULT_IP_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
 const DEFAULT_SOCK_ADDR: SocketAddr = SocketAddr::new(DEFAULT_IP_ADDR, 0);
 
+// Slow clients and slowloris-style attacks take seconds or more to send the first byte
+const TIME_TO_FIRST_BYTE_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+// Options for the Unix socket
+#[derive(Clone, Debug)]
+pub struct SocketUnixOptions {
+    pub backlog: u32,
+    // Up to this many connections are accepted per poll_accept() call,
+    // the extra ones are buffered and returned on subsequent calls
+    pub max_accepts_per_poll: usize,
+    // Maximum read bandwidth of each accepted connection in bytes per second, unlimited if None.
+    // The SEQPACKET messages are never split, so it's only kept on average for them
+    pub max_read_bytes_per_sec: Option<u64>,
+    // Maximum number of connections open at the same time, unlimited if None.
+    // The ones above it are closed right after being accepted
+    pub max_connections: Option<usize>,
+}
+
+impl Default for SocketUnixOptions {
+    fn default() -> Self {
+        Self {
+            backlog: 1024,
+            max_accepts_per_poll: 1,
+            max_read_bytes_per_sec: None,
+            max_connections: None,
+        }
+    }
+}
+
+// Options for the TCP socket
+#[derive(Clone, Debug)]
+pub struct SocketTcpOptions {
+    pub backlog: u32,
+    // Up to this many connections are accepted per poll_accept() call,
+    // the extra ones are buffered and returned on subsequent calls
+    pub max_accepts_per_poll: usize,
+    // Whether to set TCP_NODELAY on accepted connections (i.e. disable Nagle's algorithm)
+    pub nodelay: bool,
+    // Maximum read bandwidth of each accepted connection in bytes per second, unlimited if None
+    pub max_read_bytes_per_sec: Option<u64>,
+    // Maximum number of connections open at the same time, unlimited if None.
+    // The ones above it are closed right after being accepted
+    pub max_connections: Option<usize>,
+    // Same as above, but for the connections coming from a single IP address
+    pub max_connections_per_ip: Option<usize>,
+    // Lower limit on the open connections applied to the peers of one address family,
+    // so that it's shed first under load
+    pub deprioritized_family: Option<DeprioritizedFamily>,
+    // DSCP value (0-63) to mark the outgoing packets with, left to the system default if None.
+    // It's set on the listening socket and inherited by the connections accepted from it,
+    // so it applies to the packets sent over those connections
+    pub dscp: Option<u8>,
+}
+
+impl Default for SocketTcpOptions {
+    fn default() -> Self {
+        Self {
+            backlog: 1024,
+            max_accepts_per_poll: 1,
+            nodelay: true,
+            max_read_bytes_per_sec: None,
+            max_connections: None,
+            max_connections_per_ip: None,
+            deprioritized_family: None,
+            dscp: None,
+        }
+    }
+}
+
+// Address family of a TCP peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    // IPv4 peers of a dual-stack socket show up as IPv4-mapped IPv6 addresses
+    pub fn of(addr: &SocketAddr) -> Self {
+        match addr.ip() {
+            IpAddr::V4(_) => Self::Ipv4,
+            IpAddr::V6(ip) if ip.to_ipv4_mapped().is_some() => Self::Ipv4,
+            IpAddr::V6(_) => Self::Ipv6,
+        }
+    }
+}
+
+// The peers of `family` are only accepted while fewer than `max_connections` are open overall,
+// the remaining capacity up to the socket's `max_connections` being left to the other family
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeprioritizedFamily {
+    pub family: AddressFamily,
+    pub max_connections: usize,
+}
+
+// Outcome of accepting a connection, reported to the accept hook of the socket.
+// The peer address is only known for TCP connections
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AcceptEvent {
+    Accepted(Option<SocketAddr>),
+    // Closed because the socket had `max_connections` open
+    RejectedByLimit(Option<SocketAddr>),
+    // Closed because the peer had `max_connections_per_ip` open
+    RejectedByIpCap(Option<SocketAddr>),
+    // Closed because the peer is of the deprioritized family and its limit was reached
+    RejectedByFamily(Option<SocketAddr>),
+    // Accepting failed, so there's no connection nor peer.
+    // Also reported for the extra accepts of a poll, which don't fail the poll
+    Error(io::ErrorKind),
+}
+
+// Callback invoked on every accept decision, e.g. to keep an audit log of the connections
+pub type AcceptHook = Arc<dyn Fn(AcceptEvent) + Send + Sync>;
+
+// What the socket does with the connections still in the accept queue
+// once the shutdown is signaled
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ShutdownMode {
+    // Stop accepting right away, the queued connections get reset
+    #[default]
+    StopImmediately,
+    // Accept everything that's already queued, so it gets served, and then stop
+    DrainQueue,
+}
+
+// Progress of draining the connections, e.g. for a readiness endpoint
+// to report "draining, N connections left" until it's safe to stop the process
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DrainProgress {
+    // Whether the shutdown was signaled
+    pub shutdown_initiated: bool,
+    // Accepted connections that are still open
+    pub remaining_connections: usize,
+}
+
+impl DrainProgress {
+    // The shutdown was signaled and every connection is closed
+    pub fn is_drained(&self) -> bool {
+        self.shutdown_initiated && self.remaining_connections == 0
+    }
+}
+
+// Stops the socket from accepting once the token is cancelled
+struct ShutdownSignal {
+    mode: ShutdownMode,
+    token: CancellationToken,
+    cancelled: Pin<Box<WaitForCancellationFutureOwned>>,
+    triggered: bool,
+}
+
+impl ShutdownSignal {
+    fn new(token: CancellationToken, mode: ShutdownMode) -> Self {
+        Self {
+            mode,
+            cancelled: Box::pin(token.clone().cancelled_owned()),
+            token,
+            triggered: false,
+        }
+    }
+
+    // Unlike `triggered` this doesn't wait for the socket to be polled
+    fn is_initiated(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    // Returns None while the shutdown isn't signaled.
+    // Otherwise returns what poll_accept() should yield: either nothing, or the next
+    // connection from the queue when draining it, using `accept` to get it without blocking
+    fn poll_stop<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        accept: impl FnOnce(&mut Context<'_>) -> Poll<io::Result<T>>,
+    ) -> Option<Option<io::Result<T>>> {
+        if !self.triggered {
+            self.triggered = self.cancelled.as_mut().poll(cx).is_ready();
+        }
+        if !self.triggered {
+            return None;
+        }
+
+        match self.mode {
+            ShutdownMode::StopImmediately => Some(None),
+            // Once accepting would block the queue is empty, so stop for good
+            ShutdownMode::DrainQueue => match accept(cx) {
+                Poll::Ready(res) => Some(Some(res)),
+                Poll::Pending => {
+                    self.mode = ShutdownMode::StopImmediately;
+                    Some(None)
+                }
+            },
+        }
+    }
+}
+
+// Metrics shared by the sockets, labeled by the caller-supplied name of the listener
+// so that multiple listeners can be told apart
+#[derive(Clone)]
+pub struct SocketMetrics {
+    pub open_connections: IntGaugeVec,
+    pub time_to_first_byte: HistogramVec,
+    pub empty_connections: IntCounterVec,
+}
+
+impl SocketMetrics {
+    pub fn new(registry: &Registry) -> Self {
+        Self {
+            open_connections: register_int_gauge_vec_with_registry!(
+                "open_connections",
+                "Number of currently open incoming connections",
+                &["listener_name", "socket_type"],
+                registry
+            )
+            .unwrap(),
+
+            time_to_first_byte: register_histogram_vec_with_registry!(
+                "time_to_first_byte_seconds",
+                "Time from accepting a connection until reading its first byte",
+                &["listener_name", "socket_type"],
+                TIME_TO_FIRST_BYTE_BUCKETS.to_vec(),
+                registry
+            )
+            .unwrap(),
+
+            empty_connections: register_int_counter_vec_with_registry!(
+                "empty_connections",
+                "Number of connections closed without sending a single byte",
+                &["listener_name", "socket_type"],
+                registry
+            )
+            .unwrap(),
+        }
+    }
+}
+
+// Token bucket limiting the read bandwidth of a single connection.
+// It holds up to one second worth of bytes, so short bursts up to the rate are allowed
+struct ReadRateLimiter {
+    // Bytes per second
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+    delay: Option<Pin<Box<Sleep>>>,
+    // Whether the reads must not be cut down to the tokens, e.g. since a SEQPACKET message
+    // read into a shorter buffer is truncated. Such reads take whatever fits into the buffer
+    // and put the bucket into debt instead, delaying the following reads until it's paid back
+    whole_reads: bool,
+}
+
+impl ReadRateLimiter {
+    fn new(bytes_per_sec: u64, whole_reads: bool) -> Self {
+        let rate = bytes_per_sec.max(1) as f64;
+
+        Self {
+            rate,
+            tokens: rate,
+            last_refill: Instant::now(),
+            delay: None,
+            whole_reads,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+    }
+
+    // Returns the number of bytes that can be read now, waits while the bucket is empty or in debt
+    fn poll_acquire(&mut self, cx: &mut Context<'_>) -> Poll<usize> {
+        loop {
+            if let Some(delay) = &mut self.delay {
+                ready!(delay.as_mut().poll(cx));
+                self.delay = None;
+            }
+
+            self.refill();
+            if self.tokens >= 1.0 {
+                return Poll::Ready(self.tokens as usize);
+            }
+
+            let wait = Duration::from_secs_f64((1.0 - self.tokens) / self.rate);
+            self.delay = Some(Box::pin(sleep(wait)));
+        }
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+// Counts the currently open connections of a socket, overall and per IP address
+#[derive(Clone, Default)]
+struct ConnectionTracker {
+    count: Arc<AtomicUsize>,
+    count_per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    gauge: Option<IntGauge>,
+    time_to_first_byte: Option<Histogram>,
+    empty_connections: Option<IntCounter>,
+    max_read_bytes_per_sec: Option<u64>,
+    // Whether the reads are rate limited without being cut down, see `ReadRateLimiter`
+    whole_reads: bool,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    deprioritized_family: Option<DeprioritizedFamily>,
+    hook: Option<AcceptHook>,
+    // Broadcasts whether `max_connections` are open, if it's set
+    saturation: Option<Arc<watch::Sender<bool>>>,
+}
+
+impl ConnectionTracker {
+    fn new(
+        metrics: Option<(&SocketMetrics, &str)>,
+        socket_type: &str,
+        max_read_bytes_per_sec: Option<u64>,
+    ) -> Self {
+        Self {
+            count: Arc::new(AtomicUsize::new(0)),
+            count_per_ip: Arc::new(Mutex::new(HashMap::new())),
+            gauge: metrics.map(|(x, listener_name)| {
+                x.open_connections
+                    .with_label_values(&[listener_name, socket_type])
+            }),
+            time_to_first_byte: metrics.map(|(x, listener_name)| {
+                x.time_to_first_byte
+                    .with_label_values(&[listener_name, socket_type])
+            }),
+            empty_connections: metrics.map(|(x, listener_name)| {
+                x.empty_connections
+                    .with_label_values(&[listener_name, socket_type])
+            }),
+            max_read_bytes_per_sec,
+            whole_reads: false,
+            max_connections: None,
+            max_connections_per_ip: None,
+            deprioritized_family: None,
+            hook: None,
+            saturation: None,
+        }
+    }
+
+    fn with_limits(
+        mut self,
+        max_connections: Option<usize>,
+        max_connections_per_ip: Option<usize>,
+    ) -> Self {
+        self.max_connections = max_connections;
+        self.max_connections_per_ip = max_connections_per_ip;
+        self.saturation = max_connections.map(|_| Arc::new(watch::channel(false).0));
+        self
+    }
+
+    fn with_whole_reads(mut self) -> Self {
+        self.whole_reads = true;
+        self
+    }
+
+    fn with_deprioritized_family(
+        mut self,
+        deprioritized_family: Option<DeprioritizedFamily>,
+    ) -> Self {
+        self.deprioritized_family = deprioritized_family;
+        self
+    }
+
+    fn is_saturated(&self) -> bool {
+        self.max_connections.is_some_and(|max| self.get() >= max)
+    }
+
+    // Notifies the subscribers only when the saturation state changes
+    fn update_saturation(&self) {
+        if let Some(v) = &self.saturation {
+            let saturated = self.is_saturated();
+            v.send_if_modified(|x| std::mem::replace(x, saturated) != saturated);
+        }
+    }
+
+    fn report(&self, event: impl FnOnce() -> AcceptEvent) {
+        if let Some(hook) = &self.hook {
+            hook(event());
+        }
+    }
+
+    fn report_error(&self, err: &io::Error) {
+        self.report(|| AcceptEvent::Error(err.kind()));
+    }
+
+    #[cfg(test)]
+    fn track<S>(&self, inner: S) -> TrackedStream<S> {
+        self.track_inner(inner, None)
+    }
+
+    // Tracks the connection unless it exceeds the limits, in which case it's dropped and thus closed.
+    // The counts can't change concurrently since a socket accepts from a single task
+    fn admit<S>(&self, inner: S, peer: Option<SocketAddr>) -> Option<TrackedStream<S>> {
+        if let Some(max) = self.max_connections {
+            if self.get() >= max {
+                self.report(|| AcceptEvent::RejectedByLimit(peer));
+                return None;
+            }
+        }
+
+        // The peer address is only peeked at here, before counting the connection
+        if let (Some(peer), Some(x)) = (peer, self.deprioritized_family) {
+            if AddressFamily::of(&peer) == x.family && self.get() >= x.max_connections {
+                self.report(|| AcceptEvent::RejectedByFamily(Some(peer)));
+                return None;
+            }
+        }
+
+        let ip = match (peer, self.max_connections_per_ip) {
+            (Some(peer), Some(max)) => {
+                let mut count_per_ip = self.count_per_ip.lock().unwrap();
+                let count = count_per_ip.entry(peer.ip()).or_default();
+                if *count >= max {
+                    if *count == 0 {
+                        count_per_ip.remove(&peer.ip());
+                    }
+                    drop(count_per_ip);
+                    self.report(|| AcceptEvent::RejectedByIpCap(Some(peer)));
+                    return None;
+                }
+                *count += 1;
+                Some(peer.ip())
+            }
+            _ => None,
+        };
+
+        self.report(|| AcceptEvent::Accepted(peer));
+        let conn = self.track_inner(inner, ip);
+        self.update_saturation();
+        Some(conn)
+    }
+
+    fn track_inner<S>(&self, inner: S, ip: Option<IpAddr>) -> TrackedStream<S> {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        if let Some(v) = &self.gauge {
+            v.inc();
+        }
+
+        TrackedStream {
+            inner,
+            limiter: self
+                .max_read_bytes_per_sec
+                .map(|x| ReadRateLimiter::new(x, self.whole_reads)),
+            guard: ConnectionGuard {
+                tracker: self.clone(),
+                ip,
+                accepted_at: Instant::now(),
+                first_byte_read: false,
+            },
+        }
+    }
+
+    fn get(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+// Records the time to the first byte read from the connection.
+// Decrements the open connections count when dropped, counting the connection
+// as empty if it never sent anything
+struct ConnectionGuard {
+    tracker: ConnectionTracker,
+    // Set when the connection is counted against the per IP limit
+    ip: Option<IpAddr>,
+    accepted_at: Instant,
+    first_byte_read: bool,
+}
+
+impl ConnectionGuard {
+    fn on_read(&mut self, read: usize) {
+        if read == 0 || self.first_byte_read {
+            return;
+        }
+
+        self.first_byte_read = true;
+        if let Some(v) = &self.tracker.time_to_first_byte {
+            v.observe(self.accepted_at.elapsed().as_secs_f64());
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.tracker.count.fetch_sub(1, Ordering::Relaxed);
+        if let Some(v) = &self.tracker.gauge {
+            v.dec();
+        }
+        if let Some(ip) = self.ip {
+            let mut count_per_ip = self.tracker.count_per_ip.lock().unwrap();
+            if let Some(count) = count_per_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    count_per_ip.remove(&ip);
+                }
+            }
+        }
+        self.tracker.update_saturation();
+        if !self.first_byte_read {
+            if let Some(v) = &self.tracker.empty_connections {
+                v.inc();
+            }
+        }
+    }
+}
+
+// Accepted connection that is counted as open until it's dropped.
+// Its reads are delayed when it exceeds the read bandwidth limit, if any
+pub struct TrackedStream<S> {
+    inner: S,
+    limiter: Option<ReadRateLimiter>,
+    guard: ConnectionGuard,
+}
+
+impl<S> TrackedStream<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TrackedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(limiter) = &mut this.limiter else {
+            let filled = buf.filled().len();
+            ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+            this.guard.on_read(buf.filled().len() - filled);
+            return Poll::Ready(Ok(()));
+        };
+
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        // Read at most as many bytes as the bucket currently allows, unless the reads must be whole
+        let allowed = ready!(limiter.poll_acquire(cx));
+        let allowed = if limiter.whole_reads {
+            buf.remaining()
+        } else {
+            allowed.min(buf.remaining())
+        };
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(allowed));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+
+        let read = limited.filled().len();
+        buf.advance(read);
+        limiter.consume(read);
+        this.guard.on_read(read);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TrackedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+// Passing zero to listen() has platform-dependent behavior, so reject it explicitly
+fn validate_backlog(backlog: u32) -> Result<(), io::Error> {
+    if backlog == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "socket listen backlog must be greater than zero",
+        ));
+    }
+
+    Ok(())
+}
+
+// Step of setting up a listening socket
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SocketBindStep {
+    // Validating the options and applying them to the socket
+    Configure,
+    Create,
+    Bind,
+    Listen,
+}
+
+impl fmt::Display for SocketBindStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Configure => "configure the socket for",
+            Self::Create => "create the socket for",
+            Self::Bind => "bind to",
+            Self::Listen => "listen on",
+        })
+    }
+}
+
+// Failure to set up a listening socket, telling which address or path and which step failed.
+// The variants tell apart the most common causes, so that the operator knows what to fix
+#[derive(Debug, thiserror::Error)]
+pub enum SocketBindError {
+    #[error("cannot {step} {endpoint}, invalid options: {source}")]
+    InvalidOptions {
+        endpoint: String,
+        step: SocketBindStep,
+        source: io::Error,
+    },
+    #[error("cannot {step} {endpoint}, permission denied (ports below 1024 require CAP_NET_BIND_SERVICE, Unix sockets require write access to their directory): {source}")]
+    PermissionDenied {
+        endpoint: String,
+        step: SocketBindStep,
+        source: io::Error,
+    },
+    #[error(
+        "cannot {step} {endpoint}, address already in use (is another instance running?): {source}"
+    )]
+    AddrInUse {
+        endpoint: String,
+        step: SocketBindStep,
+        source: io::Error,
+    },
+    #[error("cannot {step} {endpoint}, invalid path (its directory must exist and the path must be short enough): {source}")]
+    InvalidPath {
+        endpoint: String,
+        step: SocketBindStep,
+        source: io::Error,
+    },
+    #[error("cannot {step} {endpoint}: {source}")]
+    Other {
+        endpoint: String,
+        step: SocketBindStep,
+        source: io::Error,
+    },
+}
+
+impl SocketBindError {
+    fn tcp(addr: SocketAddr, step: SocketBindStep, source: io::Error) -> Self {
+        Self::new(addr.to_string(), step, source, false)
+    }
+
+    fn unix(path: &Path, step: SocketBindStep, source: io::Error) -> Self {
+        Self::new(path.display().to_string(), step, source, true)
+    }
+
+    fn new(endpoint: String, step: SocketBindStep, source: io::Error, is_path: bool) -> Self {
+        match (step, source.kind()) {
+            (SocketBindStep::Configure, io::ErrorKind::InvalidInput) => Self::InvalidOptions {
+                endpoint,
+                step,
+                source,
+            },
+            (_, io::ErrorKind::PermissionDenied) => Self::PermissionDenied {
+                endpoint,
+                step,
+                source,
+            },
+            (_, io::ErrorKind::AddrInUse) => Self::AddrInUse {
+                endpoint,
+                step,
+                source,
+            },
+            (SocketBindStep::Bind, io::ErrorKind::NotFound | io::ErrorKind::InvalidInput)
+                if is_path =>
+            {
+                Self::InvalidPath {
+                    endpoint,
+                    step,
+                    source,
+                }
+            }
+            _ => Self::Other {
+                endpoint,
+                step,
+                source,
+            },
+        }
+    }
+
+    pub fn step(&self) -> SocketBindStep {
+        match self {
+            Self::InvalidOptions { step, .. }
+            | Self::PermissionDenied { step, .. }
+            | Self::AddrInUse { step, .. }
+            | Self::InvalidPath { step, .. }
+            | Self::Other { step, .. } => *step,
+        }
+    }
+}
+
+// DSCP takes the upper 6 bits of the IPv4 TOS / IPv6 traffic class byte
+const MAX_DSCP: u8 = 63;
+
+// The listening socket is dual-stack, so the IPv4 TOS is set alongside the IPv6 traffic class
+// for the connections coming from IPv4 peers to be marked as well
+fn set_dscp(socket: &TcpSocket, dscp: u8) -> Result<(), io::Error> {
+    if dscp > MAX_DSCP {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("DSCP value must be at most {MAX_DSCP}"),
+        ));
+    }
+
+    let tos = u32::from(dscp) << 2;
+    let socket = socket2::SockRef::from(socket);
+    socket.set_tclass_v6(tos)?;
+    socket.set_tos(tos)
+}
+
 // Custom extractor of ConnectInfo for our Tcp listener, default does not work with it
 #[derive(Clone)]
 pub struct TcpConnectInfo(pub SocketAddr);
 
+// The address is kept as is, i.e. for IPv6 peers the flowinfo and scope_id
+// (meaningful for link-local addresses) are preserved
+impl From<SocketAddr> for TcpConnectInfo {
+    fn from(addr: SocketAddr) -> Self {
+        Self(addr)
+    }
+}
+
 impl Connected<&TcpStream> for TcpConnectInfo {
     fn connect_info(target: &TcpStream) -> Self {
-        Self(target.peer_addr().unwrap_or(DEFAULT_SOCK_ADDR))
+        target.peer_addr().unwrap_or(DEFAULT_SOCK_ADDR).into()
     }
 }
 
+impl Connected<&TrackedStream<TcpStream>> for TcpConnectInfo {
+    fn connect_info(target: &TrackedStream<TcpStream>) -> Self {
+        Self::connect_info(target.get_ref())
+    }
+}
+
+// Custom extractor of ConnectInfo for our Unix listener.
+// Carries the peer credentials (SO_PEERCRED) of the connection, which end up
+// in the extensions of every request served over it as ConnectInfo<UnixConnectInfo>
+#[derive(Clone, Debug)]
+pub struct UnixConnectInfo {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl Connected<&UnixStream> for UnixConnectInfo {
+    fn connect_info(target: &UnixStream) -> Self {
+        let cred = target.peer_cred().ok();
+
+        Self {
+            uid: cred.map(|x| x.uid()),
+            gid: cred.map(|x| x.gid()),
+        }
+    }
+}
+
+impl Connected<&TrackedStream<UnixStream>> for UnixConnectInfo {
+    fn connect_info(target: &TrackedStream<UnixStream>) -> Self {
+        Self::connect_info(target.get_ref())
+    }
+}
+
+// Zero would never accept anything, so treat it as one
+fn validate_max_accepts_per_poll(max_accepts_per_poll: usize) -> usize {
+    max_accepts_per_poll.max(1)
+}
+
 // Unix socket handler
 pub struct SocketUnix {
     listener: UnixListener,
+    max_accepts_per_poll: usize,
+    tracker: ConnectionTracker,
+    // Connections accepted in excess during previous polls
+    pending: VecDeque<TrackedStream<UnixStream>>,
+    shutdown: Option<ShutdownSignal>,
 }
 
 impl SocketUnix {
-    pub fn bind(path: impl AsRef<Path>, backlog: u32) -> Result<Self, std::io::Error> {
-        let socket = UnixSocket::new_stream()?;
-        socket.bind(path)?;
-        let listener = socket.listen(backlog)?;
-        Ok(Self { listener })
+    pub fn bind(path: impl AsRef<Path>, backlog: u32) -> Result<Self, SocketBindError> {
+        Self::bind_with_options(
+            path,
+            SocketUnixOptions {
+                backlog,
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn bind_with_options(
+        path: impl AsRef<Path>,
+        opts: SocketUnixOptions,
+    ) -> Result<Self, SocketBindError> {
+        Self::bind_inner(path, opts, None)
+    }
+
+    pub fn bind_with_metrics(
+        path: impl AsRef<Path>,
+        opts: SocketUnixOptions,
+        metrics: &SocketMetrics,
+        listener_name: &str,
+    ) -> Result<Self, SocketBindError> {
+        Self::bind_inner(path, opts, Some((metrics, listener_name)))
+    }
+
+    fn bind_inner(
+        path: impl AsRef<Path>,
+        opts: SocketUnixOptions,
+        metrics: Option<(&SocketMetrics, &str)>,
+    ) -> Result<Self, SocketBindError> {
+        let path = path.as_ref();
+        let err = |step| move |e| SocketBindError::unix(path, step, e);
+        validate_backlog(opts.backlog).map_err(err(SocketBindStep::Configure))?;
+        let socket = UnixSocket::new_stream().map_err(err(SocketBindStep::Create))?;
+        socket.bind(path).map_err(err(SocketBindStep::Bind))?;
+        let listener = socket
+            .listen(opts.backlog)
+            .map_err(err(SocketBindStep::Listen))?;
+
+        Ok(Self {
+            listener,
+            max_accepts_per_poll: validate_max_accepts_per_poll(opts.max_accepts_per_poll),
+            tracker: ConnectionTracker::new(metrics, "unix", opts.max_read_bytes_per_sec)
+                .with_limits(opts.max_connections, None),
+            pending: VecDeque::new(),
+            shutdown: None,
+        })
+    }
+
+    // Stops accepting once the token is cancelled, handling the queued connections according to `mode`
+    pub fn with_shutdown(mut self, token: CancellationToken, mode: ShutdownMode) -> Self {
+        self.shutdown = Some(ShutdownSignal::new(token, mode));
+        self
+    }
+
+    // Invokes `hook` on every accept decision
+    pub fn with_accept_hook(mut self, hook: AcceptHook) -> Self {
+        self.tracker.hook = Some(hook);
+        self
+    }
+
+    // Number of accepted connections that are still open
+    pub fn open_connections(&self) -> usize {
+        self.tracker.get()
+    }
+
+    // Whether the shutdown was signaled and how many connections are left to drain
+    pub fn drain_progress(&self) -> DrainProgress {
+        drain_progress(self.shutdown.as_ref(), &self.tracker)
+    }
+
+    // Whether `max_connections` are open, so the new ones are rejected
+    pub fn is_saturated(&self) -> bool {
+        self.tracker.is_saturated()
+    }
+
+    // Receives the saturation state whenever it changes, e.g. to report being at capacity
+    // in a health check so that the load balancer routes elsewhere. None if `max_connections` isn't set
+    pub fn saturation(&self) -> Option<watch::Receiver<bool>> {
+        self.tracker.saturation.as_ref().map(|x| x.subscribe())
     }
 }
 
 impl Accept for SocketUnix {
-    type Conn = UnixStream;
+    type Conn = TrackedStream<UnixStream>;
     type Error = io::Error;
 
     fn poll_accept(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
-        let conn = ready!(self.listener.poll_accept(cx))?.0;
+        let this = self.get_mut();
+        if let Some(conn) = this.pending.pop_front() {
+            return Poll::Ready(Some(Ok(conn)));
+        }
+
+        if let Some(shutdown) = &mut this.shutdown {
+            let listener = &this.listener;
+            match shutdown.poll_stop(cx, |cx| listener.poll_accept(cx)) {
+                None => {}
+                Some(None) => return Poll::Ready(None),
+                Some(Some(Err(e))) => {
+                    this.tracker.report_error(&e);
+                    return Poll::Ready(Some(Err(e)));
+                }
+                Some(Some(Ok((conn, _)))) => {
+                    return match this.tracker.admit(conn, None) {
+                        Some(conn) => Poll::Ready(Some(Ok(conn))),
+                        // Rejected, carry on draining
+                        None => {
+                            cx.waker().wake_by_ref();
+                            Poll::Pending
+                        }
+                    };
+                }
+            }
+        }
+
+        let conn = loop {
+            let conn = match ready!(this.listener.poll_accept(cx)) {
+                Ok((conn, _)) => conn,
+                Err(e) => {
+                    this.tracker.report_error(&e);
+                    return Poll::Ready(Some(Err(e)));
+                }
+            };
+            if let Some(conn) = this.tracker.admit(conn, None) {
+                break conn;
+            }
+        };
+
+        // Drain more of the accept queue while it's ready
+        let listener = &this.listener;
+        accept_more(
+            &this.tracker,
+            &mut this.pending,
+            this.max_accepts_per_poll,
+            cx,
+            |cx| listener.poll_accept(cx).map_ok(|(conn, _)| (conn, None)),
+        );
+
         Poll::Ready(Some(Ok(conn)))
     }
 }
 
+// Accepts more connections while the accept queue is ready, up to `max_accepts_per_poll`
+// in total including the one accepted already. An error only ends the batch: it's reported,
+// but not returned, since the connections accepted so far are still to be served
+fn accept_more<S>(
+    tracker: &ConnectionTracker,
+    pending: &mut VecDeque<TrackedStream<S>>,
+    max_accepts_per_poll: usize,
+    cx: &mut Context<'_>,
+    mut poll_accept: impl FnMut(&mut Context<'_>) -> Poll<io::Result<(S, Option<SocketAddr>)>>,
+) {
+    while pending.len() + 1 < max_accepts_per_poll {
+        match poll_accept(cx) {
+            Poll::Ready(Ok((conn, peer))) => {
+                if let Some(conn) = tracker.admit(conn, peer) {
+                    pending.push_back(conn);
+                }
+            }
+            Poll::Ready(Err(e)) => {
+                tracker.report_error(&e);
+                break;
+            }
+            Poll::Pending => break,
+        }
+    }
+}
+
+fn drain_progress(shutdown: Option<&ShutdownSignal>, tracker: &ConnectionTracker) -> DrainProgress {
+    DrainProgress {
+        shutdown_initiated: shutdown.is_some_and(|x| x.is_initiated()),
+        remaining_connections: tracker.get(),
+    }
+}
+
 // TCP socket handler
 pub struct SocketTcp {
     listener: TcpListener,
+    max_accepts_per_poll: usize,
+    nodelay: bool,
+    tracker: ConnectionTracker,
+    // Connections accepted in excess during previous polls
+    pending: VecDeque<TrackedStream<TcpStream>>,
+    shutdown: Option<ShutdownSignal>,
 }
 
 impl SocketTcp {
-    pub fn bind(addr: SocketAddr, backlog: u32) -> Result<Self, std::io::Error> {
-        let socket = TcpSocket::new_v6()?;
-        socket.bind(addr)?;
-        socket.set_keepalive(true)?;
-        let listener = socket.listen(backlog)?;
-        Ok(Self { listener })
+    pub fn bind(addr: SocketAddr, backlog: u32) -> Result<Self, SocketBindError> {
+        Self::bind_with_options(
+            addr,
+            SocketTcpOptions {
+                backlog,
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn bind_with_options(
+        addr: SocketAddr,
+        opts: SocketTcpOptions,
+    ) -> Result<Self, SocketBindError> {
+        Self::bind_inner(addr, opts, None)
+    }
+
+    pub fn bind_with_metrics(
+        addr: SocketAddr,
+        opts: SocketTcpOptions,
+        metrics: &SocketMetrics,
+        listener_name: &str,
+    ) -> Result<Self, SocketBindError> {
+        Self::bind_inner(addr, opts, Some((metrics, listener_name)))
+    }
+
+    fn bind_inner(
+        addr: SocketAddr,
+        opts: SocketTcpOptions,
+        metrics: Option<(&SocketMetrics, &str)>,
+    ) -> Result<Self, SocketBindError> {
+        let err = |step| move |e| SocketBindError::tcp(addr, step, e);
+        validate_backlog(opts.backlog).map_err(err(SocketBindStep::Configure))?;
+        let socket = TcpSocket::new_v6().map_err(err(SocketBindStep::Create))?;
+        if let Some(dscp) = opts.dscp {
+            set_dscp(&socket, dscp).map_err(err(SocketBindStep::Configure))?;
+        }
+        socket.bind(addr).map_err(err(SocketBindStep::Bind))?;
+        socket
+            .set_keepalive(true)
+            .map_err(err(SocketBindStep::Configure))?;
+        let listener = socket
+            .listen(opts.backlog)
+            .map_err(err(SocketBindStep::Listen))?;
+
+        Ok(Self {
+            listener,
+            max_accepts_per_poll: validate_max_accepts_per_poll(opts.max_accepts_per_poll),
+            nodelay: opts.nodelay,
+            tracker: ConnectionTracker::new(metrics, "tcp", opts.max_read_bytes_per_sec)
+                .with_limits(opts.max_connections, opts.max_connections_per_ip)
+                .with_deprioritized_family(opts.deprioritized_family),
+            pending: VecDeque::new(),
+            shutdown: None,
+        })
+    }
+
+    // Stops accepting once the token is cancelled, handling the queued connections according to `mode`
+    pub fn with_shutdown(mut self, token: CancellationToken, mode: ShutdownMode) -> Self {
+        self.shutdown = Some(ShutdownSignal::new(token, mode));
+        self
+    }
+
+    // Invokes `hook` on every accept decision
+    pub fn with_accept_hook(mut self, hook: AcceptHook) -> Self {
+        self.tracker.hook = Some(hook);
+        self
+    }
+
+    // Number of accepted connections that are still open
+    pub fn open_connections(&self) -> usize {
+        self.tracker.get()
+    }
+
+    // Whether the shutdown was signaled and how many connections are left to drain
+    pub fn drain_progress(&self) -> DrainProgress {
+        drain_progress(self.shutdown.as_ref(), &self.tracker)
+    }
+
+    // Whether `max_connections` are open, so the new ones are rejected
+    pub fn is_saturated(&self) -> bool {
+        self.tracker.is_saturated()
+    }
+
+    // Receives the saturation state whenever it changes, e.g. to report being at capacity
+    // in a health check so that the load balancer routes elsewhere. None if `max_connections` isn't set
+    pub fn saturation(&self) -> Option<watch::Receiver<bool>> {
+        self.tracker.saturation.as_ref().map(|x| x.subscribe())
     }
 }
 
 impl Accept for SocketTcp {
-    type Conn = TcpStream;
+    type Conn = TrackedStream<TcpStream>;
     type Error = io::Error;
 
     fn poll_accept(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
-        let conn = ready!(self.listener.poll_accept(cx))?.0;
-        conn.set_nodelay(true)?;
+        let this = self.get_mut();
+        if let Some(conn) = this.pending.pop_front() {
+            return Poll::Ready(Some(Ok(conn)));
+        }
+
+        if let Some(shutdown) = &mut this.shutdown {
+            let listener = &this.listener;
+            match shutdown.poll_stop(cx, |cx| listener.poll_accept(cx)) {
+                None => {}
+                Some(None) => return Poll::Ready(None),
+                Some(Some(Err(e))) => {
+                    this.tracker.report_error(&e);
+                    return Poll::Ready(Some(Err(e)));
+                }
+                Some(Some(Ok((conn, peer)))) => {
+                    if this.nodelay {
+                        set_nodelay(&conn, &this.tracker);
+                    }
+                    return match this.tracker.admit(conn, Some(peer)) {
+                        Some(conn) => Poll::Ready(Some(Ok(conn))),
+                        // Rejected, carry on draining
+                        None => {
+                            cx.waker().wake_by_ref();
+                            Poll::Pending
+                        }
+                    };
+                }
+            }
+        }
+
+        let conn = loop {
+            let (conn, peer) = match ready!(this.listener.poll_accept(cx)) {
+                Ok(x) => x,
+                Err(e) => {
+                    this.tracker.report_error(&e);
+                    return Poll::Ready(Some(Err(e)));
+                }
+            };
+            if this.nodelay {
+                set_nodelay(&conn, &this.tracker);
+            }
+            if let Some(conn) = this.tracker.admit(conn, Some(peer)) {
+                break conn;
+            }
+        };
+
+        // Drain more of the accept queue while it's ready
+        let (listener, nodelay, tracker) = (&this.listener, this.nodelay, &this.tracker);
+        accept_more(
+            &this.tracker,
+            &mut this.pending,
+            this.max_accepts_per_poll,
+            cx,
+            |cx| {
+                listener.poll_accept(cx).map_ok(|(conn, peer)| {
+                    if nodelay {
+                        set_nodelay(&conn, tracker);
+                    }
+                    (conn, Some(peer))
+                })
+            },
+        );
+
         Poll::Ready(Some(Ok(conn)))
     }
 }
 
+// Failing to set the option is not a reason to drop the connection, nor to stop accepting,
+// so the error is only reported
+fn set_nodelay(conn: &TcpStream, tracker: &ConnectionTracker) {
+    if let Err(e) = conn.set_nodelay(true) {
+        tracker.report_error(&e);
+    }
+}
+
 // Convenience methods for constructing a Hyper Server listening on TCP/Unix sockets with a backlog set
 pub trait UnixServerExt {
-    fn bind_unix(path: impl AsRef<Path>, backlog: u32) -> Result<Builder<SocketUnix>, io::Error>;
+    fn bind_unix(
+        path: impl AsRef<Path>,
+        opts: SocketUnixOptions,
+        metrics: &SocketMetrics,
+        listener_name: &str,
+    ) -> Result<Builder<SocketUnix>, SocketBindError>;
 }
 
 pub trait TcpServerExt {
-    fn bind_tcp(addr: SocketAddr, backlog: u32) -> Result<Builder<SocketTcp>, io::Error>;
+    fn bind_tcp(
+        addr: SocketAddr,
+        opts: SocketTcpOptions,
+        metrics: &SocketMetrics,
+        listener_name: &str,
+    ) -> Result<Builder<SocketTcp>, SocketBindError>;
 }
 
 impl UnixServerExt for Server<SocketUnix, ()> {
-    fn bind_unix(path: impl AsRef<Path>, backlog: u32) -> Result<Builder<SocketUnix>, io::Error> {
-        let incoming = SocketUnix::bind(path, backlog)?;
+    fn bind_unix(
+        path: impl AsRef<Path>,
+        opts: SocketUnixOptions,
+        metrics: &SocketMetrics,
+        listener_name: &str,
+    ) -> Result<Builder<SocketUnix>, SocketBindError> {
+        let incoming = SocketUnix::bind_with_metrics(path, opts, metrics, listener_name)?;
         Ok(Server::builder(incoming))
     }
 }
 
 impl TcpServerExt for Server<SocketTcp, ()> {
-    fn bind_tcp(addr: SocketAddr, backlog: u32) -> Result<Builder<SocketTcp>, io::Error> {
-        let incoming = SocketTcp::bind(addr, backlog)?;
+    fn bind_tcp(
+        addr: SocketAddr,
+        opts: SocketTcpOptions,
+        metrics: &SocketMetrics,
+        listener_name: &str,
+    ) -> Result<Builder<SocketTcp>, SocketBindError> {
+        let incoming = SocketTcp::bind_with_metrics(addr, opts, metrics, listener_name)?;
         Ok(Server::builder(incoming))
     }
 }
 
 #[cfg(feature = "tls")]
 pub fn listen_tcp_backlog(addr: SocketAddr, backlog: u32) -> Result<std::net::TcpListener, Error> {
+    validate_backlog(backlog)?;
+
     // Create tokio TcpListener that can set the backlog
     let socket = TcpSocket::new_v6()?;
     socket.bind(addr)?;
@@ -123,3 +1263,12 @@ pub fn listen_tcp_backlog(addr: SocketAddr, backlog: u32) -> Result<std::net::Tc
 
     Ok(listener)
 }
+
+#[cfg(target_os = "linux")]
+pub mod seqpacket;
+
+#[cfg(feature = "tls")]
+pub mod sniff;
+
+#[cfg(test)]
+pub mod test;
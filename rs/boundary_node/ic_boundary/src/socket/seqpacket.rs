@@ -0,0 +1,265 @@
+// Unix SOCK_SEQPACKET sockets, which preserve the message boundaries.
+// Tokio has no native support for them, so they're built on top of socket2 and AsyncFd
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::Shutdown,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_util::ready;
+use hyper::server::accept::Accept;
+use socket2::{Domain, SockAddr, Socket, Type};
+use tokio::{
+    io::{unix::AsyncFd, AsyncRead, AsyncWrite, ReadBuf},
+    sync::watch,
+};
+use tokio_util::sync::CancellationToken;
+
+use super::{
+    accept_more, drain_progress, validate_backlog, validate_max_accepts_per_poll, AcceptHook,
+    ConnectionTracker, DrainProgress, ShutdownMode, ShutdownSignal, SocketBindError,
+    SocketBindStep, SocketMetrics, SocketUnix, SocketUnixOptions, TrackedStream,
+};
+
+// Accepted SEQPACKET connection.
+// Every read returns a single message (truncated to the buffer size if it doesn't fit)
+// and every write sends a single message
+pub struct SeqpacketStream {
+    inner: AsyncFd<Socket>,
+}
+
+impl SeqpacketStream {
+    fn new(socket: Socket) -> Result<Self, io::Error> {
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            inner: AsyncFd::new(socket)?,
+        })
+    }
+
+    pub fn get_ref(&self) -> &Socket {
+        self.inner.get_ref()
+    }
+}
+
+impl AsyncRead for SeqpacketStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.inner.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+
+            match guard.try_io(|x| x.get_ref().read(unfilled)) {
+                Ok(res) => {
+                    buf.advance(res?);
+                    return Poll::Ready(Ok(()));
+                }
+                // Readiness was cleared, wait for the next one
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for SeqpacketStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.inner.poll_write_ready(cx))?;
+
+            match guard.try_io(|x| x.get_ref().write(buf)) {
+                Ok(res) => return Poll::Ready(res),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.inner.get_ref().shutdown(Shutdown::Write))
+    }
+}
+
+// Unix SOCK_SEQPACKET socket handler
+pub struct SocketUnixSeqpacket {
+    listener: AsyncFd<Socket>,
+    max_accepts_per_poll: usize,
+    tracker: ConnectionTracker,
+    // Connections accepted in excess during previous polls
+    pending: VecDeque<TrackedStream<SeqpacketStream>>,
+    shutdown: Option<ShutdownSignal>,
+}
+
+impl SocketUnix {
+    // Binds a SOCK_SEQPACKET socket instead of a stream one
+    pub fn bind_seqpacket(
+        path: impl AsRef<Path>,
+        opts: SocketUnixOptions,
+    ) -> Result<SocketUnixSeqpacket, SocketBindError> {
+        SocketUnixSeqpacket::bind_inner(path, opts, None)
+    }
+
+    pub fn bind_seqpacket_with_metrics(
+        path: impl AsRef<Path>,
+        opts: SocketUnixOptions,
+        metrics: &SocketMetrics,
+        listener_name: &str,
+    ) -> Result<SocketUnixSeqpacket, SocketBindError> {
+        SocketUnixSeqpacket::bind_inner(path, opts, Some((metrics, listener_name)))
+    }
+}
+
+impl SocketUnixSeqpacket {
+    fn bind_inner(
+        path: impl AsRef<Path>,
+        opts: SocketUnixOptions,
+        metrics: Option<(&SocketMetrics, &str)>,
+    ) -> Result<Self, SocketBindError> {
+        let path = path.as_ref();
+        let err = |step| move |e| SocketBindError::unix(path, step, e);
+        validate_backlog(opts.backlog).map_err(err(SocketBindStep::Configure))?;
+        // socket2 sets SOCK_CLOEXEC on Linux by itself
+        let socket = Socket::new(Domain::UNIX, Type::SEQPACKET, None)
+            .map_err(err(SocketBindStep::Create))?;
+        let addr = SockAddr::unix(path).map_err(err(SocketBindStep::Bind))?;
+        socket.bind(&addr).map_err(err(SocketBindStep::Bind))?;
+        // Anything above i32::MAX is capped by the kernel anyway
+        socket
+            .listen(opts.backlog.min(i32::MAX as u32) as i32)
+            .map_err(err(SocketBindStep::Listen))?;
+        socket
+            .set_nonblocking(true)
+            .map_err(err(SocketBindStep::Configure))?;
+
+        Ok(Self {
+            listener: AsyncFd::new(socket).map_err(err(SocketBindStep::Configure))?,
+            max_accepts_per_poll: validate_max_accepts_per_poll(opts.max_accepts_per_poll),
+            // Cutting a read down to the rate limit would truncate the message
+            tracker: ConnectionTracker::new(metrics, "unix_seqpacket", opts.max_read_bytes_per_sec)
+                .with_limits(opts.max_connections, None)
+                .with_whole_reads(),
+            pending: VecDeque::new(),
+            shutdown: None,
+        })
+    }
+
+    // Stops accepting once the token is cancelled, handling the queued connections according to `mode`
+    pub fn with_shutdown(mut self, token: CancellationToken, mode: ShutdownMode) -> Self {
+        self.shutdown = Some(ShutdownSignal::new(token, mode));
+        self
+    }
+
+    // Invokes `hook` on every accept decision
+    pub fn with_accept_hook(mut self, hook: AcceptHook) -> Self {
+        self.tracker.hook = Some(hook);
+        self
+    }
+
+    // Number of accepted connections that are still open
+    pub fn open_connections(&self) -> usize {
+        self.tracker.get()
+    }
+
+    // Whether the shutdown was signaled and how many connections are left to drain
+    pub fn drain_progress(&self) -> DrainProgress {
+        drain_progress(self.shutdown.as_ref(), &self.tracker)
+    }
+
+    // Whether `max_connections` are open, so the new ones are rejected
+    pub fn is_saturated(&self) -> bool {
+        self.tracker.is_saturated()
+    }
+
+    // Receives the saturation state whenever it changes. None if `max_connections` isn't set
+    pub fn saturation(&self) -> Option<watch::Receiver<bool>> {
+        self.tracker.saturation.as_ref().map(|x| x.subscribe())
+    }
+
+    fn poll_accept_one(
+        listener: &AsyncFd<Socket>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<SeqpacketStream>> {
+        loop {
+            let mut guard = ready!(listener.poll_read_ready(cx))?;
+
+            match guard.try_io(|x| x.get_ref().accept()) {
+                Ok(res) => return Poll::Ready(res.and_then(|(x, _)| SeqpacketStream::new(x))),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl Accept for SocketUnixSeqpacket {
+    type Conn = TrackedStream<SeqpacketStream>;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+        if let Some(conn) = this.pending.pop_front() {
+            return Poll::Ready(Some(Ok(conn)));
+        }
+
+        if let Some(shutdown) = &mut this.shutdown {
+            let listener = &this.listener;
+            match shutdown.poll_stop(cx, |cx| Self::poll_accept_one(listener, cx)) {
+                None => {}
+                Some(None) => return Poll::Ready(None),
+                Some(Some(Err(e))) => {
+                    this.tracker.report_error(&e);
+                    return Poll::Ready(Some(Err(e)));
+                }
+                Some(Some(Ok(conn))) => {
+                    return match this.tracker.admit(conn, None) {
+                        Some(conn) => Poll::Ready(Some(Ok(conn))),
+                        // Rejected, carry on draining
+                        None => {
+                            cx.waker().wake_by_ref();
+                            Poll::Pending
+                        }
+                    };
+                }
+            }
+        }
+
+        let conn = loop {
+            let conn = match ready!(Self::poll_accept_one(&this.listener, cx)) {
+                Ok(conn) => conn,
+                Err(e) => {
+                    this.tracker.report_error(&e);
+                    return Poll::Ready(Some(Err(e)));
+                }
+            };
+            if let Some(conn) = this.tracker.admit(conn, None) {
+                break conn;
+            }
+        };
+
+        // Drain more of the accept queue while it's ready
+        let listener = &this.listener;
+        accept_more(
+            &this.tracker,
+            &mut this.pending,
+            this.max_accepts_per_poll,
+            cx,
+            |cx| Self::poll_accept_one(listener, cx).map_ok(|conn| (conn, None)),
+        );
+
+        Poll::Ready(Some(Ok(conn)))
+    }
+}
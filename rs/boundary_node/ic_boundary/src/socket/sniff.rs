@@ -0,0 +1,260 @@
+// Serving plaintext and TLS on the same port to ease the migration between them.
+// The first byte of each connection tells whether it starts with a TLS ClientHello,
+// in which case the connection is wrapped in TLS, otherwise it's served as plaintext
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::extract::connect_info::Connected;
+use futures_util::{future::BoxFuture, stream::FuturesUnordered, StreamExt};
+use hyper::server::accept::Accept;
+use rustls::ServerConfig;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    time::timeout,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+use super::{DrainProgress, SocketTcp, TcpConnectInfo, TrackedStream};
+
+// Type of the TLS record carrying handshake messages, the ClientHello being the first one
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+
+// Stream with the bytes already read from it put back in front
+pub struct PeekedStream<S> {
+    peeked: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> PeekedStream<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PeekedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.pos < this.peeked.len() {
+            let len = (this.peeked.len() - this.pos).min(buf.remaining());
+            buf.put_slice(&this.peeked[this.pos..this.pos + len]);
+            this.pos += len;
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PeekedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// Connection that turned out to be either plaintext or TLS
+pub enum SniffedStream<S> {
+    Plain(PeekedStream<S>),
+    Tls(Box<TlsStream<PeekedStream<S>>>),
+}
+
+impl<S> SniffedStream<S> {
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Self::Tls(_))
+    }
+
+    pub fn get_ref(&self) -> &S {
+        match self {
+            Self::Plain(x) => x.get_ref(),
+            Self::Tls(x) => x.get_ref().0.get_ref(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for SniffedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(x) => Pin::new(x).poll_read(cx, buf),
+            Self::Tls(x) => Pin::new(x.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for SniffedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(x) => Pin::new(x).poll_write(cx, buf),
+            Self::Tls(x) => Pin::new(x.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(x) => Pin::new(x).poll_flush(cx),
+            Self::Tls(x) => Pin::new(x.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(x) => Pin::new(x).poll_shutdown(cx),
+            Self::Tls(x) => Pin::new(x.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+impl Connected<&SniffedStream<TrackedStream<TcpStream>>> for TcpConnectInfo {
+    fn connect_info(target: &SniffedStream<TrackedStream<TcpStream>>) -> Self {
+        Self::connect_info(target.get_ref())
+    }
+}
+
+// Reads the first byte of the connection and wraps it in TLS if it starts a handshake.
+// Both have to happen within `handshake_timeout`, so that connections sending nothing don't linger
+pub async fn sniff<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    acceptor: TlsAcceptor,
+    handshake_timeout: Duration,
+) -> io::Result<SniffedStream<S>> {
+    let sniff = async move {
+        let mut first = [0u8; 1];
+        if stream.read(&mut first).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before sending anything",
+            ));
+        }
+
+        let stream = PeekedStream {
+            peeked: first.to_vec(),
+            pos: 0,
+            inner: stream,
+        };
+
+        if first[0] == TLS_HANDSHAKE_RECORD {
+            Ok(SniffedStream::Tls(Box::new(acceptor.accept(stream).await?)))
+        } else {
+            Ok(SniffedStream::Plain(stream))
+        }
+    };
+
+    timeout(handshake_timeout, sniff).await.map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            "connection didn't complete the handshake in time",
+        )
+    })?
+}
+
+type SniffFuture = BoxFuture<'static, io::Result<SniffedStream<TrackedStream<TcpStream>>>>;
+
+// TCP socket handler serving both plaintext and TLS connections
+pub struct SocketTcpSniffing {
+    inner: SocketTcp,
+    acceptor: TlsAcceptor,
+    handshake_timeout: Duration,
+    // Connections accepted but not yet sniffed
+    sniffing: FuturesUnordered<SniffFuture>,
+    stopped: bool,
+}
+
+impl SocketTcp {
+    // Tells plaintext and TLS connections apart by their first byte and serves both,
+    // the ones not sending it or not completing the TLS handshake within `handshake_timeout` are dropped
+    pub fn with_tls_sniffing(
+        self,
+        tls_config: Arc<ServerConfig>,
+        handshake_timeout: Duration,
+    ) -> SocketTcpSniffing {
+        SocketTcpSniffing {
+            inner: self,
+            acceptor: TlsAcceptor::from(tls_config),
+            handshake_timeout,
+            sniffing: FuturesUnordered::new(),
+            stopped: false,
+        }
+    }
+}
+
+impl SocketTcpSniffing {
+    pub fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.inner.listener.local_addr()
+    }
+
+    // Number of accepted connections that are still open, including the ones being sniffed
+    pub fn open_connections(&self) -> usize {
+        self.inner.open_connections()
+    }
+
+    // Whether the shutdown was signaled and how many connections are left to drain,
+    // including the ones being sniffed
+    pub fn drain_progress(&self) -> DrainProgress {
+        self.inner.drain_progress()
+    }
+}
+
+impl Accept for SocketTcpSniffing {
+    type Conn = SniffedStream<TrackedStream<TcpStream>>;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+
+        // Start sniffing everything that's ready to be accepted
+        while !this.stopped {
+            match Pin::new(&mut this.inner).poll_accept(cx) {
+                Poll::Ready(Some(Ok(conn))) => this.sniffing.push(Box::pin(sniff(
+                    conn,
+                    this.acceptor.clone(),
+                    this.handshake_timeout,
+                ))),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => this.stopped = true,
+                Poll::Pending => break,
+            }
+        }
+
+        loop {
+            match this.sniffing.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(conn))) => return Poll::Ready(Some(Ok(conn))),
+                // Failing a single connection must not stop the server, so it's just dropped
+                Poll::Ready(Some(Err(_))) => continue,
+                Poll::Ready(None) if this.stopped => return Poll::Ready(None),
+                _ => return Poll::Pending,
+            }
+        }
+    }
+}
@@ -0,0 +1,1148 @@
+use super::*;
+
+use std::net::{Ipv6Addr, SocketAddrV6};
+
+use axum::{extract::ConnectInfo, routing::get, Router};
+use hyper::{Body, Request};
+
+#[tokio::test]
+async fn test_bind_tcp_zero_backlog() {
+    let addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0);
+
+    let err = SocketTcp::bind(addr, 0).err().unwrap();
+    assert!(matches!(err, SocketBindError::InvalidOptions { .. }));
+    assert!(err
+        .to_string()
+        .contains("backlog must be greater than zero"));
+
+    assert!(SocketTcp::bind(addr, 1).is_ok());
+}
+
+#[tokio::test]
+async fn test_bind_unix_zero_backlog() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("socket");
+
+    let err = SocketUnix::bind(&path, 0).err().unwrap();
+    assert!(matches!(err, SocketBindError::InvalidOptions { .. }));
+    assert!(err
+        .to_string()
+        .contains("backlog must be greater than zero"));
+    // Validation happens before the socket file is created
+    assert!(!path.exists());
+
+    assert!(SocketUnix::bind(&path, 1).is_ok());
+}
+
+#[tokio::test]
+async fn test_bind_tcp_addr_in_use() {
+    let socket = SocketTcp::bind(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0), 1).unwrap();
+    let addr = socket.listener.local_addr().unwrap();
+
+    let err = SocketTcp::bind(addr, 1).err().unwrap();
+    assert!(matches!(err, SocketBindError::AddrInUse { .. }));
+    assert_eq!(err.step(), SocketBindStep::Bind);
+    let msg = err.to_string();
+    assert!(msg.contains(&format!("cannot bind to {addr}")));
+    assert!(msg.contains("address already in use"));
+}
+
+#[tokio::test]
+async fn test_bind_tcp_privileged_port() {
+    let addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 1);
+
+    // Whether binding is allowed depends on the privileges of the process running the test
+    match SocketTcp::bind(addr, 1) {
+        Ok(_) => {}
+        Err(err) => {
+            assert!(matches!(err, SocketBindError::PermissionDenied { .. }));
+            assert_eq!(err.step(), SocketBindStep::Bind);
+            assert!(err.to_string().contains("CAP_NET_BIND_SERVICE"));
+        }
+    }
+
+    // The classification doesn't depend on them
+    let err = SocketBindError::tcp(
+        addr,
+        SocketBindStep::Bind,
+        io::ErrorKind::PermissionDenied.into(),
+    );
+    assert!(matches!(err, SocketBindError::PermissionDenied { .. }));
+    assert!(err
+        .to_string()
+        .starts_with(&format!("cannot bind to {addr}, permission denied")));
+}
+
+#[tokio::test]
+async fn test_bind_unix_invalid_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("missing").join("socket");
+
+    let err = SocketUnix::bind(&path, 1).err().unwrap();
+    assert!(matches!(err, SocketBindError::InvalidPath { .. }));
+    assert!(err.to_string().contains(&path.display().to_string()));
+}
+
+#[tokio::test]
+async fn test_unix_connect_info_carries_peer_credentials() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("socket");
+
+    let router = Router::new().route(
+        "/",
+        get(
+            |ConnectInfo(info): ConnectInfo<UnixConnectInfo>| async move {
+                format!("{}:{}", info.uid.unwrap(), info.gid.unwrap())
+            },
+        ),
+    );
+
+    let srv = Server::bind_unix(
+        &path,
+        SocketUnixOptions::default(),
+        &SocketMetrics::new(&Registry::new()),
+        "test",
+    )
+    .unwrap()
+    .serve(router.into_make_service_with_connect_info::<UnixConnectInfo>());
+    tokio::spawn(srv);
+
+    let stream = UnixStream::connect(&path).await.unwrap();
+    // Both ends of the connection belong to this process
+    let cred = stream.peer_cred().unwrap();
+
+    let (mut sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+    tokio::spawn(conn);
+
+    let response = sender
+        .send_request(Request::get("/").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+    assert_eq!(body, format!("{}:{}", cred.uid(), cred.gid()));
+}
+
+// Drains `count` connections from the socket, returns the number of times
+// the listener itself had to be polled
+async fn drain_accepts<A: Accept + Unpin>(
+    socket: &mut A,
+    count: usize,
+    pending: impl Fn(&A) -> bool,
+) -> usize
+where
+    A::Error: std::fmt::Debug,
+{
+    let mut listener_polls = 0;
+    for _ in 0..count {
+        if !pending(socket) {
+            listener_polls += 1;
+        }
+        std::future::poll_fn(|cx| Pin::new(&mut *socket).poll_accept(cx))
+            .await
+            .unwrap()
+            .unwrap();
+    }
+    listener_polls
+}
+
+#[tokio::test]
+async fn test_tcp_max_accepts_per_poll() {
+    const CONNS: usize = 8;
+
+    for (max_accepts_per_poll, expected_polls) in [(1, CONNS), (4, 2)] {
+        let mut socket = SocketTcp::bind_with_options(
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0),
+            SocketTcpOptions {
+                max_accepts_per_poll,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let addr = socket.listener.local_addr().unwrap();
+
+        let mut clients = vec![];
+        for _ in 0..CONNS {
+            clients.push(TcpStream::connect(addr).await.unwrap());
+        }
+
+        let polls = drain_accepts(&mut socket, CONNS, |s| !s.pending.is_empty()).await;
+        assert_eq!(polls, expected_polls);
+        assert!(socket.pending.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn test_unix_max_accepts_per_poll() {
+    const CONNS: usize = 8;
+
+    let dir = tempfile::tempdir().unwrap();
+
+    for (max_accepts_per_poll, expected_polls) in [(1, CONNS), (4, 2)] {
+        let path = dir.path().join(format!("socket{max_accepts_per_poll}"));
+        let mut socket = SocketUnix::bind_with_options(
+            &path,
+            SocketUnixOptions {
+                max_accepts_per_poll,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mut clients = vec![];
+        for _ in 0..CONNS {
+            clients.push(UnixStream::connect(&path).await.unwrap());
+        }
+
+        let polls = drain_accepts(&mut socket, CONNS, |s| !s.pending.is_empty()).await;
+        assert_eq!(polls, expected_polls);
+        assert!(socket.pending.is_empty());
+    }
+}
+
+#[test]
+fn test_accept_more_stops_on_error() {
+    let tracker = ConnectionTracker::new(None, "mock", None);
+    let mut pending = VecDeque::new();
+    let mut results = VecDeque::from([
+        Poll::Ready(Ok(tokio::io::duplex(64).1)),
+        Poll::Ready(Err(io::Error::from(io::ErrorKind::ConnectionAborted))),
+        Poll::Ready(Ok(tokio::io::duplex(64).1)),
+    ]);
+
+    let mut cx = Context::from_waker(futures_util::task::noop_waker_ref());
+    accept_more(&tracker, &mut pending, 8, &mut cx, |_| {
+        results.pop_front().unwrap().map_ok(|conn| (conn, None))
+    });
+
+    // The connection accepted before the error is kept, the batch ends at the error
+    assert_eq!(pending.len(), 1);
+    assert_eq!(tracker.get(), 1);
+    assert_eq!(results.len(), 1);
+}
+
+#[test]
+fn test_accept_more_reports_errors_to_hook() {
+    let events = Arc::new(Mutex::new(vec![]));
+    let hook_events = events.clone();
+    let mut tracker = ConnectionTracker::new(None, "mock", None);
+    tracker.hook = Some(Arc::new(move |event| {
+        hook_events.lock().unwrap().push(event)
+    }));
+    let mut pending = VecDeque::new();
+    let mut results = VecDeque::from([
+        Poll::Ready(Ok(tokio::io::duplex(64).1)),
+        Poll::Ready(Err(io::Error::from(io::ErrorKind::ConnectionAborted))),
+    ]);
+
+    let mut cx = Context::from_waker(futures_util::task::noop_waker_ref());
+    accept_more(&tracker, &mut pending, 8, &mut cx, |_| {
+        results.pop_front().unwrap().map_ok(|conn| (conn, None))
+    });
+
+    // The error isn't returned by the poll, but the hook still gets it
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            AcceptEvent::Accepted(None),
+            AcceptEvent::Error(io::ErrorKind::ConnectionAborted),
+        ]
+    );
+}
+
+// Accepts connections from the socket until it stops, returns their number
+async fn accept_until_stopped<A: Accept + Unpin>(socket: &mut A) -> usize
+where
+    A::Error: std::fmt::Debug,
+{
+    let mut count = 0;
+    while let Some(conn) = std::future::poll_fn(|cx| Pin::new(&mut *socket).poll_accept(cx)).await {
+        conn.unwrap();
+        count += 1;
+    }
+    count
+}
+
+#[tokio::test]
+async fn test_tcp_shutdown_mode() {
+    const CONNS: usize = 4;
+
+    for (mode, expected_accepted) in [
+        (ShutdownMode::StopImmediately, 0),
+        (ShutdownMode::DrainQueue, CONNS),
+    ] {
+        let token = CancellationToken::new();
+        let mut socket = SocketTcp::bind(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0), 16)
+            .unwrap()
+            .with_shutdown(token.clone(), mode);
+        let addr = socket.listener.local_addr().unwrap();
+
+        let mut clients = vec![];
+        for _ in 0..CONNS {
+            clients.push(TcpStream::connect(addr).await.unwrap());
+        }
+        // Let the runtime notice the queued connections
+        sleep(Duration::from_millis(50)).await;
+
+        token.cancel();
+        assert_eq!(accept_until_stopped(&mut socket).await, expected_accepted);
+    }
+}
+
+#[tokio::test]
+async fn test_unix_shutdown_drain_queue() {
+    const CONNS: usize = 4;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("socket");
+    let token = CancellationToken::new();
+    let mut socket = SocketUnix::bind(&path, 16)
+        .unwrap()
+        .with_shutdown(token.clone(), ShutdownMode::DrainQueue);
+
+    let mut clients = vec![];
+    for _ in 0..CONNS {
+        clients.push(UnixStream::connect(&path).await.unwrap());
+    }
+    // The connections accepted before the shutdown are unaffected
+    accept_conns(&mut socket, 1).await;
+    sleep(Duration::from_millis(50)).await;
+
+    token.cancel();
+    assert_eq!(accept_until_stopped(&mut socket).await, CONNS - 1);
+    // Once stopped, the socket stays stopped
+    let _client = UnixStream::connect(&path).await.unwrap();
+    assert_eq!(accept_until_stopped(&mut socket).await, 0);
+}
+
+#[tokio::test]
+async fn test_tcp_drain_progress() {
+    const CONNS: usize = 3;
+
+    let token = CancellationToken::new();
+    let mut socket = SocketTcp::bind(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0), 16)
+        .unwrap()
+        .with_shutdown(token.clone(), ShutdownMode::StopImmediately);
+    let addr = socket.listener.local_addr().unwrap();
+
+    let mut clients = vec![];
+    for _ in 0..CONNS {
+        clients.push(TcpStream::connect(addr).await.unwrap());
+    }
+    let mut conns = accept_conns(&mut socket, CONNS).await;
+
+    let progress = socket.drain_progress();
+    assert!(!progress.shutdown_initiated);
+    assert_eq!(progress.remaining_connections, CONNS);
+    assert!(!progress.is_drained());
+
+    // Reported without the socket having to be polled
+    token.cancel();
+    assert_eq!(
+        socket.drain_progress(),
+        DrainProgress {
+            shutdown_initiated: true,
+            remaining_connections: CONNS,
+        }
+    );
+
+    for remaining in (0..CONNS).rev() {
+        conns.pop();
+        let progress = socket.drain_progress();
+        assert!(progress.shutdown_initiated);
+        assert_eq!(progress.remaining_connections, remaining);
+    }
+    assert!(socket.drain_progress().is_drained());
+}
+
+#[tokio::test]
+async fn test_tcp_nodelay() {
+    for nodelay in [true, false] {
+        let mut socket = SocketTcp::bind_with_options(
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0),
+            SocketTcpOptions {
+                nodelay,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let addr = socket.listener.local_addr().unwrap();
+
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let conn = std::future::poll_fn(|cx| Pin::new(&mut socket).poll_accept(cx))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(conn.get_ref().nodelay().unwrap(), nodelay);
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_tcp_dscp() {
+    const DSCP: u8 = 46; // Expedited Forwarding
+
+    let err = SocketTcp::bind_with_options(
+        SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0),
+        SocketTcpOptions {
+            dscp: Some(64),
+            ..Default::default()
+        },
+    )
+    .err()
+    .unwrap();
+    assert!(matches!(err, SocketBindError::InvalidOptions { .. }));
+
+    let mut socket = SocketTcp::bind_with_options(
+        SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+        SocketTcpOptions {
+            dscp: Some(DSCP),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let listener = socket2::SockRef::from(&socket.listener);
+    assert_eq!(listener.tclass_v6().unwrap(), u32::from(DSCP) << 2);
+    assert_eq!(listener.tos().unwrap(), u32::from(DSCP) << 2);
+
+    // Accepted connections inherit it
+    let port = socket.listener.local_addr().unwrap().port();
+    let _v6_client = TcpStream::connect(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port))
+        .await
+        .unwrap();
+    let _v4_client = TcpStream::connect(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port))
+        .await
+        .unwrap();
+    for conn in accept_conns(&mut socket, 2).await {
+        let conn = socket2::SockRef::from(conn.get_ref());
+        assert_eq!(conn.tclass_v6().unwrap(), u32::from(DSCP) << 2);
+        assert_eq!(conn.tos().unwrap(), u32::from(DSCP) << 2);
+    }
+}
+
+// Accepts `count` connections from the socket
+async fn accept_conns<A: Accept + Unpin>(socket: &mut A, count: usize) -> Vec<A::Conn>
+where
+    A::Error: std::fmt::Debug,
+{
+    let mut conns = vec![];
+    for _ in 0..count {
+        let conn = std::future::poll_fn(|cx| Pin::new(&mut *socket).poll_accept(cx))
+            .await
+            .unwrap()
+            .unwrap();
+        conns.push(conn);
+    }
+    conns
+}
+
+#[tokio::test]
+async fn test_tcp_open_connections() {
+    const CONNS: usize = 3;
+
+    let metrics = SocketMetrics::new(&Registry::new());
+    let gauge = metrics.open_connections.with_label_values(&["test", "tcp"]);
+    let mut socket = SocketTcp::bind_with_metrics(
+        SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0),
+        SocketTcpOptions::default(),
+        &metrics,
+        "test",
+    )
+    .unwrap();
+    let addr = socket.listener.local_addr().unwrap();
+
+    let mut clients = vec![];
+    for _ in 0..CONNS {
+        clients.push(TcpStream::connect(addr).await.unwrap());
+    }
+    let mut conns = accept_conns(&mut socket, CONNS).await;
+    assert_eq!(socket.open_connections(), CONNS);
+    assert_eq!(gauge.get(), CONNS as i64);
+
+    conns.pop();
+    assert_eq!(socket.open_connections(), CONNS - 1);
+    assert_eq!(gauge.get(), CONNS as i64 - 1);
+
+    drop(conns);
+    assert_eq!(socket.open_connections(), 0);
+    assert_eq!(gauge.get(), 0);
+}
+
+#[tokio::test]
+async fn test_tcp_accept_hook() {
+    let events = Arc::new(Mutex::new(vec![]));
+    let hook_events = events.clone();
+    let mut socket = SocketTcp::bind_with_options(
+        SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0),
+        SocketTcpOptions {
+            max_connections: Some(1),
+            ..Default::default()
+        },
+    )
+    .unwrap()
+    .with_accept_hook(Arc::new(move |event| {
+        hook_events.lock().unwrap().push(event)
+    }));
+    let addr = socket.listener.local_addr().unwrap();
+
+    let first = TcpStream::connect(addr).await.unwrap();
+    let conns = accept_conns(&mut socket, 1).await;
+
+    // The second one is over the limit, so it's rejected and the third one is returned once the first is closed
+    let second = TcpStream::connect(addr).await.unwrap();
+    let accept = tokio::spawn(async move { accept_conns(&mut socket, 1).await.len() });
+    sleep(Duration::from_millis(50)).await;
+    drop(conns);
+    let third = TcpStream::connect(addr).await.unwrap();
+    assert_eq!(accept.await.unwrap(), 1);
+
+    let peer = |client: &TcpStream| Some(client.local_addr().unwrap());
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            AcceptEvent::Accepted(peer(&first)),
+            AcceptEvent::RejectedByLimit(peer(&second)),
+            AcceptEvent::Accepted(peer(&third)),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_tcp_saturation() {
+    let mut socket = SocketTcp::bind_with_options(
+        SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0),
+        SocketTcpOptions {
+            max_connections: Some(2),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let addr = socket.listener.local_addr().unwrap();
+    let mut saturation = socket.saturation().unwrap();
+    assert!(!socket.is_saturated());
+
+    let mut clients = vec![];
+    for _ in 0..2 {
+        clients.push(TcpStream::connect(addr).await.unwrap());
+    }
+    let mut conns = accept_conns(&mut socket, 1).await;
+    assert!(!socket.is_saturated());
+    assert!(!saturation.has_changed().unwrap());
+
+    conns.extend(accept_conns(&mut socket, 1).await);
+    assert!(socket.is_saturated());
+    saturation.changed().await.unwrap();
+    assert!(*saturation.borrow_and_update());
+
+    // Freeing a slot makes room again
+    conns.pop();
+    assert!(!socket.is_saturated());
+    saturation.changed().await.unwrap();
+    assert!(!*saturation.borrow_and_update());
+
+    // No limit, no saturation
+    let socket = SocketTcp::bind(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0), 16).unwrap();
+    assert!(socket.saturation().is_none());
+    assert!(!socket.is_saturated());
+}
+
+#[tokio::test]
+async fn test_tcp_max_connections_per_ip() {
+    let events = Arc::new(Mutex::new(vec![]));
+    let hook_events = events.clone();
+    let mut socket = SocketTcp::bind_with_options(
+        SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0),
+        SocketTcpOptions {
+            max_connections_per_ip: Some(2),
+            max_accepts_per_poll: 4,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+    .with_accept_hook(Arc::new(move |event| {
+        hook_events.lock().unwrap().push(event)
+    }));
+    let addr = socket.listener.local_addr().unwrap();
+
+    let mut clients = vec![];
+    for _ in 0..3 {
+        clients.push(TcpStream::connect(addr).await.unwrap());
+    }
+    // Let the runtime notice the queued connections
+    sleep(Duration::from_millis(50)).await;
+
+    let conns = accept_conns(&mut socket, 2).await;
+    assert_eq!(socket.open_connections(), 2);
+    assert!(socket.pending.is_empty());
+    assert_eq!(
+        events.lock().unwrap().last(),
+        Some(&AcceptEvent::RejectedByIpCap(Some(
+            clients[2].local_addr().unwrap()
+        )))
+    );
+
+    // Closing a connection makes room for the IP again
+    drop(conns);
+    assert!(socket.tracker.count_per_ip.lock().unwrap().is_empty());
+    clients.push(TcpStream::connect(addr).await.unwrap());
+    accept_conns(&mut socket, 1).await;
+    assert_eq!(
+        events.lock().unwrap().last(),
+        Some(&AcceptEvent::Accepted(Some(
+            clients[3].local_addr().unwrap()
+        )))
+    );
+}
+
+#[tokio::test]
+async fn test_tcp_deprioritized_family() {
+    use tokio::io::AsyncReadExt;
+
+    let events = Arc::new(Mutex::new(vec![]));
+    let hook_events = events.clone();
+    // Dual-stack, so that both families can connect
+    let mut socket = SocketTcp::bind_with_options(
+        SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+        SocketTcpOptions {
+            max_connections: Some(2),
+            deprioritized_family: Some(DeprioritizedFamily {
+                family: AddressFamily::Ipv4,
+                max_connections: 1,
+            }),
+            ..Default::default()
+        },
+    )
+    .unwrap()
+    .with_accept_hook(Arc::new(move |event| {
+        hook_events.lock().unwrap().push(event)
+    }));
+    let port = socket.listener.local_addr().unwrap().port();
+    let v4_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+    let v6_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port);
+
+    // Below the family limit both families are admitted
+    let _v4_client = TcpStream::connect(v4_addr).await.unwrap();
+    let mut conns = accept_conns(&mut socket, 1).await;
+    assert_eq!(
+        AddressFamily::of(&conns[0].get_ref().peer_addr().unwrap()),
+        AddressFamily::Ipv4
+    );
+    drop(conns.pop());
+
+    let _v6_client = TcpStream::connect(v6_addr).await.unwrap();
+    conns.extend(accept_conns(&mut socket, 1).await);
+
+    // At the margin, the deprioritized family is dropped while the preferred one is admitted
+    let mut rejected_client = TcpStream::connect(v4_addr).await.unwrap();
+    let preferred_client = TcpStream::connect(v6_addr).await.unwrap();
+    // Let the runtime notice the queued connections
+    sleep(Duration::from_millis(50)).await;
+    conns.extend(accept_conns(&mut socket, 1).await);
+    assert_eq!(socket.open_connections(), 2);
+    assert_eq!(
+        conns[1].get_ref().peer_addr().unwrap(),
+        preferred_client.local_addr().unwrap()
+    );
+    assert!(matches!(
+        events.lock().unwrap()[..],
+        [
+            AcceptEvent::Accepted(_),
+            AcceptEvent::Accepted(_),
+            AcceptEvent::RejectedByFamily(Some(ref peer)),
+            AcceptEvent::Accepted(_),
+        ] if AddressFamily::of(peer) == AddressFamily::Ipv4
+    ));
+
+    // The rejected connection is closed
+    let mut buf = [0u8; 1];
+    assert!(matches!(
+        rejected_client.read(&mut buf).await,
+        Ok(0) | Err(_)
+    ));
+}
+
+#[tokio::test]
+async fn test_metrics_labeled_by_listener_name() {
+    let registry = Registry::new();
+    let metrics = SocketMetrics::new(&registry);
+    let bind = |name| {
+        SocketTcp::bind_with_metrics(
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0),
+            SocketTcpOptions::default(),
+            &metrics,
+            name,
+        )
+        .unwrap()
+    };
+    let mut public = bind("public");
+    let mut private = bind("private");
+
+    let mut clients = vec![];
+    for _ in 0..2 {
+        clients.push(
+            TcpStream::connect(public.listener.local_addr().unwrap())
+                .await
+                .unwrap(),
+        );
+    }
+    clients.push(
+        TcpStream::connect(private.listener.local_addr().unwrap())
+            .await
+            .unwrap(),
+    );
+    let _public_conns = accept_conns(&mut public, 2).await;
+    let _private_conns = accept_conns(&mut private, 1).await;
+
+    // Both listeners report under their own label in the same registry
+    let family = registry
+        .gather()
+        .into_iter()
+        .find(|x| x.get_name() == "open_connections")
+        .unwrap();
+    let mut reported: Vec<_> = family
+        .get_metric()
+        .iter()
+        .map(|x| {
+            let labels: Vec<_> = x.get_label().iter().map(|l| l.get_value()).collect();
+            (labels.join("/"), x.get_gauge().get_value() as i64)
+        })
+        .collect();
+    reported.sort();
+    assert_eq!(
+        reported,
+        vec![
+            ("private/tcp".to_string(), 1),
+            ("public/tcp".to_string(), 2)
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_unix_open_connections() {
+    const CONNS: usize = 3;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("socket");
+
+    let metrics = SocketMetrics::new(&Registry::new());
+    let gauge = metrics
+        .open_connections
+        .with_label_values(&["test", "unix"]);
+    let mut socket = SocketUnix::bind_with_metrics(
+        &path,
+        SocketUnixOptions {
+            max_accepts_per_poll: CONNS,
+            ..Default::default()
+        },
+        &metrics,
+        "test",
+    )
+    .unwrap();
+
+    let mut clients = vec![];
+    for _ in 0..CONNS {
+        clients.push(UnixStream::connect(&path).await.unwrap());
+    }
+    let conn = accept_conns(&mut socket, 1).await;
+    // Buffered connections count as open too
+    assert_eq!(socket.open_connections(), CONNS);
+    assert_eq!(gauge.get(), CONNS as i64);
+
+    let conns = accept_conns(&mut socket, CONNS - 1).await;
+    drop(conn);
+    drop(conns);
+    assert_eq!(socket.open_connections(), 0);
+    assert_eq!(gauge.get(), 0);
+}
+
+#[test]
+fn test_tcp_connect_info_preserves_ipv6_scope_id() {
+    let addr = SocketAddrV6::new("fe80::1".parse().unwrap(), 443, 0x12345, 2);
+
+    let info = TcpConnectInfo::from(SocketAddr::V6(addr));
+    let SocketAddr::V6(v6) = info.0 else {
+        panic!("expected an IPv6 address, got {}", info.0);
+    };
+    assert_eq!(v6, addr);
+    assert_eq!(v6.flowinfo(), 0x12345);
+    assert_eq!(v6.scope_id(), 2);
+}
+
+#[tokio::test]
+async fn test_tcp_connect_info_carries_peer_address() {
+    let mut socket = SocketTcp::bind(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0), 16).unwrap();
+    let addr = socket.listener.local_addr().unwrap();
+
+    let client = TcpStream::connect(addr).await.unwrap();
+    let conn = std::future::poll_fn(|cx| Pin::new(&mut socket).poll_accept(cx))
+        .await
+        .unwrap()
+        .unwrap();
+
+    let info = TcpConnectInfo::connect_info(&conn);
+    assert_eq!(info.0, client.local_addr().unwrap());
+}
+
+#[tokio::test]
+async fn test_time_to_first_byte() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const DELAY: Duration = Duration::from_millis(200);
+
+    let metrics = SocketMetrics::new(&Registry::new());
+    let histogram = metrics
+        .time_to_first_byte
+        .with_label_values(&["test", "mock"]);
+    let empty = metrics
+        .empty_connections
+        .with_label_values(&["test", "mock"]);
+    let tracker = ConnectionTracker::new(Some((&metrics, "test")), "mock", None);
+
+    // The client delays its first byte, then keeps sending
+    let (mut client, server) = tokio::io::duplex(64);
+    let mut conn = tracker.track(server);
+    tokio::spawn(async move {
+        sleep(DELAY).await;
+        client.write_all(b"hello").await.unwrap();
+        sleep(DELAY).await;
+        client.write_all(b"world").await.unwrap();
+    });
+
+    let mut buf = [0; 10];
+    conn.read_exact(&mut buf).await.unwrap();
+    drop(conn);
+
+    // Only the first byte is recorded
+    assert_eq!(histogram.get_sample_count(), 1);
+    let ttfb = histogram.get_sample_sum();
+    assert!(
+        (DELAY.as_secs_f64()..DELAY.as_secs_f64() + 0.1).contains(&ttfb),
+        "unexpected time to first byte {ttfb}"
+    );
+    assert_eq!(empty.get(), 0);
+
+    // The client closes the connection without sending anything
+    let (client, server) = tokio::io::duplex(64);
+    let mut conn = tracker.track(server);
+    drop(client);
+    assert_eq!(conn.read(&mut buf).await.unwrap(), 0);
+    drop(conn);
+
+    assert_eq!(histogram.get_sample_count(), 1);
+    assert_eq!(empty.get(), 1);
+}
+
+#[tokio::test]
+async fn test_tcp_max_read_bytes_per_sec() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const RATE: u64 = 50_000;
+    // The bucket allows a burst of RATE bytes, the rest is throttled
+    const TOTAL: usize = 3 * RATE as usize;
+
+    let mut socket = SocketTcp::bind_with_options(
+        SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0),
+        SocketTcpOptions {
+            max_read_bytes_per_sec: Some(RATE),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let addr = socket.listener.local_addr().unwrap();
+
+    let client = tokio::spawn(async move {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&[42; TOTAL]).await.unwrap();
+    });
+
+    let mut conn = accept_conns(&mut socket, 1).await.pop().unwrap();
+    let start = std::time::Instant::now();
+    let mut buf = vec![];
+    conn.read_to_end(&mut buf).await.unwrap();
+    let elapsed = start.elapsed().as_secs_f64();
+    client.await.unwrap();
+
+    assert_eq!(buf.len(), TOTAL);
+    // Everything beyond the initial burst is read at about the configured rate
+    let throughput = (TOTAL as u64 - RATE) as f64 / elapsed;
+    assert!(
+        throughput <= RATE as f64 * 1.1,
+        "throughput {throughput} exceeds the rate {RATE}"
+    );
+    assert!(
+        throughput >= RATE as f64 * 0.5,
+        "throughput {throughput} is far below the rate {RATE}"
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_unix_seqpacket_preserves_message_boundaries() {
+    use socket2::{Domain, SockAddr, Socket, Type};
+    use std::io::Read;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("socket");
+
+    let metrics = SocketMetrics::new(&Registry::new());
+    let gauge = metrics
+        .open_connections
+        .with_label_values(&["test", "unix_seqpacket"]);
+    let mut socket = SocketUnix::bind_seqpacket_with_metrics(
+        &path,
+        SocketUnixOptions::default(),
+        &metrics,
+        "test",
+    )
+    .unwrap();
+
+    let client = Socket::new(Domain::UNIX, Type::SEQPACKET, None).unwrap();
+    client.connect(&SockAddr::unix(&path).unwrap()).unwrap();
+    client.send(b"hello").unwrap();
+    client.send(b"world!").unwrap();
+
+    let mut conn = accept_conns(&mut socket, 1).await.pop().unwrap();
+    assert_eq!(socket.open_connections(), 1);
+    assert_eq!(gauge.get(), 1);
+
+    // Each read returns exactly one message, even though the buffer fits both
+    let mut buf = [0; 64];
+    let n = conn.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"hello");
+    let n = conn.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"world!");
+
+    conn.write_all(b"ack").await.unwrap();
+    let mut reply = [0; 64];
+    let n = (&client).read(&mut reply).unwrap();
+    assert_eq!(&reply[..n], b"ack");
+
+    // Closing the client is seen as EOF
+    drop(client);
+    assert_eq!(conn.read(&mut buf).await.unwrap(), 0);
+
+    drop(conn);
+    assert_eq!(socket.open_connections(), 0);
+    assert_eq!(gauge.get(), 0);
+}
+
+// Server config with a self-signed certificate for localhost and a client config trusting it
+#[cfg(feature = "tls")]
+fn sniffing_tls_configs() -> (Arc<rustls::ServerConfig>, Arc<rustls::ClientConfig>) {
+    let cert =
+        rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec!["localhost".into()]))
+            .unwrap();
+    let der = rustls::Certificate(cert.serialize_der().unwrap());
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+
+    let server = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(vec![der.clone()], key)
+        .unwrap();
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&der).unwrap();
+    let client = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    (Arc::new(server), Arc::new(client))
+}
+
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn test_tcp_tls_sniffing_routes_by_first_byte() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    let (server_cfg, client_cfg) = sniffing_tls_configs();
+    let mut socket = SocketTcp::bind(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0), 16)
+        .unwrap()
+        .with_tls_sniffing(server_cfg, Duration::from_secs(5));
+    let addr = socket.local_addr().unwrap();
+
+    // Plaintext, the sniffed byte is still read from the connection
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+
+    let mut conn = accept_conns(&mut socket, 1).await.pop().unwrap();
+    assert!(!conn.is_tls());
+    let mut buf = [0; 16];
+    conn.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"GET / HTTP/1.1\r\n");
+
+    // TLS, the ClientHello starts with a handshake record and is served by the TLS acceptor
+    let connector = TlsConnector::from(client_cfg);
+    let client = TcpStream::connect(addr).await.unwrap();
+    let (client, conns) = tokio::join!(
+        connector.connect("localhost".try_into().unwrap(), client),
+        accept_conns(&mut socket, 1)
+    );
+    let mut client = client.unwrap();
+    let mut conn = conns.into_iter().next().unwrap();
+    assert!(conn.is_tls());
+
+    client.write_all(b"hello").await.unwrap();
+    client.flush().await.unwrap();
+    let mut buf = [0; 5];
+    conn.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+
+    // Both connections are tracked, whatever they turned out to be
+    assert_eq!(socket.open_connections(), 2);
+}
+
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn test_tcp_tls_sniffing_drops_silent_connections() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (server_cfg, _) = sniffing_tls_configs();
+    let mut socket = SocketTcp::bind(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0), 16)
+        .unwrap()
+        .with_tls_sniffing(server_cfg, Duration::from_millis(100));
+    let addr = socket.local_addr().unwrap();
+
+    // Nothing is yielded for a connection sending nothing, it's dropped after the handshake timeout
+    let mut silent = TcpStream::connect(addr).await.unwrap();
+    assert!(
+        tokio::time::timeout(Duration::from_millis(500), accept_conns(&mut socket, 1))
+            .await
+            .is_err()
+    );
+    assert_eq!(silent.read(&mut [0; 1]).await.unwrap(), 0);
+    assert_eq!(socket.open_connections(), 0);
+
+    // The socket keeps serving the other connections
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client.write_all(b"GET").await.unwrap();
+    let conn = accept_conns(&mut socket, 1).await.pop().unwrap();
+    assert!(!conn.is_tls());
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_unix_seqpacket_max_connections() {
+    use socket2::{Domain, SockAddr, Socket, Type};
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("socket");
+
+    let token = CancellationToken::new();
+    let events = Arc::new(Mutex::new(vec![]));
+    let hook_events = events.clone();
+    let mut socket = SocketUnix::bind_seqpacket(
+        &path,
+        SocketUnixOptions {
+            max_connections: Some(1),
+            ..Default::default()
+        },
+    )
+    .unwrap()
+    .with_shutdown(token.clone(), ShutdownMode::StopImmediately)
+    .with_accept_hook(Arc::new(move |event| {
+        hook_events.lock().unwrap().push(event)
+    }));
+    let mut saturation = socket.saturation().unwrap();
+    assert!(!socket.is_saturated());
+
+    let connect = || {
+        let client = Socket::new(Domain::UNIX, Type::SEQPACKET, None).unwrap();
+        client.connect(&SockAddr::unix(&path).unwrap()).unwrap();
+        client
+    };
+
+    let _first = connect();
+    let conns = accept_conns(&mut socket, 1).await;
+    assert!(socket.is_saturated());
+    saturation.changed().await.unwrap();
+    assert!(*saturation.borrow_and_update());
+
+    // The second one is over the limit, so it's rejected
+    let _second = connect();
+    assert!(
+        tokio::time::timeout(Duration::from_millis(50), accept_conns(&mut socket, 1))
+            .await
+            .is_err()
+    );
+    assert_eq!(socket.open_connections(), 1);
+
+    // Freeing a slot makes room again
+    drop(conns);
+    assert!(!socket.is_saturated());
+    saturation.changed().await.unwrap();
+    assert!(!*saturation.borrow_and_update());
+
+    let _third = connect();
+    let mut conns = accept_conns(&mut socket, 1).await;
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            AcceptEvent::Accepted(None),
+            AcceptEvent::RejectedByLimit(None),
+            AcceptEvent::Accepted(None),
+        ]
+    );
+
+    token.cancel();
+    assert_eq!(
+        socket.drain_progress(),
+        DrainProgress {
+            shutdown_initiated: true,
+            remaining_connections: 1,
+        }
+    );
+    conns.pop();
+    assert!(socket.drain_progress().is_drained());
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_unix_seqpacket_max_read_bytes_per_sec() {
+    use socket2::{Domain, SockAddr, Socket, Type};
+    use tokio::io::AsyncReadExt;
+
+    const RATE: u64 = 50_000;
+    // The message is larger than the bucket, so it puts the bucket a second into debt
+    const MESSAGE: usize = 2 * RATE as usize;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("socket");
+    let mut socket = SocketUnix::bind_seqpacket(
+        &path,
+        SocketUnixOptions {
+            max_read_bytes_per_sec: Some(RATE),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let client = Socket::new(Domain::UNIX, Type::SEQPACKET, None).unwrap();
+    client.set_send_buffer_size(4 * MESSAGE).unwrap();
+    client.connect(&SockAddr::unix(&path).unwrap()).unwrap();
+    let message: Vec<u8> = (0..MESSAGE).map(|i| i as u8).collect();
+    client.send(&message).unwrap();
+    client.send(b"next").unwrap();
+
+    let mut conn = accept_conns(&mut socket, 1).await.pop().unwrap();
+
+    // The message is read whole rather than truncated to the bucket
+    let mut buf = vec![0; 2 * MESSAGE];
+    let n = conn.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], message.as_slice());
+
+    // The next one waits until the debt is paid back
+    let start = std::time::Instant::now();
+    let n = conn.read(&mut buf).await.unwrap();
+    let elapsed = start.elapsed().as_secs_f64();
+    assert_eq!(&buf[..n], b"next");
+    let debt = (MESSAGE as u64 - RATE) as f64 / RATE as f64;
+    assert!(
+        elapsed >= debt * 0.9,
+        "the next message was read after {elapsed}s, before the debt of {debt}s was paid back"
+    );
+}
@@ -1,4 +1,5 @@
 use std::{
+    collections::HashSet,
     fs,
     fs::File,
     io::{self, ErrorKind},
@@ -19,13 +20,19 @@ use axum_server::{accept::Accept, tls_rustls::RustlsAcceptor};
 use futures_util::future::BoxFuture;
 use instant_acme::{Account, AccountCredentials, LetsEncrypt, NewAccount};
 use mockall::automock;
-use prometheus::Registry;
+use prometheus::{register_int_counter_with_registry, IntCounter, Registry};
 use rcgen::{Certificate, CertificateParams, DistinguishedName};
 use regex::Regex;
 use rustls::{
-    cipher_suite::{TLS13_AES_128_GCM_SHA256, TLS13_AES_256_GCM_SHA384},
-    server::{ServerConfig, ServerSessionMemoryCache},
-    version::TLS13,
+    cipher_suite::{
+        TLS13_AES_128_GCM_SHA256, TLS13_AES_256_GCM_SHA384,
+        TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256, TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+        TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256, TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    },
+    server::{ClientHello, ResolvesServerCert, ServerConfig, ServerSessionMemoryCache},
+    sign::{any_supported_type, CertifiedKey},
+    version::{TLS12, TLS13},
+    PeerIncompatible, SupportedCipherSuite, SupportedProtocolVersion,
 };
 use tokio::{
     io::{AsyncRead, AsyncWrite},
@@ -45,6 +52,45 @@ use crate::{
 
 const DAY: Duration = Duration::from_secs(24 * 3600);
 
+const TLS13_CIPHER_SUITES: &[SupportedCipherSuite] =
+    &[TLS13_AES_256_GCM_SHA384, TLS13_AES_128_GCM_SHA256];
+
+// TLS 1.2 is only allowed with forward secrecy and AEAD ciphers
+const TLS12_CIPHER_SUITES: &[SupportedCipherSuite] = &[
+    TLS13_AES_256_GCM_SHA384,
+    TLS13_AES_128_GCM_SHA256,
+    TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+    TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+    TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+];
+
+// Minimum TLS protocol version accepted on the ingress listener
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum TlsVersion {
+    #[value(name = "1.2")]
+    Tls12,
+    #[value(name = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    // Protocol versions from this one up
+    fn protocol_versions(self) -> &'static [&'static SupportedProtocolVersion] {
+        match self {
+            Self::Tls12 => &[&TLS13, &TLS12],
+            Self::Tls13 => &[&TLS13],
+        }
+    }
+
+    fn cipher_suites(self) -> &'static [SupportedCipherSuite] {
+        match self {
+            Self::Tls12 => TLS12_CIPHER_SUITES,
+            Self::Tls13 => TLS13_CIPHER_SUITES,
+        }
+    }
+}
+
 // Public + Private key pair
 #[derive(Clone, Debug, PartialEq)]
 pub struct TLSCert(pub String, pub String);
@@ -90,6 +136,127 @@ where
     }
 }
 
+// Whether the handshake failed because the client doesn't support any protocol version
+// enabled in the server config, i.e. it only offered versions below the minimum
+fn is_tls_version_rejection(err: &io::Error) -> bool {
+    matches!(
+        err.get_ref()
+            .and_then(|e| e.downcast_ref::<rustls::Error>()),
+        Some(rustls::Error::PeerIncompatible(
+            PeerIncompatible::Tls12NotOffered
+                | PeerIncompatible::Tls12NotOfferedOrEnabled
+                | PeerIncompatible::SupportedVersionsExtensionRequired
+        ))
+    )
+}
+
+// Wraps a rustls-backed acceptor whose config enforces a minimum TLS version
+// and counts the handshakes refused because the client offered only versions below it.
+// The refusal happens during the handshake, so such clients never reach the HTTP layer
+#[derive(Clone)]
+pub struct MinTlsVersionAcceptor<A> {
+    inner: A,
+    rejected: IntCounter,
+}
+
+impl<A> MinTlsVersionAcceptor<A> {
+    pub fn new(inner: A, registry: &Registry) -> Self {
+        Self {
+            inner,
+            rejected: register_int_counter_with_registry!(
+                "tls_version_rejected",
+                "Counts TLS handshakes rejected due to a protocol version below the minimum",
+                registry
+            )
+            .unwrap(),
+        }
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected.get()
+    }
+}
+
+impl<I, S, A> Accept<I, S> for MinTlsVersionAcceptor<A>
+where
+    A: Accept<I, S>,
+    A::Future: Send + 'static,
+{
+    type Stream = A::Stream;
+    type Service = A::Service;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let accepted = self.inner.accept(stream, service);
+        let rejected = self.rejected.clone();
+
+        Box::pin(async move {
+            let result = accepted.await;
+
+            if let Err(e) = &result {
+                if is_tls_version_rejection(e) {
+                    rejected.inc();
+                    debug!("TLS: rejected handshake below the minimum protocol version: {e}");
+                }
+            }
+
+            result
+        })
+    }
+}
+
+// Restricts the server names (SNI) for which TLS connections are accepted
+#[derive(Clone)]
+pub struct SniFilter {
+    allowed: Arc<HashSet<String>>,
+    rejected: IntCounter,
+}
+
+impl SniFilter {
+    pub fn new(allowed: Vec<String>, registry: &Registry) -> Self {
+        Self {
+            allowed: Arc::new(allowed.iter().map(|x| x.to_ascii_lowercase()).collect()),
+            rejected: register_int_counter_with_registry!(
+                "rejected_sni_total",
+                "Counts TLS connections rejected due to a server name (SNI) that is not allowed",
+                registry
+            )
+            .unwrap(),
+        }
+    }
+
+    // Connections without SNI are rejected as well
+    pub fn is_allowed(&self, server_name: Option<&str>) -> bool {
+        server_name.is_some_and(|x| self.allowed.contains(&x.to_ascii_lowercase()))
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected.get()
+    }
+}
+
+// Resolves the certificate only for the allowed server names.
+// Not resolving a certificate aborts the handshake, so that rejected connections
+// never reach the HTTP layer.
+struct SniFilteringResolver {
+    filter: SniFilter,
+    key: Arc<CertifiedKey>,
+}
+
+impl ResolvesServerCert for SniFilteringResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let server_name = client_hello.server_name();
+
+        if !self.filter.is_allowed(server_name) {
+            self.filter.rejected.inc();
+            debug!("TLS: rejected connection with server name {server_name:?}");
+            return None;
+        }
+
+        Some(self.key.clone())
+    }
+}
+
 pub struct TokenOwner(Arc<RwLock<Option<String>>>);
 
 impl TokenOwner {
@@ -512,7 +679,14 @@ fn prepare_static_provisioner(loader: Loader) -> Result<Box<dyn Provision>, Erro
 pub async fn prepare_tls(
     cli: &Cli,
     registry: &Registry,
-) -> Result<(impl Run, CustomAcceptor, Arc<TokenOwner>), Error> {
+) -> Result<
+    (
+        impl Run,
+        MinTlsVersionAcceptor<CustomAcceptor>,
+        Arc<TokenOwner>,
+    ),
+    Error,
+> {
     // TLS Certificates Loader (Ingress)
     let tls_loader = Loader {
         cert_path: cli.tls.tls_cert_path.clone(),
@@ -559,13 +733,22 @@ pub async fn prepare_tls(
     // TLS (Ingress) Configurator
     let tls_acceptor = Arc::new(ArcSwapOption::new(None));
 
-    let tls_configurator = TlsConfigurator::new(tls_acceptor.clone(), tls_provisioner);
+    // Allowed server names (SNI), all are allowed if none are specified
+    let sni_filter = (!cli.tls.allowed_sni.is_empty())
+        .then(|| SniFilter::new(cli.tls.allowed_sni.clone(), registry));
+
+    let tls_configurator = TlsConfigurator::new(
+        tls_acceptor.clone(),
+        tls_provisioner,
+        sni_filter,
+        cli.tls.tls_min_version,
+    );
     let tls_configurator = WithMetrics(
         tls_configurator,
         MetricParams::new(registry, "configure_tls"),
     );
 
-    let tls_acceptor = CustomAcceptor::new(tls_acceptor);
+    let tls_acceptor = MinTlsVersionAcceptor::new(CustomAcceptor::new(tls_acceptor), registry);
 
     // Service Configurator
     let svc_configurator = Configurator {
@@ -607,13 +790,28 @@ pub fn load_pem(
 pub fn generate_rustls_config(
     certs: Vec<rustls::Certificate>,
     key: rustls::PrivateKey,
+    sni_filter: Option<SniFilter>,
+    min_tls_version: TlsVersion,
 ) -> Result<ServerConfig, Error> {
-    let mut cfg = ServerConfig::builder()
-        .with_cipher_suites(&[TLS13_AES_256_GCM_SHA384, TLS13_AES_128_GCM_SHA256])
+    // Clients offering only versions below the minimum are refused during the handshake
+    let builder = ServerConfig::builder()
+        .with_cipher_suites(min_tls_version.cipher_suites())
         .with_safe_default_kx_groups()
-        .with_protocol_versions(&[&TLS13])?
-        .with_no_client_auth()
-        .with_single_cert(certs, key)?;
+        .with_protocol_versions(min_tls_version.protocol_versions())?
+        .with_no_client_auth();
+
+    let mut cfg = match sni_filter {
+        Some(filter) => {
+            let key = any_supported_type(&key).map_err(|_| anyhow!("unsupported private key"))?;
+
+            builder.with_cert_resolver(Arc::new(SniFilteringResolver {
+                filter,
+                key: Arc::new(CertifiedKey::new(certs, key)),
+            }))
+        }
+
+        None => builder.with_single_cert(certs, key)?,
+    };
 
     // Create custom session storage with higher limit to allow effective TLS session resumption
     cfg.session_storage = ServerSessionMemoryCache::new(131072);
@@ -1,13 +1,26 @@
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
 use anyhow::{bail, Error};
+use arc_swap::ArcSwapOption;
+use axum_server::{
+    accept::Accept,
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
+};
 use mockall::predicate;
+use prometheus::Registry;
 use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType, DnValue};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    version::{TLS12, TLS13},
+    ClientConfig, ServerConfig, ServerName, SupportedProtocolVersion,
+};
 use tempfile::NamedTempFile;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
 
 use crate::tls::{
-    extract_cert_validity, LoadError, MockLoad, MockProvision, MockStore, Provision,
-    ProvisionResult, WithLoad, WithStore,
+    extract_cert_validity, generate_rustls_config, CustomAcceptor, LoadError,
+    MinTlsVersionAcceptor, MockLoad, MockProvision, MockStore, Provision, ProvisionResult,
+    SniFilter, TlsVersion, WithLoad, WithStore,
 };
 
 use wiremock::{
@@ -348,3 +361,144 @@ async fn load_or_create_acme_account_test() -> Result<(), Error> {
 
     Ok(())
 }
+
+// Accepts any server certificate, only the server side is under test
+struct NoServerCertVerification;
+
+impl ServerCertVerifier for NoServerCertVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+// Performs a TLS handshake with the given server name over an in-memory stream
+async fn tls_handshake(cfg: ServerConfig, server_name: &str) -> Result<(), Error> {
+    let (client, server) = tokio::io::duplex(65536);
+
+    let acceptor = TlsAcceptor::from(Arc::new(cfg));
+    let connector = TlsConnector::from(Arc::new(
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(NoServerCertVerification))
+            .with_no_client_auth(),
+    ));
+
+    let server_name = ServerName::try_from(server_name)?;
+    let (accepted, connected) = tokio::join!(
+        acceptor.accept(server),
+        connector.connect(server_name, client)
+    );
+
+    accepted?;
+    connected?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sni_filter_test() -> Result<(), Error> {
+    let cert = Certificate::from_params(CertificateParams::new(vec![
+        "allowed.example.com".into(), // SAN
+    ]))?;
+    let certs = vec![rustls::Certificate(cert.serialize_der()?)];
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+
+    let filter = SniFilter::new(vec!["Allowed.example.com".into()], &Registry::new());
+    let cfg = generate_rustls_config(certs, key, Some(filter.clone()), TlsVersion::Tls13)?;
+
+    // Allowed server name
+    tls_handshake(cfg.clone(), "allowed.example.com").await?;
+    assert_eq!(filter.rejected(), 0);
+
+    // Disallowed server name
+    if tls_handshake(cfg, "disallowed.example.com").await.is_ok() {
+        bail!("expected handshake to be rejected");
+    }
+    assert_eq!(filter.rejected(), 1);
+
+    // Missing server name
+    assert!(!filter.is_allowed(None));
+
+    Ok(())
+}
+
+// Performs a TLS handshake through the acceptor over an in-memory stream,
+// with a client offering only the given protocol versions
+async fn tls_handshake_with_versions(
+    acceptor: &MinTlsVersionAcceptor<CustomAcceptor>,
+    versions: &[&'static SupportedProtocolVersion],
+) -> Result<(), Error> {
+    let (client, server) = tokio::io::duplex(65536);
+
+    let connector = TlsConnector::from(Arc::new(
+        ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(versions)?
+            .with_custom_certificate_verifier(Arc::new(NoServerCertVerification))
+            .with_no_client_auth(),
+    ));
+
+    let server_name = ServerName::try_from("example.com")?;
+    let (accepted, connected) = tokio::join!(
+        acceptor.accept(server, ()),
+        connector.connect(server_name, client)
+    );
+
+    accepted?;
+    connected?;
+
+    Ok(())
+}
+
+fn min_tls_version_acceptor(
+    min_tls_version: TlsVersion,
+) -> Result<MinTlsVersionAcceptor<CustomAcceptor>, Error> {
+    let cert = Certificate::from_params(CertificateParams::new(vec![
+        "example.com".into(), // SAN
+    ]))?;
+    let certs = vec![rustls::Certificate(cert.serialize_der()?)];
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+
+    let cfg = generate_rustls_config(certs, key, None, min_tls_version)?;
+    let acceptor = RustlsAcceptor::new(RustlsConfig::from_config(Arc::new(cfg)));
+
+    Ok(MinTlsVersionAcceptor::new(
+        CustomAcceptor::new(Arc::new(ArcSwapOption::new(Some(Arc::new(acceptor))))),
+        &Registry::new(),
+    ))
+}
+
+#[tokio::test]
+async fn min_tls_version_test() -> Result<(), Error> {
+    // TLS 1.3 minimum
+    let acceptor = min_tls_version_acceptor(TlsVersion::Tls13)?;
+
+    tls_handshake_with_versions(&acceptor, &[&TLS13]).await?;
+    assert_eq!(acceptor.rejected(), 0);
+
+    if tls_handshake_with_versions(&acceptor, &[&TLS12])
+        .await
+        .is_ok()
+    {
+        bail!("expected TLS 1.2 handshake to be rejected");
+    }
+    assert_eq!(acceptor.rejected(), 1);
+
+    // TLS 1.2 minimum
+    let acceptor = min_tls_version_acceptor(TlsVersion::Tls12)?;
+
+    tls_handshake_with_versions(&acceptor, &[&TLS12]).await?;
+    tls_handshake_with_versions(&acceptor, &[&TLS13]).await?;
+    assert_eq!(acceptor.rejected(), 0);
+
+    Ok(())
+}
@@ -100,6 +100,14 @@ const QUERY_SCHEDULING_TIME_SLICE_PER_CANISTER: Duration = Duration::from_millis
 /// executions and user errors.
 const QUERY_CACHE_CAPACITY: NumBytes = NumBytes::new(200 * MIB);
 
+/// The lower limit on the query cache capacity derived from a fraction
+/// of the memory, see [`query_cache_capacity_from_fraction`].
+pub const MIN_QUERY_CACHE_CAPACITY: NumBytes = NumBytes::new(MIB);
+
+/// The upper limit on the query cache capacity derived from a fraction
+/// of the memory, see [`query_cache_capacity_from_fraction`].
+pub const MAX_QUERY_CACHE_CAPACITY: NumBytes = NumBytes::new(16 * GIB);
+
 /// The upper limit on how long the cache entry stays valid in the query cache.
 const QUERY_CACHE_MAX_EXPIRY_TIME: Duration = Duration::from_secs(600);
 /// The upper limit on how long the data certificate stays valid in the query cache.
@@ -110,6 +118,10 @@ const QUERY_CACHE_MAX_EXPIRY_TIME: Duration = Duration::from_secs(600);
 /// should not be cached for more than 5 minutes.
 const QUERY_CACHE_DATA_CERTIFICATE_EXPIRY_TIME: Duration = Duration::from_secs(60);
 
+/// How long the queries of a canister are not cached once the query cache
+/// circuit breaker opens for it.
+const QUERY_CACHE_CIRCUIT_BREAKER_COOLDOWN: Duration = Duration::from_secs(60);
+
 /// Length of an epoch of query statistics in blocks
 pub const QUERY_STATS_EPOCH_LENGTH: u64 = 600;
 
@@ -132,6 +144,30 @@ pub const MAX_COMPILATION_CACHE_SIZE: NumBytes = NumBytes::new(10 * GIB);
 /// Maximum number of controllers allowed in a request (specified in the interface spec).
 pub const MAX_ALLOWED_CONTROLLERS_COUNT: usize = 10;
 
+/// The policy choosing the query cache entries to evict once the cache is full.
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub enum EvictionPolicy {
+    /// Evict the least recently used entries.
+    #[default]
+    Lru,
+    /// Evict the least frequently used entries, the least recently used
+    /// first among the entries with the same number of hits.
+    Lfu,
+    /// Evict the oldest entries, regardless of their hits.
+    Fifo,
+}
+
+impl EvictionPolicy {
+    /// Returns the name of the policy, as used in the metric labels.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EvictionPolicy::Lru => "lru",
+            EvictionPolicy::Lfu => "lfu",
+            EvictionPolicy::Fifo => "fifo",
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
 #[serde(default)]
 pub struct Config {
@@ -243,6 +279,77 @@ pub struct Config {
     /// The upper limit on how long the data certificate stays valid in the query cache.
     pub query_cache_data_certificate_expiry_time: Duration,
 
+    /// Debug mode: on every query cache hit, re-execute the query and check
+    /// that the fresh result is identical to the cached one.
+    /// Queries with non-deterministic results (e.g. depending on the data
+    /// the cache key does not capture) will trip this check.
+    pub query_cache_verify_on_hit: FlagStatus,
+
+    /// The upper limit on the number of query cache entries of a single
+    /// canister method, or `None` if the number is limited only by the capacity.
+    pub query_cache_max_entries_per_method: Option<usize>,
+
+    /// Indicates whether the query payloads are decoded and re-encoded as
+    /// canonical Candid before keying the query cache, so semantically identical
+    /// payloads with different encodings share the cache entry.
+    /// Note, this adds a Candid decoding and encoding to every cached query.
+    pub query_cache_canonical_payload: FlagStatus,
+
+    /// The number of consecutive lookups finding an invalidated query cache entry
+    /// of a canister, after which the queries of the canister are not cached
+    /// for the cooldown, or `None` if the queries are always cached.
+    /// Caching a canister which changes on nearly every lookup is a pure overhead.
+    pub query_cache_circuit_breaker_threshold: Option<usize>,
+
+    /// How long the queries of a canister are not cached once the query cache
+    /// circuit breaker opens for it.
+    pub query_cache_circuit_breaker_cooldown: Duration,
+
+    /// Indicates whether the concurrent identical query cache misses are coalesced,
+    /// i.e. only the first one is executed, while the rest wait for its result.
+    pub query_cache_coalesce_misses: FlagStatus,
+
+    /// How long the last successful reply of an invalidated query cache entry
+    /// is served instead of a canister trap, or `None` if the traps are always returned.
+    /// This trades the freshness of the replies for the availability of flaky canisters.
+    pub query_cache_stale_on_error_window: Option<Duration>,
+
+    /// Indicates whether the composite queries are cached. As the composite queries
+    /// may call other canisters, they are not cached by default.
+    pub query_cache_composite_queries: FlagStatus,
+
+    /// The expected number of query cache entries, used to pre-allocate the cache,
+    /// so it's not resized under load. If `None`, the cache grows as needed.
+    pub query_cache_expected_entries: Option<usize>,
+
+    /// The upper limit on the age of the served query cache entries, measured
+    /// from their insertion. If `None`, only the max expiry time applies.
+    pub query_cache_max_serve_age: Option<Duration>,
+
+    /// The number of identical query cache misses before the result is stored,
+    /// so the queries which never repeat don't take the cache space.
+    pub query_cache_min_requests: usize,
+
+    /// The policy choosing the query cache entries to evict once the cache is full.
+    /// The least recently used entries are evicted by default.
+    pub query_cache_eviction_policy: EvictionPolicy,
+
+    /// The upper limit on the total size of the query cache entries of a single
+    /// canister, or `None` if the size is limited only by the capacity.
+    /// A canister exceeding it evicts only its own least recently used entries.
+    pub query_cache_per_canister_capacity: Option<NumBytes>,
+
+    /// Indicates whether the query error results are cached. The transient
+    /// errors are never cached, only the deterministic canister-level ones.
+    /// Enabled by default, as the error results were always cached before
+    /// the flag was introduced.
+    pub query_cache_errors: FlagStatus,
+
+    /// The upper limit on the size of a single query cache entry, including
+    /// the key and the reply, or `None` if it's limited only by the capacity.
+    /// The larger entries are not stored, so they can't dominate the cache.
+    pub query_cache_max_entry_size: Option<NumBytes>,
+
     /// The capacity of the Wasm compilation cache.
     pub max_compilation_cache_size: NumBytes,
 
@@ -327,6 +434,21 @@ impl Default for Config {
             query_cache_capacity: QUERY_CACHE_CAPACITY,
             query_cache_max_expiry_time: QUERY_CACHE_MAX_EXPIRY_TIME,
             query_cache_data_certificate_expiry_time: QUERY_CACHE_DATA_CERTIFICATE_EXPIRY_TIME,
+            query_cache_verify_on_hit: FlagStatus::Disabled,
+            query_cache_max_entries_per_method: None,
+            query_cache_canonical_payload: FlagStatus::Disabled,
+            query_cache_circuit_breaker_threshold: None,
+            query_cache_circuit_breaker_cooldown: QUERY_CACHE_CIRCUIT_BREAKER_COOLDOWN,
+            query_cache_coalesce_misses: FlagStatus::Disabled,
+            query_cache_stale_on_error_window: None,
+            query_cache_composite_queries: FlagStatus::Disabled,
+            query_cache_expected_entries: None,
+            query_cache_max_serve_age: None,
+            query_cache_min_requests: 1,
+            query_cache_eviction_policy: EvictionPolicy::Lru,
+            query_cache_per_canister_capacity: None,
+            query_cache_errors: FlagStatus::Enabled,
+            query_cache_max_entry_size: None,
             max_compilation_cache_size: MAX_COMPILATION_CACHE_SIZE,
             query_stats_aggregation: FlagStatus::Enabled,
             query_stats_epoch_length: QUERY_STATS_EPOCH_LENGTH,
@@ -338,6 +460,19 @@ impl Default for Config {
     }
 }
 
+/// Returns the query cache capacity amounting to the `fraction` of the `total_memory`,
+/// clamped between [`MIN_QUERY_CACHE_CAPACITY`] and [`MAX_QUERY_CACHE_CAPACITY`].
+///
+/// This makes the cache sizing portable across machines with different memory.
+pub fn query_cache_capacity_from_fraction(fraction: f64, total_memory: NumBytes) -> NumBytes {
+    // The float to integer cast saturates, and maps `NaN` to zero.
+    let capacity = (total_memory.get() as f64 * fraction) as u64;
+    NumBytes::new(capacity.clamp(
+        MIN_QUERY_CACHE_CAPACITY.get(),
+        MAX_QUERY_CACHE_CAPACITY.get(),
+    ))
+}
+
 #[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize, Default)]
 pub struct BitcoinConfig {
     /// Canisters that have access to privileged bitcoin API (e.g. `bitcoin_get_successors`)
@@ -182,17 +182,49 @@ pub enum ManagedCanisterStatus {
         canister_id: Principal,
         installed_wasm_hash: String,
     },
+    InstallFailed {
+        canister_id: Principal,
+        error: String,
+    },
 }
 
 impl<T> From<&Canister<T>> for ManagedCanisterStatus {
     fn from(canister: &Canister<T>) -> Self {
-        let canister_id = *canister.canister_id();
-        match canister.installed_wasm_hash() {
-            None => ManagedCanisterStatus::Created { canister_id },
-            Some(installed_wasm_hash) => ManagedCanisterStatus::Installed {
+        use crate::state::ManagedCanisterStatus as StateStatus;
+
+        match canister.status().clone() {
+            StateStatus::Created { canister_id } => ManagedCanisterStatus::Created { canister_id },
+            StateStatus::Installed {
+                canister_id,
+                installed_wasm_hash,
+            } => ManagedCanisterStatus::Installed {
                 canister_id,
                 installed_wasm_hash: installed_wasm_hash.to_string(),
             },
+            StateStatus::InstallFailed { canister_id, error } => {
+                ManagedCanisterStatus::InstallFailed { canister_id, error }
+            }
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum SuiteHealth {
+    Healthy,
+    LowCycles,
+    Unreachable,
+    Stopped,
+}
+
+impl From<crate::state::SuiteHealth> for SuiteHealth {
+    fn from(health: crate::state::SuiteHealth) -> Self {
+        use crate::state::SuiteHealth as StateHealth;
+
+        match health {
+            StateHealth::Healthy => SuiteHealth::Healthy,
+            StateHealth::LowCycles => SuiteHealth::LowCycles,
+            StateHealth::Unreachable => SuiteHealth::Unreachable,
+            StateHealth::Stopped => SuiteHealth::Stopped,
         }
     }
 }
@@ -204,6 +236,7 @@ pub struct ManagedCanisters {
     pub ledger: Option<ManagedCanisterStatus>,
     pub index: Option<ManagedCanisterStatus>,
     pub archives: Vec<Principal>,
+    pub health: Option<SuiteHealth>,
 }
 
 impl From<(Erc20Token, Canisters)> for ManagedCanisters {
@@ -217,16 +250,22 @@ impl From<(Erc20Token, Canisters)> for ManagedCanisters {
             ledger: canisters.ledger.as_ref().map(ManagedCanisterStatus::from),
             index: canisters.index.as_ref().map(ManagedCanisterStatus::from),
             archives: canisters.archives.clone(),
+            health: None,
         }
     }
 }
 
+/// Version of the candid interface of the orchestrator.
+/// Must be bumped on every breaking change to the interface.
+pub const INTERFACE_VERSION: u32 = 1;
+
 #[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct OrchestratorInfo {
     pub managed_canisters: Vec<ManagedCanisters>,
     pub cycles_management: CyclesManagement,
     pub more_controller_ids: Vec<Principal>,
     pub minter_id: Option<Principal>,
+    pub interface_version: u32,
 }
 
 #[derive(
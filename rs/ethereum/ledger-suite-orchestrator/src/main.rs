@@ -1,6 +1,9 @@
 use ic_cdk_macros::{init, post_upgrade, query};
 use ic_ledger_suite_orchestrator::candid::Erc20Contract as CandidErc20Contract;
-use ic_ledger_suite_orchestrator::candid::{ManagedCanisterIds, OrchestratorArg, OrchestratorInfo};
+use ic_ledger_suite_orchestrator::candid::{
+    ManagedCanisterIds, ManagedCanisters, OrchestratorArg, OrchestratorInfo, SuiteHealth,
+    INTERFACE_VERSION,
+};
 use ic_ledger_suite_orchestrator::lifecycle;
 use ic_ledger_suite_orchestrator::scheduler::{
     encode_orchestrator_metrics, Erc20Token, IC_CANISTER_RUNTIME,
@@ -23,11 +26,15 @@ fn get_orchestrator_info() -> OrchestratorInfo {
     read_state(|s| OrchestratorInfo {
         managed_canisters: s
             .managed_canisters_iter()
-            .map(|(token, canisters)| (token.clone(), canisters.clone()).into())
+            .map(|(token, canisters)| ManagedCanisters {
+                health: s.suite_health(token).cloned().map(SuiteHealth::from),
+                ..(token.clone(), canisters.clone()).into()
+            })
             .collect(),
         cycles_management: s.cycles_management().clone(),
         more_controller_ids: s.more_controller_ids().to_vec(),
         minter_id: s.minter_id().cloned(),
+        interface_version: INTERFACE_VERSION,
     })
 }
 
@@ -194,7 +201,24 @@ fn http_request(
                             .flat_map(|(_erc20, canisters)| &canisters.archives)
                             .count() as f64,
                         "Total count of ckERC20 archives managed by the orchestrator.",
-                    )
+                    )?;
+
+                    let mut per_token_gauge = w.gauge_vec(
+                        "ledger_suite_orchestrator_token_managed_canisters",
+                        "Count of canisters (ledger, index and archives) managed for each ckERC20 token.",
+                    )?;
+                    for (token, canisters) in s.managed_canisters_iter() {
+                        let chain_id = token.chain_id().as_ref().to_string();
+                        let erc20_contract_address = token.address().to_string();
+                        per_token_gauge = per_token_gauge.value(
+                            &[
+                                ("chain_id", chain_id.as_str()),
+                                ("erc20_contract_address", erc20_contract_address.as_str()),
+                            ],
+                            canisters.collect_principals().len() as f64,
+                        )?;
+                    }
+                    Ok(())
                 })?;
 
                 let num_tasks = TASKS.with(|t| t.borrow().queue.len());
@@ -204,6 +228,18 @@ fn http_request(
                     "Total number of pending tasks.",
                 )?;
 
+                let deadlines = TASKS.with(|t| t.borrow().earliest_deadline_by_task_name());
+                let mut deadline_gauge = w.gauge_vec(
+                    "ledger_suite_orchestrator_task_deadline_seconds",
+                    "Time at which the next task of each type is scheduled, in seconds since the epoch.",
+                )?;
+                for (task_name, execute_at_ns) in deadlines {
+                    deadline_gauge = deadline_gauge.value(
+                        &[("task", task_name)],
+                        execute_at_ns as f64 / 1_000_000_000.0,
+                    )?;
+                }
+
                 encode_orchestrator_metrics(w)?;
                 Ok(())
             }
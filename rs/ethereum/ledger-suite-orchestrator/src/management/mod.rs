@@ -91,6 +91,14 @@ impl Reason {
     }
 }
 
+/// Status of a canister as reported by the management canister.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CanisterStatus {
+    pub cycles: u128,
+    /// Whether the canister is running, i.e. neither stopping nor stopped.
+    pub is_running: bool,
+}
+
 #[async_trait]
 pub trait CanisterRuntime {
     /// Returns the canister id of the current canister.
@@ -119,7 +127,8 @@ pub trait CanisterRuntime {
         arg: Vec<u8>,
     ) -> Result<(), CallError>;
 
-    async fn canister_cycles(&self, canister_id: Principal) -> Result<u128, CallError>;
+    /// Returns the cycles balance of the given canister and whether it's running.
+    async fn canister_status(&self, canister_id: Principal) -> Result<CanisterStatus, CallError>;
 
     fn send_cycles(&self, canister_id: Principal, cycles: u128) -> Result<(), CallError>;
 
@@ -238,22 +247,22 @@ impl CanisterRuntime for IcCanisterRuntime {
         Ok(())
     }
 
-    async fn canister_cycles(&self, canister_id: Principal) -> Result<u128, CallError> {
-        let result = ic_cdk::api::management_canister::main::canister_status(
+    async fn canister_status(&self, canister_id: Principal) -> Result<CanisterStatus, CallError> {
+        use ic_cdk::api::management_canister::main::CanisterStatusType;
+
+        let (result,) = ic_cdk::api::management_canister::main::canister_status(
             ic_cdk::api::management_canister::main::CanisterIdRecord { canister_id },
         )
         .await
         .map_err(|(code, msg)| CallError {
             method: "canister_status".to_string(),
             reason: Reason::from_reject(code, msg),
-        })?
-        .0
-        .cycles
-        .0
-        .try_into()
-        .unwrap();
-
-        Ok(result)
+        })?;
+
+        Ok(CanisterStatus {
+            cycles: result.cycles.0.try_into().unwrap(),
+            is_running: result.status == CanisterStatusType::Running,
+        })
     }
 
     fn send_cycles(&self, canister_id: Principal, cycles: u128) -> Result<(), CallError> {
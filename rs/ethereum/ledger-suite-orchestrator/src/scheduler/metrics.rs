@@ -1,4 +1,5 @@
-use crate::scheduler::{Task, TaskError};
+use crate::scheduler::{Erc20Token, Task, TaskError};
+use candid::Principal;
 use ic_metrics_encoder::MetricsEncoder;
 use std::cell::RefCell;
 use std::collections::BTreeMap;
@@ -21,6 +22,14 @@ pub fn observe_task_duration(
     });
 }
 
+pub fn observe_managed_canister_cycles(token: &Erc20Token, canister_id: Principal, balance: u128) {
+    METRICS.with(|metrics| {
+        metrics
+            .borrow_mut()
+            .observe_managed_canister_cycles(token, canister_id, balance)
+    });
+}
+
 pub fn encode_orchestrator_metrics<W: std::io::Write>(
     encoder: &mut MetricsEncoder<W>,
 ) -> std::io::Result<()> {
@@ -30,6 +39,8 @@ pub fn encode_orchestrator_metrics<W: std::io::Write>(
 #[derive(Default)]
 pub struct OrchestratorMetrics {
     histogram_per_task: BTreeMap<TaskExecutionResult, TaskHistogram>,
+    /// Last observed cycles balance of each managed canister, grouped by ckERC20 token.
+    cycles_per_token: BTreeMap<Erc20Token, BTreeMap<Principal, u128>>,
 }
 
 impl OrchestratorMetrics {
@@ -43,13 +54,7 @@ impl OrchestratorMetrics {
         let elapsed_ns = end_time_ns.saturating_sub(start_time_ns);
         let duration_secs = Duration::from_nanos(elapsed_ns).as_secs_f64();
         let task = TaskExecutionResult {
-            task_name: match task {
-                Task::InstallLedgerSuite(_) => "install_ledger_suite",
-                Task::MaybeTopUp => "maybe_top_up",
-                Task::NotifyErc20Added { .. } => "notify_erc20_added",
-                Task::DiscoverArchives => "discover_archives",
-            }
-            .to_string(),
+            task_name: task.name().to_string(),
             result: match result {
                 Ok(_) => MetricsResult::Ok,
                 Err(_) => MetricsResult::Err,
@@ -66,10 +71,40 @@ impl OrchestratorMetrics {
         }
     }
 
+    pub fn observe_managed_canister_cycles(
+        &mut self,
+        token: &Erc20Token,
+        canister_id: Principal,
+        balance: u128,
+    ) {
+        self.cycles_per_token
+            .entry(token.clone())
+            .or_default()
+            .insert(canister_id, balance);
+    }
+
     pub fn encode<W: std::io::Write>(
         &self,
         encoder: &mut MetricsEncoder<W>,
     ) -> std::io::Result<()> {
+        if !self.cycles_per_token.is_empty() {
+            let mut cycles_gauge = encoder.gauge_vec(
+                "ledger_suite_orchestrator_token_cycles_balance",
+                "Total cycles balance of the canisters managed for each ckERC20 token, as last observed when topping them up.",
+            )?;
+            for (token, balances) in &self.cycles_per_token {
+                let chain_id = token.chain_id().as_ref().to_string();
+                let erc20_contract_address = token.address().to_string();
+                cycles_gauge = cycles_gauge.value(
+                    &[
+                        ("chain_id", chain_id.as_str()),
+                        ("erc20_contract_address", erc20_contract_address.as_str()),
+                    ],
+                    balances.values().sum::<u128>() as f64,
+                )?;
+            }
+        }
+
         if self.histogram_per_task.is_empty() {
             return Ok(());
         }
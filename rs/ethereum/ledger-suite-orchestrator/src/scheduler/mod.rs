@@ -11,7 +11,7 @@ use crate::management::IcCanisterRuntime;
 use crate::management::{CallError, CanisterRuntime, Reason};
 use crate::state::{
     mutate_state, read_state, Canisters, CanistersMetadata, Index, Ledger, ManageSingleCanister,
-    ManagedCanisterStatus, State, WasmHash,
+    ManagedCanisterStatus, State, SuiteHealth, WasmHash,
 };
 use crate::storage::{
     read_wasm_store, validate_wasm_hashes, wasm_store_try_get, StorableWasm, TaskQueue,
@@ -26,7 +26,7 @@ use ic_icrc1_index_ng::{IndexArg, InitArg as IndexInitArg};
 use ic_icrc1_ledger::{ArchiveOptions, InitArgs as LedgerInitArgs, LedgerArgument};
 use icrc_ledger_types::icrc3::archive::ArchiveInfo;
 pub use metrics::encode_orchestrator_metrics;
-use metrics::observe_task_duration;
+use metrics::{observe_managed_canister_cycles, observe_task_duration};
 use num_traits::ToPrimitive;
 use serde::{Deserialize, Serialize};
 use std::cell::Cell;
@@ -59,6 +59,16 @@ pub enum Task {
 }
 
 impl Task {
+    /// Name of the task type, e.g. to label metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Task::InstallLedgerSuite(_) => "install_ledger_suite",
+            Task::MaybeTopUp => "maybe_top_up",
+            Task::NotifyErc20Added { .. } => "notify_erc20_added",
+            Task::DiscoverArchives => "discover_archives",
+        }
+    }
+
     fn is_periodic(&self) -> bool {
         match self {
             Task::InstallLedgerSuite(_) => false,
@@ -140,6 +150,18 @@ impl TaskQueue {
     pub fn len(&self) -> usize {
         self.queue.len() as usize
     }
+
+    /// Returns the earliest deadline of the scheduled tasks of each type, keyed by task name.
+    pub fn earliest_deadline_by_task_name(&self) -> BTreeMap<&'static str, u64> {
+        let mut deadlines = BTreeMap::new();
+        for (task, execute_at_ns) in self.deadline_by_task.iter() {
+            deadlines
+                .entry(task.name())
+                .and_modify(|deadline: &mut u64| *deadline = (*deadline).min(execute_at_ns))
+                .or_insert(execute_at_ns);
+        }
+        deadlines
+    }
 }
 
 /// Schedules a task for execution after the given delay.
@@ -276,6 +298,7 @@ impl InstallLedgerSuiteArgs {
 pub enum InvalidAddErc20ArgError {
     InvalidErc20Contract(String),
     Erc20ContractAlreadyManaged(Erc20Token),
+    AnonymousMintingAccount,
     WasmHashError(WasmHashError),
 }
 
@@ -292,6 +315,9 @@ impl InstallLedgerSuiteArgs {
                 contract,
             ));
         }
+        if args.ledger_init_arg.minting_account.owner == Principal::anonymous() {
+            return Err(InvalidAddErc20ArgError::AnonymousMintingAccount);
+        }
         let [ledger_compressed_wasm_hash, index_compressed_wasm_hash, _archive_compressed_wasm_hash] =
             validate_wasm_hashes(
                 wasm_store,
@@ -314,12 +340,19 @@ impl InstallLedgerSuiteArgs {
 pub enum TaskError {
     CanisterCreationError(CallError),
     InstallCodeError(CallError),
+    InstallFailed {
+        canister_id: Principal,
+        error: String,
+    },
     CanisterStatusError(CallError),
     WasmHashNotFound(WasmHash),
     WasmStoreError(WasmStoreError),
     LedgerNotFound(Erc20Token),
     InterCanisterCallError(CallError),
-    InsufficientCyclesToTopUp { required: u128, available: u128 },
+    InsufficientCyclesToTopUp {
+        required: u128,
+        available: u128,
+    },
 }
 
 impl TaskError {
@@ -328,7 +361,8 @@ impl TaskError {
     fn is_recoverable(&self) -> bool {
         match self {
             TaskError::CanisterCreationError(_) => true,
-            TaskError::InstallCodeError(_) => true,
+            TaskError::InstallCodeError(CallError { method: _, reason }) => !is_trap(reason),
+            TaskError::InstallFailed { .. } => false,
             TaskError::CanisterStatusError(_) => true,
             TaskError::WasmHashNotFound(_) => false,
             TaskError::WasmStoreError(_) => false,
@@ -347,6 +381,37 @@ impl TaskError {
     }
 }
 
+/// Whether the call failed because the callee trapped, e.g. when a canister traps in its init method.
+/// Retrying such a call with the same arguments will not help.
+///
+/// The caller only gets the reject code and message of an error, not its `ErrorCode`.
+/// A trap is therefore recognized by the `CANISTER_ERROR` reject code together with the message
+/// of the `CanisterCalledTrap` or `CanisterTrapped` error codes, which is either
+/// `Error from Canister <id>: Canister trapped[ explicitly]: <reason>`
+/// or, on older replicas, `Canister <id> trapped[ explicitly]: <reason>`.
+fn is_trap(reason: &Reason) -> bool {
+    let msg = match reason {
+        Reason::CanisterError(msg) => msg.as_str(),
+        _ => return false,
+    };
+    let is_canister_id = |id: &str| Principal::from_text(id).is_ok();
+    let description = match msg
+        .strip_prefix("Error from Canister ")
+        .and_then(|rest| rest.split_once(": "))
+    {
+        Some((canister_id, description)) if is_canister_id(canister_id) => description,
+        _ => msg,
+    };
+    let trap = match description.strip_prefix("Canister ") {
+        Some(rest) => match rest.split_once(' ') {
+            Some((canister_id, trap)) if is_canister_id(canister_id) => trap,
+            _ => rest,
+        },
+        None => return false,
+    };
+    trap.starts_with("trapped explicitly: ") || trap.starts_with("trapped: ")
+}
+
 impl TaskExecution {
     pub async fn execute<R: CanisterRuntime>(&self, runtime: &R) -> Result<(), TaskError> {
         match &self.task_type {
@@ -362,11 +427,17 @@ impl TaskExecution {
 }
 
 async fn maybe_top_up<R: CanisterRuntime>(runtime: &R) -> Result<(), TaskError> {
-    let managed_principals: Vec<Principal> = read_state(|s| {
+    let managed_canisters: Vec<(Erc20Token, Principal)> = read_state(|s| {
         s.managed_canisters_iter()
-            .flat_map(|(_, canisters)| canisters.collect_principals())
+            .flat_map(|(token, canisters)| {
+                canisters
+                    .collect_principals()
+                    .into_iter()
+                    .map(|p| (token.clone(), p))
+            })
             .collect()
     });
+    let managed_principals: Vec<Principal> = managed_canisters.iter().map(|(_, p)| *p).collect();
     if managed_principals.is_empty() {
         log!(INFO, "[maybe_top_up]: No managed canisters to top-up");
         return Ok(());
@@ -385,8 +456,8 @@ async fn maybe_top_up<R: CanisterRuntime>(runtime: &R) -> Result<(), TaskError>
     Monitored canister minimum target cycles balance {minimum_monitored_canister_cycles}", display_vec(&managed_principals)
     );
 
-    let mut orchestrator_cycle_balance = match runtime.canister_cycles(runtime.id()).await {
-        Ok(balance) => balance,
+    let mut orchestrator_cycle_balance = match runtime.canister_status(runtime.id()).await {
+        Ok(status) => status.cycles,
         Err(e) => {
             log!(
                 INFO,
@@ -396,24 +467,34 @@ async fn maybe_top_up<R: CanisterRuntime>(runtime: &R) -> Result<(), TaskError>
             return Err(TaskError::CanisterStatusError(e));
         }
     };
-    if orchestrator_cycle_balance < minimum_orchestrator_cycles {
-        return Err(TaskError::InsufficientCyclesToTopUp {
-            required: minimum_orchestrator_cycles,
-            available: orchestrator_cycle_balance,
-        });
-    }
+    // Managed canisters are still probed when the orchestrator cannot top them up,
+    // so that the health of each ledger suite stays up-to-date.
+    let mut insufficient_cycles_to_top_up = false;
+    let mut suite_health: BTreeMap<Erc20Token, SuiteHealth> = managed_canisters
+        .iter()
+        .map(|(token, _)| (token.clone(), SuiteHealth::Healthy))
+        .collect();
 
     let results = future::join_all(
         managed_principals
             .iter()
-            .map(|p| runtime.canister_cycles(*p)),
+            .map(|p| runtime.canister_status(*p)),
     )
     .await;
     assert!(!results.is_empty());
 
-    for (canister_id, cycles_result) in managed_principals.iter().zip(results) {
-        match cycles_result {
-            Ok(balance) => {
+    for ((token, canister_id), status_result) in managed_canisters.iter().zip(results) {
+        match status_result {
+            Ok(status) => {
+                let balance = status.cycles;
+                observe_managed_canister_cycles(token, *canister_id, balance);
+                let health = suite_health
+                    .get_mut(token)
+                    .expect("BUG: missing health of managed ledger suite");
+                if !status.is_running {
+                    log!(INFO, "[maybe_top_up] canister {canister_id} is not running");
+                    health.worsen(SuiteHealth::Stopped);
+                }
                 match (
                     balance.cmp(&minimum_monitored_canister_cycles),
                     orchestrator_cycle_balance.cmp(&minimum_orchestrator_cycles),
@@ -425,10 +506,8 @@ async fn maybe_top_up<R: CanisterRuntime>(runtime: &R) -> Result<(), TaskError>
                         );
                     }
                     (_, Ordering::Less) => {
-                        return Err(TaskError::InsufficientCyclesToTopUp {
-                            required: minimum_orchestrator_cycles,
-                            available: orchestrator_cycle_balance,
-                        });
+                        insufficient_cycles_to_top_up = true;
+                        health.worsen(SuiteHealth::LowCycles);
                     }
                     (Ordering::Less, Ordering::Equal) | (Ordering::Less, Ordering::Greater) => {
                         log!(
@@ -446,6 +525,7 @@ async fn maybe_top_up<R: CanisterRuntime>(runtime: &R) -> Result<(), TaskError>
                                     canister_id,
                                     e
                                 );
+                                health.worsen(SuiteHealth::LowCycles);
                             }
                         }
                     }
@@ -458,10 +538,26 @@ async fn maybe_top_up<R: CanisterRuntime>(runtime: &R) -> Result<(), TaskError>
                     canister_id,
                     e
                 );
+                suite_health
+                    .get_mut(token)
+                    .expect("BUG: missing health of managed ledger suite")
+                    .worsen(SuiteHealth::Unreachable);
             }
         }
     }
 
+    mutate_state(|s| {
+        for (token, health) in suite_health {
+            s.record_suite_health(token, health);
+        }
+    });
+
+    if insufficient_cycles_to_top_up {
+        return Err(TaskError::InsufficientCyclesToTopUp {
+            required: minimum_orchestrator_cycles,
+            available: orchestrator_cycle_balance,
+        });
+    }
     Ok(())
 }
 
@@ -492,8 +588,10 @@ async fn install_ledger_suite<R: CanisterRuntime>(
         create_canister_once::<Ledger, _>(&args.contract, runtime, cycles_for_ledger_creation)
             .await?;
 
-    let more_controllers = read_state(|s| s.more_controller_ids().to_vec())
+    // The orchestrator is already the archive controller.
+    let more_controllers = controllers_of_children_canisters(runtime)
         .into_iter()
+        .skip(1)
         .map(PrincipalId)
         .collect();
     install_canister_once::<Ledger, _, _>(
@@ -652,12 +750,16 @@ where
     Ok(canister_id)
 }
 
+/// Returns the orchestrator followed by the additional controllers, each listed only once.
 fn controllers_of_children_canisters<R: CanisterRuntime>(runtime: &R) -> Vec<Principal> {
     let more_controllers = read_state(|s| s.more_controller_ids().to_vec());
-    vec![runtime.id()]
-        .into_iter()
-        .chain(more_controllers)
-        .collect()
+    let mut controllers = vec![runtime.id()];
+    for controller in more_controllers {
+        if !controllers.contains(&controller) {
+            controllers.push(controller);
+        }
+    }
+    controllers
 }
 
 async fn install_canister_once<C, R, I>(
@@ -681,6 +783,9 @@ where
         }
         Some(ManagedCanisterStatus::Created { canister_id }) => canister_id,
         Some(ManagedCanisterStatus::Installed { .. }) => return Ok(()),
+        Some(ManagedCanisterStatus::InstallFailed { canister_id, error }) => {
+            return Err(TaskError::InstallFailed { canister_id, error })
+        }
     };
 
     let wasm = match read_wasm_store(|s| wasm_store_try_get::<C>(s, wasm_hash)) {
@@ -737,6 +842,9 @@ where
                 init_args,
                 e
             );
+            if is_trap(&e.reason) {
+                mutate_state(|s| s.record_failed_install::<C>(contract, e.to_string()));
+            }
             return Err(TaskError::InstallCodeError(e));
         }
     };
@@ -861,13 +969,30 @@ impl TryFrom<crate::candid::Erc20Contract> for Erc20Token {
     type Error = String;
 
     fn try_from(contract: crate::candid::Erc20Contract) -> Result<Self, Self::Error> {
+        let address = Address::from_str(&contract.address)?;
+        validate_address_checksum(&contract.address, &address)?;
         Ok(Self(
             ChainId(contract.chain_id.0.to_u64().ok_or("chain_id is not u64")?),
-            Address::from_str(&contract.address)?,
+            address,
         ))
     }
 }
 
+/// Mixed-case addresses carry an EIP-55 checksum that must match,
+/// while all-lowercase or all-uppercase addresses are accepted as is.
+fn validate_address_checksum(address_str: &str, address: &Address) -> Result<(), String> {
+    let hex_digits = address_str.trim_start_matches("0x");
+    let is_mixed_case = hex_digits.chars().any(|c| c.is_ascii_lowercase())
+        && hex_digits.chars().any(|c| c.is_ascii_uppercase());
+    if is_mixed_case && address.to_string() != address_str {
+        return Err(format!(
+            "address has an invalid checksum, expected {}",
+            address
+        ));
+    }
+    Ok(())
+}
+
 fn display_vec<T: Display>(v: &[T]) -> String {
     format!(
         "[{}]",
@@ -1,12 +1,12 @@
 use crate::candid::{AddCkErc20Token, CyclesManagement, InitArg, LedgerInitArg};
-use crate::management::{CallError, Reason};
+use crate::management::{CallError, CanisterStatus, Reason};
 use crate::scheduler::test_fixtures::{usdc, usdc_metadata};
 use crate::scheduler::tests::mock::MockCanisterRuntime;
 use crate::scheduler::{cycles_to_u128, InstallLedgerSuiteArgs, Task, TaskError, TaskExecution};
 use crate::state::test_fixtures::new_state;
 use crate::state::{
     read_state, Canisters, GitCommitHash, IndexCanister, LedgerCanister, ManagedCanisterStatus,
-    State, WasmHash, INDEX_BYTECODE, LEDGER_BYTECODE,
+    State, SuiteHealth, WasmHash, INDEX_BYTECODE, LEDGER_BYTECODE,
 };
 use crate::storage::{mutate_wasm_store, record_icrc1_ledger_suite_wasms};
 use candid::Principal;
@@ -82,15 +82,15 @@ async fn should_top_up_canister() {
     };
     let mut seq = Sequence::new();
     runtime
-        .expect_canister_cycles()
+        .expect_canister_status()
         .times(1)
         .in_sequence(&mut seq)
-        .return_const(Ok(orchestrator_cycles));
+        .return_const(Ok(running_with(orchestrator_cycles)));
     runtime
-        .expect_canister_cycles()
+        .expect_canister_status()
         .times(2)
         .in_sequence(&mut seq)
-        .return_const(Ok(low_cycles));
+        .return_const(Ok(running_with(low_cycles)));
 
     runtime
         .expect_send_cycles()
@@ -100,18 +100,22 @@ async fn should_top_up_canister() {
         .times(2)
         .return_const(Ok(()));
     assert_eq!(task.execute(&runtime).await, Ok(()));
+    assert_eq!(
+        read_state(|s| s.suite_health(&usdc()).cloned()),
+        Some(SuiteHealth::Healthy)
+    );
 
     let mut seq = Sequence::new();
     runtime
-        .expect_canister_cycles()
+        .expect_canister_status()
         .times(1)
         .in_sequence(&mut seq)
-        .return_const(Ok(orchestrator_cycles));
+        .return_const(Ok(running_with(orchestrator_cycles)));
     runtime
-        .expect_canister_cycles()
+        .expect_canister_status()
         .times(2)
         .in_sequence(&mut seq)
-        .return_const(Ok(low_cycles));
+        .return_const(Ok(running_with(low_cycles)));
     runtime
         .expect_send_cycles()
         .times(1)
@@ -121,20 +125,188 @@ async fn should_top_up_canister() {
         }));
     runtime.expect_send_cycles().times(1).return_const(Ok(()));
     assert_eq!(task.execute(&runtime).await, Ok(()));
+    assert_eq!(
+        read_state(|s| s.suite_health(&usdc()).cloned()),
+        Some(SuiteHealth::LowCycles)
+    );
 
     let mut seq = Sequence::new();
     runtime
-        .expect_canister_cycles()
+        .expect_canister_status()
         .times(1)
         .in_sequence(&mut seq)
-        .return_const(Ok(orchestrator_cycles));
+        .return_const(Ok(running_with(orchestrator_cycles)));
     runtime
-        .expect_canister_cycles()
+        .expect_canister_status()
         .times(2)
         .in_sequence(&mut seq)
-        .return_const(Ok(enough_cycles));
+        .return_const(Ok(running_with(enough_cycles)));
+    runtime.expect_send_cycles().never();
+    assert_eq!(task.execute(&runtime).await, Ok(()));
+    assert_eq!(
+        read_state(|s| s.suite_health(&usdc()).cloned()),
+        Some(SuiteHealth::Healthy)
+    );
+}
+
+#[tokio::test]
+async fn should_record_suite_health_even_when_orchestrator_cannot_top_up() {
+    use mockall::Sequence;
+    init_state();
+    let cycles_management = CyclesManagement::default();
+    let minimum_orchestrator_cycles =
+        cycles_to_u128(cycles_management.minimum_orchestrator_cycles());
+    let orchestrator_cycles = minimum_orchestrator_cycles / 2;
+    let low_cycles = cycles_to_u128(cycles_management.minimum_monitored_canister_cycles()) / 2;
+    let enough_cycles = cycles_to_u128(cycles_management.minimum_monitored_canister_cycles());
+    let mut runtime = MockCanisterRuntime::new();
+    runtime.expect_id().return_const(ORCHESTRATOR_PRINCIPAL);
+    expect_create_canister_returning(
+        &mut runtime,
+        vec![ORCHESTRATOR_PRINCIPAL],
+        vec![Ok(LEDGER_PRINCIPAL), Ok(INDEX_PRINCIPAL)],
+    );
+    runtime.expect_install_code().times(2).return_const(Ok(()));
+
+    let task = TaskExecution {
+        task_type: Task::InstallLedgerSuite(usdc_install_args()),
+        execute_at_ns: 0,
+    };
+    assert_eq!(task.execute(&runtime).await, Ok(()));
+    assert_eq!(read_state(|s| s.suite_health(&usdc()).cloned()), None);
+
+    let task = TaskExecution {
+        task_type: Task::MaybeTopUp,
+        execute_at_ns: 0,
+    };
+    let insufficient_cycles = Err(TaskError::InsufficientCyclesToTopUp {
+        required: minimum_orchestrator_cycles,
+        available: orchestrator_cycles,
+    });
+    let mut seq = Sequence::new();
+    runtime
+        .expect_canister_status()
+        .times(1)
+        .in_sequence(&mut seq)
+        .return_const(Ok(running_with(orchestrator_cycles)));
+    runtime
+        .expect_canister_status()
+        .times(2)
+        .in_sequence(&mut seq)
+        .return_const(Ok(running_with(low_cycles)));
+    runtime.expect_send_cycles().never();
+    assert_eq!(task.execute(&runtime).await, insufficient_cycles);
+    assert_eq!(
+        read_state(|s| s.suite_health(&usdc()).cloned()),
+        Some(SuiteHealth::LowCycles)
+    );
+
+    let mut seq = Sequence::new();
+    runtime
+        .expect_canister_status()
+        .times(1)
+        .in_sequence(&mut seq)
+        .return_const(Ok(running_with(orchestrator_cycles)));
+    runtime
+        .expect_canister_status()
+        .times(1)
+        .in_sequence(&mut seq)
+        .return_const(Ok(CanisterStatus {
+            cycles: enough_cycles,
+            is_running: false,
+        }));
+    runtime
+        .expect_canister_status()
+        .times(1)
+        .in_sequence(&mut seq)
+        .return_const(Ok(running_with(low_cycles)));
+    runtime.expect_send_cycles().never();
+    assert_eq!(task.execute(&runtime).await, insufficient_cycles);
+    assert_eq!(
+        read_state(|s| s.suite_health(&usdc()).cloned()),
+        Some(SuiteHealth::Stopped)
+    );
+}
+
+#[tokio::test]
+async fn should_record_suite_health_when_canister_status_fails() {
+    use mockall::Sequence;
+    init_state();
+    let cycles_management = CyclesManagement::default();
+    let orchestrator_cycles = cycles_to_u128(cycles_management.minimum_orchestrator_cycles()) * 2;
+    let low_cycles = cycles_to_u128(cycles_management.minimum_monitored_canister_cycles()) / 2;
+    let enough_cycles = cycles_to_u128(cycles_management.minimum_monitored_canister_cycles());
+    let mut runtime = MockCanisterRuntime::new();
+    runtime.expect_id().return_const(ORCHESTRATOR_PRINCIPAL);
+    expect_create_canister_returning(
+        &mut runtime,
+        vec![ORCHESTRATOR_PRINCIPAL],
+        vec![Ok(LEDGER_PRINCIPAL), Ok(INDEX_PRINCIPAL)],
+    );
+    runtime.expect_install_code().times(2).return_const(Ok(()));
+
+    let task = TaskExecution {
+        task_type: Task::InstallLedgerSuite(usdc_install_args()),
+        execute_at_ns: 0,
+    };
+    assert_eq!(task.execute(&runtime).await, Ok(()));
+
+    let task = TaskExecution {
+        task_type: Task::MaybeTopUp,
+        execute_at_ns: 0,
+    };
+    let status_error = Err(CallError {
+        method: "canister_status".to_string(),
+        reason: Reason::TransientInternalError("subnet is overloaded".to_string()),
+    });
+    let mut seq = Sequence::new();
+    runtime
+        .expect_canister_status()
+        .times(1)
+        .in_sequence(&mut seq)
+        .return_const(Ok(running_with(orchestrator_cycles)));
+    runtime
+        .expect_canister_status()
+        .times(1)
+        .in_sequence(&mut seq)
+        .return_const(status_error.clone());
+    runtime
+        .expect_canister_status()
+        .times(1)
+        .in_sequence(&mut seq)
+        .return_const(Ok(running_with(low_cycles)));
+    runtime.expect_send_cycles().times(1).return_const(Ok(()));
+    assert_eq!(task.execute(&runtime).await, Ok(()));
+    assert_eq!(
+        read_state(|s| s.suite_health(&usdc()).cloned()),
+        Some(SuiteHealth::Unreachable)
+    );
+
+    let mut seq = Sequence::new();
+    runtime
+        .expect_canister_status()
+        .times(1)
+        .in_sequence(&mut seq)
+        .return_const(Ok(running_with(orchestrator_cycles)));
+    runtime
+        .expect_canister_status()
+        .times(1)
+        .in_sequence(&mut seq)
+        .return_const(status_error);
+    runtime
+        .expect_canister_status()
+        .times(1)
+        .in_sequence(&mut seq)
+        .return_const(Ok(CanisterStatus {
+            cycles: enough_cycles,
+            is_running: false,
+        }));
     runtime.expect_send_cycles().never();
     assert_eq!(task.execute(&runtime).await, Ok(()));
+    assert_eq!(
+        read_state(|s| s.suite_health(&usdc()).cloned()),
+        Some(SuiteHealth::Stopped)
+    );
 }
 
 #[tokio::test]
@@ -183,6 +355,36 @@ async fn should_install_ledger_suite_with_additional_controllers() {
     );
 }
 
+#[tokio::test]
+async fn should_install_ledger_suite_with_deduplicated_controllers() {
+    const OTHER_PRINCIPAL: Principal = Principal::from_slice(&[3_u8; 29]);
+    crate::state::init_state(
+        State::try_from(InitArg {
+            more_controller_ids: vec![OTHER_PRINCIPAL, ORCHESTRATOR_PRINCIPAL, OTHER_PRINCIPAL],
+            minter_id: None,
+            cycles_management: None,
+        })
+        .unwrap(),
+    );
+    register_embedded_wasms();
+
+    let mut runtime = MockCanisterRuntime::new();
+
+    runtime.expect_id().return_const(ORCHESTRATOR_PRINCIPAL);
+    expect_create_canister_returning(
+        &mut runtime,
+        vec![ORCHESTRATOR_PRINCIPAL, OTHER_PRINCIPAL],
+        vec![Ok(LEDGER_PRINCIPAL), Ok(INDEX_PRINCIPAL)],
+    );
+    runtime.expect_install_code().times(2).return_const(Ok(()));
+
+    let task = TaskExecution {
+        task_type: Task::InstallLedgerSuite(usdc_install_args()),
+        execute_at_ns: 0,
+    };
+    assert_eq!(task.execute(&runtime).await, Ok(()));
+}
+
 #[tokio::test]
 async fn should_not_retry_successful_operation_after_failing_one() {
     init_state();
@@ -309,6 +511,69 @@ async fn should_not_retry_successful_operation_after_failing_one() {
     );
 }
 
+#[tokio::test]
+async fn should_record_install_failure_when_ledger_traps_on_init() {
+    init_state();
+    let mut runtime = MockCanisterRuntime::new();
+
+    runtime.expect_id().return_const(ORCHESTRATOR_PRINCIPAL);
+    expect_create_canister_returning(
+        &mut runtime,
+        vec![ORCHESTRATOR_PRINCIPAL],
+        vec![Ok(LEDGER_PRINCIPAL)],
+    );
+    let expected_error = CallError {
+        method: "install_code".to_string(),
+        reason: Reason::CanisterError(
+            "Canister mxzaz-hqaaa-aaaar-qaada-cai trapped explicitly: Panicked at 'failed to convert transfer fee'".to_string(),
+        ),
+    };
+    runtime
+        .expect_install_code()
+        .times(1)
+        .return_const(Err(expected_error.clone()));
+
+    let task = TaskExecution {
+        task_type: Task::InstallLedgerSuite(usdc_install_args()),
+        execute_at_ns: 0,
+    };
+    let result = task.execute(&runtime).await;
+    assert_eq!(
+        result,
+        Err(TaskError::InstallCodeError(expected_error.clone()))
+    );
+    assert!(!result.unwrap_err().is_recoverable());
+    let expected_canisters = Canisters {
+        ledger: Some(LedgerCanister::new(ManagedCanisterStatus::InstallFailed {
+            canister_id: LEDGER_PRINCIPAL,
+            error: expected_error.to_string(),
+        })),
+        index: None,
+        archives: vec![],
+        metadata: usdc_metadata(),
+    };
+    assert_eq!(
+        read_state(|s| s.managed_canisters(&usdc()).cloned()),
+        Some(expected_canisters.clone())
+    );
+
+    runtime.checkpoint();
+    runtime.expect_id().return_const(ORCHESTRATOR_PRINCIPAL);
+    runtime.expect_install_code().never();
+    runtime.expect_create_canister().never();
+    assert_eq!(
+        task.execute(&runtime).await,
+        Err(TaskError::InstallFailed {
+            canister_id: LEDGER_PRINCIPAL,
+            error: expected_error.to_string(),
+        })
+    );
+    assert_eq!(
+        read_state(|s| s.managed_canisters(&usdc()).cloned()),
+        Some(expected_canisters)
+    );
+}
+
 #[tokio::test]
 async fn should_discard_add_erc20_task_when_ledger_wasm_not_found() {
     init_state();
@@ -722,6 +987,13 @@ mod run_task {
     }
 }
 
+fn running_with(cycles: u128) -> CanisterStatus {
+    CanisterStatus {
+        cycles,
+        is_running: true,
+    }
+}
+
 fn init_state() {
     crate::state::init_state(new_state());
     register_embedded_wasms();
@@ -825,6 +1097,51 @@ fn expect_call_canister_archives(
         .return_const(mocked_result);
 }
 
+mod is_trap {
+    use crate::management::Reason;
+    use crate::scheduler::is_trap;
+
+    #[test]
+    fn should_recognize_traps() {
+        for msg in [
+            "Error from Canister mxzaz-hqaaa-aaaar-qaada-cai: Canister trapped explicitly: Panicked at 'failed to convert transfer fee'",
+            "Error from Canister mxzaz-hqaaa-aaaar-qaada-cai: Canister trapped: unreachable",
+            "Canister mxzaz-hqaaa-aaaar-qaada-cai trapped explicitly: Panicked at 'failed to convert transfer fee'",
+            "Canister mxzaz-hqaaa-aaaar-qaada-cai trapped: integer division by 0",
+        ] {
+            assert!(
+                is_trap(&Reason::CanisterError(msg.to_string())),
+                "BUG: expected {} to be a trap",
+                msg
+            );
+        }
+    }
+
+    #[test]
+    fn should_not_recognize_other_errors_as_traps() {
+        for reason in [
+            Reason::CanisterError(
+                "Canister mxzaz-hqaaa-aaaar-qaada-cai is stopped".to_string(),
+            ),
+            Reason::CanisterError(
+                "Error from Canister mxzaz-hqaaa-aaaar-qaada-cai: Canister exceeded the limit of 200000000000 instructions for single message execution.".to_string(),
+            ),
+            Reason::CanisterError(
+                "Error from Canister mxzaz-hqaaa-aaaar-qaada-cai: Canister violated contract: the call to the ledger trapped".to_string(),
+            ),
+            Reason::Rejected(
+                "Error from Canister mxzaz-hqaaa-aaaar-qaada-cai: Canister trapped: unreachable"
+                    .to_string(),
+            ),
+            Reason::InternalError(
+                "Canister mxzaz-hqaaa-aaaar-qaada-cai trapped: unreachable".to_string(),
+            ),
+        ] {
+            assert!(!is_trap(&reason), "BUG: expected {:?} not to be a trap", reason);
+        }
+    }
+}
+
 mod metrics {
     use crate::management::CallError;
     use crate::scheduler::metrics::observe_task_duration;
@@ -926,7 +1243,7 @@ orchestrator_tasks_duration_seconds_count{task="maybe_top_up",result="err"} 1 12
 }
 
 mod mock {
-    use crate::management::CanisterRuntime;
+    use crate::management::{CanisterRuntime, CanisterStatus};
     use crate::scheduler::CallError;
     use async_trait::async_trait;
     use candid::CandidType;
@@ -961,10 +1278,10 @@ mod mock {
                 arg: Vec<u8>,
             ) -> Result<(), CallError>;
 
-            async fn canister_cycles(
+            async fn canister_status(
                 &self,
                 canister_id: Principal,
-            ) -> Result<u128, CallError>;
+            ) -> Result<CanisterStatus, CallError>;
 
             fn send_cycles(
                 &self,
@@ -987,7 +1304,7 @@ mod mock {
 
 mod install_ledger_suite_args {
     use crate::candid::{AddErc20Arg, LedgerInitArg};
-    use crate::scheduler::tests::usdc_metadata;
+    use crate::scheduler::tests::{usdc_metadata, MINTER_PRINCIPAL};
     use crate::scheduler::{ChainId, Erc20Token, InstallLedgerSuiteArgs, InvalidAddErc20ArgError};
     use crate::state::test_fixtures::new_state;
     use crate::state::{GitCommitHash, IndexWasm, LedgerWasm, State};
@@ -1017,6 +1334,19 @@ mod install_ledger_suite_args {
         );
     }
 
+    #[test]
+    fn should_error_on_anonymous_minting_account() {
+        let state = new_state();
+        let wasm_store = wasm_store_with_icrc1_ledger_suite();
+        let mut arg = valid_add_erc20_arg(&state, &wasm_store);
+        arg.ledger_init_arg.minting_account.owner = Principal::anonymous();
+
+        assert_eq!(
+            InstallLedgerSuiteArgs::validate_add_erc20(&state, &wasm_store, arg),
+            Err(InvalidAddErc20ArgError::AnonymousMintingAccount)
+        );
+    }
+
     proptest! {
         #[test]
         fn queue_holds_one_copy_of_each_task(
@@ -1072,6 +1402,39 @@ mod install_ledger_suite_args {
         }
     }
 
+    #[test]
+    fn should_error_on_invalid_address_checksum() {
+        let state = new_state();
+        let wasm_store = wasm_store_with_icrc1_ledger_suite();
+        let mut arg = valid_add_erc20_arg(&state, &wasm_store);
+        // EIP-55 checksummed address with the case of the first letter flipped
+        arg.contract.address = "0xa0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48".to_string();
+
+        assert_matches!(
+            InstallLedgerSuiteArgs::validate_add_erc20(&state, &wasm_store, arg),
+            Err(InvalidAddErc20ArgError::InvalidErc20Contract(e)) if e.contains("invalid checksum")
+        );
+    }
+
+    #[test]
+    fn should_accept_ethereum_address_with_valid_checksum_or_without_checksum() {
+        for address in [
+            "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48",
+            "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48",
+            "0xA0B86991C6218B36C1D19D4A2E9EB0CE3606EB48",
+        ] {
+            let state = new_state();
+            let wasm_store = wasm_store_with_icrc1_ledger_suite();
+            let mut arg = valid_add_erc20_arg(&state, &wasm_store);
+            arg.contract.address = address.to_string();
+
+            assert_matches!(
+                InstallLedgerSuiteArgs::validate_add_erc20(&state, &wasm_store, arg),
+                Ok(_)
+            );
+        }
+    }
+
     #[test]
     fn should_accept_valid_erc20_arg() {
         let state = new_state();
@@ -1106,7 +1469,7 @@ mod install_ledger_suite_args {
             },
             ledger_init_arg: LedgerInitArg {
                 minting_account: LedgerAccount {
-                    owner: Principal::anonymous(),
+                    owner: MINTER_PRINCIPAL,
                     subaccount: None,
                 },
                 fee_collector_account: None,
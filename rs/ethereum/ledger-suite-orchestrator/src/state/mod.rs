@@ -297,6 +297,10 @@ impl<T> Canister<T> {
     pub fn installed_wasm_hash(&self) -> Option<&WasmHash> {
         self.status.installed_wasm_hash()
     }
+
+    pub fn status(&self) -> &ManagedCanisterStatus {
+        &self.status
+    }
 }
 
 impl<T> Serialize for Canister<T> {
@@ -337,19 +341,30 @@ pub enum ManagedCanisterStatus {
         canister_id: Principal,
         installed_wasm_hash: WasmHash,
     },
+
+    /// Canister created but the installation of the wasm module failed
+    /// because the canister trapped, e.g. due to invalid init args.
+    /// The orchestrator will not retry installing this canister.
+    InstallFailed {
+        canister_id: Principal,
+        error: String,
+    },
 }
 
 impl ManagedCanisterStatus {
     pub fn canister_id(&self) -> &Principal {
         match self {
             ManagedCanisterStatus::Created { canister_id }
-            | ManagedCanisterStatus::Installed { canister_id, .. } => canister_id,
+            | ManagedCanisterStatus::Installed { canister_id, .. }
+            | ManagedCanisterStatus::InstallFailed { canister_id, .. } => canister_id,
         }
     }
 
     fn installed_wasm_hash(&self) -> Option<&WasmHash> {
         match self {
-            ManagedCanisterStatus::Created { .. } => None,
+            ManagedCanisterStatus::Created { .. } | ManagedCanisterStatus::InstallFailed { .. } => {
+                None
+            }
             ManagedCanisterStatus::Installed {
                 installed_wasm_hash,
                 ..
@@ -358,6 +373,35 @@ impl ManagedCanisterStatus {
     }
 }
 
+/// Health of a ledger suite, as last observed by the periodic top-up task.
+///
+/// Variants are ordered by increasing severity, so that the health of a ledger suite
+/// is the worst health of its canisters.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Clone)]
+pub enum SuiteHealth {
+    /// All canisters are running with enough cycles.
+    Healthy,
+
+    /// Some canister is below the minimum monitored cycles balance
+    /// and the orchestrator could not top it up.
+    LowCycles,
+
+    /// The status of some canister could not be retrieved.
+    Unreachable,
+
+    /// Some canister is stopping or stopped.
+    Stopped,
+}
+
+impl SuiteHealth {
+    /// Replaces the current health by `other` if the latter is more severe.
+    pub fn worsen(&mut self, other: SuiteHealth) {
+        if other > *self {
+            *self = other;
+        }
+    }
+}
+
 /// Configuration state of the ledger orchestrator.
 #[derive(Debug, PartialEq, Clone, Default)]
 enum ConfigState {
@@ -409,6 +453,9 @@ pub struct State {
     cycles_management: CyclesManagement,
     more_controller_ids: Vec<Principal>,
     minter_id: Option<Principal>,
+    /// Health of each managed ledger suite, as last observed by the periodic top-up task.
+    #[serde(default)]
+    suite_health: BTreeMap<Erc20Token, SuiteHealth>,
     /// Locks preventing concurrent execution timer tasks
     pub active_tasks: BTreeSet<Task>,
 }
@@ -430,6 +477,14 @@ impl State {
         &mut self.cycles_management
     }
 
+    pub fn suite_health(&self, contract: &Erc20Token) -> Option<&SuiteHealth> {
+        self.suite_health.get(contract)
+    }
+
+    pub fn record_suite_health(&mut self, contract: Erc20Token, health: SuiteHealth) {
+        self.suite_health.insert(contract, health);
+    }
+
     pub fn managed_canisters_iter(&self) -> impl Iterator<Item = (&Erc20Token, &Canisters)> {
         self.managed_canisters.canisters.iter()
     }
@@ -519,6 +574,24 @@ impl State {
         };
     }
 
+    pub fn record_failed_install<T>(&mut self, contract: &Erc20Token, error: String)
+    where
+        Canisters: ManageSingleCanister<T>,
+    {
+        let managed_canister = self
+            .managed_canisters_mut(contract)
+            .and_then(Canisters::get_mut)
+            .unwrap_or_else(|| {
+                panic!(
+                    "BUG: no managed canisters or no {} canister for {:?}",
+                    Canisters::display_name(),
+                    contract
+                )
+            });
+        let canister_id = *managed_canister.canister_id();
+        managed_canister.status = ManagedCanisterStatus::InstallFailed { canister_id, error };
+    }
+
     pub fn validate_config(&self) -> Result<(), InvalidStateError> {
         const MAX_ADDITIONAL_CONTROLLERS: usize = 9;
         if self.more_controller_ids.len() > MAX_ADDITIONAL_CONTROLLERS {
@@ -619,6 +692,7 @@ impl TryFrom<InitArg> for State {
             cycles_management: cycles_management.unwrap_or_default(),
             more_controller_ids,
             minter_id,
+            suite_health: Default::default(),
             active_tasks: Default::default(),
         };
         state.validate_config()?;
@@ -3,7 +3,87 @@ use ic_ledger_suite_orchestrator::candid::{CyclesManagement, InitArg};
 use proptest::arbitrary::any;
 use proptest::collection::vec;
 use proptest::option;
-use proptest::prelude::Strategy;
+use proptest::prelude::{ProptestConfig, RngCore, Strategy};
+use proptest::test_runner::{RngAlgorithm, TestCaseResult, TestError, TestRng, TestRunner};
+use std::fmt::{self, Debug};
+
+/// Environment variable used to pin the seed of the orchestrator property tests.
+pub const PROPTEST_SEED_ENV_VAR: &str = "IC_ORCHESTRATOR_PROPTEST_SEED";
+
+/// Number of cases run by the orchestrator property tests.
+const PROPTEST_CASES: u32 = 10;
+
+/// Returns a proptest runner for the orchestrator property tests.
+///
+/// If [`PROPTEST_SEED_ENV_VAR`] is set to a `u64`, the runner is seeded with it,
+/// so that CI can pin a seed. Otherwise, the runner uses a random seed.
+/// Either way, a failure reports the seed, so that it can be replayed deterministically.
+pub fn seeded_runner() -> SeededTestRunner {
+    let config = ProptestConfig {
+        cases: PROPTEST_CASES,
+        ..ProptestConfig::default()
+    };
+    let seed = proptest_seed().unwrap_or_else(|| TestRunner::new(config.clone()).rng().next_u64());
+    let mut rng_seed = [0_u8; 32];
+    rng_seed[..8].copy_from_slice(&seed.to_le_bytes());
+    SeededTestRunner {
+        runner: TestRunner::new_with_rng(
+            config,
+            TestRng::from_seed(RngAlgorithm::ChaCha, &rng_seed),
+        ),
+        seed,
+    }
+}
+
+/// Proptest runner reporting its seed along with the failures.
+pub struct SeededTestRunner {
+    runner: TestRunner,
+    seed: u64,
+}
+
+impl SeededTestRunner {
+    /// Returns the seed of the runner.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Runs the `test` like [`TestRunner::run`], wrapping the failure with the seed.
+    pub fn run<S: Strategy>(
+        &mut self,
+        strategy: &S,
+        test: impl Fn(S::Value) -> TestCaseResult,
+    ) -> Result<(), SeededTestError<S::Value>> {
+        let seed = self.seed;
+        self.runner
+            .run(strategy, test)
+            .map_err(|error| SeededTestError { seed, error })
+    }
+}
+
+/// Failure of a [`SeededTestRunner`] along with the seed to replay it.
+pub struct SeededTestError<T> {
+    pub seed: u64,
+    pub error: TestError<T>,
+}
+
+impl<T: Debug> Debug for SeededTestError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} (replay with {}={})",
+            self.error, PROPTEST_SEED_ENV_VAR, self.seed
+        )
+    }
+}
+
+fn proptest_seed() -> Option<u64> {
+    let seed = std::env::var(PROPTEST_SEED_ENV_VAR).ok()?;
+    Some(
+        seed.trim().parse::<u64>().unwrap_or_else(|e| {
+            panic!("BUG: invalid value '{seed}' for {PROPTEST_SEED_ENV_VAR}: {e}")
+        }),
+    )
+}
 
 pub fn arb_init_arg() -> impl Strategy<Value = InitArg> {
     // at most 10 principals, including the orchestrator's principal
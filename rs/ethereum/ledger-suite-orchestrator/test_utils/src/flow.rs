@@ -1,12 +1,23 @@
 use crate::metrics::MetricsAssert;
-use crate::{assert_reply, LedgerAccount, LedgerMetadataValue, LedgerSuiteOrchestrator, MAX_TICKS};
-use candid::{Decode, Encode, Nat, Principal};
+use crate::{
+    assert_reply, index_wasm, ledger_getblocksdisabled_wasm, ledger_wasm, LedgerAccount,
+    LedgerMetadataValue, LedgerSuiteOrchestrator, CKERC20_TRANSFER_FEE, LEDGER_MAX_MEMO_LENGTH,
+    MAX_TICKS, MINTER_PRINCIPAL, NNS_ROOT_PRINCIPAL,
+};
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
 use ic_base_types::{CanisterId, PrincipalId};
-use ic_ledger_suite_orchestrator::candid::{AddErc20Arg, ManagedCanisterIds};
-use ic_state_machine_tests::StateMachine;
-use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+use ic_ledger_suite_orchestrator::candid::{
+    AddErc20Arg, Erc20Contract, ManagedCanisterIds, ManagedCanisterStatus, OrchestratorArg,
+};
+use ic_state_machine_tests::{CanisterStatusType, ErrorCode, StateMachine, UserError, WasmResult};
+use icrc_ledger_types::icrc1::transfer::{Memo, TransferArg, TransferError};
 use icrc_ledger_types::icrc3::archive::ArchiveInfo;
+use icrc_ledger_types::icrc3::blocks::GetBlocksRequest;
+use icrc_ledger_types::icrc3::transactions::{
+    GetTransactionsRequest, GetTransactionsResponse, Transaction,
+};
 use std::collections::BTreeSet;
+use std::time::{Duration, UNIX_EPOCH};
 
 pub struct AddErc20TokenFlow {
     pub setup: LedgerSuiteOrchestrator,
@@ -19,6 +30,21 @@ impl AddErc20TokenFlow {
             self.setup.env.tick();
         }
 
+        if let Some(canisters) = self
+            .setup
+            .snapshot_orchestrator_state()
+            .managed_canisters(&self.params.contract)
+        {
+            for status in canisters.ledger.iter().chain(canisters.index.iter()) {
+                if let ManagedCanisterStatus::InstallFailed { canister_id, error } = status {
+                    panic!(
+                        "BUG: failed to install canister {} for contract {:?}: {}",
+                        canister_id, self.params.contract, error
+                    );
+                }
+            }
+        }
+
         let canister_ids = self
             .setup
             .call_orchestrator_canister_ids(&self.params.contract)
@@ -39,6 +65,203 @@ impl AddErc20TokenFlow {
             canister_ids,
         }
     }
+
+    /// Expects that no canister could be created for the new token (e.g. because the subnet is full)
+    /// and that the canisters managed for other tokens are left untouched.
+    pub fn expect_canister_creation_failure(self) -> Self {
+        let before = self.setup.snapshot_orchestrator_state();
+        for _ in 0..MAX_TICKS {
+            self.setup.env.tick();
+        }
+        let after = self.setup.snapshot_orchestrator_state();
+        before.assert_preserved_by(&after);
+
+        if let Some(canister_ids) = self
+            .setup
+            .call_orchestrator_canister_ids(&self.params.contract)
+        {
+            assert_eq!(
+                canister_ids,
+                ManagedCanisterIds {
+                    ledger: None,
+                    index: None,
+                    archives: vec![],
+                },
+                "BUG: unexpected canisters created for contract {:?}",
+                self.params.contract
+            );
+        }
+        self
+    }
+
+    /// Expects that the ledger canister was created but trapped while being installed
+    /// with an error containing `expected_error`, and that the orchestrator reports the failure
+    /// without retrying the installation nor creating the index.
+    pub fn expect_ledger_install_failure(self, expected_error: &str) -> Self {
+        for _ in 0..MAX_TICKS {
+            self.setup.env.tick();
+        }
+
+        let before = self.setup.snapshot_orchestrator_state();
+        let canisters = before
+            .managed_canisters(&self.params.contract)
+            .unwrap_or_else(|| {
+                panic!(
+                    "BUG: no managed canisters found for contract {:?}",
+                    self.params.contract
+                )
+            });
+        match &canisters.ledger {
+            Some(ManagedCanisterStatus::InstallFailed { error, .. }) => assert!(
+                error.contains(expected_error),
+                "BUG: unexpected install failure for contract {:?}: {}",
+                self.params.contract,
+                error
+            ),
+            status => panic!(
+                "BUG: expected ledger install failure for contract {:?}, got: {:?}",
+                self.params.contract, status
+            ),
+        }
+        assert_eq!(
+            canisters.index, None,
+            "BUG: unexpected index for contract {:?}",
+            self.params.contract
+        );
+
+        for _ in 0..MAX_TICKS {
+            self.setup.env.tick();
+        }
+        assert_eq!(
+            self.setup.snapshot_orchestrator_state(),
+            before,
+            "BUG: orchestrator retried installing the ledger suite"
+        );
+        self
+    }
+
+    /// Polls the install status of the ledger and index over `num_ticks` ticks and checks
+    /// that it only advances, i.e. from not yet created to `Created` and then to either
+    /// `Installed` or `InstallFailed`, and that the canister IDs never change once assigned.
+    pub fn assert_install_status_monotonic_across_ticks(self, num_ticks: usize) -> Self {
+        fn rank(status: &Option<ManagedCanisterStatus>) -> u8 {
+            match status {
+                None => 0,
+                Some(ManagedCanisterStatus::Created { .. }) => 1,
+                Some(ManagedCanisterStatus::Installed { .. })
+                | Some(ManagedCanisterStatus::InstallFailed { .. }) => 2,
+            }
+        }
+
+        fn canister_id(status: &Option<ManagedCanisterStatus>) -> Option<Principal> {
+            match status {
+                None => None,
+                Some(ManagedCanisterStatus::Created { canister_id })
+                | Some(ManagedCanisterStatus::Installed { canister_id, .. })
+                | Some(ManagedCanisterStatus::InstallFailed { canister_id, .. }) => {
+                    Some(*canister_id)
+                }
+            }
+        }
+
+        let statuses = || {
+            self.setup
+                .snapshot_orchestrator_state()
+                .managed_canisters(&self.params.contract)
+                .map(|canisters| [canisters.ledger.clone(), canisters.index.clone()])
+                .unwrap_or_default()
+        };
+
+        let mut transitions = vec![statuses()];
+        for _ in 0..num_ticks {
+            self.setup.env.tick();
+            let previous = transitions.last().unwrap();
+            let current = statuses();
+            for (canister, (before, after)) in ["ledger", "index"]
+                .into_iter()
+                .zip(previous.iter().zip(current.iter()))
+            {
+                assert!(
+                    before == after || rank(before) < rank(after),
+                    "BUG: {canister} install status of contract {:?} regressed, transitions: {:?}",
+                    self.params.contract,
+                    transitions
+                );
+                if let Some(id_before) = canister_id(before) {
+                    assert_eq!(
+                        canister_id(after),
+                        Some(id_before),
+                        "BUG: {canister} canister ID of contract {:?} changed",
+                        self.params.contract
+                    );
+                }
+            }
+            if previous != &current {
+                transitions.push(current);
+            }
+        }
+        self
+    }
+
+    /// Simulates a stall of the subnet in the middle of the installation of the ledger suite:
+    /// executes `ticks_before_stall` rounds, then lets `stall_duration` pass without executing
+    /// anything, and expects the installation to complete once the subnet resumes execution.
+    pub fn expect_install_completes_after_stall(
+        self,
+        ticks_before_stall: usize,
+        stall_duration: Duration,
+    ) -> ManagedCanistersAssert {
+        let contract = self.params.contract.clone();
+        let is_installed = |status: &Option<ManagedCanisterStatus>| {
+            matches!(status, Some(ManagedCanisterStatus::Installed { .. }))
+        };
+        let statuses = |setup: &LedgerSuiteOrchestrator| {
+            setup
+                .snapshot_orchestrator_state()
+                .managed_canisters(&contract)
+                .map(|canisters| (canisters.ledger.clone(), canisters.index.clone()))
+                .unwrap_or_default()
+        };
+
+        for _ in 0..ticks_before_stall {
+            self.setup.env.tick();
+        }
+        let before_stall = statuses(&self.setup);
+        assert!(
+            !(is_installed(&before_stall.0) && is_installed(&before_stall.1)),
+            "BUG: ledger suite for contract {:?} was installed before the stall, use fewer ticks",
+            contract
+        );
+
+        self.setup.env.advance_time(stall_duration);
+        assert_eq!(
+            statuses(&self.setup),
+            before_stall,
+            "BUG: install status of contract {:?} changed while the subnet was stalled",
+            contract
+        );
+
+        let canisters = self.expect_new_ledger_and_index_canisters();
+        let (ledger, index) = statuses(&canisters.setup);
+        assert!(
+            is_installed(&ledger) && is_installed(&index),
+            "BUG: ledger suite for contract {:?} was not installed after the stall. Ledger: {:?}, index: {:?}",
+            contract,
+            ledger,
+            index
+        );
+        canisters
+    }
+
+    /// Lifts the limit on the number of canisters of the subnet
+    /// and waits long enough for the orchestrator to retry failed tasks.
+    pub fn free_subnet(self) -> Self {
+        const RETRY_FREQUENCY: Duration = Duration::from_secs(5);
+
+        self.setup.env.set_max_number_of_canisters(0);
+        self.setup.env.advance_time(RETRY_FREQUENCY);
+        self
+    }
 }
 
 pub struct ManagedCanistersAssert {
@@ -56,13 +279,7 @@ impl ManagedCanistersAssert {
     pub fn assert_all_controlled_by(self, expected_controllers: &[Principal]) -> Self {
         for canister_id in self.all_canister_ids() {
             assert_eq!(
-                self.setup
-                    .canister_status_of(canister_id)
-                    .settings()
-                    .controllers()
-                    .into_iter()
-                    .map(|p| p.0)
-                    .collect::<BTreeSet<_>>(),
+                self.controllers_of(canister_id),
                 expected_controllers
                     .iter()
                     .copied()
@@ -75,11 +292,166 @@ impl ManagedCanistersAssert {
         self
     }
 
+    /// Asserts that the ledger, index and all archives are controlled by the `expected_controllers`,
+    /// with each controller listed exactly once, even if it's duplicated in `expected_controllers`.
+    pub fn assert_all_controlled_by_distinct(self, expected_controllers: &[Principal]) -> Self {
+        let expected_controllers: BTreeSet<_> = expected_controllers.iter().copied().collect();
+        for canister_id in self.all_canister_ids() {
+            let controllers: Vec<Principal> = self
+                .setup
+                .canister_status_of(canister_id)
+                .settings()
+                .controllers()
+                .into_iter()
+                .map(|p| p.0)
+                .collect();
+            let distinct_controllers: BTreeSet<_> = controllers.iter().copied().collect();
+            assert_eq!(
+                controllers.len(),
+                distinct_controllers.len(),
+                "BUG: duplicate controllers {:?} for canister {} in managed canisters {}",
+                controllers,
+                canister_id,
+                self.canister_ids
+            );
+            assert_eq!(
+                distinct_controllers, expected_controllers,
+                "BUG: unexpected controller for canister {} in managed canisters {}",
+                canister_id, self.canister_ids
+            );
+        }
+        self
+    }
+
+    /// Asserts that the ledger, index and all archives are running, i.e. not stopped or stopping.
+    pub fn assert_all_running(self) -> Self {
+        for canister_id in self.all_canister_ids() {
+            let status = self.setup.canister_status_of(canister_id).status();
+            assert_eq!(
+                status,
+                CanisterStatusType::Running,
+                "BUG: canister {} in managed canisters {} is not running",
+                canister_id,
+                self.canister_ids
+            );
+        }
+        self
+    }
+
+    /// Stops the ledger as one of its additional controllers, i.e. NNS root.
+    pub fn stop_ledger(self) -> Self {
+        self.setup
+            .env
+            .stop_canister_as(
+                PrincipalId::from(NNS_ROOT_PRINCIPAL),
+                self.ledger_canister_id(),
+            )
+            .expect("BUG: failed to stop ledger");
+        self
+    }
+
+    /// Asserts that the ledger, index and all archives have the `expected_memory_allocation`
+    /// (in bytes) and the `expected_compute_allocation` (in percent),
+    /// where zero means that nothing is reserved, i.e. best-effort.
+    pub fn assert_all_have_allocations(
+        self,
+        expected_memory_allocation: u64,
+        expected_compute_allocation: u64,
+    ) -> Self {
+        for canister_id in self.all_canister_ids() {
+            let status = self.setup.canister_status_of(canister_id);
+            assert_eq!(
+                (status.memory_allocation(), status.compute_allocation()),
+                (expected_memory_allocation, expected_compute_allocation),
+                "BUG: unexpected (memory, compute) allocation for canister {} in managed canisters {}",
+                canister_id,
+                self.canister_ids
+            );
+        }
+        self
+    }
+
+    /// Asserts that the ledger, index and all archives are controlled by the orchestrator,
+    /// so that it can always upgrade them, whatever the configured additional controllers.
+    pub fn assert_all_controlled_by_orchestrator(self) -> Self {
+        let orchestrator: Principal = self.setup.ledger_suite_orchestrator_id.get().into();
+        for canister_id in self.all_canister_ids() {
+            let controllers = self.controllers_of(canister_id);
+            assert!(
+                controllers.contains(&orchestrator),
+                "BUG: canister {} in managed canisters {} is not controlled by the orchestrator {}, but by {:?}",
+                canister_id,
+                self.canister_ids,
+                orchestrator,
+                controllers
+            );
+        }
+        self
+    }
+
+    /// Asserts that all managed canisters, including archives, were created within
+    /// the canister ID ranges the routing table assigns to the subnet of the state machine.
+    pub fn assert_all_within_subnet_canister_ranges(self) -> Self {
+        let env = &self.setup.env;
+        let ranges = env
+            .get_latest_state()
+            .metadata
+            .network_topology
+            .routing_table
+            .ranges(env.get_subnet_id());
+        for canister_id in self.all_canister_ids() {
+            assert!(
+                ranges.contains(&canister_id),
+                "BUG: canister {} is outside of the subnet canister ranges {:?}",
+                canister_id,
+                ranges
+            );
+        }
+        self
+    }
+
+    /// Asserts that the index is controlled exactly by the orchestrator and the configured
+    /// additional controllers, and in particular not by the ledger.
+    pub fn assert_index_controlled_by_orchestrator(self) -> Self {
+        let orchestrator: Principal = self.setup.ledger_suite_orchestrator_id.get().into();
+        let expected_controllers: BTreeSet<_> = std::iter::once(orchestrator)
+            .chain(self.setup.get_orchestrator_info().more_controller_ids)
+            .collect();
+        let index_controllers = self.controllers_of(self.index_canister_id());
+        assert_eq!(
+            index_controllers,
+            expected_controllers,
+            "BUG: unexpected controllers for index {} in managed canisters {}",
+            self.index_canister_id(),
+            self.canister_ids
+        );
+        assert!(
+            !index_controllers.contains(&self.ledger_canister_id().get().into()),
+            "BUG: index {} is controlled by the ledger {}",
+            self.index_canister_id(),
+            self.ledger_canister_id()
+        );
+        self
+    }
+
+    fn controllers_of(&self, canister_id: CanisterId) -> BTreeSet<Principal> {
+        self.setup
+            .canister_status_of(canister_id)
+            .settings()
+            .controllers()
+            .into_iter()
+            .map(|p| p.0)
+            .collect()
+    }
+
     pub fn check_metrics(self) -> MetricsAssert<Self> {
         let canister_id = self.setup.ledger_suite_orchestrator_id;
         MetricsAssert::from_querying_metrics(self, canister_id)
     }
 
+    /// Triggers the creation of an archive by minting many tokens.
+    ///
+    /// The ledger must have been spawned with [`MINTER_PRINCIPAL`] as minting account.
     pub fn trigger_creation_of_archive(self) -> Self {
         const ARCHIVE_TRIGGER_THRESHOLD: u64 = 2_000;
 
@@ -90,7 +462,7 @@ impl ManagedCanistersAssert {
             .collect();
 
         for _i in 0..ARCHIVE_TRIGGER_THRESHOLD {
-            let from = Principal::anonymous();
+            let from = MINTER_PRINCIPAL;
             let to = Principal::management_canister();
             self.call_ledger_icrc1_transfer(
                 from,
@@ -130,26 +502,925 @@ impl ManagedCanistersAssert {
         }
     }
 
+    /// Triggers the creation of `num_archives` archives by minting many tokens.
+    ///
+    /// The archive options of the ledger are first shrunk, so that each archive only holds a
+    /// few batches of blocks and new archives keep being spawned as the ledger grows.
+    /// Before each batch, time is advanced until the orchestrator topped up the ledger with
+    /// enough cycles to spawn a new archive.
+    ///
+    /// The ledger must have been spawned with [`MINTER_PRINCIPAL`] as minting account,
+    /// must be controlled by [`NNS_ROOT_PRINCIPAL`] and must not have any archive yet.
+    pub fn trigger_creation_of_archives(self, num_archives: usize) -> Self {
+        const TRIGGER_THRESHOLD: usize = 20;
+        const NUM_BLOCKS_TO_ARCHIVE: usize = 10;
+        // Holds more than a single batch of archived blocks,
+        // so that at most one archive is spawned per batch.
+        const NODE_MAX_MEMORY_SIZE_BYTES: u64 = 4 * 1024;
+        const MAX_BATCHES_PER_ARCHIVE: usize = 20;
+        // Top-ups are made in increments of a tenth of the cycles needed to spawn an archive.
+        const MAX_TOP_UPS_PER_BATCH: usize = 10;
+
+        assert!(
+            self.call_ledger_archives().is_empty(),
+            "BUG: ledger already has archives"
+        );
+        self.setup
+            .env
+            .upgrade_canister_as(
+                PrincipalId::from(NNS_ROOT_PRINCIPAL),
+                self.ledger_canister_id(),
+                ledger_wasm().to_bytes(),
+                Encode!(&LedgerArgument::Upgrade(Some(LedgerUpgradeArgs {
+                    change_archive_options: Some(ChangeArchiveOptions {
+                        trigger_threshold: Some(TRIGGER_THRESHOLD),
+                        num_blocks_to_archive: Some(NUM_BLOCKS_TO_ARCHIVE),
+                        node_max_memory_size_bytes: Some(NODE_MAX_MEMORY_SIZE_BYTES),
+                    }),
+                })))
+                .unwrap(),
+            )
+            .expect("BUG: failed to shrink the archive options of the ledger");
+
+        let cycles_for_archive_creation = u128::try_from(
+            &self
+                .setup
+                .get_orchestrator_info()
+                .cycles_management
+                .cycles_for_archive_creation
+                .0,
+        )
+        .unwrap();
+
+        for _batch in 0..num_archives * MAX_BATCHES_PER_ARCHIVE {
+            if self.call_ledger_archives().len() >= num_archives {
+                break;
+            }
+            for _ in 0..MAX_TOP_UPS_PER_BATCH {
+                if self
+                    .setup
+                    .canister_status_of(self.ledger_canister_id())
+                    .cycles()
+                    >= cycles_for_archive_creation
+                {
+                    break;
+                }
+                self.setup.advance_time_for_cycles_top_up();
+            }
+            for _i in 0..TRIGGER_THRESHOLD {
+                self.call_ledger_icrc1_transfer(
+                    MINTER_PRINCIPAL,
+                    &TransferArg {
+                        from_subaccount: None,
+                        to: Principal::management_canister().into(),
+                        fee: None,
+                        created_at_time: None,
+                        memo: None,
+                        amount: Nat::from(1_u8),
+                    },
+                )
+                .expect("BUG: fail to make a transfer to trigger archive creation");
+            }
+            self.setup.env.run_until_completion(/*max_ticks=*/ 10);
+        }
+
+        let archive_ids: BTreeSet<_> = self
+            .call_ledger_archives()
+            .into_iter()
+            .map(|info| info.canister_id)
+            .collect();
+        assert_eq!(
+            archive_ids.len(),
+            num_archives,
+            "BUG: expected {num_archives} archive canisters"
+        );
+
+        Self {
+            setup: self.setup,
+            canister_ids: ManagedCanisterIds {
+                ledger: self.canister_ids.ledger,
+                index: self.canister_ids.index,
+                archives: Vec::from_iter(archive_ids),
+            },
+        }
+    }
+
+    /// Checks that the orchestrator discovers all archives spawned by the ledger managing
+    /// `contract`, that it controls them and that it tops them up.
+    pub fn assert_orchestrator_manages_all_archives(self, contract: &Erc20Contract) -> Self {
+        // Archives are discovered before being topped up.
+        self.setup.advance_time_for_cycles_top_up();
+
+        let managed_archives: BTreeSet<_> = self
+            .setup
+            .call_orchestrator_canister_ids(contract)
+            .expect("BUG: ERC-20 contract is not managed by the orchestrator")
+            .archives
+            .into_iter()
+            .collect();
+        let ledger_archives: BTreeSet<_> = self.canister_ids.archives.iter().copied().collect();
+        assert_eq!(
+            managed_archives, ledger_archives,
+            "BUG: orchestrator does not manage all archives of the ledger"
+        );
+
+        let cycles_before: Vec<_> = self
+            .archive_canister_ids()
+            .into_iter()
+            .map(|archive| self.setup.canister_status_of(archive).cycles())
+            .collect();
+        self.setup.advance_time_for_cycles_top_up();
+        for (archive, before) in self.archive_canister_ids().into_iter().zip(cycles_before) {
+            let after = self.setup.canister_status_of(archive).cycles();
+            assert!(
+                after > before,
+                "BUG: archive {archive} was not topped up, cycles before {before}, after {after}"
+            );
+        }
+
+        self.assert_all_controlled_by_orchestrator()
+    }
+
+    /// Checks that the orchestrator keeps the archives funded like the ledger and index:
+    /// every archive drained below the minimum monitored cycles balance is topped up
+    /// on the next top-up cycle.
+    pub fn assert_drained_archives_are_topped_up(self) -> Self {
+        // Cycles an archive may burn until the top-up task checks its balance,
+        // negligible compared to the threshold.
+        const BURN_MARGIN: u128 = 1_000_000_000;
+
+        assert!(
+            !self.canister_ids.archives.is_empty(),
+            "BUG: no archives in managed canisters {}",
+            self.canister_ids
+        );
+        // Archives are discovered before being topped up.
+        self.setup.advance_time_for_cycles_top_up();
+
+        let cycles_management = self.setup.get_orchestrator_info().cycles_management;
+        let threshold = u128::try_from(&cycles_management.minimum_monitored_canister_cycles().0)
+            .expect("BUG: threshold does not fit in u128");
+        let top_up_increment = u128::try_from(&cycles_management.cycles_top_up_increment.0)
+            .expect("BUG: top-up increment does not fit in u128");
+
+        let drained = threshold / 2;
+        for archive in self.archive_canister_ids() {
+            self.setup.set_cycles_balance(archive, drained);
+        }
+        self.setup.advance_time_for_cycles_top_up();
+
+        for archive in self.archive_canister_ids() {
+            let after = self.setup.canister_status_of(archive).cycles();
+            assert!(
+                after + BURN_MARGIN >= drained + top_up_increment,
+                "BUG: archive {archive} drained to {drained} cycles was not topped up, cycles after: {after}"
+            );
+        }
+        self
+    }
+
+    /// Checks that the ledger accepts a transfer whose memo has exactly
+    /// [`LEDGER_MAX_MEMO_LENGTH`] bytes and rejects one with a longer memo.
+    ///
+    /// Transfers are minted from `minter`, which must be the ledger's minting account.
+    pub fn assert_ledger_enforces_max_memo_length(self, minter: Principal) -> Self {
+        let transfer_with_memo_of_length = |memo_length: usize| TransferArg {
+            from_subaccount: None,
+            to: Principal::management_canister().into(),
+            fee: None,
+            created_at_time: None,
+            memo: Some(Memo::from(vec![0xff_u8; memo_length])),
+            amount: Nat::from(1_u8),
+        };
+        let max_memo_length = LEDGER_MAX_MEMO_LENGTH as usize;
+
+        self.call_ledger_icrc1_transfer(minter, &transfer_with_memo_of_length(max_memo_length))
+            .expect("BUG: failed to transfer with a memo of maximum length");
+
+        let error = self
+            .execute_ledger_icrc1_transfer(
+                minter,
+                &transfer_with_memo_of_length(max_memo_length + 1),
+            )
+            .expect_err("BUG: ledger accepted a transfer with a memo that is too long");
+        assert_eq!(error.code(), ErrorCode::CanisterCalledTrap);
+        assert!(
+            error.description().contains(&format!(
+                "the memo field size of {} bytes is above the allowed limit of {} bytes",
+                max_memo_length + 1,
+                max_memo_length
+            )),
+            "BUG: unexpected error {:?}",
+            error
+        );
+        self
+    }
+
+    /// Checks that transfer fees are credited to the `expected` fee collector account
+    /// and, if it has a subaccount, not to the default subaccount of its owner.
+    ///
+    /// Tokens are minted from `minter`, which must be the ledger's minting account.
+    pub fn assert_ledger_fee_collector(self, minter: Principal, expected: LedgerAccount) -> Self {
+        let fee = call_ledger_icrc1_fee(&self.setup.env, self.ledger_canister_id());
+        let sender = LedgerAccount {
+            owner: Principal::from_slice(&[0xfe_u8; 29]),
+            subaccount: None,
+        };
+        let owner_default_account = LedgerAccount {
+            owner: expected.owner,
+            subaccount: None,
+        };
+        let fee_collector_balance_before = self.call_ledger_icrc1_balance_of(expected);
+        let owner_default_balance_before = self.call_ledger_icrc1_balance_of(owner_default_account);
+
+        self.call_ledger_icrc1_transfer(
+            minter,
+            &TransferArg {
+                from_subaccount: None,
+                to: sender,
+                fee: None,
+                created_at_time: None,
+                memo: None,
+                amount: fee.clone() + Nat::from(1_u8),
+            },
+        )
+        .expect("BUG: failed to mint tokens");
+        self.call_ledger_icrc1_transfer(
+            sender.owner,
+            &TransferArg {
+                from_subaccount: None,
+                to: Principal::management_canister().into(),
+                fee: Some(fee.clone()),
+                created_at_time: None,
+                memo: None,
+                amount: Nat::from(1_u8),
+            },
+        )
+        .expect("BUG: failed to transfer tokens");
+
+        assert_eq!(
+            self.call_ledger_icrc1_balance_of(expected),
+            fee_collector_balance_before + fee,
+            "BUG: transfer fee was not credited to the fee collector {:?}",
+            expected
+        );
+        if expected != owner_default_account {
+            assert_eq!(
+                self.call_ledger_icrc1_balance_of(owner_default_account),
+                owner_default_balance_before,
+                "BUG: transfer fee was credited to the default subaccount of the fee collector {:?}",
+                expected
+            );
+        }
+        self
+    }
+
+    /// Checks that the ledger accepts a transfer from an account to itself as per ICRC-1,
+    /// i.e. the account is only charged the fee, and that such a transfer is rejected
+    /// without any balance change when the account cannot pay for the amount and the fee.
+    ///
+    /// The tokens are first minted from `minter`, which must be the ledger's minting account.
+    pub fn assert_ledger_self_transfer_charges_fee(self, minter: Principal) -> Self {
+        let fee = call_ledger_icrc1_fee(&self.setup.env, self.ledger_canister_id());
+        let account = LedgerAccount {
+            owner: Principal::from_slice(&[0xfa_u8; 29]),
+            subaccount: None,
+        };
+        let amount = Nat::from(1_000_u16);
+        self.call_ledger_icrc1_transfer(
+            minter,
+            &TransferArg {
+                from_subaccount: None,
+                to: account,
+                fee: None,
+                created_at_time: None,
+                memo: None,
+                amount: fee.clone() + amount.clone(),
+            },
+        )
+        .expect("BUG: failed to mint tokens");
+        let self_transfer = |amount: Nat| TransferArg {
+            from_subaccount: account.subaccount,
+            to: account,
+            fee: None,
+            created_at_time: None,
+            memo: None,
+            amount,
+        };
+
+        let balance_before = self.call_ledger_icrc1_balance_of(account);
+        self.call_ledger_icrc1_transfer(account.owner, &self_transfer(amount.clone()))
+            .expect("BUG: failed to transfer tokens to self");
+        let balance_after = self.call_ledger_icrc1_balance_of(account);
+        assert_eq!(
+            balance_after,
+            balance_before - fee.clone(),
+            "BUG: self-transfer of {} did not charge exactly the fee {} to {}",
+            amount,
+            fee,
+            account
+        );
+
+        // The remaining balance `amount` cannot pay for transferring `amount` and the fee.
+        assert_eq!(
+            self.call_ledger_icrc1_transfer(account.owner, &self_transfer(amount.clone())),
+            Err(TransferError::InsufficientFunds {
+                balance: balance_after.clone()
+            }),
+            "BUG: unexpected result of self-transfer exceeding the balance of {}",
+            account
+        );
+        assert_eq!(
+            self.call_ledger_icrc1_balance_of(account),
+            balance_after,
+            "BUG: rejected self-transfer changed the balance of {}",
+            account
+        );
+        self
+    }
+
+    /// Checks that a transfer to the minting account burns the transferred amount as per ICRC-1,
+    /// i.e. the balance and the total supply decrease by exactly the burned amount without
+    /// any fee, and that the transaction is recorded as a burn by both the ledger and the index.
+    ///
+    /// The tokens are first minted from `minter`, which must be the ledger's minting account.
+    pub fn assert_ledger_burn(self, minter: Principal) -> Self {
+        let fee = call_ledger_icrc1_fee(&self.setup.env, self.ledger_canister_id());
+        let account = LedgerAccount {
+            owner: Principal::from_slice(&[0xf9_u8; 29]),
+            subaccount: None,
+        };
+        // Burns below the fee are rejected.
+        let amount = fee + Nat::from(1_000_u16);
+        self.call_ledger_icrc1_transfer(
+            minter,
+            &TransferArg {
+                from_subaccount: None,
+                to: account,
+                fee: None,
+                created_at_time: None,
+                memo: None,
+                amount: amount.clone() + amount.clone(),
+            },
+        )
+        .expect("BUG: failed to mint tokens");
+
+        let balance_before = self.call_ledger_icrc1_balance_of(account);
+        let total_supply_before =
+            call_ledger_icrc1_total_supply(&self.setup.env, self.ledger_canister_id());
+        let block_index = self
+            .burn(account, amount.clone())
+            .expect("BUG: failed to burn tokens");
+        assert_eq!(
+            self.call_ledger_icrc1_balance_of(account),
+            balance_before - amount.clone(),
+            "BUG: burning {} from {} did not decrease its balance by exactly the burned amount",
+            amount,
+            account
+        );
+        assert_eq!(
+            call_ledger_icrc1_total_supply(&self.setup.env, self.ledger_canister_id()),
+            total_supply_before - amount.clone(),
+            "BUG: burning {} did not decrease the total supply by exactly the burned amount",
+            amount
+        );
+
+        let assert_burn = |transaction: &Transaction, source: &str| {
+            assert_eq!(
+                transaction.kind, "burn",
+                "BUG: {} recorded transaction {} as {}",
+                source, block_index, transaction.kind
+            );
+            let burn = transaction.burn.as_ref().unwrap_or_else(|| {
+                panic!(
+                    "BUG: {} recorded burn {} without details",
+                    source, block_index
+                )
+            });
+            assert_eq!(
+                (burn.from, &burn.amount),
+                (account, &amount),
+                "BUG: {} recorded unexpected burn {}",
+                source,
+                block_index
+            );
+        };
+        assert_burn(
+            &self.call_ledger_get_transaction(block_index.clone()),
+            "ledger",
+        );
+
+        self.wait_for_index_sync();
+        let latest = self
+            .call_index_get_account_transactions(account, None, 1)
+            .transactions
+            .pop()
+            .expect("BUG: index returned no transactions after the burn");
+        assert_eq!(
+            latest.id, block_index,
+            "BUG: the latest indexed transaction of {} is not the burn",
+            account
+        );
+        assert_burn(&latest.transaction, "index");
+        self
+    }
+
+    /// Checks that the ledger deduplicates transfers as per ICRC-1, i.e. submitting again
+    /// a transfer with the same `created_at_time` within the deduplication window is rejected
+    /// as a duplicate of the first one and does not change any balance.
+    ///
+    /// Tokens are minted from `minter`, which must be the ledger's minting account.
+    pub fn assert_ledger_deduplicates_transfers(self, minter: Principal) -> Self {
+        let account = LedgerAccount {
+            owner: Principal::from_slice(&[0xf8_u8; 29]),
+            subaccount: None,
+        };
+        let created_at_time = self
+            .setup
+            .env
+            .time()
+            .duration_since(UNIX_EPOCH)
+            .expect("BUG: state machine time is before the epoch")
+            .as_nanos() as u64;
+        let transfer = TransferArg {
+            from_subaccount: None,
+            to: account,
+            fee: None,
+            created_at_time: Some(created_at_time),
+            memo: None,
+            amount: Nat::from(1_000_u16),
+        };
+
+        let block_index = self
+            .call_ledger_icrc1_transfer(minter, &transfer)
+            .expect("BUG: failed to mint tokens");
+        let balance_after_first = self.call_ledger_icrc1_balance_of(account);
+
+        assert_eq!(
+            self.call_ledger_icrc1_transfer(minter, &transfer),
+            Err(TransferError::Duplicate {
+                duplicate_of: block_index
+            }),
+            "BUG: ledger did not deduplicate transfer created at {}",
+            created_at_time
+        );
+        assert_eq!(
+            self.call_ledger_icrc1_balance_of(account),
+            balance_after_first,
+            "BUG: duplicate transfer changed the balance of {}",
+            account
+        );
+        self
+    }
+
+    /// Checks that re-adding the ERC-20 token managed by these canisters with `params`,
+    /// where only the token name, symbol and logo are changed, is rejected by the orchestrator.
+    ///
+    /// The orchestrator does not update the metadata of an existing ledger (that requires
+    /// upgrading the ledger), so the ledger metadata and the managed canisters must be unchanged.
+    pub fn assert_re_adding_with_changed_metadata_is_rejected(self, params: &AddErc20Arg) -> Self {
+        let mut changed = params.clone();
+        changed.ledger_init_arg.token_name = format!("{} v2", params.ledger_init_arg.token_name);
+        changed.ledger_init_arg.token_symbol = format!("{}v2", params.ledger_init_arg.token_symbol);
+        changed.ledger_init_arg.token_logo = "data:image/svg+xml;base64,djI=".to_string();
+        let metadata_before =
+            call_ledger_icrc1_metadata(&self.setup.env, self.ledger_canister_id());
+
+        let result = self
+            .setup
+            .upgrade_ledger_suite_orchestrator(&OrchestratorArg::AddErc20Arg(changed));
+        match result {
+            Err(e) => assert!(
+                e.code() == ErrorCode::CanisterCalledTrap
+                    && e.description().contains("Erc20ContractAlreadyManaged"),
+                "BUG: unexpected error when re-adding {:?} with changed metadata: {:?}",
+                params.contract,
+                e
+            ),
+            Ok(()) => panic!(
+                "BUG: re-adding {:?} with changed metadata was accepted",
+                params.contract
+            ),
+        }
+        for _ in 0..MAX_TICKS {
+            self.setup.env.tick();
+        }
+
+        assert_eq!(
+            call_ledger_icrc1_metadata(&self.setup.env, self.ledger_canister_id()),
+            metadata_before,
+            "BUG: re-adding {:?} with changed metadata changed the ledger metadata",
+            params.contract
+        );
+        assert_eq!(
+            self.setup.call_orchestrator_canister_ids(&params.contract),
+            Some(self.canister_ids.clone()),
+            "BUG: re-adding {:?} with changed metadata changed the managed canisters",
+            params.contract
+        );
+        self.assert_ledger_icrc1_name(params.ledger_init_arg.token_name.clone())
+            .assert_ledger_icrc1_symbol(params.ledger_init_arg.token_symbol.clone())
+    }
+
+    /// Checks that, once the index caught up with the ledger, the index reports as many
+    /// transactions as the ledger has blocks after `num_transfers` more transfers.
+    /// The blocks of ckERC20 ledgers are all transactions, so both counts must be equal.
+    ///
+    /// Tokens are minted from `minter`, which must be the ledger's minting account.
+    pub fn assert_index_transaction_count_matches_ledger(
+        self,
+        minter: Principal,
+        num_transfers: u64,
+    ) -> Self {
+        let account = LedgerAccount {
+            owner: Principal::from_slice(&[0xf7_u8; 29]),
+            subaccount: None,
+        };
+        let chain_length_before = self.call_ledger_chain_length();
+        for i in 0..num_transfers {
+            self.call_ledger_icrc1_transfer(
+                minter,
+                &TransferArg {
+                    from_subaccount: None,
+                    to: account,
+                    fee: None,
+                    created_at_time: None,
+                    memo: None,
+                    amount: Nat::from(1_000_u64 + i),
+                },
+            )
+            .expect("BUG: failed to mint tokens");
+        }
+        let chain_length = self.call_ledger_chain_length();
+        assert_eq!(
+            chain_length,
+            chain_length_before + num_transfers,
+            "BUG: {} transfers did not add as many blocks to the ledger",
+            num_transfers
+        );
+
+        let mut index_chain_length = self.call_index_chain_length();
+        for _ in 0..MAX_TICKS {
+            if index_chain_length == chain_length {
+                break;
+            }
+            self.setup.env.advance_time(Duration::from_secs(1));
+            self.setup.env.tick();
+            index_chain_length = self.call_index_chain_length();
+        }
+        assert_eq!(
+            index_chain_length, chain_length,
+            "BUG: index reports {} transactions while the ledger has {} blocks after {} ticks",
+            index_chain_length, chain_length, MAX_TICKS
+        );
+        assert_eq!(
+            self.call_index_num_blocks_synced(),
+            Nat::from(chain_length),
+            "BUG: index status disagrees with the number of indexed transactions"
+        );
+        self
+    }
+
+    /// Burns `amount` tokens of `from` by transferring them to the ledger's minting account.
+    pub fn burn(&self, from: LedgerAccount, amount: Nat) -> Result<Nat, TransferError> {
+        let minting_account =
+            call_ledger_icrc1_minting_account(&self.setup.env, self.ledger_canister_id())
+                .expect("BUG: ledger has no minting account");
+        self.call_ledger_icrc1_transfer(
+            from.owner,
+            &TransferArg {
+                from_subaccount: from.subaccount,
+                to: minting_account,
+                fee: None,
+                created_at_time: None,
+                memo: None,
+                amount,
+            },
+        )
+    }
+
+    /// Checks that the ledger balances and blocks, as well as the blocks indexed by the index,
+    /// are unchanged by upgrading the ledger to another wasm and then upgrading the index.
+    ///
+    /// Some tokens are first minted from `minter`, which must be the ledger's minting account,
+    /// so that the ledger state is not trivial.
+    /// The ledger and the index must be controlled by [`NNS_ROOT_PRINCIPAL`].
+    pub fn assert_ledger_state_preserved_across_ledger_and_index_upgrade(
+        self,
+        minter: Principal,
+    ) -> Self {
+        let accounts: Vec<LedgerAccount> = (1..=3_u8)
+            .map(|i| LedgerAccount {
+                owner: Principal::from_slice(&[0xfb_u8, i]),
+                subaccount: None,
+            })
+            .collect();
+        for (i, account) in accounts.iter().enumerate() {
+            self.call_ledger_icrc1_transfer(
+                minter,
+                &TransferArg {
+                    from_subaccount: None,
+                    to: *account,
+                    fee: None,
+                    created_at_time: None,
+                    memo: None,
+                    amount: Nat::from(1_000_000_u64 * (i as u64 + 1)),
+                },
+            )
+            .expect("BUG: failed to mint tokens");
+        }
+        self.wait_for_index_sync();
+        let before = self.snapshot_ledger_state(&accounts);
+        let ledger_module_hash_before = self.ledger_module_hash();
+        let index_version_before = self.canister_version_of(self.index_canister_id());
+
+        // The ledger is upgraded first, since the index only looks up
+        // which endpoint to fetch the ledger blocks from after its own upgrade.
+        self.setup
+            .env
+            .upgrade_canister_as(
+                PrincipalId::from(NNS_ROOT_PRINCIPAL),
+                self.ledger_canister_id(),
+                ledger_getblocksdisabled_wasm().to_bytes(),
+                Encode!(&LedgerArgument::Upgrade(None)).unwrap(),
+            )
+            .expect("BUG: failed to upgrade ledger");
+        self.setup
+            .env
+            .upgrade_canister_as(
+                PrincipalId::from(NNS_ROOT_PRINCIPAL),
+                self.index_canister_id(),
+                index_wasm().to_bytes(),
+                Encode!(&()).unwrap(),
+            )
+            .expect("BUG: failed to upgrade index");
+        assert_ne!(
+            self.ledger_module_hash(),
+            ledger_module_hash_before,
+            "BUG: ledger module hash unchanged by upgrade"
+        );
+        assert!(
+            self.canister_version_of(self.index_canister_id()) > index_version_before,
+            "BUG: index canister version unchanged by upgrade"
+        );
+
+        self.wait_for_index_sync();
+        let after = self.snapshot_ledger_state(&accounts);
+
+        assert_eq!(
+            before, after,
+            "BUG: ledger state changed across ledger and index upgrade"
+        );
+        self
+    }
+
+    /// Checks that the index returns every transaction of an account exactly once when
+    /// paging through `get_account_transactions` with at most `page_size` transactions per page.
+    ///
+    /// `num_transactions` tokens are first minted from `minter`, which must be the ledger's
+    /// minting account, to a fresh account.
+    pub fn assert_index_paginates_account_transactions(
+        self,
+        minter: Principal,
+        num_transactions: u64,
+        page_size: u64,
+    ) -> Self {
+        assert!(page_size > 0, "BUG: page size must be positive");
+        let account = LedgerAccount {
+            owner: Principal::from_slice(&[0xfc_u8, 1]),
+            subaccount: None,
+        };
+        let mut expected_ids: Vec<Nat> = (0..num_transactions)
+            .map(|i| {
+                self.call_ledger_icrc1_transfer(
+                    minter,
+                    &TransferArg {
+                        from_subaccount: None,
+                        to: account,
+                        fee: None,
+                        created_at_time: None,
+                        memo: None,
+                        amount: Nat::from(1_000_000_u64 + i),
+                    },
+                )
+                .expect("BUG: failed to mint tokens")
+            })
+            .collect();
+        // The index returns the most recent transactions first.
+        expected_ids.reverse();
+        self.wait_for_index_sync();
+
+        let max_pages = num_transactions / page_size + 1;
+        let mut ids: Vec<Nat> = vec![];
+        let mut start = None;
+        for _ in 0..=max_pages {
+            let page = self.call_index_get_account_transactions(account, start, page_size);
+            assert!(
+                page.transactions.len() as u64 <= page_size,
+                "BUG: index returned {} transactions for page size {}",
+                page.transactions.len(),
+                page_size
+            );
+            assert_eq!(
+                page.oldest_tx_id,
+                expected_ids.last().cloned(),
+                "BUG: unexpected oldest transaction id of account {}",
+                account
+            );
+            match page.transactions.last() {
+                Some(last) => start = Some(last.id.clone()),
+                None => break,
+            }
+            ids.extend(page.transactions.into_iter().map(|tx| tx.id));
+        }
+
+        assert_eq!(
+            ids, expected_ids,
+            "BUG: paginated transactions of account {} with page size {} contain duplicates or gaps",
+            account, page_size
+        );
+        self
+    }
+
+    pub fn snapshot_ledger_state(&self, accounts: &[LedgerAccount]) -> LedgerStateSnapshot {
+        LedgerStateSnapshot {
+            total_supply: call_ledger_icrc1_total_supply(
+                &self.setup.env,
+                self.ledger_canister_id(),
+            ),
+            balances: accounts
+                .iter()
+                .map(|account| (*account, self.call_ledger_icrc1_balance_of(*account)))
+                .collect(),
+            chain_length: self.call_ledger_chain_length(),
+            index_num_blocks_synced: self.call_index_num_blocks_synced(),
+        }
+    }
+
+    fn wait_for_index_sync(&self) {
+        let chain_length = Nat::from(self.call_ledger_chain_length());
+        for _ in 0..MAX_TICKS {
+            if self.call_index_num_blocks_synced() == chain_length {
+                return;
+            }
+            self.setup.env.advance_time(Duration::from_secs(1));
+            self.setup.env.tick();
+        }
+        panic!(
+            "BUG: index did not sync {} ledger blocks in {} ticks",
+            chain_length, MAX_TICKS
+        );
+    }
+
+    fn ledger_module_hash(&self) -> Option<Vec<u8>> {
+        self.setup
+            .env
+            .canister_status(self.ledger_canister_id())
+            .unwrap()
+            .unwrap()
+            .module_hash()
+    }
+
+    fn canister_version_of(&self, canister_id: CanisterId) -> u64 {
+        self.setup
+            .env
+            .get_latest_state()
+            .canister_state(&canister_id)
+            .expect("BUG: canister not found")
+            .system_state
+            .canister_version
+    }
+
+    fn call_ledger_chain_length(&self) -> u64 {
+        Decode!(
+            &assert_reply(
+                self.setup
+                    .env
+                    .query(
+                        self.ledger_canister_id(),
+                        "get_transactions",
+                        Encode!(&GetTransactionsRequest {
+                            start: Nat::from(0_u8),
+                            length: Nat::from(0_u8),
+                        })
+                        .unwrap()
+                    )
+                    .expect("failed to query transactions on the ledger")
+            ),
+            GetTransactionsResponse
+        )
+        .expect("failed to decode transactions response")
+        .log_length
+        .0
+        .try_into()
+        .expect("BUG: ledger chain length does not fit in u64")
+    }
+
+    fn call_ledger_get_transaction(&self, block_index: Nat) -> Transaction {
+        Decode!(
+            &assert_reply(
+                self.setup
+                    .env
+                    .query(
+                        self.ledger_canister_id(),
+                        "get_transactions",
+                        Encode!(&GetTransactionsRequest {
+                            start: block_index.clone(),
+                            length: Nat::from(1_u8),
+                        })
+                        .unwrap()
+                    )
+                    .expect("failed to query transactions on the ledger")
+            ),
+            GetTransactionsResponse
+        )
+        .expect("failed to decode transactions response")
+        .transactions
+        .pop()
+        .unwrap_or_else(|| panic!("BUG: no transaction {} on the ledger", block_index))
+    }
+
+    fn call_index_chain_length(&self) -> u64 {
+        Decode!(
+            &assert_reply(
+                self.setup
+                    .env
+                    .query(
+                        self.index_canister_id(),
+                        "get_blocks",
+                        Encode!(&GetBlocksRequest {
+                            start: Nat::from(0_u8),
+                            length: Nat::from(0_u8),
+                        })
+                        .unwrap()
+                    )
+                    .expect("failed to query blocks on the index")
+            ),
+            IndexBlocks
+        )
+        .expect("failed to decode index blocks response")
+        .chain_length
+    }
+
+    fn call_index_num_blocks_synced(&self) -> Nat {
+        Decode!(
+            &assert_reply(
+                self.setup
+                    .env
+                    .query(self.index_canister_id(), "status", Encode!().unwrap())
+                    .expect("failed to query status on the index")
+            ),
+            IndexStatus
+        )
+        .expect("failed to decode index status response")
+        .num_blocks_synced
+    }
+
+    fn call_ledger_icrc1_balance_of(&self, account: LedgerAccount) -> Nat {
+        Decode!(
+            &assert_reply(
+                self.setup
+                    .env
+                    .query(
+                        self.ledger_canister_id(),
+                        "icrc1_balance_of",
+                        Encode!(&account).unwrap()
+                    )
+                    .expect("failed to query balance on the ledger")
+            ),
+            Nat
+        )
+        .expect("failed to decode balance response")
+    }
+
     fn call_ledger_icrc1_transfer(
         &self,
         from: Principal,
         arg: &TransferArg,
     ) -> Result<Nat, TransferError> {
         Decode!(
-            &self.setup.env.execute_ingress_as(
-                PrincipalId(from),
-                self.ledger_canister_id(),
-                "icrc1_transfer",
-                Encode!(arg)
-                .unwrap()
-            )
-            .expect("failed to transfer funds")
-            .bytes(),
+            &self
+                .execute_ledger_icrc1_transfer(from, arg)
+                .expect("failed to transfer funds")
+                .bytes(),
             Result<Nat, TransferError>
         )
         .expect("failed to decode transfer response")
     }
 
+    fn execute_ledger_icrc1_transfer(
+        &self,
+        from: Principal,
+        arg: &TransferArg,
+    ) -> Result<WasmResult, UserError> {
+        self.setup.env.execute_ingress_as(
+            PrincipalId(from),
+            self.ledger_canister_id(),
+            "icrc1_transfer",
+            Encode!(arg).unwrap(),
+        )
+    }
+
     fn call_ledger_archives(&self) -> Vec<ArchiveInfo> {
         Decode!(
             &self
@@ -163,6 +1434,12 @@ impl ManagedCanistersAssert {
         .expect("failed to decode archives response")
     }
 
+    /// Asserts that the ledger charges the transfer fee [`CKERC20_TRANSFER_FEE`]
+    /// shared by all standard ckERC20 tokens.
+    pub fn assert_standard_ckerc20_fee(self) -> Self {
+        self.assert_ledger_icrc1_fee(CKERC20_TRANSFER_FEE)
+    }
+
     pub fn assert_index_has_correct_ledger_id(self) -> Self {
         assert_eq!(
             self.call_index_ledger_id(),
@@ -202,6 +1479,34 @@ impl ManagedCanistersAssert {
         self
     }
 
+    fn call_index_get_account_transactions(
+        &self,
+        account: LedgerAccount,
+        start: Option<Nat>,
+        max_results: u64,
+    ) -> IndexAccountTransactions {
+        Decode!(
+            &assert_reply(
+                self.setup
+                    .env
+                    .query(
+                        self.index_canister_id(),
+                        "get_account_transactions",
+                        Encode!(&GetAccountTransactionsArgs {
+                            account,
+                            start,
+                            max_results: Nat::from(max_results),
+                        })
+                        .unwrap()
+                    )
+                    .expect("failed to query account transactions on the index")
+            ),
+            Result<IndexAccountTransactions, GetAccountTransactionsError>
+        )
+        .expect("failed to decode account transactions response")
+        .unwrap_or_else(|e| panic!("BUG: failed to get account transactions: {}", e.message))
+    }
+
     fn call_index_ledger_id(&self) -> Principal {
         Decode!(
             &assert_reply(
@@ -238,6 +1543,68 @@ impl ManagedCanistersAssert {
     }
 }
 
+/// Ledger state as observed through the public candid interfaces of the ledger and index.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LedgerStateSnapshot {
+    pub total_supply: Nat,
+    pub balances: Vec<(LedgerAccount, Nat)>,
+    pub chain_length: u64,
+    pub index_num_blocks_synced: Nat,
+}
+
+/// Subset of the ledger upgrade argument needed to change its archive options.
+#[derive(CandidType)]
+enum LedgerArgument {
+    Upgrade(Option<LedgerUpgradeArgs>),
+}
+
+#[derive(CandidType)]
+struct LedgerUpgradeArgs {
+    change_archive_options: Option<ChangeArchiveOptions>,
+}
+
+#[derive(CandidType)]
+struct ChangeArchiveOptions {
+    trigger_threshold: Option<usize>,
+    num_blocks_to_archive: Option<usize>,
+    node_max_memory_size_bytes: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct IndexStatus {
+    num_blocks_synced: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+struct IndexBlocks {
+    chain_length: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetAccountTransactionsArgs {
+    account: LedgerAccount,
+    start: Option<Nat>,
+    max_results: Nat,
+}
+
+/// Subset of the index `get_account_transactions` response needed to check pagination.
+#[derive(CandidType, Deserialize)]
+struct IndexAccountTransactions {
+    transactions: Vec<IndexTransactionWithId>,
+    oldest_tx_id: Option<Nat>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct IndexTransactionWithId {
+    id: Nat,
+    transaction: Transaction,
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetAccountTransactionsError {
+    message: String,
+}
+
 macro_rules! assert_ledger {
     ($name:expr, $ty:ty) => {
         paste::paste! {
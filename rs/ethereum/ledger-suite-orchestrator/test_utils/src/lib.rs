@@ -1,18 +1,21 @@
 use crate::flow::AddErc20TokenFlow;
 use crate::metrics::MetricsAssert;
 use candid::{Decode, Encode, Nat, Principal};
-use ic_base_types::CanisterId;
+use ic_base_types::{CanisterId, PrincipalId};
 use ic_ledger_suite_orchestrator::candid::{
     AddErc20Arg, CyclesManagement, Erc20Contract, InitArg, LedgerInitArg, ManagedCanisterIds,
-    OrchestratorArg, OrchestratorInfo,
+    ManagedCanisterStatus, ManagedCanisters, OrchestratorArg, OrchestratorInfo, SuiteHealth,
+    UpgradeArg,
 };
 use ic_ledger_suite_orchestrator::state::{IndexWasm, LedgerWasm, WasmHash};
 use ic_state_machine_tests::{
-    CanisterStatusResultV2, Cycles, StateMachine, StateMachineBuilder, UserError, WasmResult,
+    CanisterStatusResultV2, Cycles, ErrorCode, StateMachine, StateMachineBuilder, UserError,
+    WasmResult,
 };
 use ic_test_utilities_load_wasm::load_wasm;
 pub use icrc_ledger_types::icrc::generic_metadata_value::MetadataValue as LedgerMetadataValue;
 pub use icrc_ledger_types::icrc1::account::Account as LedgerAccount;
+use std::collections::BTreeSet;
 use std::sync::Arc;
 
 pub mod arbitrary;
@@ -23,13 +26,19 @@ const MAX_TICKS: usize = 10;
 const GIT_COMMIT_HASH: &str = "6a8e5fca2c6b4e12966638c444e994e204b42989";
 pub const CKERC20_TRANSFER_FEE: u64 = 4_000; //0.004 USD for ckUSDC/ckUSDT
 
+pub const LEDGER_MAX_MEMO_LENGTH: u16 = 80;
+
 pub const NNS_ROOT_PRINCIPAL: Principal = Principal::from_slice(&[0_u8]);
 
+/// Owner of the minting account of the ledgers spawned in tests.
+pub const MINTER_PRINCIPAL: Principal = Principal::from_slice(&[0xfd_u8; 29]);
+
 pub struct LedgerSuiteOrchestrator {
     pub env: Arc<StateMachine>,
     pub ledger_suite_orchestrator_id: CanisterId,
     pub embedded_ledger_wasm_hash: WasmHash,
     pub embedded_index_wasm_hash: WasmHash,
+    orchestrator_wasm: Vec<u8>,
 }
 
 impl Default for LedgerSuiteOrchestrator {
@@ -52,6 +61,15 @@ impl LedgerSuiteOrchestrator {
         };
         Self::new(Arc::new(new_state_machine()), init_arg)
     }
+
+    pub fn with_more_controller_ids(more_controller_ids: Vec<Principal>) -> Self {
+        let init_arg = InitArg {
+            more_controller_ids,
+            ..default_init_arg()
+        };
+        Self::new(Arc::new(new_state_machine()), init_arg)
+    }
+
     pub fn new(env: Arc<StateMachine>, init_arg: InitArg) -> Self {
         let ledger_suite_orchestrator_id =
             env.create_canister_with_cycles(None, Cycles::new(u128::MAX), None);
@@ -61,6 +79,7 @@ impl LedgerSuiteOrchestrator {
             ledger_suite_orchestrator_id,
             embedded_ledger_wasm_hash: ledger_wasm().hash().clone(),
             embedded_index_wasm_hash: index_wasm().hash().clone(),
+            orchestrator_wasm: ledger_suite_orchestrator_wasm(),
         }
     }
 
@@ -77,11 +96,126 @@ impl LedgerSuiteOrchestrator {
         self.env.tick(); //tick before upgrade to finish current timers which are reset afterwards
         self.env.upgrade_canister(
             self.ledger_suite_orchestrator_id,
-            ledger_suite_orchestrator_wasm(),
+            self.orchestrator_wasm.clone(),
             Encode!(upgrade_arg).unwrap(),
         )
     }
 
+    /// Upgrades the orchestrator to `orchestrator_wasm`, a build of the orchestrator
+    /// embedding the ledger wasm with hash `embedded_ledger_wasm_hash`.
+    /// Subsequent upgrades, e.g. to add ERC-20 tokens, keep using that build.
+    pub fn upgrade_ledger_suite_orchestrator_wasm(
+        mut self,
+        orchestrator_wasm: Vec<u8>,
+        embedded_ledger_wasm_hash: WasmHash,
+    ) -> Self {
+        self.orchestrator_wasm = orchestrator_wasm;
+        self.embedded_ledger_wasm_hash = embedded_ledger_wasm_hash;
+        self.upgrade_ledger_suite_orchestrator_expecting_ok(&OrchestratorArg::UpgradeArg(
+            UpgradeArg {
+                git_commit_hash: None,
+                ledger_compressed_wasm_hash: None,
+                index_compressed_wasm_hash: None,
+                archive_compressed_wasm_hash: None,
+                cycles_management: None,
+            },
+        ))
+    }
+
+    /// Checks that upgrading the orchestrator to a build embedding another ledger wasm
+    /// only affects the tokens added afterwards: the token built from `new_token` gets
+    /// a ledger running the newly embedded wasm, while the ledger of the already managed
+    /// token `existing` keeps running the previously embedded one.
+    pub fn assert_embedded_ledger_wasm_upgrade_only_applies_to_new_tokens<F>(
+        self,
+        existing: &Erc20Contract,
+        new_token: F,
+    ) -> Self
+    where
+        F: FnOnce(Principal, WasmHash, WasmHash) -> AddErc20Arg,
+    {
+        let installed_ledger = |setup: &Self, contract: &Erc20Contract| match setup
+            .snapshot_orchestrator_state()
+            .managed_canisters(contract)
+            .and_then(|canisters| canisters.ledger.clone())
+        {
+            Some(ManagedCanisterStatus::Installed {
+                canister_id,
+                installed_wasm_hash,
+            }) => {
+                let canister_id =
+                    CanisterId::unchecked_from_principal(PrincipalId::from(canister_id));
+                let module_hash = setup.canister_status_of(canister_id).module_hash();
+                (installed_wasm_hash, module_hash)
+            }
+            status => panic!(
+                "BUG: ledger for contract {:?} is not installed: {:?}",
+                contract, status
+            ),
+        };
+
+        let old_hash = self.embedded_ledger_wasm_hash.clone();
+        let existing_before = installed_ledger(&self, existing);
+        assert_eq!(
+            existing_before.0,
+            old_hash.to_string(),
+            "BUG: ledger for contract {:?} does not run the embedded ledger wasm",
+            existing
+        );
+
+        let new_ledger_wasm = ledger_getblocksdisabled_wasm();
+        let new_hash = new_ledger_wasm.hash().clone();
+        assert_ne!(
+            old_hash, new_hash,
+            "BUG: orchestrator builds MUST embed different ledger wasms"
+        );
+        let setup = self.upgrade_ledger_suite_orchestrator_wasm(
+            ledger_suite_orchestrator_getblocksdisabled_wasm(),
+            new_hash.clone(),
+        );
+
+        let params = setup.embedded_erc20_arg(new_token);
+        let contract = params.contract.clone();
+        let setup = setup
+            .add_erc20_token(params)
+            .expect_new_ledger_and_index_canisters()
+            .setup;
+
+        let (new_installed_hash, new_module_hash) = installed_ledger(&setup, &contract);
+        assert_eq!(
+            new_installed_hash,
+            new_hash.to_string(),
+            "BUG: ledger for new contract {:?} does not run the newly embedded ledger wasm",
+            contract
+        );
+        assert_ne!(
+            new_module_hash, existing_before.1,
+            "BUG: ledgers for contracts {:?} and {:?} run the same module",
+            existing, contract
+        );
+        let existing_after = installed_ledger(&setup, existing);
+        assert_eq!(
+            existing_after, existing_before,
+            "BUG: ledger for contract {:?} no longer runs the previously embedded ledger wasm",
+            existing
+        );
+        setup
+    }
+
+    /// Builds the arguments to add an ERC-20 token from a fixture such as [`usdc`] or [`usdt`],
+    /// minted by [`MINTER_PRINCIPAL`] and referring to the ledger and index wasms embedded
+    /// in this orchestrator.
+    pub fn embedded_erc20_arg<F>(&self, fixture: F) -> AddErc20Arg
+    where
+        F: FnOnce(Principal, WasmHash, WasmHash) -> AddErc20Arg,
+    {
+        fixture(
+            MINTER_PRINCIPAL,
+            self.embedded_ledger_wasm_hash.clone(),
+            self.embedded_index_wasm_hash.clone(),
+        )
+    }
+
     pub fn add_erc20_token(self, params: AddErc20Arg) -> AddErc20TokenFlow {
         let setup = self.upgrade_ledger_suite_orchestrator_expecting_ok(
             &OrchestratorArg::AddErc20Arg(params.clone()),
@@ -108,6 +242,94 @@ impl LedgerSuiteOrchestrator {
         .unwrap()
     }
 
+    /// Checks that the canister IDs of the ledger suite managing `contract` are stable
+    /// when queried repeatedly over `num_ticks` ticks: the ledger and index IDs never change,
+    /// while the archive IDs may only be added.
+    pub fn assert_canister_ids_stable_across_ticks(
+        self,
+        contract: &Erc20Contract,
+        num_ticks: usize,
+    ) -> Self {
+        let initial = self
+            .call_orchestrator_canister_ids(contract)
+            .unwrap_or_else(|| panic!("BUG: no managed canisters for contract {:?}", contract));
+        assert!(
+            initial.ledger.is_some() && initial.index.is_some(),
+            "BUG: ledger suite for contract {:?} is not created yet: {:?}",
+            contract,
+            initial
+        );
+
+        let mut previous = initial;
+        for _ in 0..num_ticks {
+            self.env.tick();
+            let current = self
+                .call_orchestrator_canister_ids(contract)
+                .unwrap_or_else(|| {
+                    panic!(
+                        "BUG: managed canisters for contract {:?} were lost",
+                        contract
+                    )
+                });
+            assert_eq!(
+                (current.ledger, current.index),
+                (previous.ledger, previous.index),
+                "BUG: ledger or index ID changed"
+            );
+            assert!(
+                previous
+                    .archives
+                    .iter()
+                    .all(|archive| current.archives.contains(archive)),
+                "BUG: archives were lost. Before: {:?}, after: {:?}",
+                previous.archives,
+                current.archives
+            );
+            previous = current;
+        }
+        self
+    }
+
+    /// Checks that upgrading the orchestrator with an upgrade arg that changes nothing
+    /// neither recreates nor renumbers any managed canister: the canister IDs of every
+    /// managed contract, including archives, are identical before and after the upgrade.
+    pub fn assert_canister_ids_stable_across_no_op_upgrade(self) -> Self {
+        let canister_ids = |setup: &Self| -> Vec<(Erc20Contract, Option<ManagedCanisterIds>)> {
+            setup
+                .get_orchestrator_info()
+                .managed_canisters
+                .into_iter()
+                .map(|canisters| {
+                    let ids = setup.call_orchestrator_canister_ids(&canisters.erc20_contract);
+                    (canisters.erc20_contract, ids)
+                })
+                .collect()
+        };
+        let before = canister_ids(&self);
+        assert!(!before.is_empty(), "BUG: no managed canisters");
+
+        let setup = self.upgrade_ledger_suite_orchestrator_expecting_ok(
+            &OrchestratorArg::UpgradeArg(UpgradeArg {
+                git_commit_hash: None,
+                ledger_compressed_wasm_hash: None,
+                index_compressed_wasm_hash: None,
+                archive_compressed_wasm_hash: None,
+                cycles_management: None,
+            }),
+        );
+        // Give a wrongly triggered re-creation of the ledger suites the time to happen
+        for _ in 0..MAX_TICKS {
+            setup.env.tick();
+        }
+
+        let after = canister_ids(&setup);
+        assert_eq!(
+            before, after,
+            "BUG: managed canister IDs changed across a no-op upgrade"
+        );
+        setup
+    }
+
     pub fn advance_time_for_cycles_top_up(&self) {
         self.env
             .advance_time(std::time::Duration::from_secs(60 * 60 + 1));
@@ -119,6 +341,128 @@ impl LedgerSuiteOrchestrator {
         self.env.tick();
     }
 
+    /// Sets the cycles balance of `canister_id` to exactly `balance`.
+    pub fn set_cycles_balance(&self, canister_id: CanisterId, balance: u128) {
+        let current = self.env.cycle_balance(canister_id);
+        let actual = if current < balance {
+            self.env.add_cycles(canister_id, balance - current)
+        } else {
+            self.env.remove_cycles(canister_id, current - balance)
+        };
+        assert_eq!(
+            actual, balance,
+            "BUG: failed to set cycles balance of {}",
+            canister_id
+        );
+    }
+
+    /// Checks that the managed canister `canister_id` is topped up if and only if its cycles
+    /// balance is below the threshold derived from the orchestrator's cycles management,
+    /// by running a top-up cycle with a balance just above and then just below it.
+    pub fn assert_top_up_only_below_threshold(self, canister_id: CanisterId) -> Self {
+        // Cycles the canister may burn until the top-up task checks its balance,
+        // e.g. by running its own timers, negligible compared to the threshold.
+        const BURN_MARGIN: u128 = 1_000_000_000;
+
+        let cycles_management = self.get_orchestrator_info().cycles_management;
+        let threshold = u128::try_from(&cycles_management.minimum_monitored_canister_cycles().0)
+            .expect("BUG: threshold does not fit in u128");
+        let top_up_increment = u128::try_from(&cycles_management.cycles_top_up_increment.0)
+            .expect("BUG: top-up increment does not fit in u128");
+
+        let balance_before = threshold + BURN_MARGIN;
+        self.set_cycles_balance(canister_id, balance_before);
+        self.advance_time_for_cycles_top_up();
+        let balance_after = self.env.cycle_balance(canister_id);
+        assert!(
+            balance_after <= balance_before,
+            "BUG: canister {} with balance {} above threshold {} was topped up to {}",
+            canister_id,
+            balance_before,
+            threshold,
+            balance_after
+        );
+
+        let balance_before = threshold - 1;
+        self.set_cycles_balance(canister_id, balance_before);
+        self.advance_time_for_cycles_top_up();
+        let balance_after = self.env.cycle_balance(canister_id);
+        assert!(
+            balance_after + BURN_MARGIN >= balance_before + top_up_increment,
+            "BUG: canister {} with balance {} below threshold {} was not topped up, balance after: {}",
+            canister_id,
+            balance_before,
+            threshold,
+            balance_after
+        );
+        self
+    }
+
+    /// Checks that a top-up cycle conserves cycles: the decrease of the orchestrator's balance
+    /// matches the sum of the increases of the managed canisters' balances, up to the fees of
+    /// the inter-canister calls and the cycles burnt by the canisters in the meantime.
+    pub fn assert_top_up_conserves_cycles(self) -> Self {
+        // Cycles each canister may burn or pay in fees during a top-up cycle,
+        // negligible compared to the top-up increment.
+        const FEES_MARGIN: u128 = 1_000_000_000;
+
+        let managed_canister_ids: Vec<CanisterId> = self
+            .get_orchestrator_info()
+            .managed_canisters
+            .into_iter()
+            .flat_map(|canisters| {
+                canisters
+                    .ledger
+                    .into_iter()
+                    .chain(canisters.index)
+                    .map(|status| match status {
+                        ManagedCanisterStatus::Created { canister_id }
+                        | ManagedCanisterStatus::Installed { canister_id, .. }
+                        | ManagedCanisterStatus::InstallFailed { canister_id, .. } => canister_id,
+                    })
+                    .chain(canisters.archives)
+                    .collect::<Vec<_>>()
+            })
+            .map(|p| CanisterId::unchecked_from_principal(PrincipalId::from(p)))
+            .collect();
+        let balances = || -> Vec<u128> {
+            managed_canister_ids
+                .iter()
+                .map(|canister_id| self.canister_status_of(*canister_id).cycles())
+                .collect()
+        };
+
+        let orchestrator_before = self.env.cycle_balance(self.ledger_suite_orchestrator_id);
+        let managed_before = balances();
+        self.advance_time_for_cycles_top_up();
+        let orchestrator_after = self.env.cycle_balance(self.ledger_suite_orchestrator_id);
+        let managed_after = balances();
+
+        let debited = orchestrator_before.saturating_sub(orchestrator_after);
+        let credited: u128 = managed_before
+            .iter()
+            .zip(managed_after.iter())
+            .map(|(before, after)| after.saturating_sub(*before))
+            .sum();
+        assert!(credited > 0, "BUG: no managed canister was topped up");
+        assert!(
+            credited <= debited,
+            "BUG: managed canisters {:?} were credited {} cycles, but the orchestrator was only debited {}",
+            managed_canister_ids,
+            credited,
+            debited
+        );
+        let margin = FEES_MARGIN * (managed_canister_ids.len() as u128 + 1);
+        assert!(
+            debited <= credited + margin,
+            "BUG: orchestrator was debited {} cycles, but managed canisters {:?} were only credited {}",
+            debited,
+            managed_canister_ids,
+            credited
+        );
+        self
+    }
+
     pub fn canister_status_of(&self, controlled_canister_id: CanisterId) -> CanisterStatusResultV2 {
         self.env
             .canister_status_as(
@@ -145,10 +489,355 @@ impl LedgerSuiteOrchestrator {
         .unwrap()
     }
 
+    /// Checks that `get_orchestrator_info` is a read-only query: calling it repeatedly
+    /// always returns the same info and neither changes the orchestrator's module hash
+    /// nor bumps its canister version.
+    pub fn assert_get_orchestrator_info_is_read_only(self) -> Self {
+        const NUM_QUERIES: usize = 100;
+
+        let module_hash_and_version = || {
+            let module_hash = self
+                .env
+                .canister_status(self.ledger_suite_orchestrator_id)
+                .unwrap()
+                .unwrap()
+                .module_hash();
+            let version = self
+                .env
+                .get_latest_state()
+                .canister_state(&self.ledger_suite_orchestrator_id)
+                .expect("BUG: orchestrator canister not found")
+                .system_state
+                .canister_version;
+            (module_hash, version)
+        };
+
+        let before = module_hash_and_version();
+        let info = self.get_orchestrator_info();
+        for _ in 0..NUM_QUERIES {
+            assert_eq!(
+                self.get_orchestrator_info(),
+                info,
+                "BUG: orchestrator info changed between queries"
+            );
+        }
+        let after = module_hash_and_version();
+        assert_eq!(
+            before, after,
+            "BUG: querying orchestrator info changed the orchestrator's module hash or canister version"
+        );
+        self
+    }
+
+    /// Asserts that the orchestrator exposes the version `expected` of its candid interface.
+    pub fn assert_interface_version(self, expected: u32) -> Self {
+        let actual = self.get_orchestrator_info().interface_version;
+        assert_eq!(
+            actual, expected,
+            "BUG: unexpected orchestrator interface version. Expected: {}, actual: {}",
+            expected, actual
+        );
+        self
+    }
+
+    /// Asserts that the orchestrator reports `expected` as its effective cycles management.
+    pub fn assert_cycles_management(self, expected: &CyclesManagement) -> Self {
+        let actual = self.get_orchestrator_info().cycles_management;
+        assert_eq!(
+            &actual, expected,
+            "BUG: unexpected cycles management. Expected: {:?}, actual: {:?}",
+            expected, actual
+        );
+        self
+    }
+
+    /// Asserts that the health of the ledger suite managed for `contract`, as last observed
+    /// by the periodic top-up task, is `expected`.
+    pub fn assert_suite_health(self, contract: &Erc20Contract, expected: SuiteHealth) -> Self {
+        let actual = self
+            .get_orchestrator_info()
+            .managed_canisters
+            .into_iter()
+            .find(|canisters| &canisters.erc20_contract == contract)
+            .unwrap_or_else(|| panic!("BUG: no managed canisters for contract {:?}", contract))
+            .health;
+        assert_eq!(
+            actual,
+            Some(expected.clone()),
+            "BUG: unexpected health of ledger suite for contract {:?}. Expected: {:?}, actual: {:?}",
+            contract,
+            expected,
+            actual
+        );
+        self
+    }
+
+    /// Asserts that the orchestrator uses the documented default cycles management,
+    /// which is the case when none was configured at installation.
+    pub fn assert_default_cycles_management(self) -> Self {
+        self.assert_cycles_management(&default_cycles_management())
+    }
+
+    /// Checks that adding the ERC-20 token `params` is rejected
+    /// with an error containing `expected_error`, leaving the orchestrator state unchanged.
+    pub fn assert_add_erc20_token_rejected(
+        self,
+        params: AddErc20Arg,
+        expected_error: &str,
+    ) -> Self {
+        let before = self.snapshot_orchestrator_state();
+        let error = self
+            .upgrade_ledger_suite_orchestrator(&OrchestratorArg::AddErc20Arg(params.clone()))
+            .expect_err(&format!("BUG: adding ERC-20 token {:?} succeeded", params));
+        assert_eq!(error.code(), ErrorCode::CanisterCalledTrap);
+        assert!(
+            error.description().contains(expected_error),
+            "BUG: unexpected error {:?}",
+            error
+        );
+        let after = self.snapshot_orchestrator_state();
+        assert_eq!(before, after, "BUG: orchestrator state changed");
+        self
+    }
+
+    /// Checks that adding the ERC-20 token `params` is rejected with a descriptive error when
+    /// its contract address is malformed, i.e. has the wrong length, is not hex or has an invalid
+    /// EIP-55 checksum, leaving the orchestrator state unchanged.
+    /// The contract address of `params` must be checksummed, i.e. contain both cases.
+    pub fn assert_add_erc20_token_rejected_for_malformed_addresses(
+        self,
+        params: AddErc20Arg,
+    ) -> Self {
+        let address = params.contract.address.clone();
+        let flipped_pos = address
+            .char_indices()
+            .skip(2)
+            .find(|(_, c)| c.is_ascii_alphabetic())
+            .map(|(i, _)| i)
+            .expect("BUG: address MUST contain letters");
+        let mut bad_checksum = address.clone().into_bytes();
+        bad_checksum[flipped_pos] ^= 0x20; // flips the case of an ASCII letter
+        let bad_checksum = String::from_utf8(bad_checksum).unwrap();
+
+        let malformed_addresses = [
+            (format!("{}00", address), "Invalid string length"),
+            (
+                address[..address.len() - 2].to_string(),
+                "Invalid string length",
+            ),
+            (
+                format!("{}g", &address[..address.len() - 1]),
+                "Invalid character",
+            ),
+            (
+                address.trim_start_matches("0x").to_string(),
+                "doesn't start with '0x'",
+            ),
+            (bad_checksum, "invalid checksum"),
+        ];
+        malformed_addresses
+            .into_iter()
+            .fold(self, |setup, (malformed_address, expected_error)| {
+                let mut params = params.clone();
+                params.contract.address = malformed_address;
+                setup.assert_add_erc20_token_rejected(params, expected_error)
+            })
+    }
+
+    /// Adds the ERC-20 tokens `first` and `second` in quick succession, with only the tick
+    /// preceding each upgrade in between, so that the orchestrator schedules the installation
+    /// of both ledger suites concurrently.
+    /// Checks that both ledger suites are created correctly and do not share any canister.
+    pub fn assert_concurrent_add_erc20_tokens(
+        self,
+        first: AddErc20Arg,
+        second: AddErc20Arg,
+    ) -> Self {
+        assert_ne!(
+            first.contract, second.contract,
+            "BUG: concurrently added tokens MUST have different contracts"
+        );
+        let mut setup = self
+            .upgrade_ledger_suite_orchestrator_expecting_ok(&OrchestratorArg::AddErc20Arg(
+                first.clone(),
+            ))
+            .upgrade_ledger_suite_orchestrator_expecting_ok(&OrchestratorArg::AddErc20Arg(
+                second.clone(),
+            ));
+
+        let mut canister_ids = Vec::with_capacity(2);
+        for params in [first, second] {
+            let symbol = params.ledger_init_arg.token_symbol.clone();
+            let suite = AddErc20TokenFlow { setup, params }
+                .expect_new_ledger_and_index_canisters()
+                .assert_ledger_icrc1_symbol(symbol)
+                .assert_index_has_correct_ledger_id();
+            canister_ids.push(suite.canister_ids.clone());
+            setup = suite.setup;
+        }
+
+        let all_canisters = |ids: &ManagedCanisterIds| -> BTreeSet<Principal> {
+            ids.ledger
+                .iter()
+                .chain(ids.index.iter())
+                .chain(ids.archives.iter())
+                .copied()
+                .collect()
+        };
+        let shared_canisters: BTreeSet<_> = all_canisters(&canister_ids[0])
+            .intersection(&all_canisters(&canister_ids[1]))
+            .copied()
+            .collect();
+        assert!(
+            shared_canisters.is_empty(),
+            "BUG: ledger suites {} and {} share canisters {:?}",
+            canister_ids[0],
+            canister_ids[1],
+            shared_canisters
+        );
+        setup
+    }
+
+    /// Checks that adding the ERC-20 token `params` on behalf of `caller`, which does not
+    /// control the orchestrator, is rejected and leaves the orchestrator state unchanged.
+    pub fn assert_add_erc20_token_rejected_for_unauthorized_caller(
+        self,
+        caller: Principal,
+        params: AddErc20Arg,
+    ) -> Self {
+        let before = self.snapshot_orchestrator_state();
+        let error = self
+            .env
+            .upgrade_canister_as(
+                PrincipalId(caller),
+                self.ledger_suite_orchestrator_id,
+                self.orchestrator_wasm.clone(),
+                Encode!(&OrchestratorArg::AddErc20Arg(params.clone())).unwrap(),
+            )
+            .expect_err(&format!(
+                "BUG: unauthorized caller {} added ERC-20 token {:?}",
+                caller, params
+            ));
+        assert_eq!(
+            error.code(),
+            ErrorCode::CanisterInvalidController,
+            "BUG: unexpected error {:?}",
+            error
+        );
+        for _ in 0..MAX_TICKS {
+            self.env.tick();
+        }
+        let after = self.snapshot_orchestrator_state();
+        assert_eq!(before, after, "BUG: orchestrator state changed");
+        assert_eq!(
+            self.call_orchestrator_canister_ids(&params.contract),
+            None,
+            "BUG: unexpected canisters created for contract {:?}",
+            params.contract
+        );
+        self
+    }
+
     pub fn check_metrics(self) -> MetricsAssert<Self> {
         let canister_id = self.ledger_suite_orchestrator_id;
         MetricsAssert::from_querying_metrics(self, canister_id)
     }
+
+    /// Restricts the subnet to the canisters it currently hosts,
+    /// so that any further canister creation fails.
+    pub fn fill_subnet(self) -> Self {
+        let num_canisters = self.env.get_latest_state().num_canisters() as u64;
+        self.env.set_max_number_of_canisters(num_canisters);
+        self
+    }
+
+    pub fn snapshot_orchestrator_state(&self) -> OrchestratorStateSnapshot {
+        OrchestratorStateSnapshot {
+            info: self.get_orchestrator_info(),
+        }
+    }
+
+    pub fn assert_state_preserved_across_upgrade(self, upgrade_arg: &OrchestratorArg) -> Self {
+        let before = self.snapshot_orchestrator_state();
+        let setup = self.upgrade_ledger_suite_orchestrator_expecting_ok(upgrade_arg);
+        let after = setup.snapshot_orchestrator_state();
+        before.assert_preserved_by(&after);
+        setup
+    }
+}
+
+/// Orchestrator state as observed through its public candid interface.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OrchestratorStateSnapshot {
+    pub info: OrchestratorInfo,
+}
+
+impl OrchestratorStateSnapshot {
+    pub fn managed_canisters(&self, contract: &Erc20Contract) -> Option<&ManagedCanisters> {
+        self.info
+            .managed_canisters
+            .iter()
+            .find(|canisters| &canisters.erc20_contract == contract)
+    }
+
+    /// Asserts that the `after` state is a faithful migration of this state:
+    /// every managed canister is still managed with the same canister ID and wasm hash,
+    /// and archives can only be added.
+    pub fn assert_preserved_by(&self, after: &OrchestratorStateSnapshot) {
+        for before_canisters in &self.info.managed_canisters {
+            let contract = &before_canisters.erc20_contract;
+            let after_canisters = after.managed_canisters(contract).unwrap_or_else(|| {
+                panic!(
+                    "BUG: managed canisters for contract {:?} were lost. Before: {:?}, after: {:?}",
+                    contract, self.info, after.info
+                )
+            });
+            assert_eq!(
+                after_canisters.ckerc20_token_symbol, before_canisters.ckerc20_token_symbol,
+                "BUG: unexpected token symbol for contract {:?}",
+                contract
+            );
+            assert_eq!(
+                after_canisters.ledger, before_canisters.ledger,
+                "BUG: unexpected ledger status for contract {:?}",
+                contract
+            );
+            assert_eq!(
+                after_canisters.index, before_canisters.index,
+                "BUG: unexpected index status for contract {:?}",
+                contract
+            );
+            for archive in &before_canisters.archives {
+                assert!(
+                    after_canisters.archives.contains(archive),
+                    "BUG: archive {} of contract {:?} is no longer managed",
+                    archive,
+                    contract
+                );
+            }
+        }
+        assert_eq!(
+            after.info.more_controller_ids, self.info.more_controller_ids,
+            "BUG: unexpected additional controllers"
+        );
+        assert_eq!(
+            after.info.minter_id, self.info.minter_id,
+            "BUG: unexpected minter ID"
+        );
+    }
+}
+
+/// Cycles management the orchestrator is documented to use when none is configured.
+pub fn default_cycles_management() -> CyclesManagement {
+    const TEN_TRILLIONS: u64 = 10_000_000_000_000;
+    const HUNDRED_TRILLIONS: u64 = 100_000_000_000_000;
+
+    CyclesManagement {
+        cycles_for_ledger_creation: Nat::from(2 * HUNDRED_TRILLIONS),
+        cycles_for_archive_creation: Nat::from(HUNDRED_TRILLIONS),
+        cycles_for_index_creation: Nat::from(HUNDRED_TRILLIONS),
+        cycles_top_up_increment: Nat::from(TEN_TRILLIONS),
+    }
 }
 
 fn default_init_arg() -> InitArg {
@@ -186,6 +875,15 @@ fn ledger_suite_orchestrator_wasm() -> Vec<u8> {
     )
 }
 
+/// Build of the orchestrator embedding the ledger wasm returned by [`ledger_getblocksdisabled_wasm`].
+fn ledger_suite_orchestrator_getblocksdisabled_wasm() -> Vec<u8> {
+    load_wasm(
+        std::env::var("CARGO_MANIFEST_DIR").unwrap(),
+        "ledger_suite_orchestrator",
+        &["getblocksdisabled"],
+    )
+}
+
 fn ledger_wasm() -> LedgerWasm {
     LedgerWasm::from(load_wasm(
         std::env::var("CARGO_MANIFEST_DIR").unwrap(),
@@ -194,6 +892,14 @@ fn ledger_wasm() -> LedgerWasm {
     ))
 }
 
+fn ledger_getblocksdisabled_wasm() -> LedgerWasm {
+    LedgerWasm::from(load_wasm(
+        std::env::var("CARGO_MANIFEST_DIR").unwrap(),
+        "ledger_canister",
+        &["getblocksdisabled"],
+    ))
+}
+
 fn index_wasm() -> IndexWasm {
     IndexWasm::from(load_wasm(
         std::env::var("CARGO_MANIFEST_DIR").unwrap(),
@@ -259,6 +965,41 @@ pub fn usdt(
     }
 }
 
+pub fn with_fee_collector_account(
+    params: AddErc20Arg,
+    fee_collector_account: LedgerAccount,
+) -> AddErc20Arg {
+    AddErc20Arg {
+        ledger_init_arg: LedgerInitArg {
+            fee_collector_account: Some(fee_collector_account),
+            ..params.ledger_init_arg
+        },
+        ..params
+    }
+}
+
+/// Sets a transfer fee that does not fit into the ledger's token amount,
+/// so that the ledger traps when executing its init method.
+pub fn with_invalid_transfer_fee(params: AddErc20Arg) -> AddErc20Arg {
+    AddErc20Arg {
+        ledger_init_arg: LedgerInitArg {
+            transfer_fee: Nat::from(u128::MAX) * Nat::from(u128::MAX) * Nat::from(2_u8),
+            ..params.ledger_init_arg
+        },
+        ..params
+    }
+}
+
+pub fn fee_collector_account_with_subaccount() -> LedgerAccount {
+    LedgerAccount {
+        owner: Principal::from_slice(&[0xfc_u8; 29]),
+        subaccount: Some([
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0xf, 0xe, 0xe,
+        ]),
+    }
+}
+
 fn ledger_init_arg<U: Into<String>, V: Into<String>>(
     minter: Principal,
     token_name: U,
@@ -276,7 +1017,7 @@ fn ledger_init_arg<U: Into<String>, V: Into<String>>(
         token_name: token_name.into(),
         token_symbol: token_symbol.into(),
         token_logo: "".to_string(),
-        max_memo_length: Some(80),
+        max_memo_length: Some(LEDGER_MAX_MEMO_LENGTH),
         feature_flags: None,
         maximum_number_of_accounts: None,
         accounts_overflow_trim_quantity: None,
@@ -1,10 +1,13 @@
 use crate::assert_reply;
 use candid::{Decode, Encode};
 use ic_base_types::CanisterId;
+use ic_ledger_suite_orchestrator::candid::Erc20Contract;
 use ic_state_machine_tests::StateMachine;
+use std::time::{Duration, UNIX_EPOCH};
 
 pub struct MetricsAssert<T> {
     setup: T,
+    canister_id: CanisterId,
     metrics: Vec<String>,
 }
 
@@ -37,7 +40,11 @@ impl<T: AsRef<StateMachine>> MetricsAssert<T> {
             .split('\n')
             .map(|line| line.to_string())
             .collect::<Vec<_>>();
-        Self { setup, metrics }
+        Self {
+            setup,
+            canister_id,
+            metrics,
+        }
     }
 
     pub fn assert_contains_metric(self, metric: &str) -> T {
@@ -49,4 +56,115 @@ impl<T: AsRef<StateMachine>> MetricsAssert<T> {
         );
         self.setup
     }
+
+    /// Captures the current value of the counter `name`, performs `action`
+    /// and asserts that the counter strictly increased afterwards.
+    /// If `by` is specified, the counter must have increased by exactly that amount.
+    pub fn assert_counter_increased<F>(self, name: &str, by: Option<u64>, action: F) -> T
+    where
+        F: FnOnce(T) -> T,
+    {
+        let before = self.counter_value(name);
+        let after = Self::from_querying_metrics(action(self.setup), self.canister_id);
+        let after_value = after.counter_value(name);
+        match by {
+            Some(delta) => assert_eq!(
+                after_value,
+                before + delta,
+                "BUG: expected counter {} to increase by {}, but went from {} to {}",
+                name,
+                delta,
+                before,
+                after_value
+            ),
+            None => assert!(
+                after_value > before,
+                "BUG: expected counter {} to increase, but went from {} to {}",
+                name,
+                before,
+                after_value
+            ),
+        }
+        after.setup
+    }
+
+    /// Asserts that the next task named `task` is scheduled to be executed
+    /// in `expected_delay` from the current time of the state machine, up to one minute.
+    pub fn assert_task_scheduled_in(self, task: &str, expected_delay: Duration) -> T {
+        const TOLERANCE: Duration = Duration::from_secs(60);
+
+        let deadline = Duration::from_secs_f64(self.metric_value(&format!(
+            "ledger_suite_orchestrator_task_deadline_seconds{{task=\"{}\"}}",
+            task
+        )));
+        let now = self
+            .setup
+            .as_ref()
+            .time()
+            .duration_since(UNIX_EPOCH)
+            .expect("BUG: state machine time is before the epoch");
+        let delay = deadline.saturating_sub(now);
+        assert!(
+            delay <= expected_delay && expected_delay - delay <= TOLERANCE,
+            "BUG: expected task {} to be scheduled in {:?}, but is scheduled in {:?}",
+            task,
+            expected_delay,
+            delay
+        );
+        self.setup
+    }
+
+    /// Asserts that the per-token metric `metric_name` of the ckERC20 token for `contract`
+    /// has the `expected` value. The contract address must be EIP-55 checksummed.
+    pub fn assert_token_metric(
+        self,
+        contract: &Erc20Contract,
+        metric_name: &str,
+        expected: f64,
+    ) -> T {
+        let name = token_metric_name(contract, metric_name);
+        let value = self.metric_value(&name);
+        assert_eq!(
+            value, expected,
+            "BUG: expected metric {} to be {}, but got {}",
+            name, expected, value
+        );
+        self.setup
+    }
+
+    fn counter_value(&self, name: &str) -> u64 {
+        self.metric_value(name) as u64
+    }
+
+    fn metric_value(&self, name: &str) -> f64 {
+        self.metrics
+            .iter()
+            .filter(|line| !line.starts_with('#'))
+            .find_map(|line| {
+                let mut tokens = line.split_whitespace();
+                match (tokens.next(), tokens.next()) {
+                    (Some(metric), Some(value)) if metric == name => Some(value),
+                    _ => None,
+                }
+            })
+            .map(|value| {
+                value
+                    .parse::<f64>()
+                    .unwrap_or_else(|e| panic!("BUG: invalid value for metric {}: {}", name, e))
+            })
+            .unwrap_or_else(|| {
+                panic!(
+                    "Searched metric not found: {} in:\n{:?}",
+                    name, self.metrics
+                )
+            })
+    }
+}
+
+/// Name of the metric `metric_name` with the labels identifying the ckERC20 token for `contract`.
+pub fn token_metric_name(contract: &Erc20Contract, metric_name: &str) -> String {
+    format!(
+        "{}{{chain_id=\"{}\",erc20_contract_address=\"{}\"}}",
+        metric_name, contract.chain_id.0, contract.address
+    )
 }
@@ -5,50 +5,61 @@ use ic_canisters_http_types::{HttpRequest, HttpResponse};
 use ic_icrc1_ledger::FeatureFlags as LedgerFeatureFlags;
 use ic_ledger_suite_orchestrator::candid::{
     AddErc20Arg, CyclesManagement, LedgerInitArg, ManagedCanisterStatus, ManagedCanisters,
-    OrchestratorArg, OrchestratorInfo, UpdateCyclesManagement, UpgradeArg,
+    OrchestratorArg, OrchestratorInfo, SuiteHealth, UpdateCyclesManagement, UpgradeArg,
 };
-use ic_ledger_suite_orchestrator_test_utils::arbitrary::arb_init_arg;
+use ic_ledger_suite_orchestrator_test_utils::arbitrary::{arb_init_arg, seeded_runner};
+use ic_ledger_suite_orchestrator_test_utils::metrics::token_metric_name;
 use ic_ledger_suite_orchestrator_test_utils::{
-    assert_reply, new_state_machine, supported_erc20_tokens, usdc, usdc_erc20_contract, usdt,
-    LedgerSuiteOrchestrator, NNS_ROOT_PRINCIPAL,
+    assert_reply, default_cycles_management, fee_collector_account_with_subaccount,
+    new_state_machine, supported_erc20_tokens, usdc, usdc_erc20_contract, usdt,
+    with_fee_collector_account, with_invalid_transfer_fee, LedgerSuiteOrchestrator,
+    MINTER_PRINCIPAL, NNS_ROOT_PRINCIPAL,
 };
 use ic_state_machine_tests::ErrorCode;
 use icrc_ledger_types::icrc::generic_metadata_value::MetadataValue as LedgerMetadataValue;
 use icrc_ledger_types::icrc1::account::Account as LedgerAccount;
-use proptest::prelude::ProptestConfig;
-use proptest::proptest;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 const MAX_TICKS: usize = 10;
 const GIT_COMMIT_HASH: &str = "6a8e5fca2c6b4e12966638c444e994e204b42989";
 
 pub const TEN_TRILLIONS: u64 = 10_000_000_000_000; // 10 TC
 
-proptest! {
-    #![proptest_config(ProptestConfig {
-            cases: 10,
-            .. ProptestConfig::default()
-        })]
-    #[test]
-    fn should_install_orchestrator_and_add_supported_erc20_tokens(init_arg in arb_init_arg()) {
-        let more_controllers = init_arg.more_controller_ids.clone();
-        let mut orchestrator = LedgerSuiteOrchestrator::new(Arc::new(new_state_machine()), init_arg);
-        let orchestrator_principal: Principal = orchestrator.ledger_suite_orchestrator_id.get().into();
-        let embedded_ledger_wasm_hash = orchestrator.embedded_ledger_wasm_hash.clone();
-        let embedded_index_wasm_hash = orchestrator.embedded_index_wasm_hash.clone();
-        let controllers: Vec<_> = std::iter::once(orchestrator_principal).chain(more_controllers.into_iter()).collect();
-
-        for token in supported_erc20_tokens(Principal::anonymous(), embedded_ledger_wasm_hash, embedded_index_wasm_hash) {
-            orchestrator = orchestrator
-                .add_erc20_token(token)
-                .expect_new_ledger_and_index_canisters()
-                .assert_all_controlled_by(&controllers)
-                .assert_ledger_icrc1_total_supply(0_u8)
-                .assert_index_has_correct_ledger_id()
-                .setup;
-        }
-    }
+#[test]
+fn should_install_orchestrator_and_add_supported_erc20_tokens() {
+    seeded_runner()
+        .run(&arb_init_arg(), |init_arg| {
+            let more_controllers = init_arg.more_controller_ids.clone();
+            let mut orchestrator =
+                LedgerSuiteOrchestrator::new(Arc::new(new_state_machine()), init_arg);
+            let orchestrator_principal: Principal =
+                orchestrator.ledger_suite_orchestrator_id.get().into();
+            let embedded_ledger_wasm_hash = orchestrator.embedded_ledger_wasm_hash.clone();
+            let embedded_index_wasm_hash = orchestrator.embedded_index_wasm_hash.clone();
+            let controllers: Vec<_> = std::iter::once(orchestrator_principal)
+                .chain(more_controllers)
+                .collect();
+
+            for token in supported_erc20_tokens(
+                MINTER_PRINCIPAL,
+                embedded_ledger_wasm_hash,
+                embedded_index_wasm_hash,
+            ) {
+                orchestrator = orchestrator
+                    .add_erc20_token(token)
+                    .expect_new_ledger_and_index_canisters()
+                    .assert_all_controlled_by(&controllers)
+                    .assert_all_running()
+                    .assert_index_controlled_by_orchestrator()
+                    .assert_ledger_icrc1_total_supply(0_u8)
+                    .assert_index_has_correct_ledger_id()
+                    .setup;
+            }
+            Ok(())
+        })
+        .unwrap();
 }
 
 #[test]
@@ -137,7 +148,7 @@ fn should_change_cycles_for_canister_creation() {
 
     let orchestrator = orchestrator
         .add_erc20_token(usdc(
-            Principal::anonymous(),
+            MINTER_PRINCIPAL,
             embedded_ledger_wasm_hash.clone(),
             embedded_index_wasm_hash.clone(),
         ))
@@ -162,7 +173,7 @@ fn should_change_cycles_for_canister_creation() {
 
     orchestrator
         .add_erc20_token(usdt(
-            Principal::anonymous(),
+            MINTER_PRINCIPAL,
             embedded_ledger_wasm_hash.clone(),
             embedded_index_wasm_hash,
         ))
@@ -181,7 +192,7 @@ fn should_spawn_archive_from_ledger_with_correct_controllers() {
     let embedded_ledger_wasm_hash = orchestrator.embedded_ledger_wasm_hash.clone();
     let embedded_index_wasm_hash = orchestrator.embedded_index_wasm_hash.clone();
     let usdc = usdc(
-        Principal::anonymous(),
+        MINTER_PRINCIPAL,
         embedded_ledger_wasm_hash,
         embedded_index_wasm_hash,
     );
@@ -190,7 +201,249 @@ fn should_spawn_archive_from_ledger_with_correct_controllers() {
         .add_erc20_token(usdc.clone())
         .expect_new_ledger_and_index_canisters()
         .trigger_creation_of_archive()
-        .assert_all_controlled_by(&expected_controllers);
+        .assert_all_controlled_by(&expected_controllers)
+        .assert_all_running();
+}
+
+#[test]
+fn should_deduplicate_controllers_of_managed_canisters() {
+    const OTHER_PRINCIPAL: Principal = Principal::from_slice(&[3_u8; 29]);
+    let orchestrator = LedgerSuiteOrchestrator::with_more_controller_ids(vec![
+        NNS_ROOT_PRINCIPAL,
+        OTHER_PRINCIPAL,
+        NNS_ROOT_PRINCIPAL,
+        OTHER_PRINCIPAL,
+    ]);
+    let expected_controllers = vec![
+        orchestrator.ledger_suite_orchestrator_id.get().into(),
+        NNS_ROOT_PRINCIPAL,
+        OTHER_PRINCIPAL,
+    ];
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+
+    orchestrator
+        .add_erc20_token(usdc)
+        .expect_new_ledger_and_index_canisters()
+        .trigger_creation_of_archive()
+        .assert_all_controlled_by_distinct(&expected_controllers)
+        .assert_index_controlled_by_orchestrator()
+        .assert_all_running();
+}
+
+#[test]
+fn should_spawn_managed_canisters_within_subnet_canister_ranges() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+
+    orchestrator
+        .add_erc20_token(usdc)
+        .expect_new_ledger_and_index_canisters()
+        .trigger_creation_of_archive()
+        .assert_all_within_subnet_canister_ranges();
+}
+
+#[test]
+fn should_control_managed_canisters_without_additional_controllers() {
+    let orchestrator = LedgerSuiteOrchestrator::with_more_controller_ids(vec![]);
+    let expected_controllers: Vec<Principal> =
+        vec![orchestrator.ledger_suite_orchestrator_id.get().into()];
+    let embedded_ledger_wasm_hash = orchestrator.embedded_ledger_wasm_hash.clone();
+    let embedded_index_wasm_hash = orchestrator.embedded_index_wasm_hash.clone();
+
+    orchestrator
+        .add_erc20_token(usdc(
+            MINTER_PRINCIPAL,
+            embedded_ledger_wasm_hash,
+            embedded_index_wasm_hash,
+        ))
+        .expect_new_ledger_and_index_canisters()
+        .trigger_creation_of_archive()
+        .assert_all_controlled_by_orchestrator()
+        .assert_all_controlled_by(&expected_controllers)
+        .assert_index_controlled_by_orchestrator();
+}
+
+#[test]
+fn should_spawn_managed_canisters_with_best_effort_allocations() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+
+    // The orchestrator does not reserve any memory nor compute for the canisters it manages.
+    orchestrator
+        .add_erc20_token(usdc)
+        .expect_new_ledger_and_index_canisters()
+        .trigger_creation_of_archive()
+        .assert_all_have_allocations(0, 0);
+}
+
+#[test]
+fn should_spawn_ledger_enforcing_max_memo_length() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let embedded_ledger_wasm_hash = orchestrator.embedded_ledger_wasm_hash.clone();
+    let embedded_index_wasm_hash = orchestrator.embedded_index_wasm_hash.clone();
+    let minter = MINTER_PRINCIPAL;
+
+    orchestrator
+        .add_erc20_token(usdc(
+            minter,
+            embedded_ledger_wasm_hash,
+            embedded_index_wasm_hash,
+        ))
+        .expect_new_ledger_and_index_canisters()
+        .assert_ledger_enforces_max_memo_length(minter);
+}
+
+#[test]
+fn should_spawn_ledger_with_standard_ckerc20_fee() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+
+    orchestrator
+        .add_erc20_token(usdc)
+        .expect_new_ledger_and_index_canisters()
+        .assert_standard_ckerc20_fee();
+}
+
+#[test]
+fn should_spawn_ledger_with_fee_collector_subaccount() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let embedded_ledger_wasm_hash = orchestrator.embedded_ledger_wasm_hash.clone();
+    let embedded_index_wasm_hash = orchestrator.embedded_index_wasm_hash.clone();
+    let minter = MINTER_PRINCIPAL;
+    let fee_collector = fee_collector_account_with_subaccount();
+
+    orchestrator
+        .add_erc20_token(with_fee_collector_account(
+            usdc(minter, embedded_ledger_wasm_hash, embedded_index_wasm_hash),
+            fee_collector,
+        ))
+        .expect_new_ledger_and_index_canisters()
+        .assert_ledger_fee_collector(minter, fee_collector);
+}
+
+#[test]
+fn should_charge_only_fee_on_ledger_self_transfer() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let embedded_ledger_wasm_hash = orchestrator.embedded_ledger_wasm_hash.clone();
+    let embedded_index_wasm_hash = orchestrator.embedded_index_wasm_hash.clone();
+
+    orchestrator
+        .add_erc20_token(usdc(
+            MINTER_PRINCIPAL,
+            embedded_ledger_wasm_hash,
+            embedded_index_wasm_hash,
+        ))
+        .expect_new_ledger_and_index_canisters()
+        .assert_ledger_self_transfer_charges_fee(MINTER_PRINCIPAL);
+}
+
+#[test]
+fn should_burn_on_ledger_transfer_to_minting_account() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+
+    orchestrator
+        .add_erc20_token(usdc)
+        .expect_new_ledger_and_index_canisters()
+        .assert_ledger_burn(MINTER_PRINCIPAL);
+}
+
+#[test]
+fn should_index_as_many_transactions_as_ledger_blocks() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+
+    orchestrator
+        .add_erc20_token(usdc)
+        .expect_new_ledger_and_index_canisters()
+        .assert_index_transaction_count_matches_ledger(MINTER_PRINCIPAL, 10);
+}
+
+#[test]
+fn should_deduplicate_ledger_transfers_with_same_created_at_time() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+
+    orchestrator
+        .add_erc20_token(usdc)
+        .expect_new_ledger_and_index_canisters()
+        .assert_ledger_deduplicates_transfers(MINTER_PRINCIPAL);
+}
+
+#[test]
+fn should_preserve_ledger_state_across_ledger_and_index_upgrade() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+
+    orchestrator
+        .add_erc20_token(usdc)
+        .expect_new_ledger_and_index_canisters()
+        .assert_ledger_state_preserved_across_ledger_and_index_upgrade(MINTER_PRINCIPAL);
+}
+
+#[test]
+fn should_paginate_account_transactions_on_index() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let embedded_ledger_wasm_hash = orchestrator.embedded_ledger_wasm_hash.clone();
+    let embedded_index_wasm_hash = orchestrator.embedded_index_wasm_hash.clone();
+
+    orchestrator
+        .add_erc20_token(usdc(
+            MINTER_PRINCIPAL,
+            embedded_ledger_wasm_hash,
+            embedded_index_wasm_hash,
+        ))
+        .expect_new_ledger_and_index_canisters()
+        .assert_index_paginates_account_transactions(MINTER_PRINCIPAL, 10, 3);
+}
+
+#[test]
+fn should_report_stable_canister_ids() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let embedded_ledger_wasm_hash = orchestrator.embedded_ledger_wasm_hash.clone();
+    let embedded_index_wasm_hash = orchestrator.embedded_index_wasm_hash.clone();
+
+    orchestrator
+        .add_erc20_token(usdc(
+            MINTER_PRINCIPAL,
+            embedded_ledger_wasm_hash,
+            embedded_index_wasm_hash,
+        ))
+        .expect_new_ledger_and_index_canisters()
+        .trigger_creation_of_archive()
+        .setup
+        .assert_canister_ids_stable_across_ticks(&usdc_erc20_contract(), MAX_TICKS);
+}
+
+#[test]
+fn should_keep_canister_ids_across_no_op_upgrade() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+    let usdt = orchestrator.embedded_erc20_arg(usdt);
+
+    orchestrator
+        .add_erc20_token(usdc)
+        .expect_new_ledger_and_index_canisters()
+        .trigger_creation_of_archive()
+        .setup
+        .add_erc20_token(usdt)
+        .expect_new_ledger_and_index_canisters()
+        .setup
+        .assert_canister_ids_stable_across_no_op_upgrade()
+        .assert_canister_ids_stable_across_no_op_upgrade();
+}
+
+#[test]
+fn should_top_up_drained_archives() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+
+    orchestrator
+        .add_erc20_token(usdc)
+        .expect_new_ledger_and_index_canisters()
+        .trigger_creation_of_archive()
+        .assert_all_archives_have_cycles(100_000_000_000_000_u128)
+        .assert_drained_archives_are_topped_up();
 }
 
 #[test]
@@ -200,7 +453,7 @@ fn should_discover_new_archive_and_top_up() {
     let embedded_ledger_wasm_hash = orchestrator.embedded_ledger_wasm_hash.clone();
     let embedded_index_wasm_hash = orchestrator.embedded_index_wasm_hash.clone();
     let usdc = usdc(
-        Principal::anonymous(),
+        MINTER_PRINCIPAL,
         embedded_ledger_wasm_hash,
         embedded_index_wasm_hash,
     );
@@ -231,13 +484,199 @@ fn should_discover_new_archive_and_top_up() {
         .assert_contains_metric("ledger_suite_orchestrator_managed_archives 1");
 }
 
+#[test]
+fn should_discover_and_top_up_multiple_archives() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+
+    orchestrator
+        .add_erc20_token(usdc)
+        .expect_new_ledger_and_index_canisters()
+        .trigger_creation_of_archives(3)
+        .assert_orchestrator_manages_all_archives(&usdc_erc20_contract())
+        .check_metrics()
+        .assert_contains_metric("ledger_suite_orchestrator_managed_archives 3");
+}
+
+#[test]
+fn should_conserve_cycles_when_topping_up() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+
+    orchestrator
+        .add_erc20_token(usdc)
+        .expect_new_ledger_and_index_canisters()
+        .trigger_creation_of_archive()
+        .setup
+        .assert_top_up_conserves_cycles();
+}
+
+#[test]
+fn should_retry_adding_erc20_token_once_subnet_is_no_longer_full() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let embedded_ledger_wasm_hash = orchestrator.embedded_ledger_wasm_hash.clone();
+    let embedded_index_wasm_hash = orchestrator.embedded_index_wasm_hash.clone();
+
+    orchestrator
+        .add_erc20_token(usdt(
+            MINTER_PRINCIPAL,
+            embedded_ledger_wasm_hash.clone(),
+            embedded_index_wasm_hash.clone(),
+        ))
+        .expect_new_ledger_and_index_canisters()
+        .setup
+        .fill_subnet()
+        .add_erc20_token(usdc(
+            MINTER_PRINCIPAL,
+            embedded_ledger_wasm_hash,
+            embedded_index_wasm_hash,
+        ))
+        .expect_canister_creation_failure()
+        .free_subnet()
+        .expect_new_ledger_and_index_canisters()
+        .assert_index_has_correct_ledger_id();
+}
+
+#[test]
+fn should_only_advance_install_status_when_retrying() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+
+    orchestrator
+        .fill_subnet()
+        .add_erc20_token(usdc)
+        .expect_canister_creation_failure()
+        .free_subnet()
+        .assert_install_status_monotonic_across_ticks(10 * MAX_TICKS)
+        .expect_new_ledger_and_index_canisters()
+        .assert_index_has_correct_ledger_id();
+}
+
+#[test]
+fn should_complete_install_after_subnet_stall() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+
+    orchestrator
+        .add_erc20_token(usdc)
+        .expect_install_completes_after_stall(2, Duration::from_secs(60 * 60))
+        .assert_index_has_correct_ledger_id()
+        .assert_all_controlled_by_orchestrator();
+}
+
+#[test]
+fn should_record_install_failure_when_ledger_traps_on_init() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+
+    orchestrator
+        .add_erc20_token(with_invalid_transfer_fee(usdc))
+        .expect_ledger_install_failure("failed to convert transfer fee");
+}
+
+#[test]
+fn should_add_erc20_tokens_concurrently() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+    let usdt = orchestrator.embedded_erc20_arg(usdt);
+
+    orchestrator.assert_concurrent_add_erc20_tokens(usdc, usdt);
+}
+
+#[test]
+fn should_increase_managed_canisters_metrics_when_adding_erc20_token() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+
+    orchestrator
+        .check_metrics()
+        .assert_counter_increased(
+            "ledger_suite_orchestrator_managed_ledgers",
+            Some(1),
+            |orchestrator| {
+                orchestrator
+                    .add_erc20_token(usdc)
+                    .expect_new_ledger_and_index_canisters()
+                    .setup
+            },
+        )
+        .check_metrics()
+        .assert_contains_metric("ledger_suite_orchestrator_managed_indexes 1");
+}
+
+#[test]
+fn should_report_metrics_per_erc20_token() {
+    const MANAGED_CANISTERS: &str = "ledger_suite_orchestrator_token_managed_canisters";
+    const CYCLES_BALANCE: &str = "ledger_suite_orchestrator_token_cycles_balance";
+
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+    let usdt = orchestrator.embedded_erc20_arg(usdt);
+    let usdc_cycles_balance = token_metric_name(&usdc.contract, CYCLES_BALANCE);
+    let usdt_cycles_balance = token_metric_name(&usdt.contract, CYCLES_BALANCE);
+    assert_ne!(usdc_cycles_balance, usdt_cycles_balance);
+
+    let orchestrator = orchestrator
+        .add_erc20_token(usdc.clone())
+        .expect_new_ledger_and_index_canisters()
+        .setup
+        .add_erc20_token(usdt.clone())
+        .expect_new_ledger_and_index_canisters()
+        .trigger_creation_of_archive()
+        .setup;
+    orchestrator.advance_time_for_cycles_top_up();
+
+    orchestrator
+        .check_metrics()
+        .assert_token_metric(&usdc.contract, MANAGED_CANISTERS, 2.0)
+        .check_metrics()
+        .assert_token_metric(&usdt.contract, MANAGED_CANISTERS, 3.0)
+        .check_metrics()
+        .assert_contains_metric(&usdc_cycles_balance)
+        .check_metrics()
+        .assert_contains_metric(&usdt_cycles_balance);
+}
+
+#[test]
+fn should_build_add_erc20_arg_from_embedded_wasms() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdt = orchestrator.embedded_erc20_arg(usdt);
+
+    assert_eq!(
+        usdt.ledger_compressed_wasm_hash,
+        orchestrator.embedded_ledger_wasm_hash.to_string()
+    );
+    assert_eq!(
+        usdt.index_compressed_wasm_hash,
+        orchestrator.embedded_index_wasm_hash.to_string()
+    );
+    assert_eq!(usdt.ledger_init_arg.minting_account.owner, MINTER_PRINCIPAL);
+
+    orchestrator
+        .add_erc20_token(usdt)
+        .expect_new_ledger_and_index_canisters()
+        .assert_index_has_correct_ledger_id();
+}
+
+#[test]
+fn should_use_newly_embedded_ledger_wasm_only_for_new_tokens() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+
+    orchestrator
+        .add_erc20_token(usdc.clone())
+        .expect_new_ledger_and_index_canisters()
+        .setup
+        .assert_embedded_ledger_wasm_upgrade_only_applies_to_new_tokens(&usdc.contract, usdt);
+}
+
 #[test]
 fn should_reject_adding_an_already_managed_erc20_token() {
     let orchestrator = LedgerSuiteOrchestrator::default();
     let embedded_ledger_wasm_hash = orchestrator.embedded_ledger_wasm_hash.clone();
     let embedded_index_wasm_hash = orchestrator.embedded_index_wasm_hash.clone();
     let usdc = usdc(
-        Principal::anonymous(),
+        MINTER_PRINCIPAL,
         embedded_ledger_wasm_hash,
         embedded_index_wasm_hash,
     );
@@ -252,6 +691,25 @@ fn should_reject_adding_an_already_managed_erc20_token() {
     assert_matches!(result, Err(e) if e.code() == ErrorCode::CanisterCalledTrap && e.description().contains("Erc20ContractAlreadyManaged"));
 }
 
+#[test]
+fn should_reject_adding_erc20_token_with_malformed_address() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+
+    orchestrator.assert_add_erc20_token_rejected_for_malformed_addresses(usdc);
+}
+
+#[test]
+fn should_reject_re_adding_an_erc20_token_with_changed_metadata() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+
+    orchestrator
+        .add_erc20_token(usdc.clone())
+        .expect_new_ledger_and_index_canisters()
+        .assert_re_adding_with_changed_metadata_is_rejected(&usdc);
+}
+
 #[test]
 fn should_top_up_spawned_canisters() {
     let orchestrator = LedgerSuiteOrchestrator::with_cycles_management(CyclesManagement {
@@ -261,7 +719,7 @@ fn should_top_up_spawned_canisters() {
     let embedded_ledger_wasm_hash = orchestrator.embedded_ledger_wasm_hash.clone();
     let embedded_index_wasm_hash = orchestrator.embedded_index_wasm_hash.clone();
     let usdc = usdc(
-        Principal::anonymous(),
+        MINTER_PRINCIPAL,
         embedded_ledger_wasm_hash,
         embedded_index_wasm_hash,
     );
@@ -312,6 +770,96 @@ fn should_top_up_spawned_canisters() {
     );
 }
 
+#[test]
+fn should_top_up_spawned_canisters_only_below_threshold() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+    let canisters = orchestrator
+        .add_erc20_token(usdc)
+        .expect_new_ledger_and_index_canisters();
+    let ledger_canister_id = canisters.ledger_canister_id();
+    let index_canister_id = canisters.index_canister_id();
+
+    canisters
+        .setup
+        .assert_top_up_only_below_threshold(ledger_canister_id)
+        .assert_top_up_only_below_threshold(index_canister_id);
+}
+
+#[test]
+fn should_report_stopped_ledger_suite() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+    let canisters = orchestrator
+        .add_erc20_token(usdc.clone())
+        .expect_new_ledger_and_index_canisters();
+    canisters.setup.advance_time_for_cycles_top_up();
+
+    let canisters = canisters.stop_ledger();
+    let orchestrator = canisters
+        .setup
+        .assert_suite_health(&usdc.contract, SuiteHealth::Healthy);
+
+    orchestrator.advance_time_for_cycles_top_up();
+    orchestrator.assert_suite_health(&usdc.contract, SuiteHealth::Stopped);
+}
+
+#[test]
+fn should_report_ledger_suite_low_on_cycles_when_orchestrator_cannot_top_up() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+    let canisters = orchestrator
+        .add_erc20_token(usdc.clone())
+        .expect_new_ledger_and_index_canisters();
+    let ledger_canister_id = canisters.ledger_canister_id();
+    let orchestrator = canisters.setup;
+    orchestrator.advance_time_for_cycles_top_up();
+    let orchestrator = orchestrator.assert_suite_health(&usdc.contract, SuiteHealth::Healthy);
+
+    let cycles_management = orchestrator.get_orchestrator_info().cycles_management;
+    let minimum_orchestrator_cycles =
+        u128::try_from(&cycles_management.minimum_orchestrator_cycles().0).unwrap();
+    let minimum_monitored_canister_cycles =
+        u128::try_from(&cycles_management.minimum_monitored_canister_cycles().0).unwrap();
+    orchestrator.set_cycles_balance(
+        orchestrator.ledger_suite_orchestrator_id,
+        minimum_orchestrator_cycles / 2,
+    );
+    orchestrator.set_cycles_balance(ledger_canister_id, minimum_monitored_canister_cycles / 2);
+
+    orchestrator.advance_time_for_cycles_top_up();
+    orchestrator.assert_suite_health(&usdc.contract, SuiteHealth::LowCycles);
+}
+
+#[test]
+fn should_reject_adding_erc20_token_with_anonymous_minting_account() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let embedded_ledger_wasm_hash = orchestrator.embedded_ledger_wasm_hash.clone();
+    let embedded_index_wasm_hash = orchestrator.embedded_index_wasm_hash.clone();
+
+    orchestrator.assert_add_erc20_token_rejected(
+        usdc(
+            Principal::anonymous(),
+            embedded_ledger_wasm_hash,
+            embedded_index_wasm_hash,
+        ),
+        "AnonymousMintingAccount",
+    );
+}
+
+#[test]
+fn should_reject_adding_erc20_token_from_unauthorized_caller() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+    let unauthorized_caller = Principal::from_slice(&[0xfe_u8; 29]);
+
+    orchestrator
+        .assert_add_erc20_token_rejected_for_unauthorized_caller(unauthorized_caller, usdc.clone())
+        .assert_add_erc20_token_rejected_for_unauthorized_caller(MINTER_PRINCIPAL, usdc.clone())
+        .add_erc20_token(usdc)
+        .expect_new_ledger_and_index_canisters();
+}
+
 #[test]
 fn should_reject_upgrade_with_invalid_args() {
     const UNKNOWN_WASM_HASH: &str =
@@ -329,7 +877,7 @@ fn should_reject_upgrade_with_invalid_args() {
     let embedded_ledger_wasm_hash = orchestrator.embedded_ledger_wasm_hash.clone();
     let embedded_index_wasm_hash = orchestrator.embedded_index_wasm_hash.clone();
     let usdc = usdc(
-        Principal::anonymous(),
+        MINTER_PRINCIPAL,
         embedded_ledger_wasm_hash.clone(),
         embedded_index_wasm_hash,
     );
@@ -428,18 +976,37 @@ fn should_reject_update_calls_to_http_request() {
     );
 }
 
+#[test]
+fn should_use_default_cycles_management_when_none_configured() {
+    LedgerSuiteOrchestrator::default().assert_default_cycles_management();
+}
+
+#[test]
+fn should_override_default_cycles_management() {
+    let cycles_management = CyclesManagement {
+        cycles_for_ledger_creation: Nat::from(30 * TEN_TRILLIONS),
+        cycles_for_archive_creation: Nat::from(5 * TEN_TRILLIONS),
+        cycles_for_index_creation: Nat::from(7 * TEN_TRILLIONS),
+        cycles_top_up_increment: Nat::from(TEN_TRILLIONS / 2),
+    };
+    assert_ne!(cycles_management, default_cycles_management());
+
+    LedgerSuiteOrchestrator::with_cycles_management(cycles_management.clone())
+        .assert_cycles_management(&cycles_management);
+}
+
 #[test]
 fn should_retrieve_orchestrator_info() {
     let orchestrator = LedgerSuiteOrchestrator::default();
     let embedded_ledger_wasm_hash = orchestrator.embedded_ledger_wasm_hash.clone();
     let embedded_index_wasm_hash = orchestrator.embedded_index_wasm_hash.clone();
     let usdc = usdc(
-        Principal::anonymous(),
+        MINTER_PRINCIPAL,
         embedded_ledger_wasm_hash.clone(),
         embedded_index_wasm_hash.clone(),
     );
     let usdt = usdt(
-        Principal::anonymous(),
+        MINTER_PRINCIPAL,
         embedded_ledger_wasm_hash,
         embedded_index_wasm_hash,
     );
@@ -457,6 +1024,7 @@ fn should_retrieve_orchestrator_info() {
     let usdt_ledger_id = canisters.ledger_canister_id();
     let usdt_index_id = canisters.index_canister_id();
 
+    canisters.setup.advance_time_for_cycles_top_up();
     let info = canisters.setup.get_orchestrator_info();
     assert_eq!(
         info,
@@ -473,7 +1041,8 @@ fn should_retrieve_orchestrator_info() {
                         canister_id: usdc_index_id.into(),
                         installed_wasm_hash: usdc.index_compressed_wasm_hash,
                     }),
-                    archives: vec![]
+                    archives: vec![],
+                    health: Some(SuiteHealth::Healthy),
                 },
                 ManagedCanisters {
                     erc20_contract: usdt.contract.clone(),
@@ -486,7 +1055,8 @@ fn should_retrieve_orchestrator_info() {
                         canister_id: usdt_index_id.into(),
                         installed_wasm_hash: usdt.index_compressed_wasm_hash,
                     }),
-                    archives: vec![]
+                    archives: vec![],
+                    health: Some(SuiteHealth::Healthy),
                 }
             ],
             cycles_management: CyclesManagement {
@@ -496,11 +1066,119 @@ fn should_retrieve_orchestrator_info() {
                 cycles_top_up_increment: Nat::from(10000000000000_u64),
             },
             more_controller_ids: vec![NNS_ROOT_PRINCIPAL],
-            minter_id: None
+            minter_id: None,
+            interface_version: 1,
         }
     );
 }
 
+#[test]
+fn should_retrieve_orchestrator_info_without_changing_state() {
+    let orchestrator =
+        LedgerSuiteOrchestrator::default().assert_get_orchestrator_info_is_read_only();
+    let usdc = orchestrator.embedded_erc20_arg(usdc);
+    assert_eq!(
+        orchestrator
+            .snapshot_orchestrator_state()
+            .managed_canisters(&usdc.contract),
+        None
+    );
+
+    let canisters = orchestrator
+        .add_erc20_token(usdc.clone())
+        .expect_new_ledger_and_index_canisters();
+    let usdc_ledger_id = canisters.ledger_canister_id();
+    let usdc_index_id = canisters.index_canister_id();
+
+    let orchestrator = canisters.setup.assert_get_orchestrator_info_is_read_only();
+    let snapshot = orchestrator.snapshot_orchestrator_state();
+    let managed_canisters = snapshot
+        .managed_canisters(&usdc.contract)
+        .expect("BUG: usdc canisters are not managed");
+    assert_eq!(
+        managed_canisters.ledger,
+        Some(ManagedCanisterStatus::Installed {
+            canister_id: usdc_ledger_id.into(),
+            installed_wasm_hash: usdc.ledger_compressed_wasm_hash,
+        })
+    );
+    assert_eq!(
+        managed_canisters.index,
+        Some(ManagedCanisterStatus::Installed {
+            canister_id: usdc_index_id.into(),
+            installed_wasm_hash: usdc.index_compressed_wasm_hash,
+        })
+    );
+}
+
+#[test]
+fn should_schedule_periodic_tasks_after_init() {
+    const ONE_HOUR: Duration = Duration::from_secs(60 * 60);
+
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    // Let the tasks scheduled at init run once, they are then rescheduled periodically.
+    for _ in 0..MAX_TICKS {
+        orchestrator.env.tick();
+    }
+
+    orchestrator
+        .check_metrics()
+        .assert_task_scheduled_in("maybe_top_up", ONE_HOUR)
+        .check_metrics()
+        .assert_task_scheduled_in("discover_archives", ONE_HOUR);
+}
+
+#[test]
+fn should_report_interface_version() {
+    // Update the expected version when bumping the interface version after a breaking change.
+    LedgerSuiteOrchestrator::default().assert_interface_version(1);
+}
+
+#[test]
+fn should_preserve_state_across_upgrade() {
+    let orchestrator = LedgerSuiteOrchestrator::default();
+    let embedded_ledger_wasm_hash = orchestrator.embedded_ledger_wasm_hash.clone();
+    let embedded_index_wasm_hash = orchestrator.embedded_index_wasm_hash.clone();
+    let usdc = usdc(
+        MINTER_PRINCIPAL,
+        embedded_ledger_wasm_hash.clone(),
+        embedded_index_wasm_hash.clone(),
+    );
+    let usdt = usdt(
+        MINTER_PRINCIPAL,
+        embedded_ledger_wasm_hash,
+        embedded_index_wasm_hash,
+    );
+
+    let orchestrator = orchestrator
+        .add_erc20_token(usdc)
+        .expect_new_ledger_and_index_canisters()
+        .trigger_creation_of_archive()
+        .setup
+        .add_erc20_token(usdt)
+        .expect_new_ledger_and_index_canisters()
+        .setup;
+
+    orchestrator
+        .assert_state_preserved_across_upgrade(&OrchestratorArg::UpgradeArg(UpgradeArg {
+            git_commit_hash: None,
+            ledger_compressed_wasm_hash: None,
+            index_compressed_wasm_hash: None,
+            archive_compressed_wasm_hash: None,
+            cycles_management: Some(UpdateCyclesManagement {
+                cycles_top_up_increment: Some(20_000_000_000_000_u128.into()),
+                ..Default::default()
+            }),
+        }))
+        .assert_state_preserved_across_upgrade(&OrchestratorArg::UpgradeArg(UpgradeArg {
+            git_commit_hash: None,
+            ledger_compressed_wasm_hash: None,
+            index_compressed_wasm_hash: None,
+            archive_compressed_wasm_hash: None,
+            cycles_management: None,
+        }));
+}
+
 #[test]
 fn should_query_logs_and_metrics() {
     let orchestrator = LedgerSuiteOrchestrator::default();
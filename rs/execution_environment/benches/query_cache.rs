@@ -0,0 +1,145 @@
+///
+/// Benchmark the query cache lookup and insert throughput.
+///
+/// Every scenario runs synthetic user queries against a Universal Canister
+/// through the query handler with caching enabled, so a cache hit measures
+/// the lookup path and a cache miss measures the execution and insert path.
+///
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use ic_test_utilities::universal_canister::wasm;
+use ic_test_utilities_execution_environment::{ExecutionTest, ExecutionTestBuilder};
+use ic_types::CanisterId;
+
+/// Number of distinct queries kept warm in the cache.
+const WARM_QUERIES: u32 = 100;
+/// Reply sizes of the synthetic queries.
+const REPLY_SIZES: [usize; 3] = [0, 10_000, 100_000];
+/// Reply sizes of the synthetic queries for the eviction scenario.
+const EVICTION_REPLY_SIZES: [usize; 2] = [10_000, 100_000];
+/// Query cache capacity for the eviction scenario.
+const SMALL_QUERY_CACHE_CAPACITY: u64 = 1024 * 1024;
+/// Reply size of the synthetic query for the big reply hits scenario, i.e. 16 stable memory pages.
+const BIG_REPLY_SIZE: u32 = 1024 * 1024;
+
+/// Returns a Universal Canister payload replying with `reply_size` bytes.
+///
+/// The `seq` is part of the payload, so queries with different `seq`
+/// have different cache keys.
+fn payload(seq: u32, reply_size: usize) -> Vec<u8> {
+    wasm()
+        .push_int(seq)
+        .reply_data(&vec![42; reply_size])
+        .build()
+}
+
+fn query(test: &mut ExecutionTest, canister_id: CanisterId, seq: u32, reply_size: usize) {
+    test.non_replicated_query(canister_id, "query", payload(seq, reply_size))
+        .expect("Error executing a query");
+}
+
+/// Runs queries where `misses_per_ten` in ten queries are cache misses,
+/// and the rest are hits on the warm queries.
+fn run_mixed(c: &mut Criterion, group_name: &str, misses_per_ten: u32) {
+    let mut group = c.benchmark_group(group_name);
+    for reply_size in REPLY_SIZES {
+        let mut test = ExecutionTestBuilder::new().build();
+        let canister_id = test.universal_canister().unwrap();
+        for seq in 0..WARM_QUERIES {
+            query(&mut test, canister_id, seq, reply_size);
+        }
+        // Sequence numbers of the misses start after the warm queries.
+        let mut next_miss = WARM_QUERIES;
+        let mut i = 0;
+        group.bench_with_input(
+            BenchmarkId::from_parameter(reply_size),
+            &reply_size,
+            |b, &reply_size| {
+                b.iter(|| {
+                    i += 1;
+                    let seq = if i % 10 < misses_per_ten {
+                        next_miss += 1;
+                        next_miss
+                    } else {
+                        i % WARM_QUERIES
+                    };
+                    query(&mut test, canister_id, seq, reply_size);
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+pub fn query_cache_hit_heavy_bench(c: &mut Criterion) {
+    run_mixed(c, "query_cache_hit_heavy", 1);
+}
+
+pub fn query_cache_miss_heavy_bench(c: &mut Criterion) {
+    run_mixed(c, "query_cache_miss_heavy", 9);
+}
+
+pub fn query_cache_insert_with_eviction_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("query_cache_insert_with_eviction");
+    for reply_size in EVICTION_REPLY_SIZES {
+        let mut test = ExecutionTestBuilder::new()
+            .with_query_cache_capacity(SMALL_QUERY_CACHE_CAPACITY)
+            .build();
+        let canister_id = test.universal_canister().unwrap();
+        // Overfill the cache, so every new entry evicts an old one.
+        let mut seq = 0;
+        while seq < 2 * (SMALL_QUERY_CACHE_CAPACITY / reply_size as u64) as u32 {
+            query(&mut test, canister_id, seq, reply_size);
+            seq += 1;
+        }
+        group.bench_with_input(
+            BenchmarkId::from_parameter(reply_size),
+            &reply_size,
+            |b, &reply_size| {
+                b.iter(|| {
+                    seq += 1;
+                    query(&mut test, canister_id, seq, reply_size);
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Compares the hits of a big reply returned as an owned copy
+/// and shared with the query cache.
+pub fn query_cache_big_reply_hit_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("query_cache_big_reply_hit");
+    let mut test = ExecutionTestBuilder::new().build();
+    let canister_id = test.universal_canister().unwrap();
+    // Read the reply from the stable memory, so the payload in the cache key stays small.
+    let payload = wasm()
+        .stable_grow(16)
+        .stable_read(0, BIG_REPLY_SIZE)
+        .append_and_reply()
+        .build();
+    // Warm up the cache with the big reply.
+    test.non_replicated_query(canister_id, "query", payload.clone())
+        .expect("Error executing a query");
+    group.bench_function("owned", |b| {
+        b.iter(|| {
+            test.non_replicated_query(canister_id, "query", payload.clone())
+                .expect("Error executing a query");
+        });
+    });
+    group.bench_function("shared", |b| {
+        b.iter(|| {
+            let result = test.non_replicated_query_shared(canister_id, "query", payload.clone());
+            assert!(result.is_ok(), "Error executing a query");
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benchmarks,
+    query_cache_hit_heavy_bench,
+    query_cache_miss_heavy_bench,
+    query_cache_insert_with_eviction_bench,
+    query_cache_big_reply_hit_bench
+);
+criterion_main!(benchmarks);
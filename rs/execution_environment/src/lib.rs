@@ -40,7 +40,10 @@ use ic_replicated_state::page_map::PageAllocatorFileDescriptor;
 use ic_replicated_state::{CallOrigin, NetworkTopology, ReplicatedState};
 use ic_types::{messages::CallContextId, SubnetId};
 pub use metrics::IngressFilterMetrics;
-pub use query_handler::InternalHttpQueryHandler;
+pub use query_handler::{
+    InternalHttpQueryHandler, QueryCacheConfig, QueryCacheMetadata, QueryCachePayloadProjection,
+    QueryCacheSourceFilter, ShardStat, SharedQueryResult,
+};
 use query_handler::{HttpQueryHandler, QueryScheduler, QuerySchedulerFlag};
 pub use scheduler::RoundSchedule;
 use scheduler::SchedulerImpl;
@@ -8,6 +8,12 @@ mod query_scheduler;
 #[cfg(test)]
 mod tests;
 
+use query_cache::InFlightJoin;
+pub use query_cache::{
+    QueryCacheConfig, QueryCacheMetadata, QueryCachePayloadProjection, QueryCacheSourceFilter,
+    ShardStat, SharedQueryResult,
+};
+
 use crate::execution_environment::subnet_memory_capacity;
 use crate::{
     hypervisor::Hypervisor,
@@ -23,6 +29,7 @@ use ic_error_types::{ErrorCode, UserError};
 use ic_interfaces::execution_environment::{
     QueryExecutionError, QueryExecutionResponse, QueryExecutionService,
 };
+use ic_interfaces::time_source::TimeSource;
 use ic_interfaces_state_manager::{Labeled, StateReader};
 use ic_logger::ReplicaLogger;
 use ic_metrics::MetricsRegistry;
@@ -34,7 +41,7 @@ use ic_types::QueryStatsEpoch;
 use ic_types::{
     ingress::WasmResult,
     messages::{Blob, Certificate, CertificateDelegation, UserQuery},
-    CanisterId, NumInstructions, PrincipalId,
+    CanisterId, NumBytes, NumInstructions, PrincipalId, Time,
 };
 use prometheus::Histogram;
 use serde::Serialize;
@@ -148,9 +155,45 @@ impl InternalHttpQueryHandler {
         cycles_account_manager: Arc<CyclesAccountManager>,
         local_query_execution_stats: QueryStatsCollector,
     ) -> Self {
+        let query_caching = config.query_caching == FlagStatus::Enabled;
         let query_cache_capacity = config.query_cache_capacity;
         let query_max_expiry_time = config.query_cache_max_expiry_time;
         let query_data_certificate_expiry_time = config.query_cache_data_certificate_expiry_time;
+        let query_cache_verify_on_hit = config.query_cache_verify_on_hit == FlagStatus::Enabled;
+        let query_cache_max_entries_per_method = config.query_cache_max_entries_per_method;
+        let query_cache_canonical_payload =
+            config.query_cache_canonical_payload == FlagStatus::Enabled;
+        let query_cache_circuit_breaker_threshold = config.query_cache_circuit_breaker_threshold;
+        let query_cache_circuit_breaker_cooldown = config.query_cache_circuit_breaker_cooldown;
+        let query_cache_stale_on_error_window = config.query_cache_stale_on_error_window;
+        let mut query_cache = query_cache::QueryCache::new(
+            metrics_registry,
+            query_caching,
+            query_cache_capacity,
+            query_max_expiry_time,
+            query_data_certificate_expiry_time,
+            query_cache_verify_on_hit,
+            query_cache_max_entries_per_method,
+            query_cache_canonical_payload,
+            query_cache_circuit_breaker_threshold,
+            query_cache_circuit_breaker_cooldown,
+            query_cache_stale_on_error_window,
+        );
+        if let Some(expected_entries) = config.query_cache_expected_entries {
+            query_cache.set_expected_entries(expected_entries);
+        }
+        if let Some(max_serve_age) = config.query_cache_max_serve_age {
+            query_cache.set_max_serve_age(max_serve_age);
+        }
+        query_cache.set_min_requests(config.query_cache_min_requests);
+        query_cache.set_eviction_policy(config.query_cache_eviction_policy);
+        if let Some(per_canister_capacity) = config.query_cache_per_canister_capacity {
+            query_cache.set_per_canister_capacity(per_canister_capacity);
+        }
+        query_cache.set_cache_errors(config.query_cache_errors == FlagStatus::Enabled);
+        if let Some(max_entry_size) = config.query_cache_max_entry_size {
+            query_cache.set_max_entry_size(max_entry_size);
+        }
         Self {
             log,
             hypervisor,
@@ -160,12 +203,7 @@ impl InternalHttpQueryHandler {
             max_instructions_per_query,
             cycles_account_manager,
             local_query_execution_stats,
-            query_cache: query_cache::QueryCache::new(
-                metrics_registry,
-                query_cache_capacity,
-                query_max_expiry_time,
-                query_data_certificate_expiry_time,
-            ),
+            query_cache,
         }
     }
 
@@ -188,50 +226,176 @@ impl InternalHttpQueryHandler {
         self.local_query_execution_stats.set_epoch(epoch);
     }
 
+    /// Key the query cache entries on the `projection` of the query payloads,
+    /// i.e. the queries with the same projected payloads share the cache entry.
+    ///
+    /// The projection must keep all the payload parts the query results depend on.
+    pub fn with_query_cache_payload_projection(
+        mut self,
+        projection: QueryCachePayloadProjection,
+    ) -> Self {
+        self.query_cache.set_payload_projection(projection);
+        self
+    }
+
+    /// Cache only the queries of the sources accepted by the `filter`.
+    ///
+    /// The queries of the other sources bypass the query cache.
+    pub fn with_query_cache_source_filter(mut self, filter: QueryCacheSourceFilter) -> Self {
+        self.query_cache.set_source_filter(filter);
+        self
+    }
+
+    /// Measure the age of the query cache entries with the `time_source`
+    /// instead of the system time.
+    pub fn with_query_cache_time_source(mut self, time_source: Arc<dyn TimeSource>) -> Self {
+        self.query_cache.set_time_source(time_source);
+        self
+    }
+
+    /// Return a snapshot of the effective query cache configuration.
+    pub fn query_cache_config(&self) -> QueryCacheConfig {
+        self.query_cache.config()
+    }
+
+    /// Evict query cache entries until the cache size is not greater than `target_bytes`.
+    ///
+    /// Returns the number of entries evicted at the `now` time.
+    pub fn trim_query_cache(&self, target_bytes: NumBytes, now: Time) -> usize {
+        self.query_cache.trim_to(target_bytes, now)
+    }
+
+    /// Remove the query cache entries expired by the `now` time.
+    ///
+    /// Returns the number of removed entries.
+    pub fn sweep_expired_query_cache_entries(&self, now: Time) -> usize {
+        self.query_cache.sweep_expired(now)
+    }
+
+    /// Shrink the query cache internal structures to fit the current entries.
+    ///
+    /// Returns the estimated number of reclaimed bytes.
+    pub fn compact_query_cache(&self) -> NumBytes {
+        self.query_cache.compact()
+    }
+
+    /// Return the number of the query cache entries and bytes per shard,
+    /// as they would be distributed in the sharded query cache.
+    pub fn query_cache_shard_stats(&self) -> Vec<ShardStat> {
+        self.query_cache.shard_stats()
+    }
+
+    /// Serialize the query cache entries into a snapshot, which can be imported
+    /// into the query cache of a fresh handler.
+    pub fn export_query_cache_snapshot(&self) -> Vec<u8> {
+        self.query_cache.export_snapshot()
+    }
+
+    /// Import the query cache entries from the `snapshot`, dropping the ones
+    /// no longer valid for the current `state`.
+    ///
+    /// Returns the number of imported entries.
+    pub fn import_query_cache_snapshot(
+        &self,
+        snapshot: &[u8],
+        state: &ReplicatedState,
+    ) -> Result<usize, serde_cbor::Error> {
+        self.query_cache.import_snapshot(snapshot, state)
+    }
+
+    /// Pin the query cache entry of the `query`, so it's never evicted.
+    ///
+    /// Returns `false` if the entry is not cached or there is no room for more pinned entries.
+    pub fn pin_query_cache_entry(&self, query: &UserQuery, cache_context: Option<Vec<u8>>) -> bool {
+        self.query_cache
+            .pin(&self.query_cache.new_key(query, cache_context))
+    }
+
+    /// Unpin the query cache entry of the `query` at the `now` time.
+    ///
+    /// Returns `false` if the entry was not pinned.
+    pub fn unpin_query_cache_entry(
+        &self,
+        query: &UserQuery,
+        cache_context: Option<Vec<u8>>,
+        now: Time,
+    ) -> bool {
+        self.query_cache
+            .unpin(&self.query_cache.new_key(query, cache_context), now)
+    }
+
     /// Handle a query of type `UserQuery` which was sent by an end user.
     pub fn query(
         &self,
-        mut query: UserQuery,
+        query: UserQuery,
+        state: Labeled<Arc<ReplicatedState>>,
+        data_certificate: Vec<u8>,
+    ) -> Result<WasmResult, UserError> {
+        self.query_with_cache_context(query, state, data_certificate, None)
+    }
+
+    /// Handle a query of type `UserQuery` which was sent by an end user.
+    ///
+    /// The optional `cache_context` (i.e. the identity of the forwarding boundary node)
+    /// becomes a part of the query cache key, so the queries with different contexts
+    /// never share the query cache entries.
+    pub fn query_with_cache_context(
+        &self,
+        query: UserQuery,
         state: Labeled<Arc<ReplicatedState>>,
         data_certificate: Vec<u8>,
+        cache_context: Option<Vec<u8>>,
     ) -> Result<WasmResult, UserError> {
+        self.query_with_cache_metadata(query, state, data_certificate, cache_context)
+            .0
+    }
+
+    /// Handle a query just like `query_with_cache_context()`, also returning
+    /// the out-of-band metadata on how the query cache served it,
+    /// i.e. the age of the served cache entry.
+    pub fn query_with_cache_metadata(
+        &self,
+        query: UserQuery,
+        state: Labeled<Arc<ReplicatedState>>,
+        data_certificate: Vec<u8>,
+        cache_context: Option<Vec<u8>>,
+    ) -> (Result<WasmResult, UserError>, QueryCacheMetadata) {
+        let mut metadata = QueryCacheMetadata::default();
+        let result =
+            self.execute_query(query, state, data_certificate, cache_context, &mut metadata);
+        // The result is only copied if it's shared with the query cache.
+        (Arc::unwrap_or_clone(result), metadata)
+    }
+
+    /// Handle a query just like `query()`, returning the result shared with the query cache,
+    /// so the cache hits don't copy the reply.
+    pub fn query_shared(
+        &self,
+        query: UserQuery,
+        state: Labeled<Arc<ReplicatedState>>,
+        data_certificate: Vec<u8>,
+    ) -> SharedQueryResult {
+        let mut metadata = QueryCacheMetadata::default();
+        self.execute_query(query, state, data_certificate, None, &mut metadata)
+    }
+
+    fn execute_query(
+        &self,
+        mut query: UserQuery,
+        state: Labeled<Arc<ReplicatedState>>,
+        data_certificate: Vec<u8>,
+        cache_context: Option<Vec<u8>>,
+        metadata: &mut QueryCacheMetadata,
+    ) -> SharedQueryResult {
         let measurement_scope = MeasurementScope::root(&self.metrics.query);
 
         // Update the query receiver if the query is for the management canister.
         if query.receiver == CanisterId::ic_00() {
-            let network = match QueryMethod::from_str(query.method_name.as_str()) {
-                Ok(QueryMethod::BitcoinGetUtxosQuery) => {
-                    BitcoinGetUtxosArgs::decode(&query.method_payload)?.network
-                }
-                Ok(QueryMethod::BitcoinGetBalanceQuery) => {
-                    BitcoinGetBalanceArgs::decode(&query.method_payload)?.network
-                }
-                Ok(QueryMethod::FetchCanisterLogs) => {
-                    return match self.config.embedders_config.feature_flags.canister_logging {
-                        FlagStatus::Enabled => fetch_canister_logs(
-                            query.source.get(),
-                            state.get_ref(),
-                            FetchCanisterLogsRequest::decode(&query.method_payload)?,
-                        ),
-                        FlagStatus::Disabled => Err(UserError::new(
-                            ErrorCode::CanisterContractViolation,
-                            format!(
-                                "{} API is not enabled on this subnet",
-                                QueryMethod::FetchCanisterLogs
-                            ),
-                        )),
-                    }
-                }
-                Err(_) => {
-                    return Err(UserError::new(
-                        ErrorCode::CanisterMethodNotFound,
-                        format!("Query method {} not found.", query.method_name),
-                    ));
-                }
-            };
-
-            query.receiver =
-                route_bitcoin_message(network, &state.get_ref().metadata.network_topology)?;
+            match self.route_management_canister_query(&mut query, state.get_ref()) {
+                Ok(None) => {}
+                Ok(Some(reply)) => return Arc::new(Ok(reply)),
+                Err(err) => return Arc::new(Err(err)),
+            }
         }
 
         let query_stats_collector = if self.config.query_stats_aggregation == FlagStatus::Enabled {
@@ -241,16 +405,39 @@ impl InternalHttpQueryHandler {
         };
 
         // Check the query cache first (if the query caching is enabled).
-        // If a valid cache entry found, the result will be immediately returned.
+        // If a valid cache entry found, the result will be immediately returned,
+        // unless the query cache verify on hit mode is enabled.
         // Otherwise, the key will be kept for the `push` below.
-        let cache_entry_key = if self.config.query_caching == FlagStatus::Enabled {
-            let key = query_cache::EntryKey::from(&query);
+        let mut cached_result = None;
+        let mut in_flight = None;
+        let cache_entry_key = if self.config.query_caching == FlagStatus::Enabled
+            && self.query_cache.is_cacheable_source(query.source)
+            && self
+                .query_cache
+                .is_circuit_closed(query.receiver, state.get_ref().metadata.batch_time)
+            && (self.config.query_cache_composite_queries == FlagStatus::Enabled
+                || !self
+                    .query_cache
+                    .bypass_composite_query(&query, state.get_ref()))
+        {
+            let key = self.query_cache.new_key(&query, cache_context);
             let state = state.get_ref().as_ref();
-            if let Some(result) =
+            if let Some((result, age_seconds)) =
                 self.query_cache
-                    .get_valid_result(&key, state, query_stats_collector)
+                    .get_valid_result_with_age(&key, state, query_stats_collector)
             {
-                return result;
+                metadata.served_entry_age_seconds = Some(age_seconds);
+                if self.config.query_cache_verify_on_hit == FlagStatus::Disabled {
+                    return result;
+                }
+                // Re-execute the query below to verify the cached result.
+                cached_result = Some(result);
+            } else if self.config.query_cache_coalesce_misses == FlagStatus::Enabled {
+                // Wait for the identical query in flight, if any, instead of executing it again.
+                match self.query_cache.join_in_flight(&key, state) {
+                    InFlightJoin::Execute(guard) => in_flight = guard,
+                    InFlightJoin::Coalesced(result) => return result,
+                }
             }
             Some(key)
         } else {
@@ -288,6 +475,34 @@ impl InternalHttpQueryHandler {
         let result = context.run(query, &self.metrics, &measurement_scope);
         context.accumulate_transient_errors_from_result(result.as_ref());
         context.observe_metrics(&self.metrics);
+        let result = Arc::new(result);
+
+        // Verify the cached result against the fresh one, unless the fresh
+        // execution hit a transient error.
+        if let Some(cached_result) = cached_result {
+            if context.transient_errors() == 0 {
+                self.query_cache.verify_hit(&cached_result, &result);
+            }
+            return cached_result;
+        }
+
+        // Serve the last successful reply instead of a canister trap, if it's not too stale.
+        // The trap is not cached then, so the query is executed again next time.
+        if let Some(key) = &cache_entry_key {
+            let now = state.get_ref().metadata.batch_time;
+            if let Some(stale_result) = self.query_cache.get_stale_result_on_trap(key, &result, now)
+            {
+                if let Some(in_flight) = in_flight {
+                    in_flight.finish(
+                        &stale_result,
+                        state.get_ref().as_ref(),
+                        context.system_api_call_counters(),
+                        context.evaluated_canister_stats(),
+                    );
+                }
+                return stale_result;
+            }
+        }
 
         // Add the query execution result to the query cache (if the query caching is enabled).
         // Query caching is disabled if the key is set to `None`.
@@ -299,8 +514,64 @@ impl InternalHttpQueryHandler {
             self.query_cache
                 .push(key, &result, state, counters, stats, errors);
         }
+        // Share the result with the identical queries waiting for it,
+        // unless there were transient errors, so they execute the query themselves.
+        if let Some(in_flight) = in_flight {
+            if context.transient_errors() == 0 {
+                in_flight.finish(
+                    &result,
+                    state.get_ref().as_ref(),
+                    context.system_api_call_counters(),
+                    context.evaluated_canister_stats(),
+                );
+            }
+        }
         result
     }
+
+    /// Route the management canister `query` to the canister handling it.
+    ///
+    /// Returns the reply if the query is handled by the management canister itself.
+    fn route_management_canister_query(
+        &self,
+        query: &mut UserQuery,
+        state: &ReplicatedState,
+    ) -> Result<Option<WasmResult>, UserError> {
+        let network = match QueryMethod::from_str(query.method_name.as_str()) {
+            Ok(QueryMethod::BitcoinGetUtxosQuery) => {
+                BitcoinGetUtxosArgs::decode(&query.method_payload)?.network
+            }
+            Ok(QueryMethod::BitcoinGetBalanceQuery) => {
+                BitcoinGetBalanceArgs::decode(&query.method_payload)?.network
+            }
+            Ok(QueryMethod::FetchCanisterLogs) => {
+                return match self.config.embedders_config.feature_flags.canister_logging {
+                    FlagStatus::Enabled => fetch_canister_logs(
+                        query.source.get(),
+                        state,
+                        FetchCanisterLogsRequest::decode(&query.method_payload)?,
+                    )
+                    .map(Some),
+                    FlagStatus::Disabled => Err(UserError::new(
+                        ErrorCode::CanisterContractViolation,
+                        format!(
+                            "{} API is not enabled on this subnet",
+                            QueryMethod::FetchCanisterLogs
+                        ),
+                    )),
+                }
+            }
+            Err(_) => {
+                return Err(UserError::new(
+                    ErrorCode::CanisterMethodNotFound,
+                    format!("Query method {} not found.", query.method_name),
+                ));
+            }
+        };
+
+        query.receiver = route_bitcoin_message(network, &state.metadata.network_topology)?;
+        Ok(None)
+    }
 }
 
 fn route_bitcoin_message(
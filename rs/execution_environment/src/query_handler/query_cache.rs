@@ -1,21 +1,69 @@
-use ic_base_types::{CanisterId, NumBytes};
-use ic_error_types::UserError;
-use ic_interfaces::execution_environment::SystemApiCallCounters;
+use candid::{DecoderConfig, IDLArgs};
+use ic_base_types::{CanisterId, NumBytes, PrincipalId};
+use ic_config::execution_environment::EvictionPolicy;
+use ic_error_types::{ErrorCode, UserError};
+use ic_interfaces::{
+    execution_environment::SystemApiCallCounters,
+    time_source::{SysTimeSource, TimeSource},
+};
 use ic_metrics::MetricsRegistry;
 use ic_query_stats::QueryStatsCollector;
-use ic_replicated_state::ReplicatedState;
+use ic_replicated_state::{CanisterState, ReplicatedState};
 use ic_types::{
-    batch::QueryStats, ingress::WasmResult, messages::UserQuery, CountBytes, Cycles, Time, UserId,
+    batch::QueryStats, ingress::WasmResult, messages::UserQuery, methods::WasmMethod, CountBytes,
+    Cycles, Time, UserId,
 };
 use ic_utils_lru_cache::LruCache;
-use prometheus::{Histogram, IntCounter, IntGauge};
-use std::{collections::BTreeMap, mem::size_of_val, sync::Mutex, time::Duration};
+use prometheus::{Gauge, Histogram, IntCounter, IntCounterVec, IntGauge, IntGaugeVec};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    mem::{size_of, size_of_val},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::Duration,
+};
 
 use crate::metrics::duration_histogram;
 
 #[cfg(test)]
 mod tests;
 
+/// The pinned entries may take at most this percentage of the query cache capacity.
+const MAX_PINNED_BYTES_PERCENT: u64 = 25;
+
+/// The stale entries kept to be served on a canister trap may take at most
+/// this percentage of the query cache capacity.
+const MAX_STALE_BYTES_PERCENT: u64 = 10;
+
+/// The tracked misses of the keys not yet stored in the query cache
+/// may take at most this percentage of the query cache capacity.
+const MAX_TRACKED_MISSES_BYTES_PERCENT: u64 = 10;
+
+/// How long a query waits for the result of the identical query in flight,
+/// before executing the query itself.
+const MAX_IN_FLIGHT_WAIT: Duration = Duration::from_secs(5);
+
+/// The number of shards of the proposed sharded query cache.
+/// Used to report the key hash distribution across the shards.
+const QUERY_CACHE_SHARDS: usize = 16;
+
+/// A function mapping the query payloads to the payloads used in the query cache keys.
+pub type QueryCachePayloadProjection = Arc<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>;
+
+/// A predicate deciding whether the queries of the given source are cached.
+pub type QueryCacheSourceFilter = Arc<dyn Fn(PrincipalId) -> bool + Send + Sync>;
+
+/// A query result shared with the query cache, so the cache hits don't copy the reply.
+pub type SharedQueryResult = Arc<Result<WasmResult, UserError>>;
+
+/// The Candid decoding quota for the payload canonicalization,
+/// so a malicious payload can't make the decoding arbitrary expensive.
+const CANONICAL_PAYLOAD_DECODING_QUOTA: usize = 1_000_000;
+
 ////////////////////////////////////////////////////////////////////////
 /// Query Cache metrics.
 pub(crate) struct QueryCacheMetrics {
@@ -25,18 +73,37 @@ pub(crate) struct QueryCacheMetrics {
     pub misses: IntCounter,
     pub evicted_entries: IntCounter,
     pub evicted_entries_duration: Histogram,
+    pub evicted_by_method_quota: IntCounter,
+    pub evicted_entries_by_canister_quota: IntCounter,
+    pub evicted_entries_by_policy: IntCounterVec,
     pub invalidated_entries: IntCounter,
     pub invalidated_entries_by_time: IntCounter,
     pub invalidated_entries_by_max_expiry_time: IntCounter,
     pub invalidated_entries_by_data_certificate_expiry_time: IntCounter,
+    pub invalidated_entries_by_max_serve_age: IntCounter,
     pub invalidated_entries_by_canister_version: IntCounter,
+    pub invalidated_entries_by_module_hash: IntCounter,
     pub invalidated_entries_by_canister_balance: IntCounter,
     pub invalidated_entries_by_transient_error: IntCounter,
     pub invalidated_entries_duration: Histogram,
+    pub mean_entry_lifetime: Gauge,
     pub count_bytes: IntGauge,
     pub len: IntGauge,
+    pub pinned_bytes: IntGauge,
+    pub verify_on_hit_mismatches: IntCounter,
     pub push_errors: IntCounter,
     pub validation_errors: IntCounter,
+    pub compactions: IntCounter,
+    pub expired_swept: IntCounter,
+    pub circuit_open: IntGaugeVec,
+    pub coalesced: IntCounter,
+    pub bytes_served: IntCounter,
+    pub stale_on_error_served: IntCounter,
+    pub composite_query_bypasses: IntCounter,
+    pub misses_not_stored: IntCounter,
+    pub error_hits: IntCounter,
+    pub error_misses: IntCounter,
+    pub too_large_entries: IntCounter,
 }
 
 impl QueryCacheMetrics {
@@ -67,6 +134,19 @@ impl QueryCacheMetrics {
                 "The duration of evicted cache entries in seconds",
                 metrics_registry,
             ),
+            evicted_by_method_quota: metrics_registry.int_counter(
+                "execution_query_cache_evicted_by_method_quota_total",
+                "The total number of evicted entries due to the per method entries limit",
+            ),
+            evicted_entries_by_canister_quota: metrics_registry.int_counter(
+                "execution_query_cache_evicted_entries_by_canister_quota_total",
+                "The total number of evicted entries due to the per canister capacity",
+            ),
+            evicted_entries_by_policy: metrics_registry.int_counter_vec(
+                "execution_query_cache_evicted_entries_by_policy_total",
+                "The total number of entries evicted to fit the new ones, by the eviction policy",
+                &["policy"],
+            ),
             invalidated_entries: metrics_registry.int_counter(
                 "execution_query_cache_invalidated_entries_total",
                 "The total number of invalidated entries in the replica side query cache",
@@ -83,10 +163,18 @@ impl QueryCacheMetrics {
                 "execution_query_cache_invalidated_entries_by_data_certificate_expiry_time_total",
                 "The total number of invalidated entries due to the data certificate expiry time",
             ),
+            invalidated_entries_by_max_serve_age: metrics_registry.int_counter(
+                "execution_query_cache_invalidated_entries_by_max_serve_age_total",
+                "The total number of invalidated entries due to the max serve age",
+            ),
             invalidated_entries_by_canister_version: metrics_registry.int_counter(
                 "execution_query_cache_invalidated_entries_by_canister_version_total",
                 "The total number of invalidated entries due to the changed canister version",
             ),
+            invalidated_entries_by_module_hash: metrics_registry.int_counter(
+                "execution_query_cache_invalidated_entries_by_module_hash_total",
+                "The total number of invalidated entries due to the changed canister module hash",
+            ),
             invalidated_entries_by_canister_balance: metrics_registry.int_counter(
                 "execution_query_cache_invalidated_entries_by_canister_balance_total",
                 "The total number of invalidated entries due to the changed canister balance",
@@ -100,6 +188,10 @@ impl QueryCacheMetrics {
                 "The duration of invalidated cache entries in seconds",
                 metrics_registry,
             ),
+            mean_entry_lifetime: metrics_registry.gauge(
+                "execution_query_cache_mean_entry_lifetime_seconds",
+                "The mean duration of both evicted and invalidated cache entries in seconds",
+            ),
             count_bytes: metrics_registry.int_gauge(
                 "execution_query_cache_count_bytes",
                 "The current replica side query cache size in bytes",
@@ -108,6 +200,14 @@ impl QueryCacheMetrics {
                 "execution_query_cache_len",
                 "The current replica side query cache len in elements",
             ),
+            pinned_bytes: metrics_registry.int_gauge(
+                "execution_query_cache_pinned_bytes",
+                "The current size in bytes of the pinned replica side query cache entries",
+            ),
+            verify_on_hit_mismatches: metrics_registry.int_counter(
+                "execution_query_cache_verify_on_hit_mismatches_total",
+                "The total number of cache hits with results different from the re-executed query",
+            ),
             push_errors: metrics_registry.int_counter(
                 "execution_query_cache_push_errors_total",
                 "The total number of errors adding new query cache entries",
@@ -116,6 +216,81 @@ impl QueryCacheMetrics {
                 "execution_query_cache_validation_errors_total",
                 "The total number of errors validating query cache entries",
             ),
+            compactions: metrics_registry.int_counter(
+                "execution_query_cache_compactions_total",
+                "The total number of replica side query cache compactions",
+            ),
+            expired_swept: metrics_registry.int_counter(
+                "execution_query_cache_expired_swept_total",
+                "The total number of expired entries removed by the query cache sweeps",
+            ),
+            circuit_open: metrics_registry.int_gauge_vec(
+                "execution_query_cache_circuit_open",
+                "Whether the queries of the canister are not cached by the circuit breaker",
+                &["canister"],
+            ),
+            coalesced: metrics_registry.int_counter(
+                "execution_query_cache_coalesced_total",
+                "The total number of query cache misses sharing the result of an identical query in flight",
+            ),
+            bytes_served: metrics_registry.int_counter(
+                "execution_query_cache_bytes_served_total",
+                "The total size in bytes of the replies served from the query cache",
+            ),
+            stale_on_error_served: metrics_registry.int_counter(
+                "execution_query_cache_stale_on_error_served_total",
+                "The total number of stale query cache replies served instead of a canister trap",
+            ),
+            error_hits: metrics_registry.int_counter(
+                "execution_query_cache_error_hits_total",
+                "The total number of query cache hits into entries with an error result",
+            ),
+            error_misses: metrics_registry.int_counter(
+                "execution_query_cache_error_misses_total",
+                "The total number of query cache misses with a non-transient error result",
+            ),
+            too_large_entries: metrics_registry.int_counter(
+                "execution_query_cache_too_large_entries_total",
+                "The total number of query cache entries not stored, as they exceed the max entry size",
+            ),
+            misses_not_stored: metrics_registry.int_counter(
+                "execution_query_cache_misses_not_stored_total",
+                "The total number of cache misses not stored, as the key was not requested enough times",
+            ),
+            composite_query_bypasses: metrics_registry.int_counter(
+                "execution_query_cache_composite_query_bypasses_total",
+                "The total number of composite queries executed bypassing the query cache",
+            ),
+        }
+    }
+
+    /// Update the mean entry lifetime from the evicted and invalidated entries durations.
+    fn update_mean_entry_lifetime(&self) {
+        let count = self.evicted_entries_duration.get_sample_count()
+            + self.invalidated_entries_duration.get_sample_count();
+        if count > 0 {
+            let sum = self.evicted_entries_duration.get_sample_sum()
+                + self.invalidated_entries_duration.get_sample_sum();
+            self.mean_entry_lifetime.set(sum / count as f64);
+        }
+    }
+
+    /// Account for the `evicted` entries removed from the cache at the `now` time,
+    /// recording their durations and updating the mean entry lifetime.
+    fn observe_evicted_entries<'a>(
+        &self,
+        evicted: impl IntoIterator<Item = &'a EntryValue>,
+        now: Time,
+    ) {
+        let mut count = 0;
+        for value in evicted {
+            self.evicted_entries_duration
+                .observe(value.elapsed_seconds(now));
+            count += 1;
+        }
+        if count > 0 {
+            self.evicted_entries.inc_by(count);
+            self.update_mean_entry_lifetime();
         }
     }
 }
@@ -135,25 +310,53 @@ pub(crate) struct EntryKey {
     pub method_name: String,
     /// Receiving canister method payload (argument).
     pub method_payload: Vec<u8>,
+    /// Optional caller-supplied context, i.e. the identity of the boundary node
+    /// forwarding the query, if the reply depends on it.
+    pub cache_context: Option<Vec<u8>>,
 }
 
 impl CountBytes for EntryKey {
     fn count_bytes(&self) -> usize {
-        size_of_val(self) + self.method_name.len() + self.method_payload.len()
+        size_of_val(self)
+            + self.method_name.len()
+            + self.method_payload.len()
+            + self
+                .cache_context
+                .as_ref()
+                .map_or(0, |context| context.len())
     }
 }
 
-impl From<&UserQuery> for EntryKey {
-    fn from(query: &UserQuery) -> Self {
+impl EntryKey {
+    /// Create a new key for the `query` with an optional `cache_context`.
+    pub(crate) fn new(query: &UserQuery, cache_context: Option<Vec<u8>>) -> Self {
         Self {
             source: query.source,
             receiver: query.receiver,
             method_name: query.method_name.clone(),
             method_payload: query.method_payload.clone(),
+            cache_context,
         }
     }
 }
 
+/// Return the `payload` re-encoded as canonical Candid,
+/// or `None` if the payload is not a valid Candid.
+fn canonical_candid_payload(payload: &[u8]) -> Option<Vec<u8>> {
+    let mut config = DecoderConfig::new();
+    config.set_decoding_quota(CANONICAL_PAYLOAD_DECODING_QUOTA);
+    config.set_skipping_quota(CANONICAL_PAYLOAD_DECODING_QUOTA);
+    IDLArgs::from_bytes_with_config(payload, &config)
+        .and_then(|args| args.to_bytes())
+        .ok()
+}
+
+impl From<&UserQuery> for EntryKey {
+    fn from(query: &UserQuery) -> Self {
+        Self::new(query, None)
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////
 /// Query Cache entry environment metadata captured before the query execution.
 ///
@@ -165,17 +368,31 @@ pub(crate) struct EntryEnv {
     pub batch_time: Time,
     /// A vector of evaluated canister IDs with their versions, balances and stats.
     pub canisters_versions_balances_stats: Vec<(CanisterId, u64, Cycles, QueryStats)>,
+    /// The module hashes of the evaluated canisters with the code installed.
+    pub module_hashes: BTreeMap<CanisterId, [u8; 32]>,
+}
+
+/// Return the module hash of the `canister` code, if there is any code installed.
+fn module_hash(canister: &CanisterState) -> Option<[u8; 32]> {
+    canister
+        .execution_state
+        .as_ref()
+        .map(|execution_state| execution_state.wasm_binary.binary.module_hash())
 }
 
 impl EntryEnv {
-    // Capture a state (canister version and balance) of the evaluated canisters.
+    // Capture a state (canister version, balance and module hash) of the evaluated canisters.
     fn try_new(
         state: &ReplicatedState,
         evaluated_stats: &BTreeMap<CanisterId, QueryStats>,
     ) -> Result<Self, UserError> {
         let mut canisters_versions_balances_stats = Vec::with_capacity(evaluated_stats.len());
+        let mut module_hashes = BTreeMap::new();
         for (id, stats) in evaluated_stats.iter() {
             let canister = state.get_active_canister(id)?;
+            if let Some(module_hash) = module_hash(canister) {
+                module_hashes.insert(*id, module_hash);
+            }
             canisters_versions_balances_stats.push((
                 *id,
                 canister.system_state.canister_version,
@@ -186,8 +403,43 @@ impl EntryEnv {
         Ok(EntryEnv {
             batch_time: state.metadata.batch_time,
             canisters_versions_balances_stats,
+            module_hashes,
         })
     }
+
+    /// Check if the code and the state of the `canister` are unchanged,
+    /// i.e. neither its module hash nor its version changed.
+    fn is_code_unchanged(&self, id: &CanisterId, version: u64, canister: &CanisterState) -> bool {
+        self.is_module_hash_unchanged(id, canister) && self.is_version_unchanged(version, canister)
+    }
+
+    /// Check if the module hash of the `canister` is unchanged.
+    ///
+    /// It's checked regardless of the canister version, which alone can't tell
+    /// the replies of a reinstalled module apart.
+    fn is_module_hash_unchanged(&self, id: &CanisterId, canister: &CanisterState) -> bool {
+        module_hash(canister).as_ref() == self.module_hashes.get(id)
+    }
+
+    /// Check if the `canister` version is unchanged.
+    ///
+    /// The version is bumped by every executed update message, so it's the only
+    /// indication of the canister state changes. It's also bumped by every code
+    /// install, upgrade and reinstall, so the entries can't skip this check
+    /// even when only the module hash is of interest.
+    fn is_version_unchanged(&self, version: u64, canister: &CanisterState) -> bool {
+        canister.system_state.canister_version == version
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+/// The number of misses of a key not yet stored in the query cache.
+struct MissCount(usize);
+
+impl CountBytes for MissCount {
+    fn count_bytes(&self) -> usize {
+        size_of_val(self)
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////
@@ -195,14 +447,18 @@ impl EntryEnv {
 pub(crate) struct EntryValue {
     /// Query Cache entry environment metadata captured before the query execution.
     env: EntryEnv,
-    /// The result produced by the query.
-    result: Result<WasmResult, UserError>,
+    /// The result produced by the query, shared with the cache hits.
+    result: SharedQueryResult,
     /// If set, the cached entry should be expired after `data_certificate_expiry_time`.
     includes_data_certificate: bool,
     /// If set, the batch time changes might be ignored.
     ignore_batch_time: bool,
     /// If set, the canister balance changes might be ignored.
     ignore_canister_balances: bool,
+    /// The query cache clock time when the entry was inserted.
+    inserted_at: Time,
+    /// The number of hits into the entry, to evict the least frequently used entries.
+    hits: AtomicU64,
 }
 
 impl CountBytes for EntryValue {
@@ -214,8 +470,9 @@ impl CountBytes for EntryValue {
 impl EntryValue {
     pub(crate) fn new(
         env: EntryEnv,
-        result: Result<WasmResult, UserError>,
+        result: SharedQueryResult,
         system_api_call_counters: &SystemApiCallCounters,
+        inserted_at: Time,
     ) -> EntryValue {
         // The cached entry should be expired after `data_certificate_expiry_time`.
         let includes_data_certificate = system_api_call_counters.data_certificate_copy > 0;
@@ -230,9 +487,21 @@ impl EntryValue {
             includes_data_certificate,
             ignore_batch_time,
             ignore_canister_balances,
+            inserted_at,
+            hits: AtomicU64::new(0),
         }
     }
 
+    /// Record a hit into the entry.
+    fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Return the number of hits into the entry.
+    fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
     fn is_valid(
         &self,
         state: &ReplicatedState,
@@ -240,9 +509,12 @@ impl EntryValue {
         metrics: &QueryCacheMetrics,
         max_expiry_time: Duration,
         data_certificate_expiry_time: Duration,
+        served_at: Time,
+        max_serve_age: Option<Duration>,
     ) -> bool {
         // Iterate over the captured data and validate it against the current state.
         let mut all_canister_versions_are_valid = true;
+        let mut all_module_hashes_are_valid = true;
         let mut all_canister_balances_are_valid = true;
         let mut canisters_stats =
             Vec::with_capacity(self.env.canisters_versions_balances_stats.len());
@@ -253,9 +525,12 @@ impl EntryValue {
             };
             canisters_stats.push((id, stats));
 
-            if &canister.system_state.canister_version != version {
+            if !self.env.is_version_unchanged(*version, canister) {
                 all_canister_versions_are_valid = false;
             }
+            if !self.env.is_module_hash_unchanged(id, canister) {
+                all_module_hashes_are_valid = false;
+            }
             if &canister.system_state.balance() != balance {
                 all_canister_balances_are_valid = false;
             }
@@ -266,16 +541,24 @@ impl EntryValue {
         let is_expired = self.is_expired(now, max_expiry_time);
         let is_expired_data_certificate =
             self.is_expired_data_certificate(now, data_certificate_expiry_time);
+        let is_past_max_serve_age = self.is_past_max_serve_age(served_at, max_serve_age);
 
         // Check if the cache entry value is valid.
         if !is_expired
             && !is_expired_data_certificate
+            && !is_past_max_serve_age
             && (self.env.batch_time == now || self.ignore_batch_time)
             && all_canister_versions_are_valid
+            && all_module_hashes_are_valid
             && (all_canister_balances_are_valid || self.ignore_canister_balances)
         {
             // The value is still valid.
             metrics.hits.inc();
+            match &*self.result {
+                Ok(WasmResult::Reply(reply)) => metrics.bytes_served.inc_by(reply.len() as u64),
+                Ok(WasmResult::Reject(_)) => {}
+                Err(_) => metrics.error_hits.inc(),
+            }
             // Apply query stats.
             for (id, stats) in canisters_stats {
                 // Add query statistics to the query aggregator.
@@ -298,6 +581,7 @@ impl EntryValue {
             metrics
                 .invalidated_entries_duration
                 .observe(self.elapsed_seconds(now));
+            metrics.update_mean_entry_lifetime();
             // To ensure all invalidation reasons are accounted for,
             // even if multiple occur simultaneously, we need a fallthrough logic here.
             if is_expired {
@@ -308,12 +592,18 @@ impl EntryValue {
                     .invalidated_entries_by_data_certificate_expiry_time
                     .inc();
             }
+            if is_past_max_serve_age {
+                metrics.invalidated_entries_by_max_serve_age.inc();
+            }
             if !(self.env.batch_time == now || self.ignore_batch_time) {
                 metrics.invalidated_entries_by_time.inc();
             }
             if !all_canister_versions_are_valid {
                 metrics.invalidated_entries_by_canister_version.inc();
             }
+            if !all_module_hashes_are_valid {
+                metrics.invalidated_entries_by_module_hash.inc();
+            }
             if !(all_canister_balances_are_valid || self.ignore_canister_balances) {
                 metrics.invalidated_entries_by_canister_balance.inc();
             }
@@ -321,6 +611,33 @@ impl EntryValue {
         }
     }
 
+    /// Check if the value is still valid for the current `state`, just like `is_valid()`,
+    /// but without updating the metrics or registering the query stats.
+    ///
+    /// Used to revalidate the entries imported from a snapshot
+    /// and the results shared by the identical queries in flight.
+    fn is_valid_without_metrics(
+        &self,
+        state: &ReplicatedState,
+        max_expiry_time: Duration,
+        data_certificate_expiry_time: Duration,
+    ) -> bool {
+        let now = state.metadata.batch_time;
+        let all_canisters_are_valid = self.env.canisters_versions_balances_stats.iter().all(
+            |(id, version, balance, _stats)| {
+                state.get_active_canister(id).map_or(false, |canister| {
+                    self.env.is_code_unchanged(id, *version, canister)
+                        && (&canister.system_state.balance() == balance
+                            || self.ignore_canister_balances)
+                })
+            },
+        );
+        all_canisters_are_valid
+            && !self.is_expired(now, max_expiry_time)
+            && !self.is_expired_data_certificate(now, data_certificate_expiry_time)
+            && (self.env.batch_time == now || self.ignore_batch_time)
+    }
+
     /// Check cache entry max expiration time.
     fn is_expired(&self, now: Time, max_expiry_time: Duration) -> bool {
         if let Some(duration) = now.checked_duration_since(self.env.batch_time) {
@@ -351,82 +668,1044 @@ impl EntryValue {
         false
     }
 
+    /// Check if the entry is older than the `max_serve_age` at the `served_at`
+    /// query cache clock time, regardless of the environment changes.
+    fn is_past_max_serve_age(&self, served_at: Time, max_serve_age: Option<Duration>) -> bool {
+        max_serve_age.map_or(false, |max_serve_age| {
+            served_at.saturating_duration_since(self.inserted_at) > max_serve_age
+        })
+    }
+
     fn elapsed_seconds(&self, now: Time) -> f64 {
         now.saturating_duration_since(self.env.batch_time)
             .as_secs_f64()
     }
 }
 
+////////////////////////////////////////////////////////////////////////
+/// Query Cache snapshot entry, i.e. the serializable cache entry key and value.
+#[derive(Deserialize, Serialize)]
+struct SnapshotEntry {
+    source: UserId,
+    receiver: CanisterId,
+    method_name: String,
+    method_payload: Vec<u8>,
+    cache_context: Option<Vec<u8>>,
+    batch_time: Time,
+    /// The evaluated canister IDs with their versions, balances and stats.
+    /// The stats are stored as `(num_calls, num_instructions, ingress_payload_size,
+    /// egress_payload_size)`, as `QueryStats` are not serializable.
+    canisters: Vec<(CanisterId, u64, Cycles, (u32, u64, u64, u64))>,
+    /// The module hashes of the evaluated canisters with the code installed.
+    #[serde(default)]
+    module_hashes: BTreeMap<CanisterId, [u8; 32]>,
+    result: Result<WasmResult, UserError>,
+    includes_data_certificate: bool,
+    ignore_batch_time: bool,
+    ignore_canister_balances: bool,
+    /// The query cache clock time when the entry was inserted,
+    /// missing in the older snapshots.
+    #[serde(default)]
+    inserted_at: Option<Time>,
+}
+
+impl From<(&EntryKey, &EntryValue)> for SnapshotEntry {
+    fn from((key, value): (&EntryKey, &EntryValue)) -> Self {
+        Self {
+            source: key.source,
+            receiver: key.receiver,
+            method_name: key.method_name.clone(),
+            method_payload: key.method_payload.clone(),
+            cache_context: key.cache_context.clone(),
+            batch_time: value.env.batch_time,
+            canisters: value
+                .env
+                .canisters_versions_balances_stats
+                .iter()
+                .map(|(id, version, balance, stats)| {
+                    (
+                        *id,
+                        *version,
+                        *balance,
+                        (
+                            stats.num_calls,
+                            stats.num_instructions,
+                            stats.ingress_payload_size,
+                            stats.egress_payload_size,
+                        ),
+                    )
+                })
+                .collect(),
+            module_hashes: value.env.module_hashes.clone(),
+            result: (*value.result).clone(),
+            includes_data_certificate: value.includes_data_certificate,
+            ignore_batch_time: value.ignore_batch_time,
+            ignore_canister_balances: value.ignore_canister_balances,
+            inserted_at: Some(value.inserted_at),
+        }
+    }
+}
+
+impl From<SnapshotEntry> for (EntryKey, EntryValue) {
+    fn from(entry: SnapshotEntry) -> Self {
+        let key = EntryKey {
+            source: entry.source,
+            receiver: entry.receiver,
+            method_name: entry.method_name,
+            method_payload: entry.method_payload,
+            cache_context: entry.cache_context,
+        };
+        let inserted_at = entry.inserted_at.unwrap_or(entry.batch_time);
+        let env = EntryEnv {
+            batch_time: entry.batch_time,
+            canisters_versions_balances_stats: entry
+                .canisters
+                .into_iter()
+                .map(|(id, version, balance, stats)| {
+                    let (num_calls, num_instructions, ingress_payload_size, egress_payload_size) =
+                        stats;
+                    (
+                        id,
+                        version,
+                        balance,
+                        QueryStats {
+                            num_calls,
+                            num_instructions,
+                            ingress_payload_size,
+                            egress_payload_size,
+                        },
+                    )
+                })
+                .collect(),
+            module_hashes: entry.module_hashes,
+        };
+        let value = EntryValue {
+            env,
+            result: Arc::new(entry.result),
+            includes_data_certificate: entry.includes_data_certificate,
+            ignore_batch_time: entry.ignore_batch_time,
+            ignore_canister_balances: entry.ignore_canister_balances,
+            inserted_at,
+            hits: AtomicU64::new(0),
+        };
+        (key, value)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+/// Snapshot of the effective query cache configuration.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QueryCacheConfig {
+    /// Whether replica side query caching is enabled.
+    pub enabled: bool,
+    /// The upper limit on the total size of the cache entries.
+    pub capacity: NumBytes,
+    /// The upper limit on the total size of the pinned entries.
+    pub max_pinned_bytes: NumBytes,
+    /// The upper limit on the total size of the stale entries.
+    pub max_stale_bytes: NumBytes,
+    /// The upper limit on how long the cache entry stays valid in the query cache.
+    pub max_expiry_time: Duration,
+    /// The upper limit on how long the data certificate stays valid in the query cache.
+    pub data_certificate_expiry_time: Duration,
+    /// Whether the cache hits are re-executed and verified.
+    pub verify_on_hit: bool,
+    /// The upper limit on the number of entries of a single canister method.
+    pub max_entries_per_method: Option<usize>,
+    /// Whether the Candid payloads are canonicalized before keying the cache.
+    pub canonical_payload: bool,
+    /// Whether the payloads are projected before keying the cache.
+    pub payload_projection: bool,
+    /// Whether only the queries of some sources are cached.
+    pub source_filter: bool,
+    /// The number of consecutive invalidations suspending the caching of a canister, if any.
+    pub circuit_breaker_threshold: Option<usize>,
+    /// How long the caching of a canister is suspended by the circuit breaker.
+    pub circuit_breaker_cooldown: Duration,
+    /// How long the last successful reply is served instead of a canister trap, if at all.
+    pub stale_on_error_window: Option<Duration>,
+    /// The upper limit on the age of the served cache entries, if any.
+    pub max_serve_age: Option<Duration>,
+    /// The number of identical misses before the result is stored.
+    pub min_requests: usize,
+    /// The policy choosing the entries to evict once the cache is full.
+    pub eviction_policy: EvictionPolicy,
+    /// The upper limit on the total size of the entries of a single canister, if any.
+    pub per_canister_capacity: Option<NumBytes>,
+    /// Whether the non-transient error results are cached.
+    pub cache_errors: bool,
+    /// The upper limit on the size of a single cache entry, if any.
+    pub max_entry_size: Option<NumBytes>,
+}
+
+////////////////////////////////////////////////////////////////////////
+/// Out-of-band metadata on how the query cache served a query.
+///
+/// It's reported alongside the reply, so the reply bytes are unchanged.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueryCacheMetadata {
+    /// The age of the served cache entry in seconds, if the reply was served from the cache.
+    pub served_entry_age_seconds: Option<f64>,
+}
+
+////////////////////////////////////////////////////////////////////////
+/// Query cache circuit breaker state of a single canister.
+#[derive(Default)]
+struct CircuitState {
+    /// The number of consecutive lookups finding an invalidated entry.
+    consecutive_invalidations: usize,
+    /// If set, the queries of the canister are not cached until this time.
+    open_until: Option<Time>,
+}
+
+////////////////////////////////////////////////////////////////////////
+/// The state of a query execution in flight.
+enum InFlightState {
+    /// The query is being executed.
+    Running,
+    /// The query is executed, and its result might be shared, if it's valid
+    /// for the state of the waiting query.
+    Done(EntryValue),
+    /// The query execution was abandoned, so its result must not be shared.
+    Abandoned,
+}
+
+/// A query execution in flight, awaited by the concurrent identical queries.
+struct InFlight {
+    state: Mutex<InFlightState>,
+    changed: Condvar,
+}
+
+/// The outcome of joining the identical query in flight.
+pub(crate) enum InFlightJoin<'a> {
+    /// The query must be executed. If there is a guard, there was no identical
+    /// query in flight, so the result must be shared using the guard.
+    Execute(Option<InFlightGuard<'a>>),
+    /// The result of the identical query in flight, valid for the state of the query.
+    Coalesced(SharedQueryResult),
+}
+
+/// The guard of a query execution in flight.
+///
+/// Once dropped, the waiting identical queries get the result shared with `finish()`,
+/// or execute the query themselves, if there is no result to share.
+pub(crate) struct InFlightGuard<'a> {
+    cache: &'a QueryCache,
+    key: EntryKey,
+    in_flight: Arc<InFlight>,
+}
+
+impl InFlightGuard<'_> {
+    /// Share the `result` with the waiting identical queries, along with the environment
+    /// of the evaluated canisters, so the result is validated against the state of each
+    /// waiting query, just like a cache entry.
+    ///
+    /// The result is not shared if the environment can't be captured.
+    pub(crate) fn finish(
+        self,
+        result: &SharedQueryResult,
+        state: &ReplicatedState,
+        system_api_call_counters: &SystemApiCallCounters,
+        evaluated_stats: &BTreeMap<CanisterId, QueryStats>,
+    ) {
+        let Ok(env) = EntryEnv::try_new(state, evaluated_stats) else {
+            return;
+        };
+        let value = EntryValue::new(
+            env,
+            Arc::clone(result),
+            system_api_call_counters,
+            self.cache.time_source.get_relative_time(),
+        );
+        *self.in_flight.state.lock().unwrap() = InFlightState::Done(value);
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.cache.in_flight.lock().unwrap().remove(&self.key);
+        let mut state = self.in_flight.state.lock().unwrap();
+        if let InFlightState::Running = *state {
+            *state = InFlightState::Abandoned;
+        }
+        self.in_flight.changed.notify_all();
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+/// Query cache entries and their size falling into a single shard.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ShardStat {
+    /// The number of entries in the shard, including the pinned ones.
+    pub entries: usize,
+    /// The total size of the entries in the shard.
+    pub bytes: NumBytes,
+}
+
+/// Return the shard of the `key`, as it would be in the sharded query cache.
+fn shard_of(key: &EntryKey) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % QUERY_CACHE_SHARDS as u64) as usize
+}
+
+////////////////////////////////////////////////////////////////////////
+/// The LRU cache of the query cache entries, indexed by the canister.
+struct CacheEntries {
+    lru: LruCache<EntryKey, EntryValue>,
+    /// The total size of the entries of each receiver canister.
+    canister_bytes: HashMap<CanisterId, usize>,
+}
+
+impl CountBytes for CacheEntries {
+    fn count_bytes(&self) -> usize {
+        self.lru.count_bytes()
+    }
+}
+
+impl CacheEntries {
+    fn new(capacity: NumBytes) -> Self {
+        CacheEntries {
+            lru: LruCache::new(capacity),
+            canister_bytes: HashMap::new(),
+        }
+    }
+
+    fn with_expected_len(capacity: NumBytes, expected_len: usize) -> Self {
+        CacheEntries {
+            lru: LruCache::with_expected_len(capacity, expected_len),
+            canister_bytes: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &EntryKey) -> Option<&EntryValue> {
+        self.lru.get(key)
+    }
+
+    fn peek(&self, key: &EntryKey) -> Option<&EntryValue> {
+        self.lru.peek(key)
+    }
+
+    /// Push the entry, returning the evicted entries just like `LruCache::push()`.
+    fn push(&mut self, key: EntryKey, value: EntryValue) -> Vec<(EntryKey, EntryValue)> {
+        *self.canister_bytes.entry(key.receiver).or_default() +=
+            key.count_bytes() + value.count_bytes();
+        let evicted_entries = self.lru.push(key, value);
+        self.forget(&evicted_entries);
+        evicted_entries
+    }
+
+    fn pop(&mut self, key: &EntryKey) -> Option<EntryValue> {
+        let value = self.lru.pop(key)?;
+        self.forget_entry(key, &value);
+        Some(value)
+    }
+
+    fn trim_to(&mut self, target_bytes: NumBytes) -> Vec<(EntryKey, EntryValue)> {
+        let evicted_entries = self.lru.trim_to(target_bytes);
+        self.forget(&evicted_entries);
+        evicted_entries
+    }
+
+    fn iter_lru(&self) -> impl Iterator<Item = (&EntryKey, &EntryValue)> {
+        self.lru.iter_lru()
+    }
+
+    fn len(&self) -> usize {
+        self.lru.len()
+    }
+
+    fn overhead_bytes(&self) -> usize {
+        self.lru.overhead_bytes()
+    }
+
+    fn shrink_to_fit(&mut self) -> usize {
+        self.canister_bytes.shrink_to_fit();
+        self.lru.shrink_to_fit()
+    }
+
+    /// Return the total size of the entries of the `receiver` canister.
+    fn canister_bytes(&self, receiver: &CanisterId) -> usize {
+        self.canister_bytes
+            .get(receiver)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Check that the LRU cache passes its own audit and the index matches the entries.
+    fn audit(&self) -> Result<(), String> {
+        self.lru.audit()?;
+        let mut canister_bytes: HashMap<CanisterId, usize> = HashMap::new();
+        for (key, value) in self.lru.iter_lru() {
+            *canister_bytes.entry(key.receiver).or_default() +=
+                key.count_bytes() + value.count_bytes();
+        }
+        if canister_bytes != self.canister_bytes {
+            return Err(format!(
+                "per canister sizes {:?} do not match the indexed ones {:?}",
+                canister_bytes, self.canister_bytes
+            ));
+        }
+        Ok(())
+    }
+
+    fn forget(&mut self, entries: &[(EntryKey, EntryValue)]) {
+        for (key, value) in entries {
+            self.forget_entry(key, value);
+        }
+    }
+
+    /// Subtract the removed entry from the index, dropping the canisters without entries.
+    fn forget_entry(&mut self, key: &EntryKey, value: &EntryValue) {
+        if let Some(bytes) = self.canister_bytes.get_mut(&key.receiver) {
+            *bytes = bytes.saturating_sub(key.count_bytes() + value.count_bytes());
+            if *bytes == 0 {
+                self.canister_bytes.remove(&key.receiver);
+            }
+        }
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////
 /// Replica Side Query Cache.
 pub(crate) struct QueryCache {
     // We can't use `RwLock`, as the `LruCache::get()` requires mutable reference
     // to update the LRU.
-    cache: Mutex<LruCache<EntryKey, EntryValue>>,
+    cache: Mutex<CacheEntries>,
+    /// Whether replica side query caching is enabled.
+    enabled: bool,
+    /// The upper limit on the total size of the cache entries.
+    capacity: NumBytes,
+    /// Pinned entries, which are exempt from the LRU eviction, but still
+    /// invalidated as usual when the environment changes.
+    pinned: Mutex<HashMap<EntryKey, EntryValue>>,
+    /// The upper limit on the total size of the pinned entries.
+    max_pinned_bytes: usize,
     /// The upper limit on how long the cache entry stays valid in the query cache.
     max_expiry_time: Duration,
     /// The upper limit on how long the data certificate stays valid in the query cache.
     data_certificate_expiry_time: Duration,
+    /// Whether the cache hits are re-executed and verified.
+    verify_on_hit: bool,
+    /// The upper limit on the number of entries of a single canister method.
+    max_entries_per_method: Option<usize>,
+    /// Whether the Candid payloads are canonicalized before keying the cache.
+    canonical_payload: bool,
+    /// Optional projection of the payloads before keying the cache.
+    payload_projection: Option<QueryCachePayloadProjection>,
+    /// Optional predicate deciding whether the queries of a source are cached.
+    source_filter: Option<QueryCacheSourceFilter>,
+    /// The number of consecutive invalidations suspending the caching of a canister, if any.
+    circuit_breaker_threshold: Option<usize>,
+    /// How long the caching of a canister is suspended by the circuit breaker.
+    circuit_breaker_cooldown: Duration,
+    /// The circuit breaker states of the canisters with recently invalidated entries.
+    circuits: Mutex<HashMap<CanisterId, CircuitState>>,
+    /// The query executions in flight, to coalesce the identical cache misses.
+    in_flight: Mutex<HashMap<EntryKey, Arc<InFlight>>>,
+    /// How long the last successful reply is served instead of a canister trap, if at all.
+    stale_on_error_window: Option<Duration>,
+    /// The last successful replies of the invalidated entries, served if the query traps.
+    stale: Mutex<StaleEntries>,
+    /// The upper limit on the total size of the stale entries.
+    max_stale_bytes: usize,
+    /// The upper limit on the age of the served cache entries, if any.
+    max_serve_age: Option<Duration>,
+    /// The clock measuring the age of the cache entries.
+    time_source: Arc<dyn TimeSource>,
+    /// The number of identical misses before the result is stored.
+    min_requests: usize,
+    /// The misses of the keys requested fewer than `min_requests` times so far.
+    tracked_misses: Mutex<LruCache<EntryKey, MissCount>>,
+    /// The policy choosing the entries to evict once the cache is full.
+    eviction_policy: EvictionPolicy,
+    /// The upper limit on the total size of the entries of a single canister, if any.
+    per_canister_capacity: Option<NumBytes>,
+    /// Whether the non-transient error results are cached.
+    cache_errors: bool,
+    /// The upper limit on the size of a single cache entry, if any.
+    max_entry_size: Option<NumBytes>,
     /// Query cache metrics (public for tests)
     pub(crate) metrics: QueryCacheMetrics,
 }
 
 impl CountBytes for QueryCache {
     fn count_bytes(&self) -> usize {
-        size_of_val(self) + self.cache.lock().unwrap().count_bytes()
+        size_of_val(self) + self.entries_count_bytes() + self.overhead_bytes()
+    }
+}
+
+/// Return the estimated size of the map slots left over from the removed entries.
+fn map_overhead_bytes(map: &HashMap<EntryKey, EntryValue>) -> usize {
+    (map.capacity() - map.len()) * size_of::<(EntryKey, EntryValue)>()
+}
+
+/// Return the total size of the map entries.
+fn map_count_bytes(map: &HashMap<EntryKey, EntryValue>) -> usize {
+    map.iter()
+        .map(|(key, value)| key.count_bytes() + value.count_bytes())
+        .sum()
+}
+
+/// The stale entries along with their running total size,
+/// so the size is not recomputed on every insertion.
+#[derive(Default)]
+struct StaleEntries {
+    entries: HashMap<EntryKey, EntryValue>,
+    bytes: usize,
+}
+
+impl StaleEntries {
+    fn get(&self, key: &EntryKey) -> Option<&EntryValue> {
+        self.entries.get(key)
+    }
+
+    fn insert(&mut self, key: EntryKey, value: EntryValue) {
+        self.remove(&key);
+        self.bytes += key.count_bytes() + value.count_bytes();
+        self.entries.insert(key, value);
+    }
+
+    fn remove(&mut self, key: &EntryKey) -> Option<EntryValue> {
+        let (key, value) = self.entries.remove_entry(key)?;
+        self.bytes -= key.count_bytes() + value.count_bytes();
+        Some(value)
+    }
+
+    /// Keep only the entries the predicate `f` returns `true` for.
+    fn retain(&mut self, mut f: impl FnMut(&EntryValue) -> bool) {
+        let bytes = &mut self.bytes;
+        self.entries.retain(|key, value| {
+            let keep = f(value);
+            if !keep {
+                *bytes -= key.count_bytes() + value.count_bytes();
+            }
+            keep
+        });
+    }
+
+    fn overhead_bytes(&self) -> usize {
+        map_overhead_bytes(&self.entries)
+    }
+}
+
+/// Evict the least recently used entries of the `receiver` canister `method_name`,
+/// so the method has at most `max_entries` entries in the `cache`.
+///
+/// Returns the evicted entries.
+fn evict_method_quota(
+    cache: &mut CacheEntries,
+    receiver: CanisterId,
+    method_name: &str,
+    max_entries: usize,
+) -> Vec<(EntryKey, EntryValue)> {
+    let is_method = |key: &EntryKey| key.receiver == receiver && key.method_name == method_name;
+    let method_entries = cache.iter_lru().filter(|(key, _)| is_method(key)).count();
+    let excess = method_entries.saturating_sub(max_entries);
+    if excess == 0 {
+        return vec![];
+    }
+    let excess_keys: Vec<EntryKey> = cache
+        .iter_lru()
+        .filter(|(key, _)| is_method(key))
+        .take(excess)
+        .map(|(key, _)| key.clone())
+        .collect();
+    excess_keys
+        .into_iter()
+        .filter_map(|key| cache.pop(&key).map(|value| (key, value)))
+        .collect()
+}
+
+/// Evict the least recently used entries of the `receiver` canister,
+/// so its entries take at most `capacity` bytes of the `cache`.
+///
+/// Returns the evicted entries.
+fn evict_canister_quota(
+    cache: &mut CacheEntries,
+    receiver: CanisterId,
+    capacity: usize,
+) -> Vec<(EntryKey, EntryValue)> {
+    let excess = cache.canister_bytes(&receiver).saturating_sub(capacity);
+    if excess == 0 {
+        return vec![];
+    }
+    let mut excess_keys_bytes = 0;
+    let excess_keys: Vec<EntryKey> = cache
+        .iter_lru()
+        .filter(|(key, _)| key.receiver == receiver)
+        .take_while(|(key, value)| {
+            let is_excess = excess_keys_bytes < excess;
+            excess_keys_bytes += key.count_bytes() + value.count_bytes();
+            is_excess
+        })
+        .map(|(key, _)| key.clone())
+        .collect();
+    excess_keys
+        .into_iter()
+        .filter_map(|key| cache.pop(&key).map(|value| (key, value)))
+        .collect()
+}
+
+/// Evict the least frequently used entries, so the `cache` fits into the `target_bytes`.
+/// Among the entries with the same number of hits, the least recently used is evicted first.
+///
+/// Each eviction scans all the entries, which is fine for the caches of thousands of entries.
+/// Returns the evicted entries.
+fn evict_lfu_to(cache: &mut CacheEntries, target_bytes: usize) -> Vec<(EntryKey, EntryValue)> {
+    let mut evicted_entries = vec![];
+    while cache.count_bytes() > target_bytes {
+        // The first minimum is the least recently used one.
+        let Some(key) = cache
+            .iter_lru()
+            .min_by_key(|(_, value)| value.hits())
+            .map(|(key, _)| key.clone())
+        else {
+            break;
+        };
+        if let Some(value) = cache.pop(&key) {
+            evicted_entries.push((key, value));
+        }
     }
+    evicted_entries
 }
 
 impl QueryCache {
     /// Create a new `QueryCache` instance.
     pub(crate) fn new(
         metrics_registry: &MetricsRegistry,
+        enabled: bool,
         capacity: NumBytes,
         max_expiry_time: Duration,
         data_certificate_expiry_time: Duration,
+        verify_on_hit: bool,
+        max_entries_per_method: Option<usize>,
+        canonical_payload: bool,
+        circuit_breaker_threshold: Option<usize>,
+        circuit_breaker_cooldown: Duration,
+        stale_on_error_window: Option<Duration>,
     ) -> Self {
         QueryCache {
-            cache: Mutex::new(LruCache::new(capacity)),
+            enabled,
+            capacity,
+            cache: Mutex::new(CacheEntries::new(capacity)),
+            pinned: Mutex::new(HashMap::new()),
+            max_pinned_bytes: (capacity.get() / 100 * MAX_PINNED_BYTES_PERCENT) as usize,
             max_expiry_time,
             data_certificate_expiry_time,
+            verify_on_hit,
+            max_entries_per_method,
+            canonical_payload,
+            payload_projection: None,
+            source_filter: None,
+            circuit_breaker_threshold,
+            circuit_breaker_cooldown,
+            circuits: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+            stale_on_error_window,
+            stale: Mutex::new(StaleEntries::default()),
+            max_stale_bytes: (capacity.get() / 100 * MAX_STALE_BYTES_PERCENT) as usize,
+            max_serve_age: None,
+            time_source: Arc::new(SysTimeSource::new()),
+            min_requests: 1,
+            tracked_misses: Mutex::new(LruCache::new(NumBytes::new(
+                capacity.get() / 100 * MAX_TRACKED_MISSES_BYTES_PERCENT,
+            ))),
+            eviction_policy: EvictionPolicy::Lru,
+            per_canister_capacity: None,
+            cache_errors: true,
+            max_entry_size: None,
             metrics: QueryCacheMetrics::new(metrics_registry),
         }
     }
 
+    /// Create a new key for the `query` with an optional `cache_context`.
+    ///
+    /// If the payload canonicalization is enabled, valid Candid payloads
+    /// are keyed by their canonical encoding. If there is a payload projection,
+    /// it's applied last.
+    pub(crate) fn new_key(&self, query: &UserQuery, cache_context: Option<Vec<u8>>) -> EntryKey {
+        let mut key = EntryKey::new(query, cache_context);
+        if self.canonical_payload {
+            if let Some(payload) = canonical_candid_payload(&key.method_payload) {
+                key.method_payload = payload;
+            }
+        }
+        if let Some(projection) = &self.payload_projection {
+            key.method_payload = projection(&key.method_payload);
+        }
+        key
+    }
+
+    /// Set the projection of the payloads before keying the cache.
+    pub(crate) fn set_payload_projection(&mut self, projection: QueryCachePayloadProjection) {
+        self.payload_projection = Some(projection);
+    }
+
+    /// Pre-allocate the cache for the `expected_entries`, so it's not resized
+    /// until it holds as many entries. Any existing entries are dropped.
+    pub(crate) fn set_expected_entries(&mut self, expected_entries: usize) {
+        *self.cache.get_mut().unwrap() =
+            CacheEntries::with_expected_len(self.capacity, expected_entries);
+    }
+
+    /// Set the upper limit on the age of the served cache entries.
+    ///
+    /// Unlike the max expiry time, the age is measured by the query cache clock,
+    /// and the older entries are not served even if the environment is unchanged.
+    pub(crate) fn set_max_serve_age(&mut self, max_serve_age: Duration) {
+        self.max_serve_age = Some(max_serve_age);
+    }
+
+    /// Store the results only once their keys missed `min_requests` times,
+    /// so the queries which never repeat don't take the cache space.
+    pub(crate) fn set_min_requests(&mut self, min_requests: usize) {
+        self.min_requests = min_requests;
+    }
+
+    /// Set the policy choosing the entries to evict once the cache is full.
+    pub(crate) fn set_eviction_policy(&mut self, eviction_policy: EvictionPolicy) {
+        self.eviction_policy = eviction_policy;
+    }
+
+    /// Limit the total size of the entries of a single canister, so a canister
+    /// filling the cache evicts only its own least recently used entries.
+    pub(crate) fn set_per_canister_capacity(&mut self, per_canister_capacity: NumBytes) {
+        self.per_canister_capacity = Some(per_canister_capacity);
+    }
+
+    /// Set whether the non-transient error results are cached.
+    ///
+    /// The transient errors are never cached, as the query may succeed next time.
+    pub(crate) fn set_cache_errors(&mut self, cache_errors: bool) {
+        self.cache_errors = cache_errors;
+    }
+
+    /// Store only the entries, i.e. the keys along with the replies, not larger
+    /// than `max_entry_size`, so a single large reply can't dominate the cache.
+    ///
+    /// The lookups are unaffected.
+    pub(crate) fn set_max_entry_size(&mut self, max_entry_size: NumBytes) {
+        self.max_entry_size = Some(max_entry_size);
+    }
+
+    /// Set the clock measuring the age of the cache entries.
+    pub(crate) fn set_time_source(&mut self, time_source: Arc<dyn TimeSource>) {
+        self.time_source = time_source;
+    }
+
+    /// Set the predicate deciding whether the queries of a source are cached.
+    pub(crate) fn set_source_filter(&mut self, filter: QueryCacheSourceFilter) {
+        self.source_filter = Some(filter);
+    }
+
+    /// Return `true` if the queries of the `source` may be cached.
+    pub(crate) fn is_cacheable_source(&self, source: UserId) -> bool {
+        self.source_filter
+            .as_ref()
+            .map_or(true, |filter| filter(source.get()))
+    }
+
+    /// Return `true` and count the bypass if the `query` is a composite query,
+    /// i.e. the receiver canister exports its method as a composite query.
+    ///
+    /// The composite queries may call other canisters, so they depend on more
+    /// than the receiver state and are not cached unless explicitly enabled.
+    pub(crate) fn bypass_composite_query(
+        &self,
+        query: &UserQuery,
+        state: &ReplicatedState,
+    ) -> bool {
+        let is_composite_query = state
+            .canister_state(&query.receiver)
+            .map_or(false, |canister| {
+                canister.exports_method(&WasmMethod::CompositeQuery(query.method_name.clone()))
+            });
+        if is_composite_query {
+            self.metrics.composite_query_bypasses.inc();
+        }
+        is_composite_query
+    }
+
+    /// Return a snapshot of the effective query cache configuration.
+    pub(crate) fn config(&self) -> QueryCacheConfig {
+        QueryCacheConfig {
+            enabled: self.enabled,
+            capacity: self.capacity,
+            max_pinned_bytes: NumBytes::new(self.max_pinned_bytes as u64),
+            max_stale_bytes: NumBytes::new(self.max_stale_bytes as u64),
+            max_expiry_time: self.max_expiry_time,
+            data_certificate_expiry_time: self.data_certificate_expiry_time,
+            verify_on_hit: self.verify_on_hit,
+            max_entries_per_method: self.max_entries_per_method,
+            canonical_payload: self.canonical_payload,
+            payload_projection: self.payload_projection.is_some(),
+            source_filter: self.source_filter.is_some(),
+            circuit_breaker_threshold: self.circuit_breaker_threshold,
+            circuit_breaker_cooldown: self.circuit_breaker_cooldown,
+            stale_on_error_window: self.stale_on_error_window,
+            max_serve_age: self.max_serve_age,
+            min_requests: self.min_requests,
+            eviction_policy: self.eviction_policy,
+            per_canister_capacity: self.per_canister_capacity,
+            cache_errors: self.cache_errors,
+            max_entry_size: self.max_entry_size,
+        }
+    }
+
+    /// Check if the queries of the `receiver` canister are cached at the `now` time,
+    /// i.e. the circuit breaker is not open for it, closing it once the cooldown is over.
+    pub(crate) fn is_circuit_closed(&self, receiver: CanisterId, now: Time) -> bool {
+        if self.circuit_breaker_threshold.is_none() {
+            return true;
+        }
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(open_until) = circuits.get(&receiver).and_then(|c| c.open_until) else {
+            return true;
+        };
+        if now < open_until {
+            return false;
+        }
+        circuits.remove(&receiver);
+        self.metrics
+            .circuit_open
+            .with_label_values(&[&receiver.to_string()])
+            .set(0);
+        true
+    }
+
+    /// Record a lookup of the `receiver` canister entry, opening the circuit breaker
+    /// for the canister after too many consecutive invalidations.
+    fn record_lookup(&self, receiver: CanisterId, is_valid: bool, now: Time) {
+        let Some(threshold) = self.circuit_breaker_threshold else {
+            return;
+        };
+        let mut circuits = self.circuits.lock().unwrap();
+        if is_valid {
+            if circuits
+                .remove(&receiver)
+                .map_or(false, |c| c.open_until.is_some())
+            {
+                self.metrics
+                    .circuit_open
+                    .with_label_values(&[&receiver.to_string()])
+                    .set(0);
+            }
+            return;
+        }
+        let circuit = circuits.entry(receiver).or_default();
+        circuit.consecutive_invalidations += 1;
+        if circuit.consecutive_invalidations >= threshold {
+            circuit.consecutive_invalidations = 0;
+            circuit.open_until = Some(now + self.circuit_breaker_cooldown);
+            self.metrics
+                .circuit_open
+                .with_label_values(&[&receiver.to_string()])
+                .set(1);
+        }
+    }
+
     /// Return the cached `Result` if it's still valid, updating the metrics and stats.
     pub(crate) fn get_valid_result(
         &self,
         key: &EntryKey,
         state: &ReplicatedState,
         query_stats_collector: Option<&QueryStatsCollector>,
-    ) -> Option<Result<WasmResult, UserError>> {
+    ) -> Option<SharedQueryResult> {
+        self.get_valid_result_with_age(key, state, query_stats_collector)
+            .map(|(result, _age_seconds)| result)
+    }
+
+    /// Return the cached `Result` along with the age of the cache entry in seconds,
+    /// just like `get_valid_result()`.
+    ///
+    /// The returned `Result` shares the reply with the cache entry, so it's never copied.
+    pub(crate) fn get_valid_result_with_age(
+        &self,
+        key: &EntryKey,
+        state: &ReplicatedState,
+        query_stats_collector: Option<&QueryStatsCollector>,
+    ) -> Option<(SharedQueryResult, f64)> {
         let mut cache = self.cache.lock().unwrap();
+        let mut pinned = self.pinned.lock().unwrap();
+
+        let now = state.metadata.batch_time;
+        let served_at = self.time_source.get_relative_time();
+
+        if let Some(value) = pinned.get(key) {
+            let is_valid = value.is_valid(
+                state,
+                query_stats_collector,
+                &self.metrics,
+                self.max_expiry_time,
+                self.data_certificate_expiry_time,
+                served_at,
+                self.max_serve_age,
+            );
+            self.record_lookup(key.receiver, is_valid, now);
+            if is_valid {
+                // The pinned entry is valid, return it.
+                value.record_hit();
+                return Some((Arc::clone(&value.result), value.elapsed_seconds(now)));
+            }
+            // The pinned entry is no longer valid, remove it along with the pin.
+            if let Some(value) = pinned.remove(key) {
+                self.keep_stale(key, value);
+            }
+            self.metrics
+                .pinned_bytes
+                .set(map_count_bytes(&pinned) as i64);
+            return None;
+        }
 
-        if let Some(value) = cache.get(key) {
-            if value.is_valid(
+        // The FIFO hits don't update the eviction order.
+        let value = match self.eviction_policy {
+            EvictionPolicy::Lru | EvictionPolicy::Lfu => cache.get(key),
+            EvictionPolicy::Fifo => cache.peek(key),
+        };
+        if let Some(value) = value {
+            let is_valid = value.is_valid(
                 state,
                 query_stats_collector,
                 &self.metrics,
                 self.max_expiry_time,
                 self.data_certificate_expiry_time,
-            ) {
+                served_at,
+                self.max_serve_age,
+            );
+            self.record_lookup(key.receiver, is_valid, now);
+            if is_valid {
                 // The cache entry is valid, return it.
-                return Some(value.result.clone());
+                value.record_hit();
+                return Some((Arc::clone(&value.result), value.elapsed_seconds(now)));
             } else {
                 // The cache entry is no longer valid, remove it.
-                cache.pop(key);
-                // Update the `count_bytes` metric.
+                if let Some(value) = cache.pop(key) {
+                    self.keep_stale(key, value);
+                }
+                // Update the `count_bytes` and `len` metrics.
                 self.metrics.count_bytes.set(cache.count_bytes() as i64);
+                self.metrics.len.set(cache.len() as i64);
             }
         }
         None
     }
 
-    /// Push a new `result` to the cache, evicting LRU entries if needed and updating the metrics.
+    /// Keep the invalidated entry `value` to be served if the query traps,
+    /// as long as it's a successful reply and the stale entries would take at most
+    /// `MAX_STALE_BYTES_PERCENT` of the capacity.
+    fn keep_stale(&self, key: &EntryKey, value: EntryValue) {
+        if self.stale_on_error_window.is_none()
+            || !matches!(*value.result, Ok(WasmResult::Reply(_)))
+        {
+            return;
+        }
+        let mut stale = self.stale.lock().unwrap();
+        stale.remove(key);
+        let entry_bytes = key.count_bytes() + value.count_bytes();
+        if stale.bytes + entry_bytes <= self.max_stale_bytes {
+            stale.insert(key.clone(), value);
+        }
+    }
+
+    /// Return the last successful reply for the `key` if the fresh `result` is a canister trap,
+    /// and the reply is no older than the stale on error window at the `now` time.
+    ///
+    /// The stale reply must still respect its data certificate expiry time.
+    pub(crate) fn get_stale_result_on_trap(
+        &self,
+        key: &EntryKey,
+        result: &Result<WasmResult, UserError>,
+        now: Time,
+    ) -> Option<SharedQueryResult> {
+        let window = self.stale_on_error_window?;
+        let Err(err) = result else {
+            // A fresh reply supersedes the stale one.
+            self.stale.lock().unwrap().remove(key);
+            return None;
+        };
+        if !matches!(
+            err.code(),
+            ErrorCode::CanisterTrapped | ErrorCode::CanisterCalledTrap
+        ) {
+            return None;
+        }
+
+        let mut stale = self.stale.lock().unwrap();
+        let value = stale.get(key)?;
+        if value.is_expired(now, window)
+            || value.is_expired_data_certificate(now, self.data_certificate_expiry_time)
+        {
+            stale.remove(key);
+            return None;
+        }
+        self.metrics.stale_on_error_served.inc();
+        Some(Arc::clone(&value.result))
+    }
+
+    /// Join the identical query in flight, waiting for its result, or register
+    /// a new query in flight, if there is none.
+    ///
+    /// If the identical query is abandoned (i.e. due to a transient error),
+    /// it's joined again, so one of the waiting queries gets executed.
+    /// If its result is not valid for the `state`, or it takes longer than
+    /// `MAX_IN_FLIGHT_WAIT`, the query is executed without sharing its result.
+    pub(crate) fn join_in_flight(
+        &self,
+        key: &EntryKey,
+        state: &ReplicatedState,
+    ) -> InFlightJoin<'_> {
+        loop {
+            let in_flight = {
+                let mut in_flight = self.in_flight.lock().unwrap();
+                match in_flight.get(key) {
+                    Some(identical) => Arc::clone(identical),
+                    None => {
+                        let new = Arc::new(InFlight {
+                            state: Mutex::new(InFlightState::Running),
+                            changed: Condvar::new(),
+                        });
+                        in_flight.insert(key.clone(), Arc::clone(&new));
+                        return InFlightJoin::Execute(Some(InFlightGuard {
+                            cache: self,
+                            key: key.clone(),
+                            in_flight: new,
+                        }));
+                    }
+                }
+            };
+            let (in_flight_state, wait) = in_flight
+                .changed
+                .wait_timeout_while(
+                    in_flight.state.lock().unwrap(),
+                    MAX_IN_FLIGHT_WAIT,
+                    |in_flight_state| matches!(in_flight_state, InFlightState::Running),
+                )
+                .unwrap();
+            if wait.timed_out() {
+                return InFlightJoin::Execute(None);
+            }
+            if let InFlightState::Done(value) = &*in_flight_state {
+                // The state might differ from the one the result was produced for.
+                if !value.is_valid_without_metrics(
+                    state,
+                    self.max_expiry_time,
+                    self.data_certificate_expiry_time,
+                ) {
+                    return InFlightJoin::Execute(None);
+                }
+                self.metrics.coalesced.inc();
+                return InFlightJoin::Coalesced(Arc::clone(&value.result));
+            }
+        }
+    }
+
+    /// Push a new `result` to the cache, evicting entries by the eviction policy if needed
+    /// and updating the metrics.
     pub(crate) fn push(
         &self,
         key: EntryKey,
-        result: &Result<WasmResult, UserError>,
+        result: &SharedQueryResult,
         state: &ReplicatedState,
         system_api_counters: &SystemApiCallCounters,
         evaluated_stats: &BTreeMap<CanisterId, QueryStats>,
@@ -441,10 +1720,25 @@ impl QueryCache {
             // Because of the transient error, the cache entry is immediately invalidated.
             self.metrics.invalidated_entries.inc();
             self.metrics.invalidated_entries_duration.observe(0_f64);
+            self.metrics.update_mean_entry_lifetime();
             self.metrics.invalidated_entries_by_transient_error.inc();
             return;
         }
 
+        // The remaining errors are deterministic, so they're cached if enabled.
+        if result.is_err() {
+            self.metrics.error_misses.inc();
+            if !self.cache_errors {
+                return;
+            }
+        }
+
+        // The result is not stored until the key is requested enough times.
+        if !self.record_miss(&key) {
+            self.metrics.misses_not_stored.inc();
+            return;
+        }
+
         // This can fail only if there is no active canister ID,
         // which should not happen, as we just evaluated those canisters.
         let Ok(env) = EntryEnv::try_new(state, evaluated_stats) else {
@@ -452,20 +1746,395 @@ impl QueryCache {
             return;
         };
 
-        let value = EntryValue::new(env, result.clone(), system_api_counters);
+        let value = EntryValue::new(
+            env,
+            Arc::clone(result),
+            system_api_counters,
+            self.time_source.get_relative_time(),
+        );
+        // The entries too large to be cached don't take the cache space at all.
+        if let Some(max_entry_size) = self.max_entry_size {
+            if key.count_bytes() + value.count_bytes() > max_entry_size.get() as usize {
+                self.metrics.too_large_entries.inc();
+                return;
+            }
+        }
         let mut cache = self.cache.lock().unwrap();
-        let evicted_entries = cache.push(key, value);
+        if self.pinned.lock().unwrap().contains_key(&key) {
+            // The entry has been pinned concurrently, keep the pinned value.
+            return;
+        }
+        let receiver = key.receiver;
+        let method_name = key.method_name.clone();
+        let mut evicted_entries = self.push_entry(&mut cache, key, value);
+        if let Some(max_entries) = self.max_entries_per_method {
+            let evicted_by_method_quota =
+                evict_method_quota(&mut cache, receiver, &method_name, max_entries);
+            self.metrics
+                .evicted_by_method_quota
+                .inc_by(evicted_by_method_quota.len() as u64);
+            evicted_entries.extend(evicted_by_method_quota);
+        }
 
         // Update other metrics.
         self.metrics
-            .evicted_entries
-            .inc_by(evicted_entries.len() as u64);
-        for (_evicted_key, evicted_value) in &evicted_entries {
-            let d = evicted_value.elapsed_seconds(now);
-            self.metrics.evicted_entries_duration.observe(d);
-        }
+            .observe_evicted_entries(evicted_entries.iter().map(|(_, value)| value), now);
         let count_bytes = cache.count_bytes() as i64;
         self.metrics.count_bytes.set(count_bytes);
         self.metrics.len.set(cache.len() as i64);
     }
+
+    /// Push the entry into the `cache`, evicting the entries chosen by the eviction policy
+    /// if the cache is full, and the least recently used entries of the canister
+    /// if it exceeds the per canister capacity.
+    ///
+    /// Returns the evicted entries, including the replaced entry with the same key, if any.
+    fn push_entry(
+        &self,
+        cache: &mut CacheEntries,
+        key: EntryKey,
+        value: EntryValue,
+    ) -> Vec<(EntryKey, EntryValue)> {
+        let receiver = key.receiver;
+        let mut evicted_entries = vec![];
+        let mut replaced_entry = None;
+        if self.eviction_policy == EvictionPolicy::Lfu {
+            replaced_entry = cache.pop(&key).map(|replaced| (key.clone(), replaced));
+            let entry_bytes = key.count_bytes() + value.count_bytes();
+            let target_bytes = (self.capacity.get() as usize).saturating_sub(entry_bytes);
+            evicted_entries = evict_lfu_to(cache, target_bytes);
+        }
+        // The LRU cache evicts the rest in the LRU order, which is also
+        // the insertion order for the FIFO policy.
+        evicted_entries.extend(cache.push(key, value));
+        self.metrics
+            .evicted_entries_by_policy
+            .with_label_values(&[self.eviction_policy.as_str()])
+            .inc_by(evicted_entries.len() as u64);
+        if let Some(per_canister_capacity) = self.per_canister_capacity {
+            let evicted_by_canister_quota =
+                evict_canister_quota(cache, receiver, per_canister_capacity.get() as usize);
+            self.metrics
+                .evicted_entries_by_canister_quota
+                .inc_by(evicted_by_canister_quota.len() as u64);
+            evicted_entries.extend(evicted_by_canister_quota);
+        }
+        evicted_entries.extend(replaced_entry);
+        evicted_entries
+    }
+
+    /// Record a miss of the `key`, returning `true` once the key missed
+    /// `min_requests` times, i.e. its result should be stored.
+    ///
+    /// The tracked misses are bounded, so the least recently missed keys
+    /// are forgotten and start over.
+    fn record_miss(&self, key: &EntryKey) -> bool {
+        if self.min_requests <= 1 {
+            return true;
+        }
+        let mut tracked_misses = self.tracked_misses.lock().unwrap();
+        let misses = tracked_misses.get(key).map_or(0, |count| count.0) + 1;
+        if misses >= self.min_requests {
+            tracked_misses.pop(key);
+            return true;
+        }
+        tracked_misses.push(key.clone(), MissCount(misses));
+        false
+    }
+
+    /// Compare the `cached_result` of a cache hit with the `fresh_result`
+    /// of the same query re-executed in the query cache verify on hit mode.
+    ///
+    /// Panics on mismatch, as it means the cache key does not capture
+    /// some of the query non-determinism.
+    pub(crate) fn verify_hit(
+        &self,
+        cached_result: &Result<WasmResult, UserError>,
+        fresh_result: &Result<WasmResult, UserError>,
+    ) {
+        if cached_result != fresh_result {
+            self.metrics.verify_on_hit_mismatches.inc();
+            panic!(
+                "Query cache hit result {:?} does not match the re-executed query result {:?}",
+                cached_result, fresh_result
+            );
+        }
+    }
+
+    /// Evict entries by the eviction policy until the cache size is not greater
+    /// than `target_bytes`.
+    ///
+    /// Unlike changing the capacity, this is a one-shot operation meant to be
+    /// called under memory pressure, i.e. the configured capacity is unchanged
+    /// and the cache can grow back afterwards.
+    ///
+    /// Returns the number of entries evicted at the `now` time.
+    pub(crate) fn trim_to(&self, target_bytes: NumBytes, now: Time) -> usize {
+        let mut cache = self.cache.lock().unwrap();
+        let evicted_entries = match self.eviction_policy {
+            EvictionPolicy::Lru | EvictionPolicy::Fifo => cache.trim_to(target_bytes),
+            EvictionPolicy::Lfu => {
+                let target_bytes = target_bytes.min(self.capacity).get() as usize;
+                evict_lfu_to(&mut cache, target_bytes)
+            }
+        };
+
+        self.metrics
+            .observe_evicted_entries(evicted_entries.iter().map(|(_, value)| value), now);
+        self.metrics.count_bytes.set(cache.count_bytes() as i64);
+        self.metrics.len.set(cache.len() as i64);
+        evicted_entries.len()
+    }
+
+    /// Remove the entries expired by the `now` time, including the pinned ones.
+    ///
+    /// The expired entries are otherwise removed only when they are looked up,
+    /// so the entries never looked up again hold the memory until they're evicted.
+    /// Returns the number of removed entries.
+    pub(crate) fn sweep_expired(&self, now: Time) -> usize {
+        let mut cache = self.cache.lock().unwrap();
+        let mut pinned = self.pinned.lock().unwrap();
+        let is_expired = |value: &EntryValue| {
+            value.is_expired(now, self.max_expiry_time)
+                || value.is_expired_data_certificate(now, self.data_certificate_expiry_time)
+        };
+
+        let expired_keys: Vec<EntryKey> = cache
+            .iter_lru()
+            .filter(|(_, value)| is_expired(value))
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut swept_values: Vec<EntryValue> = expired_keys
+            .iter()
+            .filter_map(|key| cache.pop(key))
+            .collect();
+        let expired_pinned_keys: Vec<EntryKey> = pinned
+            .iter()
+            .filter(|(_, value)| is_expired(value))
+            .map(|(key, _)| key.clone())
+            .collect();
+        swept_values.extend(
+            expired_pinned_keys
+                .iter()
+                .filter_map(|key| pinned.remove(key)),
+        );
+        let swept = swept_values.len();
+        if let Some(window) = self.stale_on_error_window {
+            self.stale
+                .lock()
+                .unwrap()
+                .retain(|value| !value.is_expired(now, window));
+        }
+
+        self.metrics.expired_swept.inc_by(swept as u64);
+        self.metrics.observe_evicted_entries(&swept_values, now);
+        self.metrics.count_bytes.set(cache.count_bytes() as i64);
+        self.metrics.len.set(cache.len() as i64);
+        self.metrics
+            .pinned_bytes
+            .set(map_count_bytes(&pinned) as i64);
+        swept
+    }
+
+    /// Pin the cache entry with the `key`, so it's exempt from the LRU eviction.
+    ///
+    /// The pinned entry is still removed (and unpinned) once it's invalidated.
+    /// Returns `false` if there is no such entry in the cache, or if the pinned
+    /// entries would take more than `MAX_PINNED_BYTES_PERCENT` of the capacity.
+    pub(crate) fn pin(&self, key: &EntryKey) -> bool {
+        let mut cache = self.cache.lock().unwrap();
+        let mut pinned = self.pinned.lock().unwrap();
+        if pinned.contains_key(key) {
+            return true;
+        }
+
+        let Some(value) = cache.get(key) else {
+            return false;
+        };
+        let entry_bytes = key.count_bytes() + value.count_bytes();
+        if map_count_bytes(&pinned) + entry_bytes > self.max_pinned_bytes {
+            return false;
+        }
+
+        let value = cache.pop(key).unwrap();
+        pinned.insert(key.clone(), value);
+
+        self.metrics.count_bytes.set(cache.count_bytes() as i64);
+        self.metrics.len.set(cache.len() as i64);
+        self.metrics
+            .pinned_bytes
+            .set(map_count_bytes(&pinned) as i64);
+        true
+    }
+
+    /// Unpin the cache entry with the `key`, returning it back to the LRU cache.
+    ///
+    /// The entries evicted to make room for it are accounted at the `now` time.
+    /// Returns `false` if the entry was not pinned.
+    pub(crate) fn unpin(&self, key: &EntryKey, now: Time) -> bool {
+        let mut cache = self.cache.lock().unwrap();
+        let mut pinned = self.pinned.lock().unwrap();
+        let Some((key, value)) = pinned.remove_entry(key) else {
+            return false;
+        };
+
+        let evicted_entries = self.push_entry(&mut cache, key, value);
+
+        self.metrics
+            .observe_evicted_entries(evicted_entries.iter().map(|(_, value)| value), now);
+        self.metrics.count_bytes.set(cache.count_bytes() as i64);
+        self.metrics.len.set(cache.len() as i64);
+        self.metrics
+            .pinned_bytes
+            .set(map_count_bytes(&pinned) as i64);
+        true
+    }
+
+    /// Shrink the internal structures to fit the current entries.
+    ///
+    /// The structures never shrink on their own, so after evicting or invalidating
+    /// many entries they keep holding the memory for them.
+    /// Returns the estimated number of reclaimed bytes.
+    pub(crate) fn compact(&self) -> NumBytes {
+        let mut cache = self.cache.lock().unwrap();
+        let mut pinned = self.pinned.lock().unwrap();
+        let pinned_reclaimed_bytes = map_overhead_bytes(&pinned);
+        pinned.shrink_to_fit();
+        let pinned_reclaimed_bytes =
+            pinned_reclaimed_bytes.saturating_sub(map_overhead_bytes(&pinned));
+        let reclaimed_bytes = cache.shrink_to_fit() + pinned_reclaimed_bytes;
+
+        self.metrics.compactions.inc();
+        NumBytes::new(reclaimed_bytes as u64)
+    }
+
+    /// Return the number of entries and bytes per shard, as they would be
+    /// distributed across the shards of the sharded query cache.
+    ///
+    /// The pinned entries are accounted as regular ones.
+    pub(crate) fn shard_stats(&self) -> Vec<ShardStat> {
+        let cache = self.cache.lock().unwrap();
+        let pinned = self.pinned.lock().unwrap();
+        let mut stats = vec![
+            ShardStat {
+                entries: 0,
+                bytes: NumBytes::new(0),
+            };
+            QUERY_CACHE_SHARDS
+        ];
+        for (key, value) in cache.iter_lru().chain(pinned.iter()) {
+            let stat = &mut stats[shard_of(key)];
+            stat.entries += 1;
+            stat.bytes += NumBytes::new((key.count_bytes() + value.count_bytes()) as u64);
+        }
+        stats
+    }
+
+    /// Return the number of entries resident in the LRU cache, excluding the pinned ones.
+    pub(crate) fn len(&self) -> usize {
+        self.cache.lock().unwrap().len()
+    }
+
+    /// Check the consistency of the cache: the LRU cache passes its own audit,
+    /// no entry is both pinned and in the LRU cache, and the pinned entries
+    /// fit into their limit.
+    ///
+    /// Returns the description of the first violation found.
+    pub(crate) fn audit(&self) -> Result<(), String> {
+        let cache = self.cache.lock().unwrap();
+        let pinned = self.pinned.lock().unwrap();
+        cache.audit()?;
+        if let Some((key, _value)) = cache.iter_lru().find(|(key, _)| pinned.contains_key(key)) {
+            return Err(format!(
+                "entry for canister {} method {} is both pinned and in the LRU cache",
+                key.receiver, key.method_name
+            ));
+        }
+        let pinned_bytes = map_count_bytes(&pinned);
+        if pinned_bytes > self.max_pinned_bytes {
+            return Err(format!(
+                "pinned entries size {} exceeds the limit {}",
+                pinned_bytes, self.max_pinned_bytes
+            ));
+        }
+        Ok(())
+    }
+
+    /// Return the total size of the cached entries, including the pinned ones.
+    fn entries_count_bytes(&self) -> usize {
+        self.cache.lock().unwrap().count_bytes()
+            + map_count_bytes(&self.pinned.lock().unwrap())
+            + self.stale.lock().unwrap().bytes
+            + self.tracked_misses.lock().unwrap().count_bytes()
+    }
+
+    /// Return the estimated size of the internal structures not taken by the entries.
+    fn overhead_bytes(&self) -> usize {
+        self.cache.lock().unwrap().overhead_bytes()
+            + map_overhead_bytes(&self.pinned.lock().unwrap())
+            + self.stale.lock().unwrap().overhead_bytes()
+            + self.tracked_misses.lock().unwrap().overhead_bytes()
+    }
+
+    /// Serialize the cache entries into a snapshot, so they can be imported
+    /// into a fresh cache, i.e. after the process restart.
+    ///
+    /// The pinned entries are exported as regular ones.
+    pub(crate) fn export_snapshot(&self) -> Vec<u8> {
+        let cache = self.cache.lock().unwrap();
+        let pinned = self.pinned.lock().unwrap();
+        // Keep the LRU order, so the most recently used entries are imported last.
+        let entries: Vec<SnapshotEntry> = pinned
+            .iter()
+            .chain(cache.iter_lru())
+            .map(SnapshotEntry::from)
+            .collect();
+        serde_cbor::to_vec(&entries).expect("Serialization failed.")
+    }
+
+    /// Import the cache entries from the `snapshot` created by `export_snapshot()`.
+    ///
+    /// Each entry is revalidated against the current `state`, and the invalid ones are dropped.
+    /// Returns the number of imported entries.
+    pub(crate) fn import_snapshot(
+        &self,
+        snapshot: &[u8],
+        state: &ReplicatedState,
+    ) -> Result<usize, serde_cbor::Error> {
+        let entries: Vec<SnapshotEntry> = serde_cbor::from_slice(snapshot)?;
+        let now = state.metadata.batch_time;
+        let mut cache = self.cache.lock().unwrap();
+        let pinned = self.pinned.lock().unwrap();
+        let mut imported = 0;
+        for entry in entries {
+            let (key, value) = entry.into();
+            if pinned.contains_key(&key)
+                || !value.is_valid_without_metrics(
+                    state,
+                    self.max_expiry_time,
+                    self.data_certificate_expiry_time,
+                )
+            {
+                continue;
+            }
+            let receiver = key.receiver;
+            let method_name = key.method_name.clone();
+            let mut evicted_entries = self.push_entry(&mut cache, key, value);
+            if let Some(max_entries) = self.max_entries_per_method {
+                let evicted_by_method_quota =
+                    evict_method_quota(&mut cache, receiver, &method_name, max_entries);
+                self.metrics
+                    .evicted_by_method_quota
+                    .inc_by(evicted_by_method_quota.len() as u64);
+                evicted_entries.extend(evicted_by_method_quota);
+            }
+            self.metrics
+                .observe_evicted_entries(evicted_entries.iter().map(|(_, value)| value), now);
+            imported += 1;
+        }
+
+        self.metrics.count_bytes.set(cache.count_bytes() as i64);
+        self.metrics.len.set(cache.len() as i64);
+        Ok(imported)
+    }
 }
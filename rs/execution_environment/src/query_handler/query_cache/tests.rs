@@ -1,26 +1,36 @@
-use super::{QueryCache, QueryCacheMetrics};
+use super::{InFlightJoin, QueryCache, QueryCacheMetrics};
 use crate::{
     metrics,
     query_handler::query_cache::{EntryEnv, EntryKey, EntryValue},
     InternalHttpQueryHandler,
 };
-use ic_base_types::CanisterId;
+use ic_base_types::{CanisterId, NumBytes, PrincipalId};
+use ic_config::execution_environment::{
+    EvictionPolicy, MAX_QUERY_CACHE_CAPACITY, MIN_QUERY_CACHE_CAPACITY,
+};
 use ic_error_types::{ErrorCode, UserError};
 use ic_interfaces::execution_environment::{SystemApiCallCounters, SystemApiCallId};
+use ic_interfaces_state_manager::Labeled;
+use ic_metrics::MetricsRegistry;
 use ic_registry_subnet_type::SubnetType;
 use ic_replicated_state::canister_state::system_state::CyclesUseCase;
 use ic_test_utilities::universal_canister::wasm;
 use ic_test_utilities_execution_environment::{ExecutionTest, ExecutionTestBuilder};
+use ic_test_utilities_time::FastForwardTimeSource;
 use ic_test_utilities_types::ids::user_test_id;
 use ic_types::{
     batch::QueryStats,
     ingress::WasmResult,
     messages::{CanisterTask, UserQuery},
-    time, CountBytes,
+    time, CountBytes, Height, Time, UserId,
 };
-use ic_types_test_utils::ids::subnet_test_id;
+use ic_types_test_utils::ids::{canister_test_id, subnet_test_id};
 use ic_universal_canister::call_args;
-use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Barrier},
+    time::Duration,
+};
 
 const MAX_EXPIRY_TIME: Duration = Duration::from_secs(10);
 const MORE_THAN_MAX_EXPIRY_TIME: Duration = Duration::from_secs(11);
@@ -85,11 +95,12 @@ fn query_cache_metrics(test: &ExecutionTest) -> &QueryCacheMetrics {
     &query_cache(test).metrics
 }
 
-/// Return `ExecutionTestBuilder` with query caching, composite queries
-/// and query stats enabled.
+/// Return `ExecutionTestBuilder` with query caching, composite queries,
+/// composite query caching and query stats enabled.
 fn builder_with_query_caching() -> ExecutionTestBuilder {
     ExecutionTestBuilder::new()
         .with_composite_queries()
+        .with_query_cache_composite_queries(true)
         .with_query_stats()
 }
 
@@ -133,11 +144,13 @@ fn query_cache_entry_value_counts_elapsed_seconds() {
     let entry_env = EntryEnv {
         batch_time: current_time,
         canisters_versions_balances_stats: vec![],
+        module_hashes: BTreeMap::new(),
     };
     let entry_value = EntryValue::new(
         entry_env,
-        Result::Ok(WasmResult::Reply(vec![])),
+        Arc::new(Result::Ok(WasmResult::Reply(vec![]))),
         &SystemApiCallCounters::default(),
+        current_time,
     );
     let forward_time = current_time + Duration::from_secs(2);
     assert_eq!(2.0, entry_value.elapsed_seconds(forward_time));
@@ -165,6 +178,25 @@ fn query_cache_reports_hits_and_misses_metrics() {
     });
 }
 
+#[test]
+fn query_cache_reports_bytes_served_metric_on_hits_only() {
+    let q = wasm().reply_data(&[42; REPLY_SIZE]);
+    for_query_and_composite_query(q, |mut test, a_id, _b_id, method, q| {
+        let res_1 = test.non_replicated_query(a_id, method, q.clone());
+        assert_eq!(res_1, Ok(WasmResult::Reply(vec![42; REPLY_SIZE])));
+        assert_eq!(query_cache_metrics(&test).misses.get(), 1);
+        assert_eq!(query_cache_metrics(&test).bytes_served.get(), 0);
+
+        let res_2 = test.non_replicated_query(a_id, method, q);
+        assert_eq!(res_1, res_2);
+        assert_eq!(query_cache_metrics(&test).hits.get(), 1);
+        assert_eq!(
+            query_cache_metrics(&test).bytes_served.get(),
+            REPLY_SIZE as u64
+        );
+    });
+}
+
 #[test]
 fn query_cache_reports_evicted_entries_and_count_bytes_metrics() {
     const QUERY_CACHE_SIZE: usize = 2;
@@ -210,6 +242,58 @@ fn query_cache_reports_evicted_entries_and_count_bytes_metrics() {
     assert!(REPLY_SIZE * 2 * QUERY_CACHE_SIZE > count_bytes);
 }
 
+#[test]
+fn query_cache_reports_mean_entry_lifetime_metric() {
+    /// Includes some room for the keys, headers etc. for a single entry.
+    const QUERY_CACHE_CAPACITY: usize = REPLY_SIZE * 2;
+    let mut test = builder_with_query_cache_capacity(QUERY_CACHE_CAPACITY).build();
+    let id = test.universal_canister().unwrap();
+    // The query must get the time, otherwise the entry won't be invalidated.
+    let q_1 = wasm().time().reply_data(&[1; REPLY_SIZE / 2]).build();
+    let q_2 = wasm().reply_data(&[2; REPLY_SIZE / 2]).build();
+
+    test.non_replicated_query(id, "query", q_1.clone()).unwrap();
+    assert_eq!(0.0, query_cache_metrics(&test).mean_entry_lifetime.get());
+
+    // The first entry is invalidated after 2 seconds.
+    test.state_mut().metadata.batch_time += Duration::from_secs(2);
+    test.non_replicated_query(id, "query", q_1).unwrap();
+    let m = query_cache_metrics(&test);
+    assert_eq!(1, m.invalidated_entries.get());
+    assert_eq!(2.0, m.mean_entry_lifetime.get());
+
+    // The replacing entry is evicted after 4 seconds.
+    test.state_mut().metadata.batch_time += Duration::from_secs(4);
+    test.non_replicated_query(id, "query", q_2).unwrap();
+    let m = query_cache_metrics(&test);
+    assert_eq!(1, m.evicted_entries.get());
+    assert_eq!(3.0, m.mean_entry_lifetime.get());
+}
+
+#[test]
+fn query_cache_hits_share_the_reply_allocation() {
+    let mut test = builder_with_query_caching().build();
+    let id = test.universal_canister().unwrap();
+    let q = wasm().reply_data(&[1; REPLY_SIZE]).build();
+
+    let miss = test.non_replicated_query_shared(id, "query", q.clone());
+    let first_hit = test.non_replicated_query_shared(id, "query", q.clone());
+    let second_hit = test.non_replicated_query_shared(id, "query", q);
+    let m = query_cache_metrics(&test);
+    assert_eq!(1, m.misses.get());
+    assert_eq!(2, m.hits.get());
+
+    // The hits share the result with the cache entry pushed on the miss.
+    assert!(Arc::ptr_eq(&miss, &first_hit));
+    assert!(Arc::ptr_eq(&first_hit, &second_hit));
+    let (Ok(WasmResult::Reply(first_reply)), Ok(WasmResult::Reply(second_reply))) =
+        (&*first_hit, &*second_hit)
+    else {
+        panic!("Unexpected query results: {:?} {:?}", first_hit, second_hit);
+    };
+    assert_eq!(first_reply.as_ptr(), second_reply.as_ptr());
+}
+
 #[test]
 fn query_cache_reports_count_bytes_metric_on_invalidation() {
     let mut test = builder_with_query_caching().build();
@@ -219,6 +303,7 @@ fn query_cache_reports_count_bytes_metric_on_invalidation() {
         receiver: a_id,
         method_name: "method".into(),
         method_payload: vec![],
+        cache_context: None,
     };
 
     // Assert initial cache state.
@@ -229,7 +314,7 @@ fn query_cache_reports_count_bytes_metric_on_invalidation() {
     assert!((initial_count_bytes as usize) < BIG_REPLY_SIZE);
 
     // Push a big result into the cache.
-    let big_result = Ok(WasmResult::Reply(vec![0; BIG_REPLY_SIZE]));
+    let big_result = Arc::new(Ok(WasmResult::Reply(vec![0; BIG_REPLY_SIZE])));
     let query_cache = &query_handler(&test).query_cache;
     let mut evaluated_stats = BTreeMap::new();
     evaluated_stats.insert(a_id, QueryStats::default());
@@ -412,6 +497,52 @@ fn query_cache_returns_different_results_for_different_method_payloads() {
     assert_eq!(res_1, res_2);
 }
 
+#[test]
+fn query_cache_returns_different_results_for_different_cache_contexts() {
+    let mut test = builder_with_query_caching().build();
+    let id = test.universal_canister().unwrap();
+    let query = UserQuery {
+        source: user_test_id(1),
+        receiver: id,
+        method_name: "query".into(),
+        method_payload: wasm().reply_data(&[42]).build(),
+        ingress_expiry: 0,
+        nonce: None,
+    };
+    let query_with_context = |test: &ExecutionTest, context: &[u8]| {
+        test.query_with_cache_context(
+            query.clone(),
+            Arc::new(test.state().clone()),
+            vec![],
+            Some(context.to_vec()),
+        )
+    };
+
+    let res_1 = query_with_context(&test, b"gateway 1");
+    assert_eq!(query_cache_metrics(&test).misses.get(), 1);
+    assert_eq!(query_cache_metrics(&test).len.get(), 1);
+    assert_eq!(res_1, Ok(WasmResult::Reply(vec![42])));
+
+    // The same query with a different context must produce a new entry.
+    let res_2 = query_with_context(&test, b"gateway 2");
+    assert_eq!(query_cache_metrics(&test).misses.get(), 2);
+    assert_eq!(query_cache_metrics(&test).len.get(), 2);
+    assert_eq!(res_1, res_2);
+
+    // The same query with the same context must hit the existing entry.
+    let res_3 = query_with_context(&test, b"gateway 1");
+    assert_eq!(query_cache_metrics(&test).hits.get(), 1);
+    assert_eq!(query_cache_metrics(&test).misses.get(), 2);
+    assert_eq!(query_cache_metrics(&test).len.get(), 2);
+    assert_eq!(res_1, res_3);
+
+    // The query without context is different from the queries with context.
+    let res_4 = test.query(query.clone(), Arc::new(test.state().clone()), vec![]);
+    assert_eq!(query_cache_metrics(&test).misses.get(), 3);
+    assert_eq!(query_cache_metrics(&test).len.get(), 3);
+    assert_eq!(res_1, res_4);
+}
+
 #[test]
 fn query_cache_returns_different_results_for_different_batch_times() {
     // The query must get the time, otherwise the entry won't be invalidated.
@@ -563,6 +694,44 @@ fn query_cache_returns_different_results_after_max_expiry_time() {
     });
 }
 
+#[test]
+fn query_cache_sweep_expired_removes_entries_never_looked_up_again() {
+    let mut test = builder_with_query_cache_expiry_times().build();
+    let id = test.universal_canister().unwrap();
+    let initial_count_bytes = query_cache_metrics(&test).count_bytes.get();
+
+    test.non_replicated_query(id, "query", wasm().reply_data(&[42; REPLY_SIZE]).build())
+        .unwrap();
+    let m = query_cache_metrics(&test);
+    assert_eq!(1, m.len.get());
+    let count_bytes = m.count_bytes.get();
+    assert!(((count_bytes - initial_count_bytes) as usize) > REPLY_SIZE);
+
+    // Nothing is expired yet.
+    let now = test.state().metadata.batch_time;
+    assert_eq!(0, query_cache(&test).sweep_expired(now));
+    assert_eq!(1, query_cache_metrics(&test).len.get());
+
+    // Change the batch time more than the max expiry time, without running the query.
+    test.state_mut().metadata.batch_time += MORE_THAN_MAX_EXPIRY_TIME;
+    let now = test.state().metadata.batch_time;
+    assert_eq!(1, query_cache(&test).sweep_expired(now));
+
+    let m = query_cache_metrics(&test);
+    assert_eq!(1, m.expired_swept.get());
+    assert_eq!(1, m.evicted_entries.get());
+    assert_eq!(1, m.evicted_entries_duration.get_sample_count());
+    assert_eq!(
+        MORE_THAN_MAX_EXPIRY_TIME.as_secs_f64(),
+        m.mean_entry_lifetime.get()
+    );
+    assert_eq!(0, m.len.get());
+    assert!(m.count_bytes.get() < count_bytes);
+    assert!((m.count_bytes.get() as usize) < REPLY_SIZE);
+    // The entry was swept, not invalidated on lookup.
+    assert_eq!(0, m.invalidated_entries.get());
+}
+
 #[test]
 // The data certificate can be called only in the normal query.
 fn query_cache_always_returns_different_results_after_data_certificate_expiry_time() {
@@ -625,6 +794,75 @@ fn query_cache_reports_invalidated_entries_duration_metric_on_negative_durations
     });
 }
 
+/// Shifts the time forward for positive and backward for negative `delta_secs`.
+fn shift_time(time: Time, delta_secs: i64) -> Time {
+    let delta = Duration::from_secs(delta_secs.unsigned_abs());
+    if delta_secs >= 0 {
+        time + delta
+    } else {
+        time.saturating_sub(delta)
+    }
+}
+
+#[test]
+fn query_cache_handles_clock_skew() {
+    // Signed time deltas in seconds, simulating clock adjustments back and forth.
+    const DELTAS: [i64; 16] = [1, -1, 0, 5, -3, 0, 0, -10, 20, -20, 3, 0, -1, 1, -11, 11];
+    // The query must get the time, otherwise the entry won't be invalidated.
+    let q = wasm().time().reply_data(&[42]);
+    for_query_and_composite_query(q, |mut test, a_id, _b_id, method, q| {
+        let mut now = time::GENESIS;
+        test.state_mut().metadata.batch_time = now;
+        let res = test.non_replicated_query(a_id, method, q.clone());
+        assert_eq!(res, Ok(WasmResult::Reply(vec![42])));
+
+        let mut cached_time = now;
+        let mut hits = 0;
+        let mut misses = 1;
+        let mut invalidated = 0;
+        let mut expired = 0;
+        let mut invalidated_duration = 0_f64;
+        for delta in DELTAS {
+            now = shift_time(now, delta);
+            test.state_mut().metadata.batch_time = now;
+            let res = test.non_replicated_query(a_id, method, q.clone());
+            assert_eq!(res, Ok(WasmResult::Reply(vec![42])));
+
+            // The entry must be invalidated exactly when the time differs from the cached one.
+            if now == cached_time {
+                hits += 1;
+            } else {
+                misses += 1;
+                invalidated += 1;
+                let elapsed = now.saturating_duration_since(cached_time);
+                if elapsed > MAX_EXPIRY_TIME {
+                    expired += 1;
+                }
+                invalidated_duration += elapsed.as_secs_f64();
+                cached_time = now;
+            }
+
+            let m = query_cache_metrics(&test);
+            assert_eq!(hits, m.hits.get());
+            assert_eq!(misses, m.misses.get());
+            assert_eq!(0, m.hits_with_ignored_time.get());
+            assert_eq!(invalidated, m.invalidated_entries.get());
+            assert_eq!(invalidated, m.invalidated_entries_by_time.get());
+            assert_eq!(expired, m.invalidated_entries_by_max_expiry_time.get());
+            // Negative durations should give just 0.
+            assert!(m.invalidated_entries_duration.get_sample_sum() >= 0.0);
+            assert_eq!(
+                invalidated_duration,
+                m.invalidated_entries_duration.get_sample_sum()
+            );
+            assert_eq!(
+                invalidated,
+                m.invalidated_entries_duration.get_sample_count()
+            );
+        }
+    });
+}
+
 #[test]
 fn query_cache_returns_different_results_for_different_canister_versions() {
     let q = wasm().reply_data(&[42]);
@@ -654,6 +892,62 @@ fn query_cache_returns_different_results_for_different_canister_versions() {
     });
 }
 
+#[test]
+fn query_cache_validates_entries_against_the_queried_state_snapshot() {
+    let mut test = builder_with_query_caching().build();
+    let id = test.universal_canister().unwrap();
+    let query = UserQuery {
+        source: user_test_id(1),
+        receiver: id,
+        method_name: "query".into(),
+        method_payload: wasm().canister_version().reply_int64().build(),
+        ingress_expiry: 0,
+        nonce: None,
+    };
+    let version = |test: &ExecutionTest| test.canister_state(id).system_state.canister_version;
+
+    let old_snapshot = Arc::new(test.state().clone());
+    let old_version = version(&test);
+    let res_1 = test.query(query.clone(), old_snapshot.clone(), vec![]);
+    assert_eq!(1, query_cache_metrics(&test).misses.get());
+    assert_eq!(
+        Ok(WasmResult::Reply(old_version.to_le_bytes().into())),
+        res_1
+    );
+
+    // Mutating the live state doesn't affect the snapshot the entry was created from.
+    test.canister_state_mut(id).system_state.canister_version += 1;
+    let res_2 = test.query(query.clone(), old_snapshot.clone(), vec![]);
+    assert_eq!(1, query_cache_metrics(&test).hits.get());
+    assert_eq!(res_1, res_2);
+
+    // A fresh snapshot carries the bumped version, so the entry is invalidated.
+    let new_snapshot = Arc::new(test.state().clone());
+    let new_version = version(&test);
+    let res_3 = test.query(query.clone(), new_snapshot.clone(), vec![]);
+    let m = query_cache_metrics(&test);
+    assert_eq!(1, m.hits.get());
+    assert_eq!(2, m.misses.get());
+    assert_eq!(1, m.invalidated_entries_by_canister_version.get());
+    assert_eq!(
+        Ok(WasmResult::Reply(new_version.to_le_bytes().into())),
+        res_3
+    );
+
+    // The new entry is served for the fresh snapshot...
+    let res_4 = test.query(query.clone(), new_snapshot, vec![]);
+    assert_eq!(2, query_cache_metrics(&test).hits.get());
+    assert_eq!(res_3, res_4);
+
+    // ...but not for the old one, even though it matches the entry's previous environment.
+    let res_5 = test.query(query, old_snapshot, vec![]);
+    let m = query_cache_metrics(&test);
+    assert_eq!(2, m.hits.get());
+    assert_eq!(3, m.misses.get());
+    assert_eq!(2, m.invalidated_entries_by_canister_version.get());
+    assert_eq!(res_1, res_5);
+}
+
 #[test]
 fn query_cache_returns_different_results_for_different_canister_balances() {
     // The query must get the balance, otherwise the entry won't be invalidated.
@@ -791,6 +1085,54 @@ fn query_cache_frees_memory_after_invalidated_entries() {
     assert!(count_bytes < BIG_RESPONSE_SIZE);
 }
 
+#[test]
+fn query_cache_does_not_store_entries_larger_than_max_entry_size() {
+    static BIG_RESPONSE_SIZE: usize = 1_000_000;
+    static SMALL_RESPONSE_SIZE: usize = 42;
+    static MAX_ENTRY_SIZE: usize = 100_000;
+
+    let mut test = builder_with_query_caching()
+        // Use system subnet so all the executions are free.
+        .with_subnet_type(SubnetType::System)
+        .with_query_cache_max_entry_size(MAX_ENTRY_SIZE as u64)
+        // The cycles balance is used to construct the replies of different sizes.
+        .with_initial_canister_cycles(BIG_RESPONSE_SIZE.try_into().unwrap())
+        .build();
+    let id = test.canister_from_wat(QUERY_CACHE_WAT).unwrap();
+    let initial_count_bytes = query_cache(&test).count_bytes();
+
+    // The 1MB reply is never stored.
+    for _ in 0..ITERATIONS {
+        let res = test
+            .non_replicated_query(id, "canister_balance_sized_reply", vec![])
+            .unwrap();
+        assert_eq!(BIG_RESPONSE_SIZE, res.count_bytes());
+        assert_eq!(initial_count_bytes, query_cache(&test).count_bytes());
+    }
+    let m = query_cache_metrics(&test);
+    assert_eq!(ITERATIONS, m.too_large_entries.get() as usize);
+    assert_eq!(ITERATIONS, m.misses.get() as usize);
+    assert_eq!(0, m.hits.get());
+    assert_eq!(0, m.len.get());
+
+    // Set the canister balance to 42, so the reply is small enough to be cached.
+    test.canister_state_mut(id).system_state.remove_cycles(
+        ((BIG_RESPONSE_SIZE - SMALL_RESPONSE_SIZE) as u64).into(),
+        CyclesUseCase::Memory,
+    );
+    for _ in 0..2 {
+        let res = test
+            .non_replicated_query(id, "canister_balance_sized_reply", vec![])
+            .unwrap();
+        assert_eq!(SMALL_RESPONSE_SIZE, res.count_bytes());
+    }
+    let m = query_cache_metrics(&test);
+    assert_eq!(ITERATIONS, m.too_large_entries.get() as usize);
+    assert_eq!(1, m.hits.get());
+    assert_eq!(1, m.len.get());
+    assert!(query_cache(&test).count_bytes() < MAX_ENTRY_SIZE);
+}
+
 #[test]
 fn query_cache_respects_cache_capacity() {
     /// Includes some room for the keys, headers etc.
@@ -811,6 +1153,8 @@ fn query_cache_respects_cache_capacity() {
         let count_bytes = query_cache(&test).count_bytes();
         assert!(count_bytes > REPLY_SIZE);
         assert!(count_bytes < QUERY_CACHE_CAPACITY);
+        assert_eq!(1, query_cache(&test).len());
+        assert_eq!(1, query_cache_metrics(&test).len.get());
     }
 
     // Now the replies should hit another entry.
@@ -821,6 +1165,8 @@ fn query_cache_respects_cache_capacity() {
         let count_bytes = query_cache(&test).count_bytes();
         assert!(count_bytes > REPLY_SIZE * 2);
         assert!(count_bytes < QUERY_CACHE_CAPACITY);
+        assert_eq!(2, query_cache(&test).len());
+        assert_eq!(2, query_cache_metrics(&test).len.get());
     }
 
     // Now the replies should evict the first entry.
@@ -831,7 +1177,183 @@ fn query_cache_respects_cache_capacity() {
         let count_bytes = query_cache(&test).count_bytes();
         assert!(count_bytes > REPLY_SIZE * 2);
         assert!(count_bytes < QUERY_CACHE_CAPACITY);
+        assert_eq!(2, query_cache(&test).len());
+        assert_eq!(2, query_cache_metrics(&test).len.get());
+    }
+
+    // The invalidated reply is no longer resident.
+    test.canister_state_mut(id).system_state.canister_version += 1;
+    let key = EntryKey {
+        source: user_test_id(0),
+        receiver: id,
+        method_name: "query".into(),
+        method_payload: wasm().reply_data(&[3; REPLY_SIZE / 2]).build(),
+        cache_context: None,
+    };
+    assert!(query_cache(&test)
+        .get_valid_result(&key, test.state(), None)
+        .is_none());
+    assert_eq!(1, query_cache_metrics(&test).invalidated_entries.get());
+    assert_eq!(1, query_cache(&test).len());
+    assert_eq!(1, query_cache_metrics(&test).len.get());
+}
+
+#[test]
+fn query_cache_per_canister_capacity_evicts_only_entries_of_the_canister() {
+    /// Includes some room for the keys, headers etc. for two entries.
+    const PER_CANISTER_CAPACITY: usize = REPLY_SIZE * 3;
+    let mut test = builder_with_query_caching()
+        .with_query_cache_per_canister_capacity(PER_CANISTER_CAPACITY as u64)
+        .build();
+    let noisy = test.universal_canister().unwrap();
+    let quiet = test.universal_canister().unwrap();
+    let quiet_payload = wasm().reply_data(&[0; REPLY_SIZE / 2]).build();
+    test.non_replicated_query(quiet, "query", quiet_payload.clone())
+        .unwrap();
+
+    // Every query of the noisy canister past the first two evicts its oldest entry.
+    for i in 1..=ITERATIONS {
+        test.non_replicated_query(
+            noisy,
+            "query",
+            wasm().reply_data(&[i as u8; REPLY_SIZE / 2]).build(),
+        )
+        .unwrap();
+        let noisy_bytes = query_cache(&test)
+            .cache
+            .lock()
+            .unwrap()
+            .canister_bytes(&noisy);
+        assert!(noisy_bytes <= PER_CANISTER_CAPACITY);
+    }
+    let m = query_cache_metrics(&test);
+    assert_eq!(
+        ITERATIONS - 2,
+        m.evicted_entries_by_canister_quota.get() as usize
+    );
+    assert_eq!(ITERATIONS - 2, m.evicted_entries.get() as usize);
+    assert_eq!(Ok(()), query_cache(&test).audit());
+
+    // The entry of the quiet canister is still cached.
+    test.non_replicated_query(quiet, "query", quiet_payload)
+        .unwrap();
+    assert_eq!(1, query_cache_metrics(&test).hits.get());
+}
+
+#[test]
+fn query_cache_lfu_keeps_hot_entry_and_evicts_cold_entries() {
+    /// Includes some room for the keys, headers etc. for two entries.
+    const QUERY_CACHE_CAPACITY: usize = REPLY_SIZE * 3;
+    let mut test = builder_with_query_cache_capacity(QUERY_CACHE_CAPACITY)
+        .with_query_cache_eviction_policy(EvictionPolicy::Lfu)
+        .build();
+    let id = test.universal_canister().unwrap();
+    let hot = wasm().reply_data(&[0; REPLY_SIZE / 2]).build();
+
+    // The hot entry is the oldest one, but the most frequently used.
+    for _ in 0..ITERATIONS {
+        test.non_replicated_query(id, "query", hot.clone()).unwrap();
+    }
+    assert_eq!(
+        ITERATIONS - 1,
+        query_cache_metrics(&test).hits.get() as usize
+    );
+
+    // Every cold query evicts the previous cold entry.
+    for i in 1..=ITERATIONS {
+        let _res = test.non_replicated_query(
+            id,
+            "query",
+            wasm().reply_data(&[i as u8; REPLY_SIZE / 2]).build(),
+        );
+        let count_bytes = query_cache(&test).count_bytes();
+        assert!(count_bytes > REPLY_SIZE * 2);
+        assert!(count_bytes < QUERY_CACHE_CAPACITY);
+    }
+    let m = query_cache_metrics(&test);
+    assert_eq!(ITERATIONS - 1, m.evicted_entries.get() as usize);
+    assert_eq!(
+        ITERATIONS - 1,
+        m.evicted_entries_by_policy
+            .with_label_values(&["lfu"])
+            .get() as usize
+    );
+
+    // The hot entry survives.
+    test.non_replicated_query(id, "query", hot).unwrap();
+    assert_eq!(ITERATIONS, query_cache_metrics(&test).hits.get() as usize);
+}
+
+#[test]
+fn query_cache_lru_evicts_hot_entry_not_used_recently() {
+    /// Includes some room for the keys, headers etc. for two entries.
+    const QUERY_CACHE_CAPACITY: usize = REPLY_SIZE * 3;
+    let mut test = builder_with_query_cache_capacity(QUERY_CACHE_CAPACITY)
+        .with_query_cache_eviction_policy(EvictionPolicy::Lru)
+        .build();
+    let id = test.universal_canister().unwrap();
+    let hot = wasm().reply_data(&[0; REPLY_SIZE / 2]).build();
+
+    for _ in 0..ITERATIONS {
+        test.non_replicated_query(id, "query", hot.clone()).unwrap();
+    }
+    for i in 1..=ITERATIONS {
+        let _res = test.non_replicated_query(
+            id,
+            "query",
+            wasm().reply_data(&[i as u8; REPLY_SIZE / 2]).build(),
+        );
     }
+    let m = query_cache_metrics(&test);
+    assert_eq!(ITERATIONS - 1, m.evicted_entries.get() as usize);
+    assert_eq!(
+        ITERATIONS - 1,
+        m.evicted_entries_by_policy
+            .with_label_values(&["lru"])
+            .get() as usize
+    );
+
+    // The hot entry is evicted, as it's the least recently used one.
+    test.non_replicated_query(id, "query", hot).unwrap();
+    assert_eq!(
+        ITERATIONS - 1,
+        query_cache_metrics(&test).hits.get() as usize
+    );
+}
+
+#[test]
+fn query_cache_fifo_evicts_oldest_entry_despite_hits() {
+    /// Includes some room for the keys, headers etc. for two entries.
+    const QUERY_CACHE_CAPACITY: usize = REPLY_SIZE * 3;
+    let mut test = builder_with_query_cache_capacity(QUERY_CACHE_CAPACITY)
+        .with_query_cache_eviction_policy(EvictionPolicy::Fifo)
+        .build();
+    let id = test.universal_canister().unwrap();
+    let oldest = wasm().reply_data(&[0; REPLY_SIZE / 2]).build();
+    let newer = wasm().reply_data(&[1; REPLY_SIZE / 2]).build();
+
+    test.non_replicated_query(id, "query", oldest.clone())
+        .unwrap();
+    test.non_replicated_query(id, "query", newer.clone())
+        .unwrap();
+    // The hit doesn't save the oldest entry from the eviction.
+    test.non_replicated_query(id, "query", oldest.clone())
+        .unwrap();
+    assert_eq!(1, query_cache_metrics(&test).hits.get());
+    test.non_replicated_query(id, "query", wasm().reply_data(&[2; REPLY_SIZE / 2]).build())
+        .unwrap();
+    assert_eq!(
+        1,
+        query_cache_metrics(&test)
+            .evicted_entries_by_policy
+            .with_label_values(&["fifo"])
+            .get()
+    );
+
+    test.non_replicated_query(id, "query", newer).unwrap();
+    assert_eq!(2, query_cache_metrics(&test).hits.get());
+    test.non_replicated_query(id, "query", oldest).unwrap();
+    assert_eq!(2, query_cache_metrics(&test).hits.get());
 }
 
 #[test]
@@ -851,43 +1373,1139 @@ fn query_cache_works_with_zero_cache_capacity() {
 }
 
 #[test]
-fn query_cache_reports_system_api_calls_metric() {
-    let q = wasm().cycles_balance().reply_data(&[42]);
-    for_query_and_composite_query(q, |mut test, a_id, _b_id, method, q| {
-        test.non_replicated_query(a_id, method, q).unwrap();
-        let m = &query_handler(&test).metrics.query_system_api_calls;
-        assert_eq!(
-            1,
-            m.with_label_values(&[metrics::SYSTEM_API_CANISTER_CYCLE_BALANCE])
-                .get()
-        );
-    });
-    let q = wasm().cycles_balance128().reply_data(&[42]);
-    for_query_and_composite_query(q, |mut test, a_id, _b_id, method, q| {
-        test.non_replicated_query(a_id, method, q).unwrap();
-        let m = &query_handler(&test).metrics.query_system_api_calls;
-        assert_eq!(
-            1,
-            m.with_label_values(&[metrics::SYSTEM_API_CANISTER_CYCLE_BALANCE128])
-                .get()
+fn query_cache_trim_to_evicts_lru_entries_and_keeps_capacity() {
+    let mut test = builder_with_query_caching().build();
+    let id = test.universal_canister().unwrap();
+
+    for i in 0..ITERATIONS {
+        // Every query is unique and should produce a new cache entry of the same size.
+        let _res = test.non_replicated_query(
+            id,
+            "query",
+            wasm().reply_data(&[i as u8; REPLY_SIZE / 2]).build(),
         );
-    });
-    let q = wasm().time().reply_data(&[42]);
-    for_query_and_composite_query(q, |mut test, a_id, _b_id, method, q| {
-        test.non_replicated_query(a_id, method, q).unwrap();
-        let m = &query_handler(&test).metrics.query_system_api_calls;
-        assert_eq!(1, m.with_label_values(&[metrics::SYSTEM_API_TIME]).get());
-    });
-}
+    }
+    let m = query_cache_metrics(&test);
+    assert_eq!(ITERATIONS, m.len.get() as usize);
+    assert_eq!(0, m.evicted_entries.get());
 
-#[test]
-fn composite_query_cache_reports_system_api_calls_metric() {
-    let mut test = builder_with_query_caching().build();
-    let a_id = test.universal_canister().unwrap();
-    let b_id = test.universal_canister().unwrap();
+    // Trim the cache to half of its current size.
+    let target_bytes = m.count_bytes.get() as u64 / 2;
+    test.state_mut().metadata.batch_time += Duration::from_secs(2);
+    let now = test.state().metadata.batch_time;
+    let evicted = query_cache(&test).trim_to(NumBytes::new(target_bytes), now);
 
-    let a = wasm()
-        // Fist group of System API calls.
+    // All the entries have the same size, so more than a half of them must be evicted.
+    assert_eq!(ITERATIONS - ITERATIONS / 2, evicted);
+    let m = query_cache_metrics(&test);
+    assert_eq!(evicted, m.evicted_entries.get() as usize);
+    assert_eq!(
+        evicted,
+        m.evicted_entries_duration.get_sample_count() as usize
+    );
+    assert_eq!(2.0, m.mean_entry_lifetime.get());
+    assert_eq!(ITERATIONS - evicted, m.len.get() as usize);
+    assert!(m.count_bytes.get() as u64 <= target_bytes);
+
+    // The capacity is unchanged, so the cache can grow back.
+    for i in 0..ITERATIONS {
+        let _res = test.non_replicated_query(
+            id,
+            "query",
+            wasm().reply_data(&[i as u8; REPLY_SIZE / 2]).build(),
+        );
+    }
+    let m = query_cache_metrics(&test);
+    assert_eq!(ITERATIONS, m.len.get() as usize);
+    assert!(m.count_bytes.get() as u64 > target_bytes);
+}
+
+#[test]
+fn query_cache_compact_reclaims_overhead_of_removed_entries() {
+    let mut test = builder_with_query_caching().build();
+    let id = test.universal_canister().unwrap();
+
+    for i in 0..ITERATIONS {
+        // Every query is unique and should produce a new cache entry of the same size.
+        let _res = test.non_replicated_query(
+            id,
+            "query",
+            wasm().reply_data(&[i as u8; REPLY_SIZE / 2]).build(),
+        );
+    }
+    assert_eq!(0, query_cache(&test).overhead_bytes());
+
+    // Evict all the entries and cache a single one back.
+    let now = test.state().metadata.batch_time;
+    let evicted = query_cache(&test).trim_to(NumBytes::new(0), now);
+    assert_eq!(ITERATIONS, evicted);
+    let _res =
+        test.non_replicated_query(id, "query", wasm().reply_data(&[0; REPLY_SIZE / 2]).build());
+    let m = query_cache_metrics(&test);
+    assert_eq!(1, m.len.get());
+    assert_eq!(0, m.compactions.get());
+    let count_bytes = m.count_bytes.get();
+    let total_bytes = query_cache(&test).count_bytes();
+    let overhead_bytes = query_cache(&test).overhead_bytes();
+    assert!(overhead_bytes > 0);
+
+    let reclaimed = query_cache(&test).compact();
+    assert_eq!(overhead_bytes as u64, reclaimed.get());
+    assert!(query_cache(&test).overhead_bytes() < overhead_bytes);
+    assert_eq!(
+        total_bytes - reclaimed.get() as usize,
+        query_cache(&test).count_bytes()
+    );
+
+    // The entries are kept.
+    let m = query_cache_metrics(&test);
+    assert_eq!(1, m.compactions.get());
+    assert_eq!(1, m.len.get());
+    assert_eq!(count_bytes, m.count_bytes.get());
+    let _res =
+        test.non_replicated_query(id, "query", wasm().reply_data(&[0; REPLY_SIZE / 2]).build());
+    assert_eq!(1, query_cache_metrics(&test).hits.get());
+
+    // Nothing to reclaim anymore.
+    assert_eq!(0, query_cache(&test).compact().get());
+    assert_eq!(2, query_cache_metrics(&test).compactions.get());
+}
+
+#[test]
+fn query_cache_snapshot_survives_cache_recreation() {
+    let mut test = builder_with_query_caching().build();
+    let id = test.universal_canister().unwrap();
+    let payload = wasm().reply_data(&[42; REPLY_SIZE / 2]).build();
+    let key = EntryKey {
+        source: user_test_id(0),
+        receiver: id,
+        method_name: "query".into(),
+        method_payload: payload.clone(),
+        cache_context: None,
+    };
+    let res = test.non_replicated_query(id, "query", payload.clone());
+    assert_eq!(1, query_cache_metrics(&test).misses.get());
+    let snapshot = query_handler(&test).export_query_cache_snapshot();
+
+    // Drop the entry and import it back through the handler.
+    let now = test.state().metadata.batch_time;
+    assert_eq!(
+        1,
+        query_handler(&test).trim_query_cache(NumBytes::new(0), now)
+    );
+    assert_eq!(
+        1,
+        query_handler(&test)
+            .import_query_cache_snapshot(&snapshot, test.state())
+            .unwrap()
+    );
+    assert_eq!(res, test.non_replicated_query(id, "query", payload));
+    let m = query_cache_metrics(&test);
+    assert_eq!(1, m.hits.get());
+    assert_eq!(1, m.misses.get());
+
+    // A fresh cache with the same configuration, i.e. after the handler is recreated.
+    let config = query_cache(&test).config();
+    let new_cache = || {
+        QueryCache::new(
+            &MetricsRegistry::new(),
+            config.enabled,
+            config.capacity,
+            config.max_expiry_time,
+            config.data_certificate_expiry_time,
+            config.verify_on_hit,
+            config.max_entries_per_method,
+            config.canonical_payload,
+            config.circuit_breaker_threshold,
+            config.circuit_breaker_cooldown,
+            config.stale_on_error_window,
+        )
+    };
+    let cache = new_cache();
+    assert_eq!(1, cache.import_snapshot(&snapshot, test.state()).unwrap());
+    assert_eq!(1, cache.metrics.len.get());
+    // The previously cached query hits without re-execution.
+    assert_eq!(
+        Some(Arc::new(res)),
+        cache.get_valid_result(&key, test.state(), None)
+    );
+    assert_eq!(1, cache.metrics.hits.get());
+    assert_eq!(0, cache.metrics.misses.get());
+
+    // The entries no longer valid for the current state are dropped.
+    test.canister_state_mut(id).system_state.canister_version += 1;
+    let cache = new_cache();
+    assert_eq!(0, cache.import_snapshot(&snapshot, test.state()).unwrap());
+    assert_eq!(0, cache.metrics.len.get());
+
+    // Malformed snapshots are rejected.
+    assert!(new_cache().import_snapshot(&[42], test.state()).is_err());
+}
+
+#[test]
+fn query_cache_config_reflects_builder_options() {
+    const QUERY_CACHE_CAPACITY: usize = 1_234_567;
+    let test = builder_with_query_cache_capacity(QUERY_CACHE_CAPACITY)
+        .with_query_cache_max_expiry_time(MAX_EXPIRY_TIME)
+        .with_query_cache_data_certificate_expiry_time(DATA_CERTIFICATE_EXPIRY_TIME)
+        .build();
+
+    let config = query_cache(&test).config();
+    assert!(config.enabled);
+    assert_eq!(config.capacity, NumBytes::new(QUERY_CACHE_CAPACITY as u64));
+    assert_eq!(
+        config.max_pinned_bytes.get(),
+        QUERY_CACHE_CAPACITY as u64 / 100 * super::MAX_PINNED_BYTES_PERCENT
+    );
+    assert_eq!(
+        config.max_stale_bytes.get(),
+        QUERY_CACHE_CAPACITY as u64 / 100 * super::MAX_STALE_BYTES_PERCENT
+    );
+    assert_eq!(config.max_expiry_time, MAX_EXPIRY_TIME);
+    assert_eq!(
+        config.data_certificate_expiry_time,
+        DATA_CERTIFICATE_EXPIRY_TIME
+    );
+    assert!(!config.verify_on_hit);
+    assert!(!config.source_filter);
+    assert_eq!(None, config.circuit_breaker_threshold);
+    assert_eq!(query_handler(&test).query_cache_config(), config);
+}
+
+#[test]
+fn query_cache_capacity_fraction_of_total_memory() {
+    const TOTAL_MEMORY: u64 = 8 * 1024 * 1024 * 1024;
+    let test = builder_with_query_caching()
+        .with_query_cache_capacity_fraction(0.25, TOTAL_MEMORY)
+        .build();
+    assert_eq!(
+        query_cache(&test).config().capacity,
+        NumBytes::new(TOTAL_MEMORY / 4)
+    );
+
+    // The capacity is clamped to sane bounds.
+    let test = builder_with_query_caching()
+        .with_query_cache_capacity_fraction(0.0, TOTAL_MEMORY)
+        .build();
+    assert_eq!(
+        query_cache(&test).config().capacity,
+        MIN_QUERY_CACHE_CAPACITY
+    );
+    let test = builder_with_query_caching()
+        .with_query_cache_capacity_fraction(1.0, u64::MAX)
+        .build();
+    assert_eq!(
+        query_cache(&test).config().capacity,
+        MAX_QUERY_CACHE_CAPACITY
+    );
+}
+
+#[test]
+fn query_cache_max_entries_per_method_evicts_lru_entries_of_the_method() {
+    const MAX_ENTRIES: usize = 3;
+    let mut test = builder_with_query_caching()
+        .with_query_cache_max_entries_per_method(MAX_ENTRIES)
+        .build();
+    let id = test.canister_from_wat(QUERY_CACHE_WAT).unwrap();
+    assert_eq!(
+        query_cache(&test).config().max_entries_per_method,
+        Some(MAX_ENTRIES)
+    );
+
+    // Cache an entry of another method first, so it's the least recently used.
+    test.non_replicated_query(id, "f2", vec![]).unwrap();
+    // Distinct payloads produce distinct cache entries for the same method.
+    for i in 0..=MAX_ENTRIES {
+        test.non_replicated_query(id, "f1", vec![i as u8]).unwrap();
+    }
+    let m = query_cache_metrics(&test);
+    assert_eq!(1, m.evicted_by_method_quota.get());
+    assert_eq!(1, m.evicted_entries.get());
+    assert_eq!((MAX_ENTRIES + 1) as i64, m.len.get());
+    let f1_entries = query_cache(&test)
+        .cache
+        .lock()
+        .unwrap()
+        .iter_lru()
+        .filter(|(key, _)| key.method_name == "f1")
+        .count();
+    assert_eq!(MAX_ENTRIES, f1_entries);
+
+    // The other method entry is unaffected.
+    test.non_replicated_query(id, "f2", vec![]).unwrap();
+    assert_eq!(1, query_cache_metrics(&test).hits.get());
+    // The most recent entries of the method are still cached.
+    test.non_replicated_query(id, "f1", vec![MAX_ENTRIES as u8])
+        .unwrap();
+    assert_eq!(2, query_cache_metrics(&test).hits.get());
+    // The least recently used entry of the method has been evicted.
+    test.non_replicated_query(id, "f1", vec![0]).unwrap();
+    assert_eq!(2, query_cache_metrics(&test).hits.get());
+}
+
+#[test]
+fn query_cache_canonical_payload_hits_differently_encoded_payloads() {
+    // The same `42: nat64` argument encoded canonically and with an unused type table entry.
+    let canonical = candid::Encode!(&42_u64).unwrap();
+    let mut non_canonical = b"DIDL\x01\x6d\x78\x01\x78".to_vec();
+    non_canonical.extend_from_slice(&42_u64.to_le_bytes());
+    assert_ne!(canonical, non_canonical);
+    assert_eq!(
+        candid::Decode!(&non_canonical, u64).unwrap(),
+        candid::Decode!(&canonical, u64).unwrap()
+    );
+
+    for canonical_payload in [true, false] {
+        let mut test = builder_with_query_caching()
+            .with_query_cache_canonical_payload(canonical_payload)
+            .build();
+        let id = test.canister_from_wat(QUERY_CACHE_WAT).unwrap();
+        assert_eq!(
+            query_cache(&test).config().canonical_payload,
+            canonical_payload
+        );
+
+        let res_1 = test.non_replicated_query(id, "f1", canonical.clone());
+        let res_2 = test.non_replicated_query(id, "f1", non_canonical.clone());
+        assert_eq!(res_1, res_2);
+        let m = query_cache_metrics(&test);
+        if canonical_payload {
+            assert_eq!(1, m.hits.get());
+            assert_eq!(1, m.misses.get());
+        } else {
+            assert_eq!(0, m.hits.get());
+            assert_eq!(2, m.misses.get());
+        }
+    }
+}
+
+#[test]
+fn query_cache_canonical_payload_keeps_non_candid_payloads() {
+    let mut test = builder_with_query_caching()
+        .with_query_cache_canonical_payload(true)
+        .build();
+    let id = test.universal_canister().unwrap();
+
+    // The Universal Canister payloads are not Candid, so they are keyed as is.
+    let res_1 = test.non_replicated_query(id, "query", wasm().reply_data(&[1]).build());
+    let res_2 = test.non_replicated_query(id, "query", wasm().reply_data(&[2]).build());
+    assert_ne!(res_1, res_2);
+    let m = query_cache_metrics(&test);
+    assert_eq!(0, m.hits.get());
+    assert_eq!(2, m.misses.get());
+}
+
+#[test]
+fn query_cache_payload_projection_hits_payloads_with_the_same_projection() {
+    // Drop the trailing nonce byte.
+    let mut test = builder_with_query_caching()
+        .with_query_cache_payload_projection(|payload| {
+            payload[..payload.len().saturating_sub(1)].to_vec()
+        })
+        .build();
+    let id = test.canister_from_wat(QUERY_CACHE_WAT).unwrap();
+    assert!(query_cache(&test).config().payload_projection);
+
+    let res_1 = test.non_replicated_query(id, "f1", vec![1, 2, 3, 0]);
+    assert_eq!(1, query_cache_metrics(&test).misses.get());
+    let res_2 = test.non_replicated_query(id, "f1", vec![1, 2, 3, 1]);
+    assert_eq!(1, query_cache_metrics(&test).hits.get());
+    assert_eq!(res_1, res_2);
+
+    // The payloads with different projections are still cached separately.
+    test.non_replicated_query(id, "f1", vec![1, 2, 4, 0])
+        .unwrap();
+    let m = query_cache_metrics(&test);
+    assert_eq!(1, m.hits.get());
+    assert_eq!(2, m.misses.get());
+}
+
+#[test]
+fn query_cache_source_filter_bypasses_filtered_sources() {
+    let mut test = builder_with_query_caching()
+        .with_query_cache_source_filter(|source| source != PrincipalId::new_anonymous())
+        .build();
+    let id = test.canister_from_wat(QUERY_CACHE_WAT).unwrap();
+    assert!(query_cache(&test).config().source_filter);
+    let query = |source| UserQuery {
+        source,
+        receiver: id,
+        method_name: "f1".into(),
+        method_payload: vec![],
+        ingress_expiry: 0,
+        nonce: None,
+    };
+
+    // The anonymous queries are never cached.
+    let anonymous = UserId::from(PrincipalId::new_anonymous());
+    for _ in 0..ITERATIONS {
+        test.query(query(anonymous), Arc::new(test.state().clone()), vec![])
+            .unwrap();
+    }
+    let m = query_cache_metrics(&test);
+    assert_eq!(0, m.hits.get());
+    assert_eq!(0, m.misses.get());
+    assert_eq!(0, m.len.get());
+
+    // The other sources are cached as usual.
+    for _ in 0..ITERATIONS {
+        test.query(
+            query(user_test_id(1)),
+            Arc::new(test.state().clone()),
+            vec![],
+        )
+        .unwrap();
+    }
+    let m = query_cache_metrics(&test);
+    assert_eq!(ITERATIONS - 1, m.hits.get() as usize);
+    assert_eq!(1, m.misses.get());
+    assert_eq!(1, m.len.get());
+}
+
+#[test]
+fn query_cache_never_serves_stale_results_after_update_calls() {
+    let mut test = builder_with_query_caching().build();
+    let id = test.universal_canister().unwrap();
+    let q = wasm().get_global_data().append_and_reply().build();
+
+    test.ingress(id, "update", wasm().set_global_data(&[1]).reply().build())
+        .unwrap();
+    let res_1 = test.non_replicated_query(id, "query", q.clone());
+    assert_eq!(res_1, Ok(WasmResult::Reply(vec![1])));
+    let m = query_cache_metrics(&test);
+    assert_eq!(0, m.hits.get());
+    assert_eq!(1, m.misses.get());
+
+    // Change the canister state without changing its code.
+    test.ingress(id, "update", wasm().set_global_data(&[2]).reply().build())
+        .unwrap();
+    let res_2 = test.non_replicated_query(id, "query", q);
+    assert_eq!(res_2, Ok(WasmResult::Reply(vec![2])));
+    let m = query_cache_metrics(&test);
+    assert_eq!(0, m.hits.get());
+    assert_eq!(2, m.misses.get());
+    assert_eq!(1, m.invalidated_entries_by_canister_version.get());
+    assert_eq!(0, m.invalidated_entries_by_module_hash.get());
+}
+
+#[test]
+fn query_cache_invalidates_entries_on_module_hash_change() {
+    /// The same query as in the `QUERY_CACHE_WAT`, but with a different reply.
+    const OTHER_WAT: &str = r#"
+    (module
+        (import "ic0" "msg_reply" (func $msg_reply))
+        (import "ic0" "msg_reply_data_append"
+            (func $msg_reply_data_append (param i32 i32)))
+        (memory 1)
+        (data (i32.const 0) "43")
+        (func (export "canister_query f1")
+            (call $msg_reply_data_append (i32.const 0) (i32.const 2))
+            (call $msg_reply)
+        )
+    )"#;
+    let mut test = builder_with_query_caching().build();
+    let id = test.canister_from_wat(QUERY_CACHE_WAT).unwrap();
+    let run_query = |test: &ExecutionTest| {
+        test.query(
+            UserQuery {
+                source: user_test_id(1),
+                receiver: id,
+                method_name: "f1".into(),
+                method_payload: vec![],
+                ingress_expiry: 0,
+                nonce: None,
+            },
+            Arc::new(test.state().clone()),
+            vec![],
+        )
+    };
+    assert_eq!(run_query(&test), Ok(WasmResult::Reply(b"42".to_vec())));
+    let version = test.canister_state(id).system_state.canister_version;
+
+    // Reinstall a different module, resetting the version to the cached one.
+    test.reinstall_canister(id, wat::parse_str(OTHER_WAT).unwrap())
+        .unwrap();
+    test.canister_state_mut(id).system_state.canister_version = version;
+
+    assert_eq!(run_query(&test), Ok(WasmResult::Reply(b"43".to_vec())));
+    let m = query_cache_metrics(&test);
+    assert_eq!(0, m.hits.get());
+    assert_eq!(2, m.misses.get());
+    assert_eq!(1, m.invalidated_entries_by_module_hash.get());
+    assert_eq!(0, m.invalidated_entries_by_canister_version.get());
+}
+
+#[test]
+fn query_cache_circuit_breaker_suspends_caching_of_invalidating_canister() {
+    const THRESHOLD: usize = 3;
+    const COOLDOWN: Duration = Duration::from_secs(60);
+    let mut test = builder_with_query_caching()
+        .with_query_cache_circuit_breaker(THRESHOLD, COOLDOWN)
+        .build();
+    let id = test.canister_from_wat(QUERY_CACHE_WAT).unwrap();
+    let run_query = |test: &ExecutionTest| {
+        test.query(
+            UserQuery {
+                source: user_test_id(1),
+                receiver: id,
+                method_name: "f1".into(),
+                method_payload: vec![],
+                ingress_expiry: 0,
+                nonce: None,
+            },
+            Arc::new(test.state().clone()),
+            vec![],
+        )
+        .unwrap()
+    };
+    let circuit_open = |test: &ExecutionTest| {
+        query_cache_metrics(test)
+            .circuit_open
+            .with_label_values(&[&id.to_string()])
+            .get()
+    };
+
+    // Every lookup finds the entry invalidated, as if the canister was changed by a heartbeat.
+    run_query(&test);
+    for _ in 0..THRESHOLD {
+        assert_eq!(0, circuit_open(&test));
+        test.canister_state_mut(id).system_state.canister_version += 1;
+        run_query(&test);
+    }
+    let m = query_cache_metrics(&test);
+    assert_eq!(0, m.hits.get());
+    assert_eq!(THRESHOLD + 1, m.misses.get() as usize);
+    assert_eq!(THRESHOLD, m.invalidated_entries.get() as usize);
+    assert_eq!(1, circuit_open(&test));
+
+    // The caching is suspended, so the cache is not even looked up.
+    for _ in 0..ITERATIONS {
+        run_query(&test);
+    }
+    let m = query_cache_metrics(&test);
+    assert_eq!(0, m.hits.get());
+    assert_eq!(THRESHOLD + 1, m.misses.get() as usize);
+
+    // After the cooldown, the caching is resumed.
+    test.state_mut().metadata.batch_time += COOLDOWN;
+    run_query(&test);
+    assert_eq!(0, circuit_open(&test));
+    assert_eq!(1, query_cache_metrics(&test).hits.get());
+}
+
+#[test]
+fn query_cache_coalesces_concurrent_identical_misses() {
+    /// The number of concurrent identical queries.
+    const QUERIES: usize = 16;
+    /// A query taking a while to execute, so the identical queries overlap.
+    const SLOW_WAT: &str = r#"
+    (module
+        (import "ic0" "msg_reply" (func $msg_reply))
+        (import "ic0" "msg_reply_data_append"
+            (func $msg_reply_data_append (param i32 i32)))
+        (memory 1)
+        (data (i32.const 0) "42")
+        (func (export "canister_query slow")
+            (local $i i32)
+            (loop $loop
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br_if $loop (i32.lt_u (local.get $i) (i32.const 10000000)))
+            )
+            (call $msg_reply_data_append (i32.const 0) (i32.const 2))
+            (call $msg_reply)
+        )
+    )"#;
+    let mut test = builder_with_query_caching()
+        .with_query_cache_coalesce_misses(true)
+        .build();
+    let id = test.canister_from_wat(SLOW_WAT).unwrap();
+    let state = Arc::new(test.state().clone());
+    let query_handler = query_handler(&test);
+    let barrier = Barrier::new(QUERIES);
+
+    let results: Vec<_> = std::thread::scope(|s| {
+        let handles: Vec<_> = (0..QUERIES)
+            .map(|_| {
+                s.spawn(|| {
+                    barrier.wait();
+                    query_handler.query(
+                        UserQuery {
+                            source: user_test_id(1),
+                            receiver: id,
+                            method_name: "slow".into(),
+                            method_payload: vec![],
+                            ingress_expiry: 0,
+                            nonce: None,
+                        },
+                        Labeled::new(Height::from(0), Arc::clone(&state)),
+                        vec![],
+                    )
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    // All the callers got the reply.
+    for result in results {
+        assert_eq!(result, Ok(WasmResult::Reply(b"42".to_vec())));
+    }
+    // Each query was either executed, coalesced or a hit, and some were coalesced.
+    let m = query_cache_metrics(&test);
+    assert!(m.coalesced.get() > 0);
+    assert_eq!(
+        QUERIES,
+        (m.misses.get() + m.coalesced.get() + m.hits.get()) as usize
+    );
+}
+
+#[test]
+fn query_cache_coalesces_only_results_valid_for_the_state() {
+    let mut test = builder_with_query_caching()
+        .with_query_cache_coalesce_misses(true)
+        .build();
+    let id = test.universal_canister().unwrap();
+    let key = EntryKey {
+        source: user_test_id(1),
+        receiver: id,
+        method_name: "method".into(),
+        method_payload: vec![],
+        cache_context: None,
+    };
+    let old_state = test.state().clone();
+    test.canister_state_mut(id).system_state.canister_version += 1;
+    let new_state = test.state();
+    let query_cache = query_cache(&test);
+    let InFlightJoin::Execute(Some(guard)) = query_cache.join_in_flight(&key, &old_state) else {
+        panic!("BUG: expected to execute the query");
+    };
+
+    let (old_state_join, new_state_join) = std::thread::scope(|s| {
+        let old_state_waiter = s.spawn(|| query_cache.join_in_flight(&key, &old_state));
+        let new_state_waiter = s.spawn(|| query_cache.join_in_flight(&key, new_state));
+        // Both identical queries joined the query in flight.
+        while query_cache
+            .in_flight
+            .lock()
+            .unwrap()
+            .get(&key)
+            .map_or(0, Arc::strong_count)
+            < 4
+        {
+            std::thread::yield_now();
+        }
+        let mut evaluated_stats = BTreeMap::new();
+        evaluated_stats.insert(id, QueryStats::default());
+        guard.finish(
+            &Arc::new(Ok(WasmResult::Reply(vec![42]))),
+            &old_state,
+            &SystemApiCallCounters::default(),
+            &evaluated_stats,
+        );
+        (
+            old_state_waiter.join().unwrap(),
+            new_state_waiter.join().unwrap(),
+        )
+    });
+
+    // Only the query of the same canister version gets the shared result.
+    assert!(matches!(
+        old_state_join,
+        InFlightJoin::Coalesced(result) if *result == Ok(WasmResult::Reply(vec![42]))
+    ));
+    assert!(matches!(new_state_join, InFlightJoin::Execute(None)));
+    assert_eq!(1, query_cache_metrics(&test).coalesced.get());
+}
+
+#[test]
+fn query_cache_serves_stale_reply_on_trap_within_window() {
+    const WINDOW: Duration = Duration::from_secs(10);
+    const TRAP_WAT: &str = r#"
+    (module
+        (func (export "canister_query f1") unreachable)
+    )"#;
+    let mut test = builder_with_query_caching()
+        .with_query_cache_stale_on_error_window(WINDOW)
+        .build();
+    let id = test.canister_from_wat(QUERY_CACHE_WAT).unwrap();
+    let run_query = |test: &ExecutionTest| {
+        test.query(
+            UserQuery {
+                source: user_test_id(1),
+                receiver: id,
+                method_name: "f1".into(),
+                method_payload: vec![],
+                ingress_expiry: 0,
+                nonce: None,
+            },
+            Arc::new(test.state().clone()),
+            vec![],
+        )
+    };
+    let good = run_query(&test);
+    assert_eq!(good, Ok(WasmResult::Reply(b"42".to_vec())));
+
+    // The canister starts trapping, but the last successful reply is served instead.
+    test.upgrade_canister(id, wat::parse_str(TRAP_WAT).unwrap())
+        .unwrap();
+    for _ in 0..ITERATIONS {
+        assert_eq!(good, run_query(&test));
+    }
+    let m = query_cache_metrics(&test);
+    assert_eq!(ITERATIONS, m.stale_on_error_served.get() as usize);
+    assert_eq!(0, m.hits.get());
+
+    // Once the window is over, the trap surfaces.
+    test.state_mut().metadata.batch_time += WINDOW + Duration::from_secs(1);
+    let err = run_query(&test).unwrap_err();
+    assert_eq!(ErrorCode::CanisterTrapped, err.code());
+    assert_eq!(
+        ITERATIONS,
+        query_cache_metrics(&test).stale_on_error_served.get() as usize
+    );
+}
+
+#[test]
+fn query_cache_stale_entries_take_at_most_max_stale_bytes() {
+    /// The stale entries fit all but the last reply, including the keys, headers etc.
+    const QUERY_CACHE_CAPACITY: usize = REPLY_SIZE * ITERATIONS * 10;
+    let test = builder_with_query_cache_capacity(QUERY_CACHE_CAPACITY)
+        .with_query_cache_stale_on_error_window(Duration::from_secs(10))
+        .build();
+    let query_cache = query_cache(&test);
+    let max_stale_bytes = query_cache.config().max_stale_bytes.get() as usize;
+
+    for i in 0..ITERATIONS {
+        let key = EntryKey {
+            source: user_test_id(1),
+            receiver: canister_test_id(i as u64),
+            method_name: "method".into(),
+            method_payload: vec![],
+            cache_context: None,
+        };
+        let value = EntryValue::new(
+            EntryEnv {
+                batch_time: time::GENESIS,
+                canisters_versions_balances_stats: vec![],
+                module_hashes: BTreeMap::new(),
+            },
+            Arc::new(Ok(WasmResult::Reply(vec![i as u8; REPLY_SIZE]))),
+            &SystemApiCallCounters::default(),
+            time::GENESIS,
+        );
+        query_cache.keep_stale(&key, value);
+
+        let stale = query_cache.stale.lock().unwrap();
+        assert!(stale.bytes <= max_stale_bytes);
+        assert_eq!(stale.bytes, super::map_count_bytes(&stale.entries));
+    }
+    assert_eq!(
+        ITERATIONS - 1,
+        query_cache.stale.lock().unwrap().entries.len()
+    );
+}
+
+#[test]
+fn query_cache_does_not_serve_entries_past_max_serve_age() {
+    const MAX_SERVE_AGE: Duration = Duration::from_secs(10);
+    let time_source = FastForwardTimeSource::new();
+    let mut test = builder_with_query_caching()
+        .with_query_cache_max_serve_age(MAX_SERVE_AGE)
+        .with_query_cache_time_source(time_source.clone())
+        .build();
+    let id = test.universal_canister().unwrap();
+    let q = wasm().reply_data(&[42]).build();
+
+    let res_1 = test.non_replicated_query(id, "query", q.clone());
+    assert_eq!(1, query_cache_metrics(&test).misses.get());
+
+    // The entry is served while it's young enough.
+    time_source.advance_time(MAX_SERVE_AGE);
+    let res_2 = test.non_replicated_query(id, "query", q.clone());
+    assert_eq!(1, query_cache_metrics(&test).hits.get());
+    assert_eq!(res_1, res_2);
+
+    // The environment is unchanged, but the entry is too old to be served.
+    time_source.advance_time(Duration::from_secs(1));
+    let res_3 = test.non_replicated_query(id, "query", q);
+    let m = query_cache_metrics(&test);
+    assert_eq!(1, m.hits.get());
+    assert_eq!(2, m.misses.get());
+    assert_eq!(1, m.invalidated_entries_by_max_serve_age.get());
+    assert_eq!(0, m.invalidated_entries_by_time.get());
+    assert_eq!(0, m.invalidated_entries_by_canister_version.get());
+    assert_eq!(res_1, res_3);
+}
+
+#[test]
+fn query_cache_stores_results_only_after_min_requests() {
+    let mut test = builder_with_query_caching()
+        .with_query_cache_min_requests(2)
+        .build();
+    let id = test.universal_canister().unwrap();
+
+    // A one-off query is executed, but not stored.
+    test.non_replicated_query(id, "query", wasm().reply_data(&[1]).build())
+        .unwrap();
+    let m = query_cache_metrics(&test);
+    assert_eq!(1, m.misses.get());
+    assert_eq!(1, m.misses_not_stored.get());
+    assert_eq!(0, m.len.get());
+
+    // A repeated query is stored on the second miss.
+    let q = wasm().reply_data(&[2]).build();
+    let res_1 = test.non_replicated_query(id, "query", q.clone());
+    assert_eq!(2, query_cache_metrics(&test).misses_not_stored.get());
+    assert_eq!(0, query_cache_metrics(&test).len.get());
+    let res_2 = test.non_replicated_query(id, "query", q.clone());
+    let m = query_cache_metrics(&test);
+    assert_eq!(3, m.misses.get());
+    assert_eq!(2, m.misses_not_stored.get());
+    assert_eq!(1, m.len.get());
+    assert_eq!(res_1, res_2);
+
+    // And served from then on.
+    let res_3 = test.non_replicated_query(id, "query", q);
+    assert_eq!(1, query_cache_metrics(&test).hits.get());
+    assert_eq!(res_1, res_3);
+}
+
+#[test]
+fn query_cache_reports_served_entry_age() {
+    let mut test = builder_with_query_caching().build();
+    let id = test.universal_canister().unwrap();
+    let query = UserQuery {
+        source: user_test_id(1),
+        receiver: id,
+        method_name: "query".into(),
+        method_payload: wasm().reply_data(&[42]).build(),
+        ingress_expiry: 0,
+        nonce: None,
+    };
+    let run_query = |test: &ExecutionTest| {
+        query_handler(test).query_with_cache_metadata(
+            query.clone(),
+            Labeled::new(Height::from(0), Arc::new(test.state().clone())),
+            vec![],
+            None,
+        )
+    };
+
+    // The executed query has no cache entry age.
+    let (res_1, metadata) = run_query(&test);
+    assert_eq!(None, metadata.served_entry_age_seconds);
+
+    // The served reply is unchanged, but comes with the age of the entry.
+    test.state_mut().metadata.batch_time += Duration::from_secs(2);
+    let (res_2, metadata) = run_query(&test);
+    assert_eq!(1, query_cache_metrics(&test).hits.get());
+    assert_eq!(res_1, res_2);
+    assert_eq!(Some(2.0), metadata.served_entry_age_seconds);
+}
+
+#[test]
+fn query_cache_shard_stats_spread_keys_evenly() {
+    /// The number of entries to push.
+    const ENTRIES: usize = 1_000;
+    /// The upper limit on the coefficient of variation of the per-shard entry counts.
+    const MAX_COEFFICIENT_OF_VARIATION: f64 = 0.3;
+    let test = builder_with_query_caching().build();
+    let query_cache = query_cache(&test);
+
+    for i in 0..ENTRIES {
+        let key = EntryKey {
+            source: user_test_id(1),
+            receiver: canister_test_id(i as u64),
+            method_name: "method".into(),
+            method_payload: vec![],
+            cache_context: None,
+        };
+        query_cache.push(
+            key,
+            &Arc::new(Ok(WasmResult::Reply(vec![]))),
+            test.state(),
+            &SystemApiCallCounters::default(),
+            &BTreeMap::new(),
+            0,
+        );
+    }
+    assert_eq!(ENTRIES, query_cache_metrics(&test).len.get() as usize);
+
+    let stats = query_cache.shard_stats();
+    assert_eq!(
+        ENTRIES,
+        stats.iter().map(|stat| stat.entries).sum::<usize>()
+    );
+    assert!(stats
+        .iter()
+        .all(|stat| (stat.entries == 0) == (stat.bytes.get() == 0)));
+
+    let mean = ENTRIES as f64 / stats.len() as f64;
+    let variance = stats
+        .iter()
+        .map(|stat| (stat.entries as f64 - mean).powi(2))
+        .sum::<f64>()
+        / stats.len() as f64;
+    let coefficient_of_variation = variance.sqrt() / mean;
+    assert!(
+        coefficient_of_variation < MAX_COEFFICIENT_OF_VARIATION,
+        "Uneven shard distribution: {:?}",
+        stats
+    );
+}
+
+#[test]
+fn query_cache_audit_passes_after_inserts_evictions_and_invalidations() {
+    /// Includes some room for the keys, headers etc.
+    const QUERY_CACHE_CAPACITY: usize = REPLY_SIZE * (ITERATIONS / 2);
+    let mut test = builder_with_query_cache_capacity(QUERY_CACHE_CAPACITY).build();
+    let id = test.universal_canister().unwrap();
+    assert_eq!(Ok(()), query_cache(&test).audit());
+
+    // The query must get the time, otherwise the entry won't be invalidated.
+    let invalidated = wasm().time().reply_data(&[0; REPLY_SIZE / 2]).build();
+    test.non_replicated_query(id, "query", invalidated.clone())
+        .unwrap();
+    for i in 1..ITERATIONS {
+        let _res = test.non_replicated_query(
+            id,
+            "query",
+            wasm().reply_data(&[i as u8; REPLY_SIZE / 2]).build(),
+        );
+        assert_eq!(Ok(()), query_cache(&test).audit());
+    }
+    let m = query_cache_metrics(&test);
+    assert!(m.evicted_entries.get() > 0);
+
+    test.non_replicated_query(id, "query", invalidated.clone())
+        .unwrap();
+    test.state_mut().metadata.batch_time += Duration::from_secs(1);
+    test.non_replicated_query(id, "query", invalidated).unwrap();
+    assert_eq!(1, query_cache_metrics(&test).invalidated_entries.get());
+    assert_eq!(Ok(()), query_cache(&test).audit());
+
+    let now = test.state().metadata.batch_time;
+    query_cache(&test).trim_to(NumBytes::new(0), now);
+    assert_eq!(Ok(()), query_cache(&test).audit());
+}
+
+#[test]
+fn query_cache_audit_detects_entry_both_pinned_and_in_lru() {
+    let mut test = builder_with_query_caching().build();
+    let id = test.universal_canister().unwrap();
+
+    let pinned_payload = wasm().reply_data(&[1; REPLY_SIZE / 2]).build();
+    let other_payload = wasm().reply_data(&[2; REPLY_SIZE / 2]).build();
+    let key = |payload: &Vec<u8>| EntryKey {
+        source: user_test_id(0),
+        receiver: id,
+        method_name: "query".into(),
+        method_payload: payload.clone(),
+        cache_context: None,
+    };
+    test.non_replicated_query(id, "query", pinned_payload.clone())
+        .unwrap();
+    test.non_replicated_query(id, "query", other_payload.clone())
+        .unwrap();
+    let query_cache = query_cache(&test);
+    assert!(query_cache.pin(&key(&pinned_payload)));
+    assert_eq!(Ok(()), query_cache.audit());
+
+    // Corrupt the cache by putting the pinned key back into the LRU cache.
+    {
+        let mut cache = query_cache.cache.lock().unwrap();
+        let value = cache.pop(&key(&other_payload)).unwrap();
+        cache.push(key(&pinned_payload), value);
+    }
+    let err = query_cache.audit().unwrap_err();
+    assert!(err.contains("both pinned and in the LRU cache"), "{}", err);
+}
+
+#[test]
+fn query_cache_pinned_entry_survives_eviction() {
+    /// Includes some room for the keys, headers etc.
+    const QUERY_CACHE_CAPACITY: usize = REPLY_SIZE * (ITERATIONS + 1);
+    let mut test = builder_with_query_cache_capacity(QUERY_CACHE_CAPACITY).build();
+    let id = test.universal_canister().unwrap();
+
+    let pinned_payload = wasm().reply_data(&[42; REPLY_SIZE / 2]).build();
+    let pinned_key = EntryKey {
+        source: user_test_id(0),
+        receiver: id,
+        method_name: "query".into(),
+        method_payload: pinned_payload.clone(),
+        cache_context: None,
+    };
+    // The entry must be cached before it can be pinned.
+    assert!(!query_cache(&test).pin(&pinned_key));
+    test.non_replicated_query(id, "query", pinned_payload.clone())
+        .unwrap();
+    assert!(query_cache(&test).pin(&pinned_key));
+    let m = query_cache_metrics(&test);
+    assert!(m.pinned_bytes.get() as usize > REPLY_SIZE);
+    assert_eq!(0, m.len.get());
+
+    // Flood the cache past its capacity.
+    for i in 0..ITERATIONS * 2 {
+        let _res = test.non_replicated_query(
+            id,
+            "query",
+            wasm().reply_data(&[i as u8; REPLY_SIZE / 2]).build(),
+        );
+    }
+    let m = query_cache_metrics(&test);
+    assert!(m.evicted_entries.get() > 0);
+    assert!(m.count_bytes.get() as usize <= QUERY_CACHE_CAPACITY);
+
+    // The pinned entry survives.
+    let hits_before = m.hits.get();
+    test.non_replicated_query(id, "query", pinned_payload)
+        .unwrap();
+    let m = query_cache_metrics(&test);
+    assert_eq!(hits_before + 1, m.hits.get());
+
+    // After unpinning, the entry is back in the LRU cache.
+    let now = test.state().metadata.batch_time;
+    assert!(query_cache(&test).unpin(&pinned_key, now));
+    assert!(!query_cache(&test).unpin(&pinned_key, now));
+    let m = query_cache_metrics(&test);
+    assert_eq!(0, m.pinned_bytes.get());
+}
+
+#[test]
+fn query_cache_pinned_entries_are_limited_by_capacity() {
+    const QUERY_CACHE_CAPACITY: usize = REPLY_SIZE * (ITERATIONS + 1);
+    let mut test = builder_with_query_cache_capacity(QUERY_CACHE_CAPACITY).build();
+    let id = test.universal_canister().unwrap();
+
+    let mut pinned = 0;
+    for i in 0..ITERATIONS {
+        let payload = wasm().reply_data(&[i as u8; REPLY_SIZE / 2]).build();
+        test.non_replicated_query(id, "query", payload.clone())
+            .unwrap();
+        let key = EntryKey {
+            source: user_test_id(0),
+            receiver: id,
+            method_name: "query".into(),
+            method_payload: payload,
+            cache_context: None,
+        };
+        if query_cache(&test).pin(&key) {
+            pinned += 1;
+        }
+    }
+
+    // Only a fraction of the capacity might be pinned.
+    assert!(pinned > 0);
+    assert!(pinned < ITERATIONS);
+    let m = query_cache_metrics(&test);
+    assert!(
+        m.pinned_bytes.get() as u64
+            <= QUERY_CACHE_CAPACITY as u64 / 100 * super::MAX_PINNED_BYTES_PERCENT
+    );
+}
+
+#[test]
+fn query_cache_verify_on_hit_accepts_deterministic_queries() {
+    let mut test = builder_with_query_caching()
+        .with_query_cache_verify_on_hit(true)
+        .build();
+    let id = test.universal_canister().unwrap();
+    let q = wasm().reply_data(&[42; REPLY_SIZE]).build();
+
+    for _ in 0..ITERATIONS {
+        let res = test.non_replicated_query(id, "query", q.clone());
+        assert_eq!(res, Ok(WasmResult::Reply(vec![42; REPLY_SIZE])));
+    }
+
+    let m = query_cache_metrics(&test);
+    assert_eq!(1, m.misses.get());
+    assert_eq!(ITERATIONS - 1, m.hits.get() as usize);
+    assert_eq!(0, m.verify_on_hit_mismatches.get());
+    // Every hit re-executes the query.
+    let query_handler_metrics = &query_handler(&test).metrics;
+    assert_eq!(
+        ITERATIONS as u64,
+        query_handler_metrics
+            .query_initial_call
+            .duration
+            .get_sample_count()
+    );
+}
+
+#[test]
+fn query_cache_reports_system_api_calls_metric() {
+    let q = wasm().cycles_balance().reply_data(&[42]);
+    for_query_and_composite_query(q, |mut test, a_id, _b_id, method, q| {
+        test.non_replicated_query(a_id, method, q).unwrap();
+        let m = &query_handler(&test).metrics.query_system_api_calls;
+        assert_eq!(
+            1,
+            m.with_label_values(&[metrics::SYSTEM_API_CANISTER_CYCLE_BALANCE])
+                .get()
+        );
+    });
+    let q = wasm().cycles_balance128().reply_data(&[42]);
+    for_query_and_composite_query(q, |mut test, a_id, _b_id, method, q| {
+        test.non_replicated_query(a_id, method, q).unwrap();
+        let m = &query_handler(&test).metrics.query_system_api_calls;
+        assert_eq!(
+            1,
+            m.with_label_values(&[metrics::SYSTEM_API_CANISTER_CYCLE_BALANCE128])
+                .get()
+        );
+    });
+    let q = wasm().time().reply_data(&[42]);
+    for_query_and_composite_query(q, |mut test, a_id, _b_id, method, q| {
+        test.non_replicated_query(a_id, method, q).unwrap();
+        let m = &query_handler(&test).metrics.query_system_api_calls;
+        assert_eq!(1, m.with_label_values(&[metrics::SYSTEM_API_TIME]).get());
+    });
+}
+
+#[test]
+fn composite_query_cache_bypasses_composite_queries_by_default() {
+    let mut test = ExecutionTestBuilder::new().with_composite_queries().build();
+    let a_id = test.universal_canister().unwrap();
+    let b_id = test.universal_canister().unwrap();
+    let b = wasm().get_global_data().append_and_reply().build();
+    let a = wasm()
+        .composite_query(b_id, call_args().other_side(b.clone()))
+        .build();
+
+    test.ingress(b_id, "update", wasm().set_global_data(&[1]).reply().build())
+        .unwrap();
+    let res_1 = test.non_replicated_query(a_id, "composite_query", a.clone());
+    assert_eq!(res_1, Ok(WasmResult::Reply(vec![1])));
+
+    // Change the state of the callee canister only.
+    test.ingress(b_id, "update", wasm().set_global_data(&[2]).reply().build())
+        .unwrap();
+    let res_2 = test.non_replicated_query(a_id, "composite_query", a);
+    assert_eq!(res_2, Ok(WasmResult::Reply(vec![2])));
+
+    let m = query_cache_metrics(&test);
+    assert_eq!(2, m.composite_query_bypasses.get());
+    assert_eq!(0, m.hits.get());
+    assert_eq!(0, m.misses.get());
+
+    // The regular queries are still cached.
+    test.non_replicated_query(b_id, "query", b.clone()).unwrap();
+    test.non_replicated_query(b_id, "query", b).unwrap();
+    let m = query_cache_metrics(&test);
+    assert_eq!(2, m.composite_query_bypasses.get());
+    assert_eq!(1, m.hits.get());
+    assert_eq!(1, m.misses.get());
+}
+
+#[test]
+fn composite_query_cache_reports_system_api_calls_metric() {
+    let mut test = builder_with_query_caching().build();
+    let a_id = test.universal_canister().unwrap();
+    let b_id = test.universal_canister().unwrap();
+
+    let a = wasm()
+        // Fist group of System API calls.
         .data_certificate()
         .data_certificate()
         .data_certificate()
@@ -1082,6 +2700,53 @@ fn query_cache_caches_errors() {
     });
 }
 
+#[test]
+fn query_cache_counts_error_hits_and_misses() {
+    let mut test = builder_with_query_caching().build();
+    let id = test.universal_canister().unwrap();
+    // The query deterministically traps.
+    let q = wasm().trap().build();
+
+    let res_1 = test.non_replicated_query(id, "query", q.clone());
+    assert!(res_1.is_err());
+    let m = query_cache_metrics(&test);
+    assert_eq!(1, m.misses.get());
+    assert_eq!(1, m.error_misses.get());
+    assert_eq!(0, m.error_hits.get());
+
+    // The second call is served from the cache.
+    let res_2 = test.non_replicated_query(id, "query", q);
+    let m = query_cache_metrics(&test);
+    assert_eq!(1, m.misses.get());
+    assert_eq!(1, m.hits.get());
+    assert_eq!(1, m.error_hits.get());
+    assert_eq!(res_1, res_2);
+}
+
+#[test]
+fn query_cache_does_not_cache_errors_if_disabled() {
+    let mut test = builder_with_query_caching()
+        .with_query_cache_errors(false)
+        .build();
+    let id = test.universal_canister().unwrap();
+    let q = wasm().trap().build();
+
+    let res_1 = test.non_replicated_query(id, "query", q.clone());
+    let res_2 = test.non_replicated_query(id, "query", q);
+    let m = query_cache_metrics(&test);
+    assert_eq!(2, m.misses.get());
+    assert_eq!(2, m.error_misses.get());
+    assert_eq!(0, m.hits.get());
+    assert_eq!(0, m.len.get());
+    assert_eq!(res_1, res_2);
+
+    // The replies are still cached.
+    let q = wasm().reply_data(&[42]).build();
+    test.non_replicated_query(id, "query", q.clone()).unwrap();
+    test.non_replicated_query(id, "query", q).unwrap();
+    assert_eq!(1, query_cache_metrics(&test).hits.get());
+}
+
 #[test]
 fn query_cache_never_caches_transient_errors() {
     let q = wasm();
@@ -59,7 +59,8 @@ use ic_registry_client_helpers::subnet::{SubnetListRegistry, SubnetRegistry};
 use ic_registry_keys::{
     make_canister_migrations_record_key, make_catch_up_package_contents_key, make_crypto_node_key,
     make_ecdsa_signing_subnet_list_key, make_node_record_key,
-    make_provisional_whitelist_record_key, make_routing_table_record_key, ROOT_SUBNET_ID_KEY,
+    make_provisional_whitelist_record_key, make_routing_table_record_key, make_subnet_record_key,
+    ROOT_SUBNET_ID_KEY,
 };
 use ic_registry_proto_data_provider::{ProtoRegistryDataProvider, INITIAL_REGISTRY_VERSION};
 use ic_registry_provisional_whitelist::ProvisionalWhitelist;
@@ -2116,6 +2117,32 @@ impl StateMachine {
         self.install_wasm_in_mode(canister_id, CanisterInstallMode::Upgrade, wasm, payload)
     }
 
+    /// Performs upgrade of the canister with the specified ID to the specified
+    /// Wasm code on behalf of the specified sender, which might not be a controller.
+    pub fn upgrade_canister_as(
+        &self,
+        sender: PrincipalId,
+        canister_id: CanisterId,
+        wasm: Vec<u8>,
+        payload: Vec<u8>,
+    ) -> Result<(), UserError> {
+        self.execute_ingress_as(
+            sender,
+            ic00::IC_00,
+            Method::InstallCode,
+            InstallCodeArgs::new(
+                CanisterInstallMode::Upgrade,
+                canister_id,
+                wasm,
+                payload,
+                None,
+                None,
+            )
+            .encode(),
+        )
+        .map(|_| ())
+    }
+
     /// Updates the settings of the given canister.
     ///
     /// This function is synchronous.
@@ -2420,6 +2447,31 @@ impl StateMachine {
         assert_eq!(next_version, self.registry_client.get_latest_version());
     }
 
+    /// Updates the subnet record so that the subnet hosts at most
+    /// `max_number_of_canisters` canisters. Zero means no limit.
+    pub fn set_max_number_of_canisters(&self, max_number_of_canisters: u64) {
+        let last_version = self.registry_client.get_latest_version();
+        let next_version = last_version.increment();
+
+        let mut subnet_record = self
+            .registry_client
+            .get_subnet_record(self.subnet_id, last_version)
+            .expect("malformed subnet record")
+            .expect("missing subnet record");
+        subnet_record.max_number_of_canisters = max_number_of_canisters;
+
+        self.registry_data_provider
+            .add(
+                &make_subnet_record_key(self.subnet_id),
+                next_version,
+                Some(subnet_record),
+            )
+            .unwrap();
+        self.registry_client.update_to_latest_version();
+
+        assert_eq!(next_version, self.registry_client.get_latest_version());
+    }
+
     /// Returns the subnet id of this state machine.
     pub fn get_subnet_id(&self) -> SubnetId {
         self.subnet_id
@@ -2649,6 +2701,25 @@ impl StateMachine {
         balance
     }
 
+    /// Removes cycle amount from the specified canister and returns the resulting cycle balance.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the specified canister does not exist.
+    pub fn remove_cycles(&self, canister_id: CanisterId, amount: u128) -> u128 {
+        let (height, mut state) = self.state_manager.take_tip();
+        let canister_state = state
+            .canister_state_mut(&canister_id)
+            .unwrap_or_else(|| panic!("Canister {} not found", canister_id));
+        canister_state
+            .system_state
+            .remove_cycles(Cycles::from(amount), CyclesUseCase::NonConsumed);
+        let balance = canister_state.system_state.balance().get();
+        self.state_manager
+            .commit_and_certify(state, height.increment(), CertificationScope::Full);
+        balance
+    }
+
     /// Returns sign with ECDSA contexts from internal subnet call context manager.
     pub fn sign_with_ecdsa_contexts(&self) -> BTreeMap<CallbackId, SignWithEcdsaContext> {
         let state = self.state_manager.get_latest_state().take();
@@ -1,8 +1,11 @@
 use ic_base_types::{NumBytes, NumSeconds, PrincipalId, SubnetId};
 use ic_config::embedders::MeteringType;
 use ic_config::{
-    embedders::Config as EmbeddersConfig, execution_environment::Config, flag_status::FlagStatus,
-    subnet_config::SchedulerConfig, subnet_config::SubnetConfig,
+    embedders::Config as EmbeddersConfig,
+    execution_environment::{query_cache_capacity_from_fraction, Config, EvictionPolicy},
+    flag_status::FlagStatus,
+    subnet_config::SchedulerConfig,
+    subnet_config::SubnetConfig,
 };
 use ic_constants::SMALL_APP_SUBNET_MAX_SIZE;
 use ic_cycles_account_manager::CyclesAccountManager;
@@ -12,11 +15,13 @@ pub use ic_execution_environment::ExecutionResponse;
 use ic_execution_environment::{
     execute_canister, CompilationCostHandling, ExecuteMessageResult, ExecutionEnvironment,
     Hypervisor, IngressFilterMetrics, IngressHistoryWriterImpl, InternalHttpQueryHandler,
-    RoundInstructions, RoundLimits,
+    QueryCachePayloadProjection, QueryCacheSourceFilter, RoundInstructions, RoundLimits,
+    SharedQueryResult,
 };
 use ic_interfaces::execution_environment::{
     ExecutionMode, IngressHistoryWriter, RegistryExecutionSettings, SubnetAvailableMemory,
 };
+use ic_interfaces::time_source::TimeSource;
 use ic_interfaces_state_manager::Labeled;
 use ic_logger::{replica_logger::no_op_logger, ReplicaLogger};
 use ic_management_canister_types::{
@@ -1063,6 +1068,34 @@ impl ExecutionTest {
         result
     }
 
+    /// Executes a non-replicated query just like `non_replicated_query()`,
+    /// returning the result shared with the query cache.
+    pub fn non_replicated_query_shared<S: ToString>(
+        &mut self,
+        canister_id: CanisterId,
+        method_name: S,
+        method_payload: Vec<u8>,
+    ) -> SharedQueryResult {
+        let state = Arc::new(self.state.take().unwrap());
+
+        let query = UserQuery {
+            source: user_test_id(0),
+            receiver: canister_id,
+            method_name: method_name.to_string(),
+            method_payload,
+            ingress_expiry: 0,
+            nonce: None,
+        };
+        let result = self.query_handler.query_shared(
+            query,
+            Labeled::new(Height::from(0), Arc::clone(&state)),
+            vec![],
+        );
+
+        self.state = Some(Arc::try_unwrap(state).unwrap());
+        result
+    }
+
     pub fn execute_response(
         &mut self,
         canister_id: CanisterId,
@@ -1502,6 +1535,24 @@ impl ExecutionTest {
         )
     }
 
+    /// Executes a query call on the given state with the specified query cache context.
+    ///
+    /// Queries with different cache contexts never share the query cache entries.
+    pub fn query_with_cache_context(
+        &self,
+        query: UserQuery,
+        state: Arc<ReplicatedState>,
+        data_certificate: Vec<u8>,
+        cache_context: Option<Vec<u8>>,
+    ) -> Result<WasmResult, UserError> {
+        self.query_handler.query_with_cache_context(
+            query,
+            Labeled::new(Height::from(0), state),
+            data_certificate,
+            cache_context,
+        )
+    }
+
     /// Returns a reference to the query handler of this test.
     ///
     /// Note that the return type is `Any` so that the caller is forced to
@@ -1604,6 +1655,10 @@ pub struct ExecutionTestBuilder {
     resource_saturation_scaling: usize,
     heap_delta_rate_limit: NumBytes,
     upload_wasm_chunk_instructions: NumInstructions,
+    query_cache_payload_projection: Option<QueryCachePayloadProjection>,
+    query_cache_source_filter: Option<QueryCacheSourceFilter>,
+    query_cache_time_source: Option<Arc<dyn TimeSource>>,
+    query_cache_capacity_fraction: Option<(f64, NumBytes)>,
 }
 
 impl Default for ExecutionTestBuilder {
@@ -1643,6 +1698,10 @@ impl Default for ExecutionTestBuilder {
             resource_saturation_scaling: 1,
             heap_delta_rate_limit: scheduler_config.heap_delta_rate_limit,
             upload_wasm_chunk_instructions: scheduler_config.upload_wasm_chunk_instructions,
+            query_cache_payload_projection: None,
+            query_cache_source_filter: None,
+            query_cache_time_source: None,
+            query_cache_capacity_fraction: None,
         }
     }
 }
@@ -1843,6 +1902,17 @@ impl ExecutionTestBuilder {
         self
     }
 
+    /// Sets the query cache capacity to the `fraction` of the `total_memory_bytes`
+    /// when building, clamped to sane bounds. Overrides `with_query_cache_capacity()`.
+    pub fn with_query_cache_capacity_fraction(
+        mut self,
+        fraction: f64,
+        total_memory_bytes: u64,
+    ) -> Self {
+        self.query_cache_capacity_fraction = Some((fraction, total_memory_bytes.into()));
+        self
+    }
+
     pub fn with_query_cache_max_expiry_time(mut self, max_expiry_time: Duration) -> Self {
         self.execution_config.query_cache_max_expiry_time = max_expiry_time;
         self
@@ -1854,6 +1924,122 @@ impl ExecutionTestBuilder {
         self
     }
 
+    pub fn with_query_cache_max_entries_per_method(mut self, max_entries: usize) -> Self {
+        self.execution_config.query_cache_max_entries_per_method = Some(max_entries);
+        self
+    }
+
+    pub fn with_query_cache_circuit_breaker(
+        mut self,
+        threshold: usize,
+        cooldown: Duration,
+    ) -> Self {
+        self.execution_config.query_cache_circuit_breaker_threshold = Some(threshold);
+        self.execution_config.query_cache_circuit_breaker_cooldown = cooldown;
+        self
+    }
+
+    pub fn with_query_cache_expected_entries(mut self, expected_entries: usize) -> Self {
+        self.execution_config.query_cache_expected_entries = Some(expected_entries);
+        self
+    }
+
+    pub fn with_query_cache_stale_on_error_window(mut self, window: Duration) -> Self {
+        self.execution_config.query_cache_stale_on_error_window = Some(window);
+        self
+    }
+
+    pub fn with_query_cache_max_serve_age(mut self, max_serve_age: Duration) -> Self {
+        self.execution_config.query_cache_max_serve_age = Some(max_serve_age);
+        self
+    }
+
+    pub fn with_query_cache_min_requests(mut self, min_requests: usize) -> Self {
+        self.execution_config.query_cache_min_requests = min_requests;
+        self
+    }
+
+    pub fn with_query_cache_eviction_policy(mut self, eviction_policy: EvictionPolicy) -> Self {
+        self.execution_config.query_cache_eviction_policy = eviction_policy;
+        self
+    }
+
+    pub fn with_query_cache_per_canister_capacity(mut self, capacity_bytes: u64) -> Self {
+        self.execution_config.query_cache_per_canister_capacity = Some(capacity_bytes.into());
+        self
+    }
+
+    pub fn with_query_cache_max_entry_size(mut self, max_entry_size_bytes: u64) -> Self {
+        self.execution_config.query_cache_max_entry_size = Some(max_entry_size_bytes.into());
+        self
+    }
+
+    pub fn with_query_cache_errors(mut self, cache_errors: bool) -> Self {
+        self.execution_config.query_cache_errors = if cache_errors {
+            FlagStatus::Enabled
+        } else {
+            FlagStatus::Disabled
+        };
+        self
+    }
+
+    pub fn with_query_cache_time_source(mut self, time_source: Arc<dyn TimeSource>) -> Self {
+        self.query_cache_time_source = Some(time_source);
+        self
+    }
+
+    pub fn with_query_cache_payload_projection(
+        mut self,
+        projection: impl Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+    ) -> Self {
+        self.query_cache_payload_projection = Some(Arc::new(projection));
+        self
+    }
+
+    pub fn with_query_cache_source_filter(
+        mut self,
+        filter: impl Fn(PrincipalId) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.query_cache_source_filter = Some(Arc::new(filter));
+        self
+    }
+
+    pub fn with_query_cache_canonical_payload(mut self, canonical_payload: bool) -> Self {
+        self.execution_config.query_cache_canonical_payload = if canonical_payload {
+            FlagStatus::Enabled
+        } else {
+            FlagStatus::Disabled
+        };
+        self
+    }
+
+    pub fn with_query_cache_coalesce_misses(mut self, coalesce_misses: bool) -> Self {
+        self.execution_config.query_cache_coalesce_misses = if coalesce_misses {
+            FlagStatus::Enabled
+        } else {
+            FlagStatus::Disabled
+        };
+        self
+    }
+
+    pub fn with_query_cache_composite_queries(mut self, composite_queries: bool) -> Self {
+        self.execution_config.query_cache_composite_queries = if composite_queries {
+            FlagStatus::Enabled
+        } else {
+            FlagStatus::Disabled
+        };
+        self
+    }
+
+    pub fn with_query_cache_verify_on_hit(mut self, verify_on_hit: bool) -> Self {
+        self.execution_config.query_cache_verify_on_hit = if verify_on_hit {
+            FlagStatus::Enabled
+        } else {
+            FlagStatus::Disabled
+        };
+        self
+    }
+
     pub fn with_query_stats(mut self) -> Self {
         self.execution_config.query_stats_aggregation = FlagStatus::Enabled;
         self
@@ -2004,7 +2190,12 @@ impl ExecutionTestBuilder {
         self.build_common(routing_table)
     }
 
-    fn build_common(self, routing_table: Arc<RoutingTable>) -> ExecutionTest {
+    fn build_common(mut self, routing_table: Arc<RoutingTable>) -> ExecutionTest {
+        if let Some((fraction, total_memory)) = self.query_cache_capacity_fraction {
+            self.execution_config.query_cache_capacity =
+                query_cache_capacity_from_fraction(fraction, total_memory);
+        }
+
         let mut state = ReplicatedState::new(self.own_subnet_id, self.subnet_type);
 
         let mut subnets = vec![self.own_subnet_id, self.nns_subnet_id];
@@ -2127,7 +2318,7 @@ impl ExecutionTestBuilder {
         let (query_stats_collector, _) =
             ic_query_stats::init_query_stats(self.log.clone(), &config, &metrics_registry);
 
-        let query_handler = InternalHttpQueryHandler::new(
+        let mut query_handler = InternalHttpQueryHandler::new(
             self.log.clone(),
             hypervisor,
             self.subnet_type,
@@ -2137,6 +2328,15 @@ impl ExecutionTestBuilder {
             Arc::clone(&cycles_account_manager),
             query_stats_collector,
         );
+        if let Some(projection) = self.query_cache_payload_projection {
+            query_handler = query_handler.with_query_cache_payload_projection(projection);
+        }
+        if let Some(filter) = self.query_cache_source_filter {
+            query_handler = query_handler.with_query_cache_source_filter(filter);
+        }
+        if let Some(time_source) = self.query_cache_time_source {
+            query_handler = query_handler.with_query_cache_time_source(time_source);
+        }
         ExecutionTest {
             state: Some(state),
             message_id: 0,
@@ -1,4 +1,5 @@
 use ic_types::{CountBytes, NumBytes};
+use std::collections::HashSet;
 use std::hash::Hash;
 
 /// The upper bound on cache item size and cache capacity.
@@ -6,6 +7,10 @@ use std::hash::Hash;
 /// do not overflow.
 const MAX_SIZE: usize = usize::MAX / 2;
 
+/// The estimated size of a single slot of the internal hash map:
+/// a pair of pointers (key reference and boxed entry) and a control byte.
+const SLOT_SIZE: usize = 2 * std::mem::size_of::<usize>() + 1;
+
 /// A cache with bounded memory capacity that evicts items using the
 /// least-recently used eviction policy. It guarantees that the sum of
 /// sizes of the cached items does not exceed the pre-configured capacity.
@@ -17,6 +22,10 @@ where
     cache: lru::LruCache<K, V>,
     capacity: usize,
     size: usize,
+    /// The largest number of items since the last `shrink_to_fit()`.
+    /// The internal hash map never shrinks on its own, so it still holds
+    /// the slots for that many items.
+    peak_len: usize,
 }
 
 impl<K, V> CountBytes for LruCache<K, V>
@@ -43,6 +52,26 @@ where
             cache: lru::LruCache::unbounded(),
             capacity,
             size: 0,
+            peak_len: 0,
+        };
+        lru_cache.check_invariants();
+        lru_cache
+    }
+
+    /// Constructs a new LRU cache with the given capacity and the internal hash map
+    /// pre-allocated for `expected_len` items, so pushing up to that many items
+    /// never grows (i.e. rehashes) the map.
+    /// The capacity must not exceed `MAX_SIZE = (2^63 - 1)`.
+    pub fn with_expected_len(capacity: NumBytes, expected_len: usize) -> Self {
+        let capacity = capacity.get() as usize;
+        assert!(capacity <= MAX_SIZE);
+        let lru_cache = Self {
+            // The bounded underlying cache pre-allocates its map.
+            // It becomes unbounded once it's full, see `push()`.
+            cache: lru::LruCache::new(expected_len),
+            capacity,
+            size: 0,
+            peak_len: 0,
         };
         lru_cache.check_invariants();
         lru_cache
@@ -59,6 +88,12 @@ where
         self.cache.get(key)
     }
 
+    /// Returns the value corresponding to the given key,
+    /// without updating the LRU order.
+    pub fn peek(&self, key: &K) -> Option<&V> {
+        self.cache.peek(key)
+    }
+
     /// Pushes a key-value pair into the cache. If an entry with key `k` already exists in
     /// the cache or other cache entries are evicted (due to the cache capacity),
     /// then it returns the old entry's key-value pairs. Otherwise, returns an empty vector.
@@ -66,7 +101,13 @@ where
         let size = key.count_bytes() + value.count_bytes();
         assert!(size <= MAX_SIZE);
 
+        // The underlying cache must never evict items on its own, so once its
+        // pre-allocated map is full, it becomes unbounded and grows as usual.
+        if self.cache.len() >= self.cache.cap() {
+            self.cache.resize(usize::MAX);
+        }
         let removed_entry = self.cache.push(key, value);
+        self.peak_len = self.peak_len.max(self.cache.len());
         if let Some((removed_key, removed_value)) = &removed_entry {
             let removed_size = removed_key.count_bytes() + removed_value.count_bytes();
             debug_assert!(self.size >= removed_size);
@@ -116,11 +157,90 @@ where
         self.cache.is_empty()
     }
 
+    /// Returns an iterator over the key-value pairs from the least recently used
+    /// to the most recently used one. It does not update the LRU order.
+    pub fn iter_lru(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.cache.iter().rev()
+    }
+
+    /// Evicts the least recently used items until the sum of the sizes
+    /// of the cached items is not greater than the `target_size`.
+    /// The cache capacity stays unchanged.
+    /// Returns the vector of evicted key-value pairs.
+    pub fn trim_to(&mut self, target_size: NumBytes) -> Vec<(K, V)> {
+        let target_size = (target_size.get() as usize).min(self.capacity);
+        let evicted_entries = self.evict_to(target_size);
+        self.check_invariants();
+        evicted_entries
+    }
+
+    /// Returns the estimated size in bytes of the internal hash map slots
+    /// left over from the removed items.
+    pub fn overhead_bytes(&self) -> usize {
+        (self.peak_len - self.cache.len()) * SLOT_SIZE
+    }
+
+    /// Shrinks the internal hash map to fit the current items, without
+    /// evicting any of them.
+    /// Returns the estimated number of reclaimed bytes.
+    pub fn shrink_to_fit(&mut self) -> usize {
+        let reclaimed_bytes = self.overhead_bytes();
+        // Resizing the underlying cache shrinks its map, so resize it down
+        // to the current length and back to the unbounded capacity.
+        self.cache.resize(self.cache.len());
+        self.cache.resize(usize::MAX);
+        self.peak_len = self.cache.len();
+        self.check_invariants();
+        reclaimed_bytes
+    }
+
+    /// Checks the consistency of the internal structures: the tracked size is
+    /// the sum of the item sizes and within the capacity, and the LRU list
+    /// holds every item exactly once.
+    /// Unlike the debug assertions, it runs in release builds as well.
+    /// Returns the description of the first violation found.
+    pub fn audit(&self) -> Result<(), String> {
+        let mut keys = HashSet::with_capacity(self.cache.len());
+        let mut size = 0;
+        for (key, value) in self.cache.iter() {
+            if !keys.insert(key) {
+                return Err("duplicate key in the LRU list".to_string());
+            }
+            size += key.count_bytes() + value.count_bytes();
+        }
+        if keys.len() != self.cache.len() {
+            return Err(format!(
+                "LRU list length {} does not match the number of items {}",
+                keys.len(),
+                self.cache.len()
+            ));
+        }
+        if size != self.size {
+            return Err(format!(
+                "sum of item sizes {} does not match the tracked size {}",
+                size, self.size
+            ));
+        }
+        if self.size > self.capacity {
+            return Err(format!(
+                "tracked size {} exceeds the capacity {}",
+                self.size, self.capacity
+            ));
+        }
+        Ok(())
+    }
+
     /// Evicts as many items as needed to restore the capacity guarantee.
     /// Returns the vector of evicted key-value pairs.
     fn evict(&mut self) -> Vec<(K, V)> {
+        self.evict_to(self.capacity)
+    }
+
+    /// Evicts as many items as needed to fit into the `target_size`.
+    /// Returns the vector of evicted key-value pairs.
+    fn evict_to(&mut self, target_size: usize) -> Vec<(K, V)> {
         let mut ret = vec![];
-        while self.size > self.capacity {
+        while self.size > target_size {
             match self.cache.pop_lru() {
                 Some((key, value)) => {
                     let size = key.count_bytes() + value.count_bytes();
@@ -146,6 +266,7 @@ where
                 .sum::<usize>()
         );
         debug_assert!(self.size <= self.capacity);
+        debug_assert!(self.cache.len() <= self.peak_len);
     }
 }
 
@@ -372,6 +493,18 @@ mod tests {
         assert!(lru.get(&Key(1)).is_none());
     }
 
+    #[test]
+    fn lru_cache_peek_does_not_update_lru_order() {
+        let mut lru = LruCache::<Key, ValueSize>::new(NumBytes::new(10));
+        lru.push(Key(0), ValueSize(0, 5));
+        lru.push(Key(1), ValueSize(1, 5));
+        assert_eq!(lru.peek(&Key(0)), Some(&ValueSize(0, 5)));
+        assert_eq!(lru.peek(&Key(2)), None);
+        // The peeked entry is still the least recently used one.
+        let evicted = lru.push(Key(2), ValueSize(2, 5));
+        assert_eq!(evicted, vec![(Key(0), ValueSize(0, 5))]);
+    }
+
     #[test]
     fn lru_cache_count_bytes_and_len() {
         let mut lru = LruCache::<Key, ValueSize>::new(NumBytes::new(10));
@@ -395,4 +528,120 @@ mod tests {
         assert_eq!(0, lru.len());
         assert!(lru.is_empty());
     }
+
+    #[test]
+    fn lru_cache_trim_to() {
+        let mut lru = LruCache::<Key, ValueSize>::new(NumBytes::new(10));
+        for i in 0..5 {
+            lru.push(Key(i), ValueSize(i, 2));
+        }
+        assert_eq!(10, lru.count_bytes());
+
+        let evicted = lru.trim_to(NumBytes::new(5));
+        // The least recently used entries are evicted first.
+        assert_eq!(
+            evicted,
+            vec![
+                (Key(0), ValueSize(0, 2)),
+                (Key(1), ValueSize(1, 2)),
+                (Key(2), ValueSize(2, 2))
+            ]
+        );
+        assert_eq!(4, lru.count_bytes());
+        assert_eq!(2, lru.len());
+
+        // The capacity is unchanged, so the cache can grow back.
+        for i in 5..8 {
+            lru.push(Key(i), ValueSize(i, 2));
+        }
+        assert_eq!(10, lru.count_bytes());
+        assert_eq!(5, lru.len());
+
+        // Trimming above the current size is a no-op.
+        let evicted = lru.trim_to(NumBytes::new(100));
+        assert_eq!(evicted, vec![]);
+        assert_eq!(10, lru.count_bytes());
+    }
+
+    #[test]
+    fn lru_cache_audit() {
+        let mut lru = LruCache::<Key, ValueSize>::new(NumBytes::new(10));
+        assert_eq!(lru.audit(), Ok(()));
+        for i in 0..8 {
+            lru.push(Key(i), ValueSize(i, 3));
+            assert_eq!(lru.audit(), Ok(()));
+        }
+        lru.pop(&Key(7));
+        assert_eq!(lru.audit(), Ok(()));
+        lru.trim_to(NumBytes::new(3));
+        assert_eq!(lru.audit(), Ok(()));
+
+        lru.size += 1;
+        assert!(lru.audit().unwrap_err().contains("tracked size"));
+        lru.size = lru.capacity + 1;
+        lru.cache
+            .put(Key(100), ValueSize(100, lru.capacity + 1 - 3));
+        assert!(lru.audit().unwrap_err().contains("exceeds the capacity"));
+    }
+
+    #[test]
+    fn lru_cache_shrink_to_fit() {
+        let mut lru = LruCache::<Key, ValueSize>::new(NumBytes::new(100));
+        for i in 0..10 {
+            lru.push(Key(i), ValueSize(i, 2));
+        }
+        assert_eq!(0, lru.overhead_bytes());
+
+        lru.trim_to(NumBytes::new(4));
+        assert_eq!(2, lru.len());
+        assert_eq!(8 * SLOT_SIZE, lru.overhead_bytes());
+
+        // Shrinking keeps all the remaining entries.
+        assert_eq!(8 * SLOT_SIZE, lru.shrink_to_fit());
+        assert_eq!(0, lru.overhead_bytes());
+        assert_eq!(4, lru.count_bytes());
+        assert_eq!(2, lru.len());
+        assert!(lru.get(&Key(8)).is_some());
+        assert!(lru.get(&Key(9)).is_some());
+
+        // Nothing to reclaim anymore.
+        assert_eq!(0, lru.shrink_to_fit());
+
+        // The capacity is unchanged, so the cache can grow back.
+        for i in 10..20 {
+            lru.push(Key(i), ValueSize(i, 2));
+        }
+        assert_eq!(12, lru.len());
+        lru.clear();
+        assert_eq!(12 * SLOT_SIZE, lru.shrink_to_fit());
+    }
+
+    #[test]
+    fn lru_cache_iter_lru() {
+        let mut lru = LruCache::<Key, ValueSize>::new(NumBytes::new(10));
+        for i in 0..3 {
+            lru.push(Key(i), ValueSize(i, 2));
+        }
+        lru.get(&Key(0));
+
+        let keys: Vec<_> = lru.iter_lru().map(|(key, _value)| key.0).collect();
+        assert_eq!(keys, vec![1, 2, 0]);
+        // Iterating does not change the LRU order.
+        let keys: Vec<_> = lru.iter_lru().map(|(key, _value)| key.0).collect();
+        assert_eq!(keys, vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn lru_cache_with_expected_len_grows_past_the_expected_len() {
+        // Once full, the cache keeps growing without evicting anything.
+        let mut lru = LruCache::<Key, ValueSize>::with_expected_len(NumBytes::new(10), 2);
+        for i in 0..10 {
+            assert_eq!(lru.push(Key(i), ValueSize(i, 1)), vec![]);
+        }
+        assert_eq!(lru.len(), 10);
+        assert_eq!(
+            lru.push(Key(10), ValueSize(10, 1)),
+            vec![(Key(0), ValueSize(0, 1))]
+        );
+    }
 }
@@ -0,0 +1,65 @@
+//! Observes the growths of the map of `LruCache::with_expected_len` by counting
+//! the heap allocations, which takes a global allocator, hence a test binary of its own.
+
+use ic_types::{CountBytes, NumBytes};
+use ic_utils_lru_cache::LruCache;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Counts the heap allocations of each thread.
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[derive(Debug, Eq, Hash, PartialEq)]
+struct Key(u32);
+
+impl CountBytes for Key {
+    fn count_bytes(&self) -> usize {
+        0
+    }
+}
+
+#[derive(Debug, Eq, Hash, PartialEq)]
+struct ValueSize(u32, usize);
+
+impl CountBytes for ValueSize {
+    fn count_bytes(&self) -> usize {
+        self.1
+    }
+}
+
+#[test]
+fn lru_cache_with_expected_len_does_not_grow_the_map() {
+    const EXPECTED_LEN: u32 = 1_000;
+    let capacity = NumBytes::new(EXPECTED_LEN as u64);
+    let allocations_to_push = |mut lru: LruCache<Key, ValueSize>| {
+        let before = ALLOCATIONS.with(Cell::get);
+        for i in 0..EXPECTED_LEN {
+            lru.push(Key(i), ValueSize(i, 1));
+        }
+        ALLOCATIONS.with(Cell::get) - before
+    };
+
+    // Every item is boxed, so pushing an item allocates once, plus the map growths.
+    let lru = LruCache::<Key, ValueSize>::with_expected_len(capacity, EXPECTED_LEN as usize);
+    assert_eq!(allocations_to_push(lru), EXPECTED_LEN as usize);
+    let lru = LruCache::<Key, ValueSize>::new(capacity);
+    assert!(allocations_to_push(lru) > EXPECTED_LEN as usize);
+}